@@ -0,0 +1,824 @@
+use mongodb::{Collection, Database};
+use poem::{handler, Error, IntoResponse, Request, Response};
+use poem::http::header::CACHE_CONTROL;
+use poem::http::StatusCode;
+use poem::web::{Data, Json, Path, Query};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::database::admin_db::{get_index_usage, IndexUsageEntry};
+use crate::database::access_log_db::FileAccessLog;
+use crate::database::activity_db::{get_activity_timeline, ActivityPage, TimelineCursor};
+use crate::database::auth_event_db::{verify_auth_event_chain, AuthEvent, AuthEventLog, ChainReport};
+use crate::database::file_db::DocumentEntry;
+use crate::database::maintenance_db::{get_maintenance_job, insert_maintenance_job, run_checksum_migration, run_vacuum, ChecksumProgress, MaintenanceJob};
+use mongodb::gridfs::GridFsBucket;
+use crate::database::file_metadata_db::{sync_file_metadata, FileMetadata, MetadataSyncReport};
+use crate::api_handlers::{extract_user, parse_object_id, query_error};
+use crate::database::user_db::{bulk_delete_users, find_user, find_users, search_users, UserBatch, UsernameMatch, UserSummary, migrate_plaintext_passwords, modify_user_roles, repair_username_index, BulkDeleteResult, User, UsernameIndexRepair};
+use crate::database::file_db::{get_document_ids_for_users, list_all_documents, AdminFileEntry, UploadIpFilter};
+use crate::config::{BulkLimits, QueryConfig};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use crate::database::file_version_db::FileVersion;
+use crate::api_handlers::file_handlers::remove_file;
+use crate::services::event_bus::EventBus;
+use crate::database::corruption_db::{get_corruption_reports, CorruptionReport, CorruptionReportEntry};
+use crate::auth::permissions::check_permission;
+use crate::api_handlers::validation::{check_item_count, MAX_USERNAME_LENGTH};
+use crate::storage::Storage;
+
+// Handles GET requests to /admin/index-usage, reporting how often each MongoDB index is used.
+//
+// # Arguments
+// - `db`: Shared MongoDB database injected using Poem's `Data`.
+//
+// # Returns
+// - `200 OK` with a JSON list of `IndexUsageEntry`, one per index across all collections.
+//   Entries with `"unused": true` have not been accessed since the last server restart and
+//   are candidates for removal.
+// - `500 Internal Server Error` if the statistics couldn't be collected.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn index_usage(
+    db: Data<&Arc<Database>>,
+) -> Result<Json<Vec<IndexUsageEntry>>, Error> {
+    get_index_usage(&db)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+#[derive(Serialize)]
+pub struct SystemVersion {
+    version: &'static str,
+    git_commit: &'static str,
+    build_date: &'static str,
+    rust_version: &'static str,
+}
+
+// Handles GET requests to /admin/system/version, reporting exactly which build is deployed.
+//
+// # Returns
+// - `200 OK` with the crate version, the git commit and date it was built from, and the minimum
+//   supported Rust version. `git_commit` is "unknown" when built outside a git repository.
+//   The response is sent with `Cache-Control: no-store`, so it always reflects the running server.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn system_version() -> Response {
+    let version = SystemVersion {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("GIT_HASH"),
+        build_date: env!("BUILD_DATE"),
+        rust_version: env!("CARGO_PKG_RUST_VERSION"),
+    };
+    Json(version)
+        .with_header(CACHE_CONTROL, "no-store")
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PasswordMigrationQuery {
+    hash_in_place: Option<bool>,
+}
+
+// Handles POST requests to /admin/migrate/passwords, hashing passwords stored before hashing was introduced.
+//
+// # Arguments
+// - `Query(query)`: `?hash_in_place=false` only flags the users with `must_change_password`
+//   instead of also hashing their plaintext passwords. Defaults to true.
+//
+// # Returns
+// - `200 OK` with `{ "migrated": n }`, the number of users with a plaintext password.
+//   Running the migration again reports 0 once every password is hashed.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn migrate_passwords(
+    Query(query): Query<PasswordMigrationQuery>,
+    db: Data<&Arc<Collection<User>>>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let migrated = migrate_plaintext_passwords(&db, query.hash_in_place.unwrap_or(true)).await?;
+    Ok(Json(serde_json::json!({ "migrated": migrated })))
+}
+
+// Handles POST requests to /admin/migrate/checksums, computing the SHA-256 of files stored before
+// checksums were recorded.
+//
+// Every file has to be read, so the migration runs in the background like /admin/maintenance/vacuum,
+// and its counts can be followed at /admin/maintenance/jobs/:id. An interrupted migration is
+// resumed by starting it again, as files that already have a checksum are skipped.
+//
+// # Returns
+// - `202 Accepted` with `{ "job_id": "<id>" }`.
+// - `500 Internal Server Error` if the job couldn't be recorded.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn migrate_checksums(
+    req: &Request,
+    documents: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    bucket: Data<&GridFsBucket>,
+    jobs: Data<&Arc<Collection<MaintenanceJob>>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), Error> {
+    let admin = extract_user(req)?;
+
+    let mut job = MaintenanceJob::new("checksum_migration", &admin.username);
+    job.checksums = Some(ChecksumProgress::default());
+    let job_id = insert_maintenance_job(&jobs, &job)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let (documents, metadata, storage, bucket, jobs) =
+        (documents.0.clone(), metadata.0.clone(), storage.0.clone(), bucket.0.clone(), jobs.0.clone());
+    tokio::spawn(async move {
+        if let Err(e) = run_checksum_migration(&documents, &metadata, &storage, &bucket, &jobs, job_id).await {
+            tracing::error!("Maintenance job {} failed: {}", job_id, e);
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "job_id": job_id.to_hex() }))))
+}
+
+#[derive(Deserialize)]
+pub struct RoleChangeRequest {
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+// Handles PATCH requests to /admin/users/:name/roles, adding and removing roles of a user.
+//
+// # Arguments
+// - `Path(name)`: The name of the user to change.
+// - `Json(body)`: `{ "add": ["editor"], "remove": ["user"] }`. Both lists are optional.
+//
+// # Returns
+// - `200 OK` with `{ "role": [...] }`, the roles of the user after the change.
+// - `400 Bad Request` if a role is both added and removed, if the user would be left without
+//   any role, or if an admin tries to remove their own admin role.
+// - `404 Not Found` if the user doesn't exist.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn update_user_roles(
+    req: &Request,
+    Path(name): Path<String>,
+    Json(body): Json<RoleChangeRequest>,
+    db: Data<&Arc<Collection<User>>>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let admin = extract_user(req)?;
+
+    if body.add.iter().any(|role| body.remove.contains(role)) {
+        return Err(Error::from_string("A role can't be both added and removed", StatusCode::BAD_REQUEST));
+    }
+    if admin.username == name && body.remove.iter().any(|role| role == "admin") {
+        return Err(Error::from_string("You can't remove your own admin role", StatusCode::BAD_REQUEST));
+    }
+
+    let roles = modify_user_roles(&db, &name, &body.add, &body.remove).await?;
+    Ok(Json(serde_json::json!({ "role": roles })))
+}
+
+// How many files /admin/files returns by default, and at most.
+const DEFAULT_ADMIN_FILE_LIMIT: usize = 100;
+const MAX_ADMIN_FILE_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct AdminFileQuery {
+    upload_ip: Option<String>,
+    upload_ip_cidr: Option<String>,
+    limit: Option<usize>,
+}
+
+// Handles GET requests to /admin/files, listing the files of every user, newest first.
+//
+// # Arguments
+// - `Query(query)`: `?upload_ip=203.0.113.7` or `?upload_ip_cidr=10.0.0.0/8` only lists files
+//   uploaded from that address or range, and `?limit=N` (at most MAX_ADMIN_FILE_LIMIT) caps the listing.
+//
+// Files uploaded before upload IPs were recorded never match an IP filter.
+//
+// # Returns
+// - `200 OK` with `[{ "id", "filename", "user", "content_type", "size_bytes", "upload_ip", "uploaded_at" }]`.
+// - `400 Bad Request` if the address or range is malformed, or both filters are sent.
+// - `503 Service Unavailable` if the listing takes longer than QUERY_MAX_TIME_MS.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn list_files(
+    Query(query): Query<AdminFileQuery>,
+    documents: Data<&Arc<Collection<DocumentEntry>>>,
+    queries: Data<&QueryConfig>,
+) -> Result<Json<Vec<AdminFileEntry>>, Error> {
+    let upload_ip = match (&query.upload_ip, &query.upload_ip_cidr) {
+        (Some(_), Some(_)) => {
+            return Err(Error::from_string("Send either upload_ip or upload_ip_cidr, not both", StatusCode::BAD_REQUEST));
+        }
+        (Some(ip), None) => Some(UploadIpFilter::Exact(
+            ip.parse::<IpAddr>()
+                .map_err(|_| Error::from_string("upload_ip is not a valid IP address", StatusCode::BAD_REQUEST))?,
+        )),
+        (None, Some(range)) => Some(UploadIpFilter::Range(
+            range
+                .parse::<IpNet>()
+                .map_err(|_| Error::from_string("upload_ip_cidr is not a valid CIDR range", StatusCode::BAD_REQUEST))?,
+        )),
+        (None, None) => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_ADMIN_FILE_LIMIT).clamp(1, MAX_ADMIN_FILE_LIMIT);
+
+    list_all_documents(&documents, upload_ip.as_ref(), limit, &queries)
+        .await
+        .map(Json)
+        .map_err(query_error)
+}
+
+// How many reports /admin/corruption-reports returns by default, and at most.
+const DEFAULT_CORRUPTION_REPORTS: i64 = 100;
+const MAX_CORRUPTION_REPORTS: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct CorruptionReportQuery {
+    limit: Option<i64>,
+}
+
+// Handles GET requests to /admin/corruption-reports, listing the files /files/:id/verify found corrupted.
+//
+// # Arguments
+// - `Query(query)`: `?limit=100` - the number of reports to return, capped at MAX_CORRUPTION_REPORTS.
+//
+// # Returns
+// - `200 OK` with `[{ "file_id", "detected_at", "stored_hash", "computed_hash" }]`, newest first.
+//   `computed_hash` is null when the content couldn't be found at all.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn corruption_reports(
+    Query(query): Query<CorruptionReportQuery>,
+    reports: Data<&Arc<Collection<CorruptionReport>>>,
+) -> Result<Json<Vec<CorruptionReportEntry>>, Error> {
+    let limit = query.limit.unwrap_or(DEFAULT_CORRUPTION_REPORTS).clamp(1, MAX_CORRUPTION_REPORTS);
+    get_corruption_reports(&reports, limit)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+#[derive(Deserialize)]
+pub struct BulkDeleteRequest {
+    usernames: Vec<String>,
+}
+
+// Handles DELETE requests to /admin/users/bulk, deleting several users and all their files.
+//
+// # Arguments
+// - `Json(body)`: `{ "usernames": ["alice", "bob"] }`, at most BULK_MAX_ITEMS names.
+//
+// The files are deleted before the users, so a request that fails halfway can be sent again.
+//
+// # Returns
+// - `200 OK` with `{ "deleted_count": n, "not_found": ["<username>", ...] }`.
+// - `400 Bad Request` if no usernames are sent or the caller is among them, or with `too_many_items`
+//   if more than BULK_MAX_ITEMS are sent.
+// - `500 Internal Server Error` if a DB error occurs. Files deleted before the error stay deleted.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn bulk_delete(
+    req: &Request,
+    Json(body): Json<BulkDeleteRequest>,
+    users: Data<&Arc<Collection<User>>>,
+    documents: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    versions: Data<&Arc<Collection<FileVersion>>>,
+    bucket: Data<&GridFsBucket>,
+    events: Data<&EventBus>,
+    bulk: Data<&BulkLimits>,
+) -> Result<Json<BulkDeleteResult>, Error> {
+    let admin = extract_user(req)?;
+    check_item_count(&bulk, body.usernames.len())?;
+
+    let mut usernames = body.usernames;
+    usernames.sort();
+    usernames.dedup();
+    if usernames.is_empty() {
+        return Err(Error::from_string("Send at least one username", StatusCode::BAD_REQUEST));
+    }
+    if usernames.contains(&admin.username) {
+        return Err(Error::from_string("You can't delete yourself", StatusCode::BAD_REQUEST));
+    }
+
+    let files = get_document_ids_for_users(&documents, &usernames)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    for (id, owner) in files {
+        remove_file(&documents, &metadata, &storage, &versions, &bucket, &events, id, &owner).await?;
+    }
+
+    bulk_delete_users(&users, &usernames).await.map(Json)
+}
+
+// The most users /admin/users/search returns.
+const MAX_USER_SEARCH_RESULTS: i64 = 50;
+
+#[derive(Deserialize)]
+pub struct UserSearchQuery {
+    q: Option<String>,
+}
+
+// Handles GET requests to /admin/users/search, finding users by part of their name.
+//
+// # Arguments
+// - `Query(query)`: `?q=ali` matches every username containing "ali", ignoring case. The text is
+//   matched literally, so characters like `.` or `*` have no special meaning.
+//
+// # Returns
+// - `200 OK` with at most MAX_USER_SEARCH_RESULTS users sorted by username, without their passwords.
+// - `400 Bad Request` if `q` is missing, empty or longer than a username can be.
+// - `503 Service Unavailable` if the search takes longer than QUERY_MAX_TIME_MS.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn user_search(
+    Query(query): Query<UserSearchQuery>,
+    users: Data<&Arc<Collection<User>>>,
+    queries: Data<&QueryConfig>,
+) -> Result<Json<Vec<UserSummary>>, Error> {
+    let q = query.q.unwrap_or_default();
+    if q.is_empty() || q.chars().count() > MAX_USERNAME_LENGTH {
+        return Err(Error::from_string(
+            format!("q must be between 1 and {} characters", MAX_USERNAME_LENGTH),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    search_users(&users, &q, UsernameMatch::Anywhere, 0, MAX_USER_SEARCH_RESULTS, &queries)
+        .await
+        .map(Json)
+        .map_err(query_error)
+}
+
+const DEFAULT_USER_SEARCH_PAGE_SIZE: i64 = 20;
+const MAX_USER_SEARCH_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct UserPrefixSearchQuery {
+    q: Option<String>,
+    page: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct UserSearchPage {
+    users: Vec<UserSummary>,
+    page: u64,
+    limit: i64,
+    // Whether there is a page after this one.
+    has_more: bool,
+}
+
+// Handles GET requests to /users/search, finding users by the start of their name a page at a time.
+//
+// # Arguments
+// - `Query(query)`: `?q=ali` matches every username starting with "ali", ignoring case, and is
+//   matched literally like in /admin/users/search. `?page=1` (counting from 1) and `?limit=20` (at
+//   most MAX_USER_SEARCH_PAGE_SIZE) pick the page.
+//
+// # Returns
+// - `200 OK` with `{ "users": [...], "page": 1, "limit": 20, "has_more": false }`, sorted by
+//   username and without passwords.
+// - `400 Bad Request` if `q` is missing, empty or longer than a username can be, or `page` is 0.
+// - `503 Service Unavailable` if the search takes longer than QUERY_MAX_TIME_MS.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn user_prefix_search(
+    Query(query): Query<UserPrefixSearchQuery>,
+    users: Data<&Arc<Collection<User>>>,
+    queries: Data<&QueryConfig>,
+) -> Result<Json<UserSearchPage>, Error> {
+    let q = query.q.unwrap_or_default();
+    if q.is_empty() || q.chars().count() > MAX_USERNAME_LENGTH {
+        return Err(Error::from_string(
+            format!("q must be between 1 and {} characters", MAX_USERNAME_LENGTH),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let page = query.page.unwrap_or(1);
+    if page == 0 {
+        return Err(Error::from_string("page starts at 1", StatusCode::BAD_REQUEST));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_USER_SEARCH_PAGE_SIZE).clamp(1, MAX_USER_SEARCH_PAGE_SIZE);
+
+    // One more than asked for, to tell whether there is another page.
+    let skip = (page - 1).saturating_mul(limit as u64);
+    let found = search_users(&users, &q, UsernameMatch::Prefix, skip, limit + 1, &queries)
+        .await
+        .map_err(query_error)?;
+    Ok(Json(search_page(found, page, limit)))
+}
+
+// Builds a page out of up to `limit + 1` users, the one past the limit only telling that there
+// is another page.
+fn search_page(mut found: Vec<UserSummary>, page: u64, limit: i64) -> UserSearchPage {
+    let has_more = found.len() as i64 > limit;
+    found.truncate(limit as usize);
+    UserSearchPage { users: found, page, limit, has_more }
+}
+
+#[derive(Deserialize)]
+pub struct UserBatchRequest {
+    usernames: Vec<String>,
+}
+
+// Handles POST requests to /users/batch, looking up several users at once, e.g. to show who the
+// users in a table are.
+//
+// # Arguments
+// - `Json(body)`: `{ "usernames": ["alice", "bob"] }`, at most BULK_MAX_ITEMS names.
+//
+// # Returns
+// - `200 OK` with `{ "users": [...], "not_found": ["<username>", ...] }`. Passwords are left out.
+// - `400 Bad Request` if no usernames are sent, or with `too_many_items` if more than
+//   BULK_MAX_ITEMS are sent.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn users_batch(
+    Json(body): Json<UserBatchRequest>,
+    users: Data<&Arc<Collection<User>>>,
+    bulk: Data<&BulkLimits>,
+) -> Result<Json<UserBatch>, Error> {
+    check_item_count(&bulk, body.usernames.len())?;
+
+    let mut usernames = body.usernames;
+    usernames.sort();
+    usernames.dedup();
+    if usernames.is_empty() {
+        return Err(Error::from_string("Send at least one username", StatusCode::BAD_REQUEST));
+    }
+
+    find_users(&users, &usernames).await.map(Json)
+}
+
+// Handles GET requests to /admin/maintenance/metadata-sync, recreating missing file metadata.
+//
+// File listings are served from the `file_metadata` collection, which is written after the file
+// itself. If that write failed, the file exists but is missing from its owner's listing.
+//
+// # Returns
+// - `200 OK` with `{ "checked": n, "repaired": ["<file id>", ...] }`.
+// - `500 Internal Server Error` if a DB error occurs. Metadata repaired before the error is kept.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn metadata_sync(
+    documents: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+) -> Result<Json<MetadataSyncReport>, Error> {
+    sync_file_metadata(&documents, &metadata)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Handles POST requests to /admin/repair/username-index, making sure no two users share a name and
+// recreating the unique username index if it was dropped or is no longer unique.
+//
+// Duplicates have to be resolved by the admin before the index can be created, e.g. by removing all
+// but one of the users by their id in the database. The request can then be sent again.
+//
+// # Returns
+// - `200 OK` with `{ "index": "intact" }`, or `{ "index": "recreated" }` if it had to be created.
+// - `409 Conflict` with `{ "error": "duplicate_usernames", "duplicates": [{ "username", "count", "ids": [...] }] }`
+//   if usernames are taken by several users. The index is left as it is.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn repair_username_index_handler(users: Data<&Arc<Collection<User>>>) -> Result<Response, Error> {
+    let repair = repair_username_index(&users)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(repair_response(repair))
+}
+
+fn repair_response(repair: UsernameIndexRepair) -> Response {
+    match repair {
+        UsernameIndexRepair::Intact => Json(serde_json::json!({ "index": "intact" })).into_response(),
+        UsernameIndexRepair::Recreated => Json(serde_json::json!({ "index": "recreated" })).into_response(),
+        UsernameIndexRepair::Conflicts(duplicates) => {
+            let body = serde_json::json!({ "error": "duplicate_usernames", "duplicates": duplicates });
+            (StatusCode::CONFLICT, Json(body)).into_response()
+        }
+    }
+}
+
+// How many events /admin/users/:name/activity-timeline returns per page by default, and at most.
+const DEFAULT_TIMELINE_PAGE_SIZE: i64 = 50;
+const MAX_TIMELINE_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct ActivityTimelineQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    page_size: Option<i64>,
+    after: Option<String>,
+}
+
+// Handles GET requests to /admin/users/:name/activity-timeline, listing everything a user did.
+//
+// # Arguments
+// - `Path(name)`: The name of the user. Users that no longer exist can be looked up as well.
+// - `Query(query)`: `?from=` and `?to=` (RFC 3339) limit the time range, `?page_size=50` (at most
+//   MAX_TIMELINE_PAGE_SIZE) and `?after=<next_cursor>` page through the events.
+//
+// # Returns
+// - `200 OK` with `{ "events": [{ timestamp, event_type, details }], "next_cursor": "..." | null }`,
+//   newest first. Logins are listed as `login`/`login_failed`, file events as `file_<action>`.
+// - `400 Bad Request` if the cursor is malformed.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn activity_timeline(
+    Path(name): Path<String>,
+    Query(query): Query<ActivityTimelineQuery>,
+    auth_events: Data<&Arc<Collection<AuthEvent>>>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+) -> Result<Json<ActivityPage>, Error> {
+    let after = match &query.after {
+        Some(cursor) => Some(
+            TimelineCursor::parse(cursor)
+                .ok_or_else(|| Error::from_string("Invalid cursor", StatusCode::BAD_REQUEST))?,
+        ),
+        None => None,
+    };
+    let page_size = query.page_size.unwrap_or(DEFAULT_TIMELINE_PAGE_SIZE).clamp(1, MAX_TIMELINE_PAGE_SIZE);
+
+    get_activity_timeline(&auth_events, &access_log, &name, query.from, query.to, after, page_size)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Handles GET requests to /admin/audit/verify, checking that no auth event has been changed,
+// removed or reordered since it was recorded.
+//
+// # Returns
+// - `200 OK` with `{ valid, checked, head_hash, first_broken }`, where `first_broken` is
+//   `{ chain_seq, id, reason }` for the first event that doesn't check out, or `null`. Only events
+//   recorded with AUDIT_HASH_CHAIN are checked.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn verify_audit_chain(auth_events: Data<&AuthEventLog>) -> Result<Json<ChainReport>, Error> {
+    verify_auth_event_chain(&auth_events)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+#[derive(Deserialize)]
+pub struct PermissionCheckQuery {
+    resource: String,
+    action: String,
+}
+
+#[derive(Serialize)]
+pub struct PermissionCheck {
+    allowed: bool,
+    reason: String,
+}
+
+// Handles GET requests to /admin/users/:name/permissions-check, telling whether a user may perform
+// an action, without performing it. Useful for debugging access problems.
+//
+// # Arguments
+// - `Path(name)`: The name of the user.
+// - `Query(query)`: `?resource=files&action=upload`. See `auth::permissions` for the known actions.
+//
+// # Returns
+// - `200 OK` with `{ "allowed": bool, "reason": "..." }`, evaluated against the roles stored for the
+//   user. Tokens issued before a role change still carry the old roles until they expire.
+// - `400 Bad Request` if `resource` or `action` is missing.
+// - `404 Not Found` if the user doesn't exist.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn permissions_check(
+    Path(name): Path<String>,
+    Query(query): Query<PermissionCheckQuery>,
+    db: Data<&Arc<Collection<User>>>,
+) -> Result<Json<PermissionCheck>, Error> {
+    let user = find_user(&db, &name)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+    let (allowed, reason) = check_permission(&user.role, &query.resource, &query.action);
+    Ok(Json(PermissionCheck { allowed, reason }))
+}
+
+#[derive(Deserialize)]
+pub struct VacuumQuery {
+    dry_run: Option<bool>,
+}
+
+// Handles POST requests to /admin/maintenance/vacuum, compacting every collection to release the
+// space left behind by deleted documents.
+//
+// `compact` can run for a long time on large collections, so the work happens in the background
+// and its progress is recorded in the `maintenance_jobs` collection, see `maintenance_job`.
+//
+// # Arguments
+// - `Query(query)`: `?dry_run=true` only validates the collections and estimates how much space
+//   compacting them would release.
+//
+// # Returns
+// - `202 Accepted` with `{ "job_id": "<id>" }`.
+// - `500 Internal Server Error` if the job couldn't be recorded.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn vacuum(
+    req: &Request,
+    Query(query): Query<VacuumQuery>,
+    db: Data<&Arc<Database>>,
+    jobs: Data<&Arc<Collection<MaintenanceJob>>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), Error> {
+    let admin = extract_user(req)?;
+    let dry_run = query.dry_run.unwrap_or(false);
+    let job_type = if dry_run { "vacuum_dry_run" } else { "vacuum" };
+
+    let job_id = insert_maintenance_job(&jobs, &MaintenanceJob::new(job_type, &admin.username))
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let db = db.0.clone();
+    let jobs = jobs.0.clone();
+    tokio::spawn(async move {
+        if let Err(e) = run_vacuum(&db, &jobs, job_id, dry_run).await {
+            tracing::error!("Maintenance job {} failed: {}", job_id, e);
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "job_id": job_id.to_hex() }))))
+}
+
+// Handles GET requests to /admin/maintenance/jobs/:id, reporting the progress of a maintenance job.
+//
+// # Returns
+// - `200 OK` with the job, including the result of every collection processed so far, or the
+//   counts of a checksum migration.
+// - `400 Bad Request` if the id is malformed, `404 Not Found` if there is no such job.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn maintenance_job(
+    Path(id): Path<String>,
+    jobs: Data<&Arc<Collection<MaintenanceJob>>>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let id = parse_object_id(&id)?;
+
+    let job = get_maintenance_job(&jobs, id)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+    Ok(Json(serde_json::json!({
+        "id": id.to_hex(),
+        "job_type": job.job_type,
+        "status": job.status,
+        "requested_by": job.requested_by,
+        "started_at": job.started_at,
+        "finished_at": job.finished_at,
+        "collections": job.collections,
+        "checksums": job.checksums,
+        "error": job.error,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_handlers::testing::{bearer, unreachable_database};
+    use crate::auth::middleware::JwtMiddleware;
+    use crate::config::Config;
+    use crate::database::user_db::DuplicateUsername;
+    use poem::http::header::AUTHORIZATION;
+    use poem::test::TestClient;
+    use poem::{Endpoint, EndpointExt, Route, get, post};
+
+    async fn batch_client() -> TestClient<impl Endpoint> {
+        let db = unreachable_database().await;
+        TestClient::new(
+            Route::new()
+                .at("/users/batch", post(users_batch))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(Arc::new(db.collection::<User>("users")))
+                .data(BulkLimits { max_items: 2 }),
+        )
+    }
+
+    #[tokio::test]
+    async fn user_batches_are_for_admins_and_capped() {
+        let client = batch_client().await;
+        let batch = |usernames: &[&str]| serde_json::json!({ "usernames": usernames });
+
+        client
+            .post("/users/batch")
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .body_json(&batch(&["bob"]))
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        for usernames in [&[][..], &["a", "b", "c"]] {
+            client
+                .post("/users/batch")
+                .header(AUTHORIZATION, bearer("root", &["admin"]))
+                .body_json(&batch(usernames))
+                .send()
+                .await
+                .assert_status(StatusCode::BAD_REQUEST);
+        }
+        // Past every check, so it fails on the database.
+        client
+            .post("/users/batch")
+            .header(AUTHORIZATION, bearer("root", &["admin"]))
+            .body_json(&batch(&["bob", "bob"]))
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    async fn search_client() -> TestClient<impl Endpoint> {
+        let db = unreachable_database().await;
+        TestClient::new(
+            Route::new()
+                .at("/users/search", get(user_prefix_search))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(Arc::new(db.collection::<User>("users")))
+                .data(Config::load().queries),
+        )
+    }
+
+    #[tokio::test]
+    async fn user_searches_need_an_admin_and_a_query() {
+        let client = search_client().await;
+        let search = |path: &'static str, roles: &'static [&'static str]| {
+            client.get(path).header(AUTHORIZATION, bearer("root", roles)).send()
+        };
+
+        search("/users/search?q=ali", &["user"]).await.assert_status(StatusCode::FORBIDDEN);
+        for path in ["/users/search", "/users/search?q=", "/users/search?q=ali&page=0"] {
+            search(path, &["admin"]).await.assert_status(StatusCode::BAD_REQUEST);
+        }
+        // Past every check, so it fails on the database.
+        search("/users/search?q=ali&page=2&limit=1000", &["admin"])
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn search_pages_tell_whether_more_users_follow() {
+        let users = |count: usize| -> Vec<UserSummary> {
+            (0..count)
+                .map(|i| bson::from_document(bson::doc! { "username": format!("alice{}", i), "role": ["user"] }).unwrap())
+                .collect()
+        };
+
+        let page = search_page(users(3), 1, 2);
+        let names: Vec<_> = page.users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!((names, page.has_more), (vec!["alice0", "alice1"], true));
+        assert!(!search_page(users(2), 2, 2).has_more);
+        assert!(!search_page(users(0), 3, 2).has_more);
+    }
+
+    #[tokio::test]
+    async fn duplicate_usernames_are_reported_as_conflicts() {
+        // As grouped by find_duplicate_usernames.
+        let (first, second) = (bson::oid::ObjectId::new().to_hex(), bson::oid::ObjectId::new().to_hex());
+        let duplicate: DuplicateUsername =
+            bson::from_document(bson::doc! { "_id": "alice", "count": 2, "ids": [&first, &second] }).unwrap();
+
+        let response = repair_response(UsernameIndexRepair::Conflicts(vec![duplicate]));
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_str(&response.into_body().into_string().await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "duplicate_usernames",
+                "duplicates": [{ "username": "alice", "count": 2, "ids": [first, second] }],
+            })
+        );
+
+        let response = repair_response(UsernameIndexRepair::Recreated);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.into_body().into_string().await.unwrap(), r#"{"index":"recreated"}"#);
+    }
+
+    #[tokio::test]
+    async fn index_repairs_are_for_admins() {
+        let db = unreachable_database().await;
+        let client = TestClient::new(
+            Route::new()
+                .at("/admin/repair/username-index", post(repair_username_index_handler))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(Arc::new(db.collection::<User>("users"))),
+        );
+        let repair = |roles: &'static [&'static str]| {
+            client.post("/admin/repair/username-index").header(AUTHORIZATION, bearer("root", roles)).send()
+        };
+        repair(&["user"]).await.assert_status(StatusCode::FORBIDDEN);
+        repair(&["admin"]).await.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
+
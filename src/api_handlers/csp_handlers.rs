@@ -0,0 +1,45 @@
+use chrono::Utc;
+use mongodb::Collection;
+use poem::handler;
+use poem::http::StatusCode;
+use poem::web::Data;
+use std::sync::Arc;
+use crate::database::csp_db::{insert_csp_violation, CspReportBody, CspViolation};
+
+// Handles POST requests to /csp-report, sent by browsers when the Content-Security-Policy is violated.
+//
+// The route is public, as browsers don't attach the user's token to CSP reports, and is rate
+// limited separately in main.rs.
+// The body is sent as `application/csp-report`, which Poem's `Json` extractor doesn't accept,
+// so we parse the raw body ourselves.
+//
+// # Returns
+// - `204 No Content` once the report has been logged.
+// - `400 Bad Request` if the body isn't a valid CSP report.
+//
+// Storing the report in the `csp_violations` collection is best-effort - a failed insert is
+// logged but doesn't fail the request, since the browser can't do anything about it anyway.
+#[handler]
+pub async fn csp_report(
+    body: Vec<u8>,
+    db: Data<&Arc<Collection<CspViolation>>>,
+) -> Result<StatusCode, StatusCode> {
+    let CspReportBody { report } = serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    tracing::warn!(
+        document_uri = ?report.document_uri,
+        violated_directive = ?report.violated_directive,
+        blocked_uri = ?report.blocked_uri,
+        "Content-Security-Policy violation reported"
+    );
+
+    let violation = CspViolation {
+        report,
+        received_at: Utc::now(),
+    };
+    if let Err(err) = insert_csp_violation(&db, violation).await {
+        tracing::error!("Failed to store CSP violation: {}", err);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
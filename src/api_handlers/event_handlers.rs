@@ -0,0 +1,37 @@
+use std::time::Duration;
+use futures::stream;
+use poem::{handler, Request, Result};
+use poem::web::Data;
+use poem::web::sse::{Event, SSE};
+use tokio::sync::broadcast::error::RecvError;
+use crate::api_handlers::extract_user;
+use crate::services::event_bus::EventBus;
+
+// Handles GET requests to /events, streaming the caller's file events as server-sent events.
+//
+// Each event is a JSON message like `{ "type": "file_uploaded", "file": { "id", "filename" } }`,
+// with `type` being `file_uploaded` or `file_deleted`. Events that happen while the client isn't
+// connected aren't replayed. A keep-alive comment is sent every 15 seconds so proxies don't close
+// an idle connection.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn events(req: &Request, bus: Data<&EventBus>) -> Result<SSE> {
+    let user = extract_user(req)?;
+    let receiver = bus.subscribe(&user.username);
+
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event).unwrap_or_default();
+                    return Some((Event::message(data), receiver));
+                }
+                // A slow client misses the events it fell behind on, but keeps the connection.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(SSE::new(events).keep_alive(Duration::from_secs(15)))
+}
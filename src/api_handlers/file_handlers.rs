@@ -1,23 +1,220 @@
 use std::sync::Arc;
-use bson::{doc, Binary, Bson, Document};
+use bson::{doc, Binary};
+use bson::oid::ObjectId;
 use bson::spec::BinarySubtype;
-use mongodb::Collection;
-use poem::{handler, Error, Response, IntoResponse, Request};
-use poem::http::{HeaderValue, StatusCode};
-use poem::web::{Data, Json, Multipart, Path};
-use serde::{Serialize};
-use crate::database::file_db::{get_image_by_filename, insert_image, ImageDocument, insert_document, get_document_by_id, DocumentEntry, get_documents_for_user, FileEntry};
-use futures_util::stream::TryStreamExt;
-use crate::api_handlers::extract_user;
+use mongodb::{Client, Collection};
+use poem::{handler, Body, Error, Response, IntoResponse, Request};
+use poem::http::{header::{CONTENT_LENGTH, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LINK, LOCATION}, HeaderValue, StatusCode, Uri};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use poem::web::{Data, Json, Multipart, Path, Query};
+use futures::future::join_all;
+use futures::{stream, FutureExt, StreamExt, TryStreamExt};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crate::database::file_db::{get_image_by_filename, get_image_info, get_images_for_user, insert_image, ImageDocument, ImageInfo, ImageListEntry, insert_document, get_document_by_id, get_file_export_rows, DocumentEntry, FileEntry, FileExportRow, update_document_description, update_document_tags, ContentTypeFilter};
+use crate::database::file_db::{delete_document, replace_document_content, set_document_content_type, set_document_folder, ContentReplacement, set_document_visibility, share_document};
+use crate::database::file_metadata_db::{count_files_by_category, FileCategoryCounts, add_metadata_share, delete_file_metadata, find_duplicate_files, find_metadata_by_filename, get_metadata_by_ids, get_metadata_for_user, FileCursor, set_metadata_content_type, set_metadata_folder, set_metadata_visibility, update_metadata_description, update_metadata_tags, upsert_file_metadata, upsert_file_metadata_in_session, DuplicateGroup, FileMetadata};
+use crate::database::blob_db::{binary_size, document_bytes, document_size, release_content, Blob};
+use crate::database::file_version_db::{delete_file_versions, insert_file_version, FileVersion};
+use crate::database::gridfs_db::delete_gridfs_file;
+use crate::services::multipart_mixed::{MultipartMixedReader, PartFuture};
+use crate::services::upload_stream::{receive_file, ReceiveError, ReceivedContent, ReceivedFile};
+use mongodb::gridfs::GridFsBucket;
+use crate::database::with_transaction;
+use crate::database::user_db::{find_user, User};
+use crate::database::access_log_db::{get_access_history, log_file_access, AccessHistoryEntry, FileAccessLog};
+use crate::api_handlers::upload_ticket_handlers::authorize_upload;
+use crate::database::upload_ticket_db::UploadTicket;
+use crate::database::recent_download_db::{get_recent_downloads, record_download, RecentDownloadEntry, RecentDownloadLog};
+use crate::api_handlers::{client_ip, extract_user, parse_object_id, query_error};
+use crate::auth::presign::verify_presigned_url;
+use crate::services::notification::{notify_file_shared, FileSharedEvent};
+use crate::services::image_conversion::{self, ImageFormat};
+use crate::services::upload_limiter::UploadLimiter;
+use crate::config::{BulkLimits, DownloadConfig, ImagePresets, MetadataLimits, QueryConfig, QuotaConfig, UploadConfig};
+use crate::api_handlers::quota_handlers::check_storage_quota;
+use crate::database::quota_alert_db::QuotaAlert;
+use crate::database::image_rendition_db::{find_image_rendition, store_image_rendition, ImageRendition};
+use crate::api_handlers::validation::{check_item_count, validate_metadata, ValidationErrors};
+use crate::services::event_bus::{EventBus, FileEvent, FileRef};
+use crate::storage::{Storage, StorageBackend};
+use crate::database::corruption_db::{insert_corruption_report, CorruptionReport};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use rand::RngCore;
 
+// How many access log entries /files/:id/access-history returns by default, and at most.
+const DEFAULT_ACCESS_HISTORY_ENTRIES: i64 = 50;
+const MAX_ACCESS_HISTORY_ENTRIES: i64 = 200;
 
+// How many downloads /files/recent returns by default, and at most.
+const DEFAULT_RECENT_DOWNLOADS: i64 = 20;
+const MAX_RECENT_DOWNLOADS: i64 = 100;
 
+
+
+// Used when the content type of a file is unknown and can't be detected.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+// Sent with `400 Bad Request` for zero-byte uploads, and as the reason of empty images in a batch.
+const EMPTY_FILE: &str = "empty_file";
+
+// Builds a `Content-Disposition` header value of the given `disposition`, e.g. `attachment`, for `filename`.
+//
+// Quotes, backslashes, slashes and control characters are dropped from the name, and other
+// non-ASCII characters are replaced with `_`, with the full name sent in `filename*` (RFC 6266)
+// for clients that can read it. A name that is empty, nothing remains of, or is just dots is
+// replaced with `fallback` (DOWNLOAD_FALLBACK_FILENAME).
+pub(crate) fn content_disposition(disposition: &str, filename: &str, fallback: &str) -> HeaderValue {
+    let filename: String = filename
+        .chars()
+        .filter(|c| !c.is_control() && !['"', '\\', '/'].contains(c))
+        .collect();
+    let filename = filename.trim();
+    let ascii: String = filename.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
+
+    let value = if filename.chars().all(|c| c == '.') {
+        // Empty, or just `.` or `..`.
+        format!("{}; filename=\"{}\"", disposition, fallback)
+    } else if filename.is_ascii() {
+        format!("{}; filename=\"{}\"", disposition, filename)
+    } else {
+        let encoded = utf8_percent_encode(filename, NON_ALPHANUMERIC);
+        format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, ascii, encoded)
+    };
+    // Only visible ASCII is left, so this never fails.
+    HeaderValue::from_str(&value).unwrap_or_else(|_| HeaderValue::from_static("attachment"))
+}
+
+// Builds the response for downloading a stored file, telling the client to save it as `filename`.
+pub(crate) fn attachment_response(filename: &str, downloads: &DownloadConfig, content_type: &str, bytes: Vec<u8>) -> Response {
+    let mut response = bytes.into_response();
+    response.headers_mut().insert(
+        "Content-Disposition",
+        content_disposition("attachment", filename, &downloads.fallback_filename),
+    );
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_str(content_type).unwrap_or(HeaderValue::from_static(DEFAULT_CONTENT_TYPE)),
+    );
+
+    response
+}
+
+// The content type to serve a file with.
+//
+// Files uploaded before content types were stored have none. For those the type is detected from
+// the magic numbers of the content, and stored so it is only detected once and the file shows up
+// in listings filtered by type. Content that can't be detected is served as DEFAULT_CONTENT_TYPE.
+pub(crate) async fn document_content_type(
+    files: &Collection<DocumentEntry>,
+    metadata: &Collection<FileMetadata>,
+    doc: &DocumentEntry,
+    bytes: &[u8],
+) -> String {
+    if let Some(content_type) = &doc.content_type {
+        return content_type.clone();
+    }
+
+    let Some(detected) = infer::get(bytes).map(|kind| kind.mime_type()) else {
+        return DEFAULT_CONTENT_TYPE.to_string();
+    };
+
+    if let Some(id) = doc.id {
+        // The download doesn't depend on it, so a failure only means detecting it again next time.
+        let stored = futures::try_join!(
+            set_document_content_type(files, id, detected),
+            set_metadata_content_type(metadata, id, detected),
+        );
+        if let Err(e) = stored {
+            tracing::warn!(file_id = %id, error = %e, "Failed to store detected content type");
+        }
+    }
+
+    detected.to_string()
+}
+
+// Builds the stored form of an uploaded image, capturing its dimensions and type so
+// /images/:filename/info never has to read the image itself.
+fn new_image_document(filename: String, user: &str, bytes: Vec<u8>) -> ImageDocument {
+    let dimensions = image_conversion::image_dimensions(&bytes);
+    ImageDocument {
+        filename,
+        user: Some(user.to_string()),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        format: infer::get(&bytes).map(|kind| kind.mime_type().to_string()),
+        size_bytes: Some(bytes.len() as u64),
+        data: Binary {
+            subtype: BinarySubtype::Generic,
+            bytes,
+        },
+    }
+}
+
+// Checks the type of an uploaded image against IMAGE_ALLOWED_TYPES, using the type detected from
+// its content rather than trusting the client, so only images the image pipeline can handle are
+// stored. Empty images, which only get here with UPLOAD_ALLOW_EMPTY, have no type to check.
+//
+// # Returns
+// - `Err(reason)` if the type isn't allowed, naming the allowed types.
+fn check_image_type(bytes: &[u8], upload_config: &UploadConfig) -> Result<(), String> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    let detected = infer::get(bytes).map(|kind| kind.mime_type());
+    if detected.is_some_and(|mime| upload_config.image_content_types.iter().any(|allowed| allowed == mime)) {
+        return Ok(());
+    }
+    Err(format!("Unsupported image type, allowed types are {}", upload_config.image_content_types.join(", ")))
+}
+
+// Images are stored inline in a single document, so they have to stay below MongoDB's 16 MiB
+// document limit, whatever UPLOAD_MAX_BYTES allows.
+const MAX_IMAGE_BYTES: u64 = 15 * 1024 * 1024;
+
+// The largest image accepted by /upload_image and /images/batch-upload.
+fn max_image_bytes(upload_config: &UploadConfig) -> u64 {
+    upload_config.max_file_bytes.min(MAX_IMAGE_BYTES)
+}
+
+// Reads an uploaded image, stopping as soon as it exceeds `limit` bytes, so an oversized image is
+// never held in memory as a whole.
+//
+// # Returns
+// - `Ok(None)` if the image is larger than `limit`.
+async fn read_image(reader: impl AsyncRead + Unpin, limit: u64) -> std::io::Result<Option<Vec<u8>>> {
+    let mut bytes = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut bytes).await?;
+    Ok((bytes.len() as u64 <= limit).then_some(bytes))
+}
+
+fn image_too_large(upload_config: &UploadConfig) -> String {
+    format!("Images can be at most {} bytes", max_image_bytes(upload_config))
+}
+
+// Handles POST requests to /upload_image, storing the first `file` field of the multipart form.
+//
+// # Returns
+// - `200 OK` with `Uploaded <filename>`.
+// - `400 Bad Request` if the form has no `file` field, or with `empty_file` if it is empty and
+//   UPLOAD_ALLOW_EMPTY isn't set.
+// - `413 Payload Too Large` if the image exceeds UPLOAD_MAX_BYTES, or MAX_IMAGE_BYTES as images are
+//   stored in a single document.
+// - `415 Unsupported Media Type` if the type detected from the content isn't in IMAGE_ALLOWED_TYPES.
 #[poem_grants::protect("user")]
 #[handler]
 pub async fn upload_image(
+    req: &Request,
     mut multipart: Multipart,
     db: Data<&Arc<Collection<ImageDocument>>>,
-) -> poem::Result<String, StatusCode> {
+    upload_limiter: Data<&UploadLimiter>,
+    upload_config: Data<&UploadConfig>,
+) -> poem::Result<String> {
+    let user = extract_user(req)?;
+    let _permit = upload_limiter.acquire(&user.username).await?;
     let image_collection = db.as_ref();
     while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
         if field.name() == Some("file") {
@@ -25,24 +222,136 @@ pub async fn upload_image(
                 .map(ToString::to_string)
                 .unwrap_or_else(|| "upload".to_string());
 
-            let bytes = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?.to_vec();
+            let bytes = read_image(field.into_async_read(), max_image_bytes(&upload_config))
+                .await
+                .map_err(|e| Error::new(e, StatusCode::BAD_REQUEST))?
+                .ok_or_else(|| Error::from_string(image_too_large(&upload_config), StatusCode::PAYLOAD_TOO_LARGE))?;
+            check_not_empty(bytes.len() as u64, &upload_config)?;
+            check_image_type(&bytes, &upload_config).map_err(|reason| Error::from_string(reason, StatusCode::UNSUPPORTED_MEDIA_TYPE))?;
 
-            let image_doc = ImageDocument {
-                filename: filename.clone(),
-                data: Binary {
-                    subtype: BinarySubtype::Generic,
-                    bytes,
-                },
-            };
+            let image_doc = new_image_document(filename.clone(), &user.username, bytes);
 
             match insert_image(image_collection, image_doc).await {
                 Ok(_) => return Ok(format!("Uploaded {}", filename)),
-                Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+                Err(e) => return Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
             }
         }
     }
 
-    Err(StatusCode::BAD_REQUEST)
+    Err(StatusCode::BAD_REQUEST.into())
+}
+
+#[derive(Serialize)]
+pub struct BatchUploadResult {
+    uploaded: Vec<UploadedImage>,
+    failed: Vec<FailedImage>,
+}
+
+#[derive(Serialize)]
+pub struct UploadedImage {
+    filename: String,
+}
+
+#[derive(Serialize)]
+pub struct FailedImage {
+    filename: String,
+    reason: String,
+}
+
+// Checks and stores a single image of a batch upload. `bytes` is `None` for an image that was too
+// large to be read.
+async fn store_batch_image(
+    collection: &Collection<ImageDocument>,
+    user: &str,
+    filename: String,
+    bytes: Option<Vec<u8>>,
+    upload_config: &UploadConfig,
+) -> Result<UploadedImage, FailedImage> {
+    let Some(bytes) = bytes else {
+        return Err(FailedImage { filename, reason: image_too_large(upload_config) });
+    };
+    if bytes.is_empty() && !upload_config.allow_empty_files {
+        return Err(FailedImage { filename, reason: EMPTY_FILE.to_string() });
+    }
+    if let Err(reason) = check_image_type(&bytes, upload_config) {
+        return Err(FailedImage { filename, reason });
+    }
+
+    let image_doc = new_image_document(filename.clone(), user, bytes);
+
+    match insert_image(collection, image_doc).await {
+        Ok(_) => Ok(UploadedImage { filename }),
+        Err(e) => {
+            tracing::error!(filename = %filename, error = %e, "Failed to store image");
+            Err(FailedImage { filename, reason: "The image could not be stored".to_string() })
+        }
+    }
+}
+
+// Handles POST requests to /images/batch-upload, uploading every `file` field of the multipart form.
+//
+// Each image is checked against IMAGE_ALLOWED_TYPES on its own, using the type detected from its
+// content, and the images are stored concurrently. One bad image doesn't fail the others. Empty
+// images are reported as failed with the reason `empty_file`, unless UPLOAD_ALLOW_EMPTY is set, and
+// images over UPLOAD_MAX_BYTES or MAX_IMAGE_BYTES are reported as failed without being read in full.
+//
+// # Returns
+// - `207 Multi-Status` with `{ "uploaded": [{ "filename" }], "failed": [{ "filename", "reason" }] }`.
+// - `400 Bad Request` if the form has no `file` fields, or more than IMAGE_BATCH_MAX of them.
+//   Nothing is stored in that case.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn batch_upload_images(
+    req: &Request,
+    mut multipart: Multipart,
+    db: Data<&Arc<Collection<ImageDocument>>>,
+    upload_limiter: Data<&UploadLimiter>,
+    upload_config: Data<&UploadConfig>,
+) -> poem::Result<Response> {
+    let user = extract_user(req)?;
+    let _permit = upload_limiter.acquire(&user.username).await?;
+
+    // The form has to be read field by field, so only storing the images can run concurrently.
+    let mut images = Vec::new();
+    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        if images.len() == upload_config.max_image_batch {
+            return Err(Error::from_string(
+                format!("A batch can contain at most {} images", upload_config.max_image_batch),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        let filename = field.file_name()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "upload".to_string());
+        let bytes = read_image(field.into_async_read(), max_image_bytes(&upload_config))
+            .await
+            .map_err(|e| Error::new(e, StatusCode::BAD_REQUEST))?;
+        images.push((filename, bytes));
+    }
+
+    if images.is_empty() {
+        return Err(Error::from_string("No images were sent", StatusCode::BAD_REQUEST));
+    }
+
+    let results = join_all(
+        images
+            .into_iter()
+            .map(|(filename, bytes)| store_batch_image(&db, &user.username, filename, bytes, &upload_config)),
+    )
+    .await;
+
+    let mut result = BatchUploadResult { uploaded: Vec::new(), failed: Vec::new() };
+    for outcome in results {
+        match outcome {
+            Ok(uploaded) => result.uploaded.push(uploaded),
+            Err(failed) => result.failed.push(failed),
+        }
+    }
+
+    Ok((StatusCode::MULTI_STATUS, Json(result)).into_response())
 }
 
 #[poem_grants::protect("user")]
@@ -50,26 +359,291 @@ pub async fn upload_image(
 pub async fn download_image(
     Path(filename): Path<String>,
     db: Data<&Arc<Collection<ImageDocument>>>,
+    downloads: Data<&DownloadConfig>,
 ) -> poem::Result<Response, Error> {
-    match get_image_by_filename(&**db, &filename).await {
-        Ok(Some(image_doc)) => {
-            let content_disposition = format!("attachment; filename=\"{}\"", image_doc.filename);
-
-            let mut response = image_doc.data.bytes.into_response();
-            response.headers_mut().insert(
-                "Content-Disposition",
-                HeaderValue::from_str(&content_disposition).unwrap(),
-            );
-            response.headers_mut().insert(
-                "Content-Type",
-                HeaderValue::from_static("application/octet-stream"),
-            );
+    match get_image_by_filename(&db, &filename).await {
+        Ok(Some(image_doc)) => Ok(attachment_response(&image_doc.filename, &downloads, DEFAULT_CONTENT_TYPE, image_doc.data.bytes)),
+        Ok(None) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+// How large an image /images inlines by default, and at most, whatever `max_bytes` asks for.
+const DEFAULT_INLINE_IMAGE_BYTES: u64 = 16 * 1024;
+const MAX_INLINE_IMAGE_BYTES: u64 = 64 * 1024;
+// The most image data a single listing inlines in total. Images past it are listed without data.
+const MAX_INLINE_LISTING_BYTES: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct ImageListQuery {
+    #[serde(default)]
+    inline: bool,
+    max_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ImageListItem {
+    filename: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<String>,
+    size_bytes: Option<u64>,
+    // A `data:` URI with the base64 encoded image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+}
+
+// Handles GET requests to /images, listing the images uploaded by the caller.
+//
+// # Arguments
+// - `Query(query)`: `?inline=true` includes the image data of small images as a `data:` URI, so
+//   thumbnails can be shown without a request per image. `?max_bytes=N` sets how small an image
+//   must be to be inlined, DEFAULT_INLINE_IMAGE_BYTES by default and never above MAX_INLINE_IMAGE_BYTES.
+//   Once MAX_INLINE_LISTING_BYTES have been inlined, the remaining images are listed without data.
+//
+// # Returns
+// - `200 OK` with `[{ "filename", "width", "height", "format", "size_bytes", "data" }]`, sorted by
+//   filename. `data` is left out for images that weren't inlined.
+// - `503 Service Unavailable` if the listing takes longer than QUERY_MAX_TIME_MS.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn list_images(
+    req: &Request,
+    Query(query): Query<ImageListQuery>,
+    db: Data<&Arc<Collection<ImageDocument>>>,
+    queries: Data<&QueryConfig>,
+) -> poem::Result<Json<Vec<ImageListItem>>> {
+    let user = extract_user(req)?;
+    let images = get_images_for_user(&db, &user.username, inline_max_bytes(&query), &queries)
+        .await
+        .map_err(query_error)?;
+    Ok(Json(image_list_items(images)))
+}
+
+// How large an image may be to be inlined into a listing, if inlining was asked for.
+fn inline_max_bytes(query: &ImageListQuery) -> Option<u64> {
+    query
+        .inline
+        .then(|| query.max_bytes.unwrap_or(DEFAULT_INLINE_IMAGE_BYTES).min(MAX_INLINE_IMAGE_BYTES))
+}
+
+// Turns listed images into the listing, inlining the data the database sent as a `data:` URI
+// until MAX_INLINE_LISTING_BYTES have been inlined.
+fn image_list_items(images: Vec<ImageListEntry>) -> Vec<ImageListItem> {
+    let mut inlined = 0;
+    let mut items = Vec::with_capacity(images.len());
+    for image in images {
+        let mut data = None;
+        if let Some(bytes) = image.data.map(|data| data.bytes)
+            && inlined + bytes.len() <= MAX_INLINE_LISTING_BYTES
+        {
+            inlined += bytes.len();
+            let content_type = image.format.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE);
+            data = Some(format!("data:{};base64,{}", content_type, STANDARD.encode(&bytes)));
+        }
+        items.push(ImageListItem {
+            filename: image.filename,
+            width: image.width,
+            height: image.height,
+            format: image.format,
+            size_bytes: image.size_bytes,
+            data,
+        });
+    }
+    items
+}
+
+// Handles GET requests to /images/:filename/info, reporting the size of an image without sending it.
+//
+// # Arguments
+// - `Path(filename)`: The filename of the stored image.
+//
+// # Returns
+// - `200 OK` with `{ "width", "height", "format", "size_bytes" }`, as captured when the image was
+//   uploaded. `width` and `height` are null if the image couldn't be parsed.
+// - `404 Not Found` if the caller hasn't uploaded an image with that filename. Images uploaded
+//   before uploaders were recorded belong to no one and are never found here.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn image_info(
+    req: &Request,
+    Path(filename): Path<String>,
+    db: Data<&Arc<Collection<ImageDocument>>>,
+) -> poem::Result<Json<ImageInfo>> {
+    let user = extract_user(req)?;
+    match get_image_info(&db, &filename, &user.username).await {
+        Ok(Some(info)) => Ok(Json(info)),
+        Ok(None) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
 
+// Handles HEAD requests to /images/:filename, sending the headers of /download_image/:imagename
+// without the image. The size is measured by MongoDB, so the image data is never read.
+//
+// # Returns
+// - `200 OK` with `Content-Length`, `Content-Type` and `Content-Disposition`.
+// - `404 Not Found` if there is no image with that filename.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn download_image_head(
+    Path(filename): Path<String>,
+    db: Data<&Arc<Collection<ImageDocument>>>,
+    downloads: Data<&DownloadConfig>,
+) -> poem::Result<Response, Error> {
+    match binary_size(&db, doc! { "filename": &filename }, "$data").await {
+        Ok(Some(size)) => {
+            let mut response = attachment_response(&filename, &downloads, DEFAULT_CONTENT_TYPE, Vec::new());
+            response.headers_mut().insert(CONTENT_LENGTH, size.into());
             Ok(response)
         }
         Ok(None) => Err(Error::from_status(StatusCode::NOT_FOUND)),
-        Err(_) => Err(Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConvertQuery {
+    format: Option<String>,
+    quality: Option<u8>,
+}
+
+// Handles GET requests to /images/:filename/convert, serving a stored image in another format.
+//
+// # Arguments
+// - `Path(filename)`: The filename of the stored image.
+// - `Query(query)`: `?format=png|jpeg|webp` and, for JPEG, `?quality=85` (0-100, defaults to 85).
+//   Without `format`, the format is negotiated from the `Accept` header, e.g. `Accept: image/webp`.
+//
+// The converted image isn't stored, it is converted again on every request.
+//
+// # Returns
+// - `200 OK` with the converted image, served inline.
+// - `400 Bad Request` if the quality is above 100.
+// - `404 Not Found` if there is no image with that filename.
+// - `415 Unsupported Media Type` if the format isn't one of png, jpeg or webp.
+// - `422 Unprocessable Entity` if the stored image can't be decoded.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn convert_image(
+    req: &Request,
+    Path(filename): Path<String>,
+    Query(query): Query<ConvertQuery>,
+    db: Data<&Arc<Collection<ImageDocument>>>,
+    downloads: Data<&DownloadConfig>,
+) -> poem::Result<Response, Error> {
+    let format = match &query.format {
+        Some(name) => ImageFormat::from_name(name),
+        None => req.header("Accept").and_then(ImageFormat::from_accept),
+    }
+    .ok_or_else(|| Error::from_string("Supported formats are png, jpeg and webp", StatusCode::UNSUPPORTED_MEDIA_TYPE))?;
+
+    let quality = query.quality.unwrap_or(85);
+    if quality > 100 {
+        return Err(Error::from_string("Quality must be between 0 and 100", StatusCode::BAD_REQUEST));
     }
+
+    let image_doc = get_image_by_filename(&db, &filename)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+    // Decoding and encoding is CPU bound, so keep it off the async worker threads.
+    let converted = tokio::task::spawn_blocking(move || image_conversion::convert_image(&image_doc.data.bytes, format, quality))
+        .await
+        .map_err(|_| Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?
+        .map_err(|_| Error::from_string("The stored image could not be decoded", StatusCode::UNPROCESSABLE_ENTITY))?;
+
+    let stem = filename.rsplit_once('.').map_or(filename.as_str(), |(stem, _)| stem);
+    let content_disposition = content_disposition(
+        "inline",
+        &format!("{}.{}", stem, format.extension()),
+        &downloads.fallback_filename,
+    );
+
+    Ok(Response::builder()
+        .header("Content-Type", format.mime())
+        .header("Content-Disposition", content_disposition)
+        .header("Vary", "Accept")
+        .body(converted))
+}
+
+// Handles GET requests to /images/:filename/:preset, serving a stored image scaled down to a named size.
+//
+// # Arguments
+// - `Path((filename, preset))`: The filename of the stored image and the preset, `small`, `medium` or
+//   `large`. Their sizes come from IMAGE_PRESET_*, and images are scaled to fit within them.
+//
+// The image keeps its format if it is png, jpeg or webp, and is sent as png otherwise. Each rendition is
+// stored in the `image_renditions` collection the first time it is asked for, and may be cached by the
+// client for a day.
+//
+// # Returns
+// - `200 OK` with the resized image, served inline.
+// - `400 Bad Request` listing the valid presets if the preset is unknown.
+// - `404 Not Found` if there is no image with that filename.
+// - `422 Unprocessable Entity` if the stored image can't be decoded.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn download_image_preset(
+    Path((filename, preset)): Path<(String, String)>,
+    db: Data<&Arc<Collection<ImageDocument>>>,
+    renditions: Data<&Arc<Collection<ImageRendition>>>,
+    presets: Data<&ImagePresets>,
+    downloads: Data<&DownloadConfig>,
+) -> poem::Result<Response, Error> {
+    let Some(preset) = presets.find(&preset) else {
+        return Err(Error::from_string(
+            format!("Unknown preset {:?}, valid presets are {}", preset, presets.names()),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    let key = ImageRendition::key(&filename, preset.width, preset.height);
+
+    let rendition = match find_image_rendition(&renditions, &key).await {
+        Ok(Some(rendition)) => rendition,
+        Ok(None) | Err(_) => {
+            let image_doc = get_image_by_filename(&db, &filename)
+                .await
+                .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+                .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+            let format = image_doc.format.as_deref().and_then(ImageFormat::from_mime).unwrap_or(ImageFormat::Png);
+
+            let (width, height) = (preset.width, preset.height);
+            let resized = tokio::task::spawn_blocking(move || {
+                image_conversion::resize_image(&image_doc.data.bytes, width, height, format, 85)
+            })
+            .await
+            .map_err(|_| Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?
+            .map_err(|_| Error::from_string("The stored image could not be decoded", StatusCode::UNPROCESSABLE_ENTITY))?;
+
+            let rendition = ImageRendition {
+                key,
+                data: Binary { subtype: BinarySubtype::Generic, bytes: resized },
+                content_type: format.mime().to_string(),
+                created_at: Utc::now(),
+            };
+            // Failing to store it only means it is resized again next time.
+            if let Err(e) = store_image_rendition(&renditions, &rendition).await {
+                tracing::warn!(filename = %filename, error = %e, "Failed to store image rendition");
+            }
+            rendition
+        }
+    };
+
+    let stem = filename.rsplit_once('.').map_or(filename.as_str(), |(stem, _)| stem);
+    let extension = ImageFormat::from_mime(&rendition.content_type).map_or("png", |format| format.extension());
+    let content_disposition = content_disposition(
+        "inline",
+        &format!("{}-{}.{}", stem, preset.name, extension),
+        &downloads.fallback_filename,
+    );
+
+    Ok(Response::builder()
+        .header("Content-Type", rendition.content_type)
+        .header("Content-Disposition", content_disposition)
+        .header("Cache-Control", "private, max-age=86400")
+        .body(rendition.data.bytes))
 }
 
 // Sends a JSON response with all the files in the mongoDB
@@ -78,120 +652,2256 @@ pub async fn download_image(
 //
 // Returns: a JSON response with the files
 //
-// We use the get_documents_for_user function to get the files from the mongodb.
-// We use the address of a double pointer to the mongodb collection.
+// We use the get_metadata_for_user function to get the files from the file_metadata collection,
+// so listing never reads the content of the files.
 // The documents are returned as a vector of FileEntry structs.
 // The documents are filtered by the user, so only the files of the user are returned.
 // The user is extracted from the request using the extract_user function.
 //
-// We return a JSON response with the documents.
+// The files can be filtered by MIME type, either exactly with `?content_type=image/png` or by prefix
+// with `?content_type_prefix=image/`. Sending both at once is a bad request. `?tag=work` only lists
+// the files tagged `work`, and can be combined with either.
+//
+// Sending `?page_size=N` (at most MAX_FILE_PAGE_SIZE), `?after=<id>` or `?before=<id>` returns a single
+// page instead of every file. The pages around it are linked from the `Link` header (RFC 8288)
+// with `rel="next"` and `rel="prev"`, and `rel="first"` is always included. The filters are kept in the links.
+//
+// The query is aborted after QUERY_MAX_TIME_MS, answering 503 Service Unavailable, so a huge listing
+// can't tie up the database. Paging with a smaller page_size avoids that.
+//
+// We return a JSON response with the documents, or XML for `Accept: application/xml`. An `Accept`
+// header allowing neither is answered with 406 Not Acceptable.
+//
+// Listings carry a weak `ETag` of their body and a `Last-Modified` header with the latest upload or
+// metadata change among the listed files, so polling clients can send `If-None-Match` or
+// `If-Modified-Since` and get 304 Not Modified while nothing changed. Deleting a file doesn't move
+// `Last-Modified`, so clients that need to notice deletions should use `If-None-Match`, which wins
+// when both are sent.
 
+const DEFAULT_FILE_PAGE_SIZE: i64 = 50;
+const MAX_FILE_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct FileListQuery {
+    content_type: Option<String>,
+    content_type_prefix: Option<String>,
+    tag: Option<String>,
+    page_size: Option<i64>,
+    after: Option<String>,
+    before: Option<String>,
+}
 
 #[poem_grants::protect("user")]
 #[handler]
 pub async fn get_files(
     req: &Request,
-    db: Data<&Arc<Collection<DocumentEntry>>>,
-) -> poem::Result<Json<Vec<FileEntry>>, StatusCode> {
+    Query(query): Query<FileListQuery>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    queries: Data<&QueryConfig>,
+) -> poem::Result<Response, Error> {
     let user = extract_user(req).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let format = ListingFormat::from_accept(req.header("Accept")).ok_or(StatusCode::NOT_ACCEPTABLE)?;
+
+    let content_type = match (&query.content_type, &query.content_type_prefix) {
+        (Some(_), Some(_)) => return Err(Error::from_status(StatusCode::BAD_REQUEST)),
+        (Some(value), None) => Some(ContentTypeFilter::Exact(value.clone())),
+        (None, Some(prefix)) => Some(ContentTypeFilter::Prefix(prefix.clone())),
+        (None, None) => None,
+    };
+
+    let cursor = match (&query.after, &query.before) {
+        (Some(_), Some(_)) => return Err(Error::from_status(StatusCode::BAD_REQUEST)),
+        (Some(after), None) => FileCursor::After(parse_object_id(after)?),
+        (None, Some(before)) => FileCursor::Before(parse_object_id(before)?),
+        (None, None) => FileCursor::Start,
+    };
+    let paginated = query.page_size.is_some() || !matches!(cursor, FileCursor::Start);
+    let page_size = paginated
+        .then(|| query.page_size.unwrap_or(DEFAULT_FILE_PAGE_SIZE).clamp(1, MAX_FILE_PAGE_SIZE));
+
+    let page = get_metadata_for_user(&metadata, &user.username, content_type.as_ref(), query.tag.as_deref(), cursor, page_size, &queries)
+        .await
+        .map_err(query_error)?;
+
+    let link = file_list_links(&query, page_size, page.next, page.prev);
+    let (content_type, body) = match format {
+        ListingFormat::Json => (
+            "application/json; charset=utf-8",
+            serde_json::to_vec(&page.files).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        ),
+        ListingFormat::Xml => ("application/xml; charset=utf-8", files_xml(&page.files).into_bytes()),
+    };
+    let etag = format!("W/\"{:x}\"", Sha256::digest(&body));
+
+    let not_modified = match req.header(IF_NONE_MATCH) {
+        Some(tags) => etag_matches(tags, &etag),
+        None => page
+            .last_modified
+            .zip(req.header(IF_MODIFIED_SINCE).and_then(parse_http_date))
+            .is_some_and(|(modified, since)| modified.timestamp() <= since.timestamp()),
+    };
+    let mut response = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        Response::builder().content_type(content_type).body(body)
+    };
+
+    let headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert(ETAG, etag);
+    }
+    if let Some(last_modified) = page.last_modified
+        && let Ok(value) = HeaderValue::from_str(&http_date(last_modified))
+    {
+        headers.insert(LAST_MODIFIED, value);
+    }
+    Ok(response.with_header(LINK, link).with_header("Vary", "Accept").into_response())
+}
+
+// Formats a time as an HTTP date (RFC 9110), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// Parses an HTTP date, as sent in `If-Modified-Since`. Invalid dates are ignored, as RFC 9110 asks.
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value).ok().map(|time| time.with_timezone(&Utc))
+}
+
+// Whether an `If-None-Match` header matches `etag`, using the weak comparison of RFC 9110.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+// The formats a file listing can be sent in.
+enum ListingFormat {
+    Json,
+    Xml,
+}
+
+impl ListingFormat {
+    // Picks the format from an `Accept` header, preferring JSON when both are equally acceptable.
+    //
+    // # Returns
+    // - `Some(format)`: JSON without an `Accept` header, or for `*/*` and `application/*`.
+    // - `None` if neither JSON nor XML is acceptable.
+    fn from_accept(accept: Option<&str>) -> Option<Self> {
+        let Some(accept) = accept else {
+            return Some(Self::Json);
+        };
+
+        let mut best: Option<(Self, f32)> = None;
+        for entry in accept.split(',') {
+            let mut parts = entry.split(';');
+            let format = match parts.next().unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+                "application/json" | "application/*" | "*/*" => Self::Json,
+                "application/xml" | "text/xml" => Self::Xml,
+                _ => continue,
+            };
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let better = match &best {
+                Some((best_format, best_quality)) => {
+                    quality > *best_quality || (quality == *best_quality && matches!(best_format, Self::Xml) && matches!(format, Self::Json))
+                }
+                None => true,
+            };
+            if quality > 0.0 && better {
+                best = Some((format, quality));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+}
+
+// Escapes text for an XML element. Characters XML 1.0 can't represent at all, like most control
+// characters, are dropped.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Serializes a file listing as `<files><file><id>...</id>...</file></files>`, with the same fields
+// as the JSON listing. Fields that are left out of the JSON are left out here too.
+fn files_xml(files: &[FileEntry]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<files>\n");
+    for file in files {
+        xml.push_str("  <file>\n");
+        let mut element = |name: &str, value: &str| {
+            xml.push_str(&format!("    <{}>{}</{}>\n", name, xml_escape(value), name));
+        };
+        element("id", &file.id);
+        element("filename", &file.filename);
+        if let Some(description) = &file.description {
+            element("description", description);
+        }
+        if let Some(content_type) = &file.content_type {
+            element("content_type", content_type);
+        }
+        element("is_public", if file.is_public { "true" } else { "false" });
+        if let Some(folder) = &file.folder {
+            element("folder", folder);
+        }
+        for tag in &file.tags {
+            element("tag", tag);
+        }
+        xml.push_str("  </file>\n");
+    }
+    xml.push_str("</files>\n");
+    xml
+}
+
+// Handles GET requests to /files/categories, counting the caller's files by category.
+//
+// # Returns
+// - `200 OK` with `{ "image": n, "document": n, "other": n }`. Categories without files are 0.
+//   Images are `image/*`, documents are text, PDF and office files, and other is everything else.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn file_categories(
+    req: &Request,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+) -> poem::Result<Json<FileCategoryCounts>> {
+    let user = extract_user(req)?;
+    count_files_by_category(&metadata, &user.username)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
 
-    let documents = get_documents_for_user(&**db, &user.username)
+// Quotes a CSV field if it contains a separator, quote or line break. Fields that a spreadsheet
+// would run as a formula are prefixed with `'`, so a filename like `=HYPERLINK(...)` stays text.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn csv_row(row: &FileExportRow) -> String {
+    format!(
+        "{},{},{},{},{}\r\n",
+        row.id.to_hex(),
+        csv_field(&row.filename),
+        row.size_bytes.map(|size| size.to_string()).unwrap_or_default(),
+        csv_field(row.content_type.as_deref().unwrap_or_default()),
+        row.id.timestamp().try_to_rfc3339_string().unwrap_or_default(),
+    )
+}
+
+// Handles GET requests to /files/export.csv, downloading a spreadsheet of the caller's files.
+//
+// The CSV has the columns id, filename, size, content_type and uploaded_at, oldest file first.
+// It is written while the files are read from the database, so it is never held in memory as a
+// whole. `size` and `content_type` are empty for files uploaded before they were recorded.
+//
+// # Returns
+// - `200 OK` with `Content-Type: text/csv` and `Content-Disposition: attachment; filename="files.csv"`.
+// - `503 Service Unavailable` if the query takes longer than QUERY_MAX_TIME_MS to start returning files.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn export_files_csv(
+    req: &Request,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    queries: Data<&QueryConfig>,
+) -> poem::Result<Response> {
+    let user = extract_user(req)?;
+    let cursor = get_file_export_rows(&db, &user.username, &queries)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(query_error)?;
+
+    let header = "id,filename,size,content_type,uploaded_at\r\n".to_string();
+    let rows = cursor.map_ok(|row| csv_row(&row)).map_err(std::io::Error::other);
+    let body = stream::once(async { Ok(header) }).chain(rows);
+
+    Ok(Response::builder()
+        .content_type("text/csv; charset=utf-8")
+        .header("Content-Disposition", "attachment; filename=\"files.csv\"")
+        .body(Body::from_bytes_stream(body)))
+}
+
+// Builds the `Link` header of a file listing page.
+fn file_list_links(query: &FileListQuery, page_size: Option<i64>, next: Option<ObjectId>, prev: Option<ObjectId>) -> String {
+    let url = |cursor: Option<(&str, ObjectId)>| {
+        let mut params: Vec<(&str, String)> = Vec::new();
+        if let Some(value) = &query.content_type {
+            params.push(("content_type", value.clone()));
+        }
+        if let Some(prefix) = &query.content_type_prefix {
+            params.push(("content_type_prefix", prefix.clone()));
+        }
+        if let Some(tag) = &query.tag {
+            params.push(("tag", tag.clone()));
+        }
+        if let Some((name, id)) = cursor {
+            params.push((name, id.to_hex()));
+        }
+        if let Some(page_size) = page_size {
+            params.push(("page_size", page_size.to_string()));
+        }
+        match serde_urlencoded::to_string(&params) {
+            Ok(query) if !query.is_empty() => format!("/files?{}", query),
+            _ => "/files".to_string(),
+        }
+    };
 
-    Ok(Json(documents))
+    let mut links = Vec::new();
+    if let Some(next) = next {
+        links.push(format!("<{}>; rel=\"next\"", url(Some(("after", next)))));
+    }
+    if let Some(prev) = prev {
+        links.push(format!("<{}>; rel=\"prev\"", url(Some(("before", prev)))));
+    }
+    links.push(format!("<{}>; rel=\"first\"", url(None)));
+    links.join(", ")
 }
 
 
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    ticket: Option<String>,
+}
 
 // Handles upload of files endpoint to DB
 //
 // Arguments: takes an adress to a request, a multipart form data and a mongodb collection
 // Returns: a string with the id of the uploaded file
 //
-// We use the multipart form data to get the file field.
-// The filename is extracted from the field, and if not found, we set it to "upload".
+// Instead of a token, the request may carry `?ticket=...` from /uploads/ticket. The ticket is used up
+// once the file is stored, for the user it was issued to, and stays usable if the upload fails. An
+// unknown, used or expired ticket is answered with 401 Unauthorized, and a ticket whose owner was
+// deactivated or lost the user role with 403 Forbidden.
+//
+// Only UPLOAD_MAX_CONCURRENT uploads are processed at once. Beyond that an upload waits for a free slot
+// for up to UPLOAD_QUEUE_TIMEOUT_MS, and then gets a 503 Service Unavailable. A user who already has
+// UPLOAD_MAX_PER_USER uploads in progress gets a 429 Too Many Requests right away.
+//
+// We go through the multipart form data looking for the file field and an optional description field.
+// The filename is extracted from the file field, and if not found, we set it to "upload".
+// The content type of the file field is stored along with it, so listings can be filtered by type.
 // The bytes are extracted from the field and converted to a vector.
-// We create a DocumentEntry struct with the filename, content and user.
+// The description may be sent before or after the file. A description over the METADATA_MAX_* limits is
+// rejected with 422 Unprocessable Entity, naming the exceeded limit.
+// Any other fields, including additional files, are ignored and logged as a warning.
+// The file is streamed in chunks while its SHA-256 hash is computed, and rejected with 413 Payload Too Large once it
+// exceeds UPLOAD_MAX_BYTES. An empty file is rejected with 400 Bad Request and `empty_file` unless UPLOAD_ALLOW_EMPTY
+// is set. Files up to UPLOAD_GRIDFS_THRESHOLD_BYTES are stored by the STORAGE_BACKEND keyed by
+// their hash, so identical files are only stored once. Larger files are streamed into GridFS as they arrive,
+// or with STORAGE_BACKEND=fs into the storage directory, where they are deduplicated as well.
+// We create a DocumentEntry struct with the filename, content hash, description and user.
 //
-// The insert_document function is called to insert the document into the mongodb.
-// If the insert is successful, we return the id of the document as a hex string.
-// If the insert fails, we return an internal server error.
-#[poem_grants::protect("user")]
+// The document and its entry in the file_metadata collection are inserted in a single transaction, so a crash
+// can't leave one without the other. If the transaction succeeds, the upload is recorded in the file access log,
+// a quota alert is recorded if the user now uses more than QUOTA_ALERT_THRESHOLD of their STORAGE_QUOTA_BYTES,
+// and we return the id of the document as a hex string.
+// If it fails, the content is removed again and we return an internal server error.
 #[handler]
 pub async fn upload_file(
     req: &Request,
+    Query(query): Query<UploadQuery>,
     mut multipart: Multipart,
     db: Data<&Arc<Collection<DocumentEntry>>>,
-) -> poem::Result<String, StatusCode> {
-    let user = extract_user(req).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+    bucket: Data<&GridFsBucket>,
+    client: Data<&Client>,
+    quota_alerts: Data<&Arc<Collection<QuotaAlert>>>,
+    tickets: Data<&Arc<Collection<UploadTicket>>>,
+    users: Data<&Arc<Collection<User>>>,
+    upload_limiter: Data<&UploadLimiter>,
+    upload_config: Data<&UploadConfig>,
+    metadata_limits: Data<&MetadataLimits>,
+    quota: Data<&QuotaConfig>,
+    events: Data<&EventBus>,
+) -> poem::Result<String> {
+    let authorization = authorize_upload(req, query.ticket.as_deref(), &tickets, &users).await?;
+    let username = authorization.username.clone();
 
-    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
-        if field.name() == Some("file") {
-            let filename = field.file_name()
-                .map(ToString::to_string)
-                .unwrap_or_else(|| "upload".to_string());
+    // Run as a block, so a ticket can be restored whichever way the upload fails.
+    let uploaded: poem::Result<String> = async {
+        let _permit = upload_limiter.acquire(&username).await?;
 
-            let bytes = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?.to_vec();
+        let mut file: Option<(String, Option<String>, ReceivedFile)> = None;
+        let mut description: Option<String> = None;
+        let mut ignored_fields = 0;
 
-            let document = DocumentEntry {
-                id: None,  // We set this to None, as MongoDB will generate an ObjectId for us
-                filename: filename.clone(),
-                content: Binary {
-                    subtype: bson::spec::BinarySubtype::Generic,
-                    bytes,
-                },
-                user: user.username,
-            };
+        let parsed: poem::Result<()> = async {
+            while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+                match field.name() {
+                    Some("file") if file.is_none() => {
+                        let filename = field.file_name()
+                            .map(ToString::to_string)
+                            .unwrap_or_else(|| "upload".to_string());
 
-            match insert_document(db.as_ref(), document).await {
-                Ok(id) => return Ok(id.to_hex()),
-                Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+                        let content_type = field.content_type().map(ToString::to_string);
+
+                        let received = receive_file(field.into_async_read(), &storage, &bucket, &filename, &upload_config)
+                            .await
+                            .map_err(|e| receive_error(e, &upload_config))?;
+                        // Empty content is always buffered, so there is nothing to clean up.
+                        check_not_empty(received.size, &upload_config)?;
+                        file = Some((filename, content_type, received));
+                    }
+                    Some("description") => {
+                        let text = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                        let mut errors = ValidationErrors::default();
+                        validate_metadata(&mut errors, &metadata_limits, Some(&text), &[]);
+                        errors.into_result()?;
+                        description = Some(text);
+                    }
+                    _ => ignored_fields += 1,
+                }
             }
+            Ok(())
         }
-    }
+        .await;
 
-    Err(StatusCode::BAD_REQUEST)
-}
+        if let Err(e) = parsed {
+            // A file streamed to GridFS or the storage directory before the rest of the form turned out to be
+            // invalid isn't referenced by anything.
+            match file {
+                Some((_, _, ReceivedFile { content: ReceivedContent::GridFs(id), .. })) => {
+                    let _ = delete_gridfs_file(&bucket, id).await;
+                }
+                Some((_, _, ReceivedFile { content: ReceivedContent::Stored, hash, .. })) => {
+                    let _ = storage.delete(&hash).await;
+                }
+                _ => {}
+            }
+            return Err(e);
+        }
 
+        // Only a single file is stored per request. Until multiple files are supported, make it
+        // visible when a client sent more than we processed, as it will assume everything was uploaded.
+        if ignored_fields > 0 {
+            tracing::warn!(
+                username = %username,
+                ignored_fields,
+                "Upload contained fields that were not processed, only the first file is stored"
+            );
+        }
 
+        let Some((filename, content_type, received)) = file else {
+            return Err(StatusCode::BAD_REQUEST.into());
+        };
 
-// This endpoint is made to handle the download of a selected file.
-//
-// Arguments: takes a path with the id of the file and a mongodb collection
-// Returns: a response with the file content
-//
-// We use the get_document_by_id function to get the file from the mongodb.
-// We use the address of a double pointer to the mongodb collection.
-// The filename is extracted from the document and used to set the content-disposition header for the response
-// The content type is set to application/octet-stream.
+        let hash = received.hash;
+        let size = received.size;
+        let gridfs_id = match received.content {
+            ReceivedContent::Buffered(bytes) => {
+                storage.put(&hash, bytes).await.map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+                None
+            }
+            ReceivedContent::GridFs(id) => Some(id),
+            ReceivedContent::Stored => None,
+        };
+
+        let document = DocumentEntry {
+            // The id is assigned here instead of by MongoDB, so the metadata mirroring the document
+            // can be built before the document is moved into the insert.
+            id: Some(ObjectId::new()),
+            filename,
+            content: None,
+            content_hash: Some(hash.clone()),
+            gridfs_id,
+            user: username.clone(),
+            description,
+            content_type,
+            shared_with: Vec::new(),
+            is_public: false,
+            size_bytes: Some(size),
+            version: 1,
+            updated_at: None,
+            upload_ip: client_ip(req),
+            folder: None,
+            tags: Vec::new(),
+        };
+
+        let entry = FileMetadata::from_document(&document);
+        let uploaded_filename = document.filename.clone();
+
+        let files = Collection::clone(&db);
+        let metadata = Collection::clone(&metadata);
+        let inserted = with_transaction(&client, move |session| {
+            async move {
+                let id = insert_document(&files, document, session).await?;
+                if let Some(entry) = entry {
+                    upsert_file_metadata_in_session(&metadata, &entry, session).await?;
+                }
+                Ok(id)
+            }
+            .boxed()
+        })
+        .await;
+
+        match inserted {
+            Ok(id) => {
+                log_file_access(&access_log, FileAccessLog::new(id, &username, "upload", client_ip(req))).await;
+                check_storage_quota(&db, &quota_alerts, &quota, &username).await;
+                events.publish(&username, FileEvent::FileUploaded { file: FileRef { id: id.to_hex(), filename: uploaded_filename } });
+                Ok(id.to_hex())
+            }
+            Err(e) => {
+                // Don't leave content behind for a file that was never stored.
+                match gridfs_id {
+                    Some(id) => { let _ = delete_gridfs_file(&bucket, id).await; }
+                    None => { let _ = storage.delete(&hash).await; }
+                }
+                Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        }
+    }
+    .await;
+
+    if uploaded.is_err() {
+        authorization.restore_ticket(&tickets).await;
+    }
+    uploaded
+}
+
+// Turns a failed upload into the response sent to the client.
+fn receive_error(error: ReceiveError, config: &UploadConfig) -> Error {
+    match error {
+        ReceiveError::TooLarge => Error::from_string(
+            format!("Files can be at most {} bytes", config.max_file_bytes),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ),
+        ReceiveError::Read(e) => Error::new(e, StatusCode::BAD_REQUEST),
+        ReceiveError::Storage(e) => Error::new(e, StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
 
+// Zero-byte uploads are usually a client bug, so they are rejected with `400 Bad Request` and
+// `empty_file` unless UPLOAD_ALLOW_EMPTY is set.
+fn check_not_empty(size: u64, config: &UploadConfig) -> poem::Result<()> {
+    if size == 0 && !config.allow_empty_files {
+        return Err(Error::from_string(EMPTY_FILE, StatusCode::BAD_REQUEST));
+    }
+    Ok(())
+}
 
-// If the file is not found, we return a 404 Not Found error
+// A MIME type must at least look like `type/subtype`.
+fn is_valid_mime_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    match essence.split_once('/') {
+        Some((kind, subtype)) => {
+            let token = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c));
+            token(kind) && token(subtype)
+        }
+        None => false,
+    }
+}
 
+#[derive(Serialize)]
+pub struct ReplacedContent {
+    id: String,
+    filename: String,
+    content_type: Option<String>,
+    content_hash: String,
+    size_bytes: u64,
+    version: i64,
+    updated_at: DateTime<Utc>,
+}
+
+// Handles PUT requests to /files/:id/content, replacing the content of a file while keeping its id,
+// name, description and shares.
+//
+// # Arguments
+// - `Path(id)`: The ObjectId of the file as a hex string.
+// - `multipart`: A form with a `file` field holding the new content. Its content type replaces the
+//   stored one, and the stored one is kept if the field has none.
+//
+// The content is received like in /upload, with the same size limit. With FILE_VERSIONING enabled the
+// previous content is kept in the `file_versions` collection, otherwise it is released.
+//
+// # Returns
+// - `200 OK` with `{ "id", "filename", "content_type", "content_hash", "size_bytes", "version", "updated_at" }`.
+// - `400 Bad Request` if the id is malformed or the form has no `file` field, or with `empty_file`
+//   if the new content is empty and UPLOAD_ALLOW_EMPTY isn't set.
+// - `404 Not Found` if the file doesn't exist or isn't owned by the caller.
+// - `409 Conflict` if the content was replaced by another request at the same time.
+// - `413 Payload Too Large` if the new content exceeds UPLOAD_MAX_BYTES.
+// - `415 Unsupported Media Type` if the content type of the field isn't a valid MIME type.
 #[poem_grants::protect("user")]
+#[handler]
+pub async fn replace_file_content(
+    req: &Request,
+    Path(id): Path<String>,
+    mut multipart: Multipart,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    versions: Data<&Arc<Collection<FileVersion>>>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+    bucket: Data<&GridFsBucket>,
+    upload_limiter: Data<&UploadLimiter>,
+    upload_config: Data<&UploadConfig>,
+) -> poem::Result<Json<ReplacedContent>> {
+    let user = extract_user(req)?;
+    let id = parse_object_id(&id)?;
+
+    let current = get_document_by_id(&db, &id.to_hex())
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .filter(|doc| doc.user == user.username)
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+    let _permit = upload_limiter.acquire(&user.username).await?;
+
+    let mut received = None;
+    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let content_type = field.content_type().map(ToString::to_string);
+        if let Some(content_type) = &content_type
+            && !is_valid_mime_type(content_type)
+        {
+            return Err(Error::from_string(
+                format!("{:?} is not a valid content type", content_type),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ));
+        }
+        let file = receive_file(field.into_async_read(), &storage, &bucket, &current.filename, &upload_config)
+            .await
+            .map_err(|e| receive_error(e, &upload_config))?;
+        check_not_empty(file.size, &upload_config)?;
+        received = Some((content_type, file));
+        break;
+    }
+    let Some((content_type, file)) = received else {
+        return Err(Error::from_string("The form has no file field", StatusCode::BAD_REQUEST));
+    };
+
+    let gridfs_id = match file.content {
+        ReceivedContent::Buffered(bytes) => {
+            storage.put(&file.hash, bytes)
+                .await
+                .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+            None
+        }
+        ReceivedContent::GridFs(gridfs_id) => Some(gridfs_id),
+        ReceivedContent::Stored => None,
+    };
+    let replacement = ContentReplacement {
+        content_hash: file.hash,
+        gridfs_id,
+        size_bytes: file.size,
+        content_type,
+    };
+
+    let previous = match replace_document_content(&db, id, &user.username, current.version, &replacement).await {
+        Ok(Some(previous)) => previous,
+        outcome => {
+            // The new content isn't referenced by anything.
+            let _ = release_content(&storage, &bucket, None, replacement.gridfs_id, Some(&replacement.content_hash)).await;
+            return Err(match outcome {
+                Err(e) => Error::new(e, StatusCode::INTERNAL_SERVER_ERROR),
+                _ => Error::from_string("The file was changed by another request, try again", StatusCode::CONFLICT),
+            });
+        }
+    };
+
+    let version = previous.version + 1;
+    if upload_config.keep_versions {
+        if let Err(e) = insert_file_version(&versions, &FileVersion::from_document(id, previous)).await {
+            tracing::error!(file_id = %id, error = %e, "Failed to keep the previous version of a file");
+        }
+    } else if let Err(e) = release_content(
+        &storage,
+        &bucket,
+        previous.content.as_ref(),
+        previous.gridfs_id,
+        previous.content_hash.as_deref(),
+    )
+    .await
+    {
+        tracing::warn!(file_id = %id, error = %e, "Failed to release the previous content of a file");
+    }
+
+    let updated = get_document_by_id(&db, &id.to_hex())
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+    if let Some(entry) = FileMetadata::from_document(&updated)
+        && let Err(e) = upsert_file_metadata(&metadata, &entry).await
+    {
+        tracing::warn!(file_id = %id, error = %e, "Failed to write file metadata");
+    }
+    log_file_access(&access_log, FileAccessLog::new(id, &user.username, "replace_content", client_ip(req))).await;
+
+    Ok(Json(ReplacedContent {
+        id: id.to_hex(),
+        filename: updated.filename,
+        content_type: updated.content_type,
+        content_hash: replacement.content_hash,
+        size_bytes: replacement.size_bytes,
+        version,
+        updated_at: updated.updated_at.unwrap_or_else(Utc::now),
+    }))
+}
+
+// Handles DELETE requests to /files/:id, deleting a file owned by the caller.
+//
+// The content is only deleted once no other file with identical content references it.
+//
+// # Returns
+// - `200 OK` once the file is deleted.
+// - `400 Bad Request` if the id is malformed.
+// - `404 Not Found` if the file doesn't exist or belongs to someone else.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn delete_file(
+    req: &Request,
+    Path(id): Path<String>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    versions: Data<&Arc<Collection<FileVersion>>>,
+    bucket: Data<&GridFsBucket>,
+    events: Data<&EventBus>,
+) -> poem::Result<StatusCode, Error> {
+    let user = extract_user(req)?;
+    let id = parse_object_id(&id)?;
+
+    if remove_file(&db, &metadata, &storage, &versions, &bucket, &events, id, &user.username).await? {
+        Ok(StatusCode::OK)
+    } else {
+        Err(Error::from_status(StatusCode::NOT_FOUND))
+    }
+}
+
+// Deletes a file owned by `owner` along with its metadata, releases its content and tells the
+// owner's /events connections about it.
+//
+// # Returns
+// - `Ok(false)` if the file doesn't exist or belongs to someone else.
+pub(crate) async fn remove_file(
+    db: &Collection<DocumentEntry>,
+    metadata: &Collection<FileMetadata>,
+    storage: &Storage,
+    versions: &Collection<FileVersion>,
+    bucket: &GridFsBucket,
+    events: &EventBus,
+    id: ObjectId,
+    owner: &str,
+) -> poem::Result<bool> {
+    let Some(document) = delete_document(db, id, owner)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+    else {
+        return Ok(false);
+    };
+
+    delete_file_metadata(metadata, id)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    release_content(storage, bucket, document.content.as_ref(), document.gridfs_id, document.content_hash.as_deref())
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // Versions kept by PUT /files/:id/content hold on to their content until the file is gone.
+    let old_versions = delete_file_versions(versions, id)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    for version in old_versions {
+        release_content(storage, bucket, version.content.as_ref(), version.gridfs_id, version.content_hash.as_deref())
+            .await
+            .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    }
+
+    events.publish(owner, FileEvent::FileDeleted { file: FileRef { id: id.to_hex(), filename: document.filename } });
+    Ok(true)
+}
+
+// Handles GET requests to /files/duplicates, finding the caller's files with identical content.
+//
+// Files uploaded before content hashes were recorded can't be compared, and are left out.
+//
+// # Returns
+// - `200 OK` with `[{ "content_hash", "files": [{ "id", "filename" }] }]`, one entry per content
+//   that was uploaded more than once.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn get_duplicate_files(
+    req: &Request,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+) -> poem::Result<Json<Vec<DuplicateGroup>>, Error> {
+    let user = extract_user(req)?;
+    find_duplicate_files(&metadata, &user.username)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+#[derive(Deserialize)]
+pub struct ResolveDuplicatesRequest {
+    keep: String,
+    delete: Vec<String>,
+}
+
+// Handles POST requests to /files/duplicates/resolve, deleting duplicates of a file in one go.
+//
+// # Arguments
+// - `Json(payload)`: `{ "keep": "id1", "delete": ["id2", "id3"] }`
+//
+// Every file is checked before anything is deleted, so a bad id leaves all files in place.
+//
+// # Returns
+// - `200 OK` with `{ "deleted": n }`.
+// - `400 Bad Request` if an id is malformed, `keep` is also listed in `delete`, or a file to
+//   delete doesn't have the same content as `keep`. With `too_many_items` if `delete` has more
+//   than BULK_MAX_ITEMS ids.
+// - `404 Not Found` if one of the files doesn't exist or belongs to someone else.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn resolve_duplicate_files(
+    req: &Request,
+    Json(payload): Json<ResolveDuplicatesRequest>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    versions: Data<&Arc<Collection<FileVersion>>>,
+    bucket: Data<&GridFsBucket>,
+    events: Data<&EventBus>,
+    bulk: Data<&BulkLimits>,
+) -> poem::Result<Json<serde_json::Value>, Error> {
+    let user = extract_user(req)?;
+    check_item_count(&bulk, payload.delete.len())?;
+
+    let keep = parse_object_id(&payload.keep)?;
+    let mut delete = payload.delete.iter().map(|id| parse_object_id(id)).collect::<poem::Result<Vec<_>>>()?;
+    delete.sort();
+    delete.dedup();
+    if delete.contains(&keep) {
+        return Err(Error::from_string("The file to keep can't be deleted", StatusCode::BAD_REQUEST));
+    }
+
+    let mut ids = delete.clone();
+    ids.push(keep);
+    let files = get_metadata_by_ids(&metadata, &user.username, &ids)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    if files.len() != ids.len() {
+        return Err(Error::from_string("One of the files doesn't exist", StatusCode::NOT_FOUND));
+    }
+
+    let kept_hash = files.iter().find(|file| file.id == keep).and_then(|file| file.content_hash.as_ref());
+    if kept_hash.is_none() || files.iter().any(|file| file.content_hash.as_ref() != kept_hash) {
+        return Err(Error::from_string("The files don't have the same content", StatusCode::BAD_REQUEST));
+    }
+
+    let mut deleted = 0;
+    for id in delete {
+        if remove_file(&db, &metadata, &storage, &versions, &bucket, &events, id, &user.username).await? {
+            deleted += 1;
+        }
+    }
+
+    Ok(Json(serde_json::json!({ "deleted": deleted })))
+}
+
+#[derive(Deserialize)]
+pub struct DescriptionUpdate {
+    description: String,
+}
+
+// Handles PATCH requests to /files/:id/description, replacing the description of a file.
+//
+// # Arguments
+// - `req`: The request, used to extract the authenticated user.
+// - `Path(id)`: The ObjectId of the file as a hex string.
+// - `Json(payload)`: `{ "description": "..." }`
+// - `db`: Shared MongoDB collection injected using Poem's `Data`.
+//
+// # Returns
+// - `200 OK` if the description was updated.
+// - `400 Bad Request` if the id is malformed.
+// - `404 Not Found` if the file doesn't exist or isn't owned by the requesting user.
+// - `422 Unprocessable Entity` with the exceeded limits if the description is over the METADATA_MAX_* limits.
+// - `500 Internal Server Error` if a DB error occurs.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn update_file_description(
+    req: &Request,
+    Path(id): Path<String>,
+    Json(payload): Json<DescriptionUpdate>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    metadata_limits: Data<&MetadataLimits>,
+) -> poem::Result<StatusCode, Error> {
+    let user = extract_user(req)?;
+
+    let mut errors = ValidationErrors::default();
+    validate_metadata(&mut errors, &metadata_limits, Some(&payload.description), &[]);
+    errors.into_result()?;
+
+    let id = parse_object_id(&id)?;
+
+    match update_document_description(&db, id, &user.username, &payload.description).await {
+        Ok(0) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Ok(_) => update_metadata_description(&metadata, id, &payload.description)
+            .await
+            .map(|_| StatusCode::OK)
+            .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BulkTagRequest {
+    ids: Vec<String>,
+    #[serde(default)]
+    add_tags: Vec<String>,
+    #[serde(default)]
+    remove_tags: Vec<String>,
+}
+
+// Trims the tags and drops repeated ones, keeping the order they were sent in.
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim().to_string();
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+// The tags a file ends up with once `add` and `remove` are applied, as MongoDB applies them.
+fn retagged(tags: &[String], add: &[String], remove: &[String]) -> Vec<String> {
+    let mut tags: Vec<String> = tags.iter().filter(|tag| !remove.contains(tag)).cloned().collect();
+    for tag in add {
+        if !tags.contains(tag) {
+            tags.push(tag.clone());
+        }
+    }
+    tags
+}
+
+// Handles POST requests to /files/bulk-tag, adding and removing tags on several files at once.
+//
+// # Arguments
+// - `Json(payload)`: `{ "ids": ["id1", "id2"], "add_tags": ["work"], "remove_tags": ["inbox"] }`.
+//   Either list of tags may be left out, but not both.
+//
+// Ids of files that don't exist or belong to someone else are ignored. The new tags of every file
+// are checked against the METADATA_MAX_* limits before any file is changed.
+//
+// # Returns
+// - `200 OK` with `{ "modified_count": n }`, the number of files whose tags changed.
+// - `400 Bad Request` if an id is malformed, or with `too_many_items` if more than BULK_MAX_ITEMS
+//   ids are sent.
+// - `422 Unprocessable Entity` if no or empty tags are sent, a tag is both added and removed, or a
+//   file would exceed the METADATA_MAX_* limits with its new tags.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn bulk_tag_files(
+    req: &Request,
+    Json(payload): Json<BulkTagRequest>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    metadata_limits: Data<&MetadataLimits>,
+    bulk: Data<&BulkLimits>,
+) -> poem::Result<Json<serde_json::Value>> {
+    let user = extract_user(req)?;
+    check_item_count(&bulk, payload.ids.len())?;
+    let mut ids = payload.ids.iter().map(|id| parse_object_id(id)).collect::<poem::Result<Vec<_>>>()?;
+    ids.sort();
+    ids.dedup();
+
+    let add = normalize_tags(payload.add_tags);
+    let remove = normalize_tags(payload.remove_tags);
+    let mut errors = ValidationErrors::default();
+    if add.is_empty() && remove.is_empty() {
+        errors.add("tags", "Send add_tags, remove_tags or both");
+    }
+    if add.iter().chain(&remove).any(String::is_empty) {
+        errors.add("tags", "Tags can't be empty");
+    }
+    if add.iter().any(|tag| remove.contains(tag)) {
+        errors.add("tags", "A tag can't be both added and removed");
+    }
+    validate_metadata(&mut errors, &metadata_limits, None, &add);
+    errors.into_result()?;
+
+    // Read first, so only files whose tags change are written and counted, and none is left over
+    // the limits.
+    let files = get_metadata_by_ids(&metadata, &user.username, &ids)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let mut changed = Vec::new();
+    for file in &files {
+        let tags = retagged(&file.tags, &add, &remove);
+        if tags == file.tags {
+            continue;
+        }
+        let mut errors = ValidationErrors::default();
+        validate_metadata(&mut errors, &metadata_limits, file.description.as_deref(), &tags);
+        errors.into_result()?;
+        changed.push(file.id);
+    }
+
+    if !changed.is_empty() {
+        update_document_tags(&db, &user.username, &changed, &add, &remove)
+            .await
+            .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+        update_metadata_tags(&metadata, &user.username, &changed, &add, &remove)
+            .await
+            .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    }
+
+    Ok(Json(serde_json::json!({ "modified_count": changed.len() })))
+}
+
+#[derive(Deserialize)]
+pub struct VisibilityUpdate {
+    is_public: bool,
+}
+
+#[derive(Serialize)]
+pub struct VerifyResult {
+    valid: bool,
+    stored_hash: String,
+    // `None` if the content couldn't be found.
+    computed_hash: Option<String>,
+    size_bytes: u64,
+}
+
+// Handles GET requests to /files/:id/verify, checking a file's content against its checksum.
+//
+// The content is read in full and hashed again. A mismatch, or content that can't be found, is
+// logged and recorded in the `corruption_reports` collection for /admin/corruption-reports.
+//
+// # Returns
+// - `200 OK` with `{ "valid": bool, "stored_hash": "...", "computed_hash": "...", "size_bytes": n }`.
+// - `400 Bad Request` if the id is malformed.
+// - `404 Not Found` if the file doesn't exist, or the caller is neither its owner nor an admin.
+// - `409 Conflict` if the file was uploaded before checksums were recorded, see /admin/migrate/checksums.
+#[poem_grants::protect(any("user", "admin"))]
+#[handler]
+pub async fn verify_file(
+    req: &Request,
+    Path(id): Path<String>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    storage: Data<&Storage>,
+    bucket: Data<&GridFsBucket>,
+    reports: Data<&Arc<Collection<CorruptionReport>>>,
+) -> poem::Result<Json<VerifyResult>> {
+    let user = extract_user(req)?;
+    let file_id = parse_object_id(&id)?;
+
+    let doc = get_document_by_id(&db, &id)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .filter(|doc| doc.user == user.username || user.is_admin())
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+    let stored_hash = doc.content_hash.clone().ok_or_else(|| {
+        Error::from_string("The file has no checksum to verify against", StatusCode::CONFLICT)
+    })?;
+
+    let bytes = document_bytes(&storage, &bucket, &doc)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let computed_hash = bytes.as_ref().map(|bytes| format!("{:x}", Sha256::digest(bytes)));
+    let size_bytes = bytes.as_ref().map_or(0, |bytes| bytes.len() as u64);
+    let valid = computed_hash.as_ref() == Some(&stored_hash);
+
+    if !valid {
+        tracing::error!(
+            file_id = %file_id,
+            stored_hash = %stored_hash,
+            computed_hash = computed_hash.as_deref().unwrap_or("missing"),
+            "File content doesn't match its checksum"
+        );
+        let report = CorruptionReport {
+            file_id,
+            detected_at: Utc::now(),
+            stored_hash: stored_hash.clone(),
+            computed_hash: computed_hash.clone(),
+        };
+        insert_corruption_report(&reports, report)
+            .await
+            .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    }
+
+    Ok(Json(VerifyResult { valid, stored_hash, computed_hash, size_bytes }))
+}
+
+// Handles POST requests to /files/:id/visibility, making a file public or private again.
+//
+// # Arguments
+// - `Path(id)`: The ObjectId of the file as a hex string.
+// - `Json(payload)`: `{ "is_public": true }`
+//
+// # Returns
+// - `200 OK` once the visibility has changed. Public files can be downloaded by anyone from
+//   /public/files/:id.
+// - `400 Bad Request` if the id is malformed.
+// - `404 Not Found` if the file doesn't exist or isn't owned by the caller.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn set_file_visibility(
+    req: &Request,
+    Path(id): Path<String>,
+    Json(payload): Json<VisibilityUpdate>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+) -> poem::Result<StatusCode, Error> {
+    let user = extract_user(req)?;
+    let id = parse_object_id(&id)?;
+
+    match set_document_visibility(&db, id, &user.username, payload.is_public).await {
+        Ok(0) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Ok(_) => set_metadata_visibility(&metadata, id, payload.is_public)
+            .await
+            .map(|_| StatusCode::OK)
+            .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+// Folders may be nested this deep, with names of at most MAX_FOLDER_NAME_LENGTH characters.
+const MAX_FOLDER_DEPTH: usize = 10;
+const MAX_FOLDER_NAME_LENGTH: usize = 100;
+
+// Normalizes a folder path like `archive//2024/` to `/archive/2024`.
+//
+// # Returns
+// - `Some(None)` for the root folder `/`.
+// - `Some(Some(folder))` for any other folder.
+// - `None` if the path has a `.` or `..` segment, a backslash or control character, or is too
+//   deep or too long, so a folder path never has more than one spelling.
+fn sanitize_folder(path: &str) -> Option<Option<String>> {
+    let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+    let valid = names.len() <= MAX_FOLDER_DEPTH
+        && names.iter().all(|name| {
+            *name != "."
+                && *name != ".."
+                && name.chars().count() <= MAX_FOLDER_NAME_LENGTH
+                && !name.chars().any(|c| c == '\\' || c.is_control())
+        });
+    if !valid {
+        return None;
+    }
+    Some((!names.is_empty()).then(|| format!("/{}", names.join("/"))))
+}
+
+// Reads the folder from the `Destination` header of a MOVE request. The header holds the path of
+// the folder under /files, like `/files/archive`, or a full URL to it, and may be percent-encoded.
+fn destination_folder(destination: &str) -> Option<Option<String>> {
+    let path = destination.parse::<Uri>().ok()?.path().to_string();
+    let path = percent_decode_str(&path).decode_utf8().ok()?;
+    let folder = path.strip_prefix("/files")?;
+    if !folder.is_empty() && !folder.starts_with('/') {
+        // e.g. "/filesarchive"
+        return None;
+    }
+    sanitize_folder(folder)
+}
+
+// Moves a file owned by `owner` into `folder` and answers with `201 Created`, pointing `Location`
+// at the file. Files are addressed by id, so moving doesn't change where they are downloaded from.
+async fn move_file(
+    db: &Collection<DocumentEntry>,
+    metadata: &Collection<FileMetadata>,
+    id: &str,
+    owner: &str,
+    folder: Option<String>,
+) -> poem::Result<Response> {
+    let id = parse_object_id(id)?;
+
+    match set_document_folder(db, id, owner, folder.as_deref()).await {
+        Ok(0) => return Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Ok(_) => {}
+        Err(e) => return Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+    set_metadata_folder(metadata, id, folder.as_deref())
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Json(serde_json::json!({ "id": id.to_hex(), "folder": folder.as_deref().unwrap_or("/") }))
+        .with_status(StatusCode::CREATED)
+        .with_header(LOCATION, format!("/files/{}", id.to_hex()))
+        .into_response())
+}
+
+// Handles MOVE requests to /files/:id, the WebDAV way of moving a file into another folder.
+//
+// # Arguments
+// - `Destination` header: the folder under /files, like `/files/archive/2024`, or `/files` for the root folder.
+//
+// # Returns
+// - `201 Created` with `{ "id": "...", "folder": "/archive/2024" }`, and `Location: /files/:id`.
+// - `400 Bad Request` if the id is malformed, or `Destination` is missing or not a folder under /files.
+// - `404 Not Found` if the file doesn't exist or isn't owned by the caller.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn move_file_webdav(
+    req: &Request,
+    Path(id): Path<String>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+) -> poem::Result<Response> {
+    let user = extract_user(req)?;
+    let folder = req
+        .header("Destination")
+        .and_then(destination_folder)
+        .ok_or_else(|| Error::from_string("Destination must be a folder under /files", StatusCode::BAD_REQUEST))?;
+    move_file(&db, &metadata, &id, &user.username, folder).await
+}
+
+#[derive(Deserialize)]
+pub struct MoveRequest {
+    folder: String,
+}
+
+// Handles POST requests to /files/:id/move, for clients that can't send MOVE requests.
+//
+// # Arguments
+// - `Json(payload)`: `{ "folder": "/archive/2024" }`, with `"/"` for the root folder.
+//
+// # Returns
+// - Like MOVE /files/:id.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn move_file_post(
+    req: &Request,
+    Path(id): Path<String>,
+    Json(payload): Json<MoveRequest>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+) -> poem::Result<Response> {
+    let user = extract_user(req)?;
+    let folder = sanitize_folder(&payload.folder)
+        .ok_or_else(|| Error::from_string("Invalid folder", StatusCode::BAD_REQUEST))?;
+    move_file(&db, &metadata, &id, &user.username, folder).await
+}
+
+#[derive(Deserialize)]
+pub struct ShareRequest {
+    username: String,
+}
+
+// Handles POST requests to /files/:id/share, letting the owner of a file share it with another user.
+//
+// # Arguments
+// - `Path(id)`: The ObjectId of the file as a hex string.
+// - `Json(payload)`: `{ "username": "recipient" }`
+//
+// # Returns
+// - `200 OK` once the recipient has access. The recipient is then notified according to their
+//   notification preferences, in the background.
+// - `400 Bad Request` if the id is malformed.
+// - `404 Not Found` if the file isn't owned by the caller, or the recipient doesn't exist.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn share_file(
+    req: &Request,
+    Path(id): Path<String>,
+    Json(payload): Json<ShareRequest>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    users: Data<&Arc<Collection<User>>>,
+) -> poem::Result<StatusCode, Error> {
+    let user = extract_user(req)?;
+    let id = parse_object_id(&id)?;
+
+    let recipient = find_user(&users, &payload.username)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_string("The user you are sharing with doesn't exist", StatusCode::NOT_FOUND))?;
+
+    let document = share_document(&db, id, &user.username, &recipient.username)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+    add_metadata_share(&metadata, id, &recipient.username)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // Re-sharing a file doesn't notify the recipient again.
+    if !document.shared_with.contains(&recipient.username) {
+        notify_file_shared(&recipient.notifications, FileSharedEvent {
+            file_id: id.to_hex(),
+            filename: document.filename,
+            shared_by: user.username,
+            recipient: recipient.username,
+        });
+    }
+
+    Ok(StatusCode::OK)
+}
+
+
+
+#[derive(Deserialize)]
+pub struct PresignedQuery {
+    sig: Option<String>,
+    exp: Option<u64>,
+}
+
+// This endpoint is made to handle the download of a selected file.
+//
+// Arguments: takes a path with the id of the file and a mongodb collection
+// Returns: a response with the file content
+//
+// We use the get_document_by_id function to get the file from the mongodb.
+// We use the address of a double pointer to the mongodb collection.
+// The filename is extracted from the document and used to set the content-disposition header for the response
+// The content type is the one stored with the file. Older files without one get it detected from their content.
+// Every successful download is recorded in the file access log, and with RECENT_DOWNLOADS in the user's recent downloads.
+//
+// Only the owner of the file, and users it has been shared with, may download it, unless the requesting user is an admin.
+//
+// Instead of a token, the request may carry `?sig=...&exp=...` from a pre-signed URL created with
+// /files/:id/presign. The download is then allowed without a token, and attributed to the owner.
+// An invalid or expired signature is answered with 403 Forbidden.
+
+
+// If the id is malformed we return a 400 Bad Request error.
+// If the file is not found, or belongs to someone else, we return a 404 Not Found error
+
 #[handler]
 pub async fn download_file(
+    req: &Request,
     Path(id): Path<String>,
+    Query(presigned): Query<PresignedQuery>,
     db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    bucket: Data<&GridFsBucket>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+    recent_downloads: Data<&RecentDownloadLog>,
+    downloads: Data<&DownloadConfig>,
 ) -> poem::Result<Response, Error> {
-    match get_document_by_id(&**db, &id).await {
-        Ok(Some(doc)) => {
-            let content_disposition = format!("attachment; filename=\"{}\"", doc.filename);
-
-            let mut response = doc.content.bytes.into_response();
-            response.headers_mut().insert(
-                "Content-Disposition",
-                HeaderValue::from_str(&content_disposition).unwrap(),
-            );
-            response.headers_mut().insert(
-                "Content-Type",
-                HeaderValue::from_static("application/octet-stream"),
-            );
+    parse_object_id(&id)?;
+
+    // `None` for pre-signed downloads, which aren't tied to a user.
+    let user = match (presigned.sig, presigned.exp) {
+        (Some(sig), Some(exp)) => {
+            if !verify_presigned_url(&id, &sig, exp) {
+                return Err(Error::from_string("Invalid or expired signature", StatusCode::FORBIDDEN));
+            }
+            None
+        }
+        // Without a signature this is an ordinary download, which requires the user role.
+        _ => {
+            let user = extract_user(req)?;
+            if !user.has_role("user") {
+                return Err(Error::from_status(StatusCode::FORBIDDEN));
+            }
+            Some(user)
+        }
+    };
+
+    match get_document_by_id(&db, &id).await {
+        Ok(Some(doc)) if user.as_ref().is_none_or(|user| doc.user == user.username || doc.shared_with.contains(&user.username) || user.is_admin()) => {
+            let bytes = document_bytes(&storage, &bucket, &doc)
+                .await
+                .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+                .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+            // Only recorded once the content was found, so failed downloads don't show up.
+            if let Some(file_id) = doc.id {
+                let entry = match &user {
+                    Some(user) => FileAccessLog::new(file_id, &user.username, "download", client_ip(req)),
+                    None => FileAccessLog::new(file_id, &doc.user, "presigned_download", client_ip(req)),
+                };
+                log_file_access(&access_log, entry).await;
+                if let Some(user) = &user {
+                    record_download(&recent_downloads, &user.username, file_id, &doc.filename).await;
+                }
+            }
 
+            let content_type = document_content_type(&db, &metadata, &doc, &bytes).await;
+            let mut response = attachment_response(&doc.filename, &downloads, &content_type, bytes);
+            if let Some(etag) = file_etag(&doc) {
+                response.headers_mut().insert(ETAG, etag);
+            }
             Ok(response)
         }
-        Ok(None) => Err(Error::from_status(StatusCode::NOT_FOUND)),
-        Err(_) => Err(Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)),
+        Ok(_) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BatchDownloadRequest {
+    ids: Vec<String>,
+}
+
+// Handles POST requests to /files/download-batch, sending several files in one `multipart/mixed`
+// response.
+//
+// Every file is a part with its own `Content-Disposition` and `Content-Type` headers, in the order
+// of `ids`. The parts are loaded one at a time while the response is sent, so clients can process
+// the first file before the rest arrive. Each file is recorded in the file access log, and with
+// RECENT_DOWNLOADS in the caller's recent downloads.
+//
+// # Returns
+// - `200 OK` with a `multipart/mixed` body.
+// - `400 Bad Request` if no ids are sent or one is malformed, or with `too_many_items` if more than
+//   BULK_MAX_ITEMS are sent.
+// - `404 Not Found` naming the first id that doesn't exist, or that the caller may not download
+//   like /files/:id, before anything is sent.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn download_batch(
+    req: &Request,
+    Json(payload): Json<BatchDownloadRequest>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    bucket: Data<&GridFsBucket>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+    recent_downloads: Data<&RecentDownloadLog>,
+    downloads: Data<&DownloadConfig>,
+    bulk: Data<&BulkLimits>,
+) -> poem::Result<Response> {
+    let user = extract_user(req)?;
+    check_item_count(&bulk, payload.ids.len())?;
+    if payload.ids.is_empty() {
+        return Err(Error::from_string("At least one id is required", StatusCode::BAD_REQUEST));
+    }
+
+    let mut documents = Vec::with_capacity(payload.ids.len());
+    for id in &payload.ids {
+        parse_object_id(id)?;
+        let doc = get_document_by_id(&db, id)
+            .await
+            .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+            .filter(|doc| doc.user == user.username || doc.shared_with.contains(&user.username) || user.is_admin())
+            .ok_or_else(|| Error::from_string(format!("File {} not found", id), StatusCode::NOT_FOUND))?;
+        documents.push(doc);
     }
-}
\ No newline at end of file
+
+    for doc in &documents {
+        let Some(file_id) = doc.id else { continue };
+        log_file_access(&access_log, FileAccessLog::new(file_id, &user.username, "download", client_ip(req))).await;
+        record_download(&recent_downloads, &user.username, file_id, &doc.filename).await;
+    }
+
+    let parts: Vec<PartFuture> = documents
+        .into_iter()
+        .map(|doc| {
+            let db = db.clone();
+            let metadata = metadata.clone();
+            let storage = Storage::clone(&storage);
+            let bucket = bucket.clone();
+            let fallback = downloads.fallback_filename.clone();
+            Box::pin(async move {
+                let bytes = document_bytes(&storage, &bucket, &doc)
+                    .await
+                    .map_err(std::io::Error::other)?
+                    .ok_or_else(|| std::io::Error::other(format!("The content of file {} is missing", doc.filename)))?;
+                let content_type = document_content_type(&db, &metadata, &doc, &bytes).await;
+                let disposition = content_disposition("attachment", &doc.filename, &fallback);
+                let headers = format!(
+                    "Content-Disposition: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+                    disposition.to_str().unwrap_or("attachment"),
+                    content_type,
+                    bytes.len()
+                );
+                Ok((headers, bytes))
+            }) as PartFuture
+        })
+        .collect();
+
+    let mut boundary_bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut boundary_bytes);
+    let boundary: String = boundary_bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    Ok(Response::builder()
+        .content_type(format!("multipart/mixed; boundary={}", boundary))
+        .body(Body::from_async_read(MultipartMixedReader::new(boundary, parts))))
+}
+
+// The ETag of a file is its SHA-256 hash. Files uploaded before hashes were stored have none.
+fn file_etag(doc: &DocumentEntry) -> Option<HeaderValue> {
+    doc.content_hash
+        .as_ref()
+        .and_then(|hash| HeaderValue::from_str(&format!("\"{}\"", hash)).ok())
+}
+
+// Handles HEAD requests to /files/:id, sending the headers of /download_file/:id without the content,
+// so clients can check that a file exists and how large it is.
+//
+// # Returns
+// - `200 OK` with `Content-Length`, `Content-Type`, `Content-Disposition` and, if known, `ETag`.
+//   Only files without a stored content type have their content read, to detect it.
+// - `404 Not Found` under the same conditions as /download_file/:id.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn download_file_head(
+    req: &Request,
+    Path(id): Path<String>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    blobs: Data<&Arc<Collection<Blob>>>,
+    storage: Data<&Storage>,
+    bucket: Data<&GridFsBucket>,
+    downloads: Data<&DownloadConfig>,
+) -> poem::Result<Response, Error> {
+    let user = extract_user(req)?;
+    parse_object_id(&id)?;
+
+    match get_document_by_id(&db, &id).await {
+        Ok(Some(doc)) if doc.user == user.username || doc.shared_with.contains(&user.username) || user.is_admin() => {
+            let size = document_size(&blobs, &bucket, &doc)
+                .await
+                .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+                .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+            let content_type = match &doc.content_type {
+                Some(content_type) => content_type.clone(),
+                None => {
+                    let bytes = document_bytes(&storage, &bucket, &doc)
+                        .await
+                        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+                        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+                    document_content_type(&db, &metadata, &doc, &bytes).await
+                }
+            };
+
+            let mut response = attachment_response(&doc.filename, &downloads, &content_type, Vec::new());
+            response.headers_mut().insert(CONTENT_LENGTH, size.into());
+            if let Some(etag) = file_etag(&doc) {
+                response.headers_mut().insert(ETAG, etag);
+            }
+            Ok(response)
+        }
+        Ok(_) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+// Handles GET requests to /public/files/:id, downloading a public file. Doesn't require a token.
+//
+// # Returns
+// - `200 OK` with the file content.
+// - `404 Not Found` if the file doesn't exist or isn't public, so private files can't be told
+//   apart from missing ones.
+#[handler]
+pub async fn download_public_file(
+    req: &Request,
+    Path(id): Path<String>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    bucket: Data<&GridFsBucket>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+    downloads: Data<&DownloadConfig>,
+) -> poem::Result<Response, Error> {
+    // A malformed id can't belong to a public file either.
+    if ObjectId::parse_str(&id).is_err() {
+        return Err(Error::from_status(StatusCode::NOT_FOUND));
+    }
+
+    match get_document_by_id(&db, &id).await {
+        Ok(Some(doc)) if doc.is_public => {
+            let bytes = document_bytes(&storage, &bucket, &doc)
+                .await
+                .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+                .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+            // Public downloads are anonymous, so they are attributed to the owner like share link downloads.
+            if let Some(file_id) = doc.id {
+                log_file_access(&access_log, FileAccessLog::new(file_id, &doc.user, "public_download", client_ip(req))).await;
+            }
+            let content_type = document_content_type(&db, &metadata, &doc, &bytes).await;
+            Ok(attachment_response(&doc.filename, &downloads, &content_type, bytes))
+        }
+        Ok(_) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+// Picks the file a filename refers to out of the files matching it.
+//
+// # Returns
+// - `Err(Error)` with `404 Not Found` if nothing matched, or `409 Conflict` listing the ids if
+//   several files did.
+fn single_match(ids: &[ObjectId]) -> poem::Result<ObjectId> {
+    match ids {
+        [] => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        [id] => Ok(*id),
+        ids => {
+            let body = serde_json::json!({
+                "error": "Several files have this name, download one of them by id",
+                "ids": ids.iter().map(|id| id.to_hex()).collect::<Vec<_>>(),
+            });
+            Err(Error::from_response((StatusCode::CONFLICT, Json(body)).into_response()))
+        }
+    }
+}
+
+// Handles GET requests to /files/by-name/:filename, downloading one of the caller's own files by name.
+//
+// Filenames aren't unique, so when the caller has uploaded several files with the same name the
+// request is rejected rather than guessing which one was meant.
+//
+// # Returns
+// - `200 OK` with the file content, like /download_file/:id.
+// - `404 Not Found` if the caller has no file with that name.
+// - `409 Conflict` with `{ "error", "ids": [...] }` if the name matches several files. The ids
+//   can be used with /download_file/:id instead.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn download_file_by_name(
+    req: &Request,
+    Path(filename): Path<String>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    bucket: Data<&GridFsBucket>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+    recent_downloads: Data<&RecentDownloadLog>,
+    downloads: Data<&DownloadConfig>,
+) -> poem::Result<Response, Error> {
+    let user = extract_user(req)?;
+
+    // Only the ids are needed to report an ambiguous name, so don't list every match.
+    let matches = find_metadata_by_filename(&metadata, &user.username, &filename, 10)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let ids: Vec<ObjectId> = matches.iter().map(|file| file.id).collect();
+    let id = single_match(&ids)?;
+
+    let doc = get_document_by_id(&db, &id.to_hex())
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .filter(|doc| doc.user == user.username)
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+    let bytes = document_bytes(&storage, &bucket, &doc)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+    log_file_access(&access_log, FileAccessLog::new(id, &user.username, "download", client_ip(req))).await;
+    record_download(&recent_downloads, &user.username, id, &doc.filename).await;
+
+    let content_type = document_content_type(&db, &metadata, &doc, &bytes).await;
+    Ok(attachment_response(&doc.filename, &downloads, &content_type, bytes))
+}
+
+#[derive(Deserialize)]
+pub struct AccessHistoryQuery {
+    limit: Option<i64>,
+}
+
+// Handles GET requests to /files/:id/access-history, listing who accessed a file and when.
+//
+// # Arguments
+// - `Path(id)`: The ObjectId of the file as a hex string.
+// - `Query(query)`: `?limit=50` - the number of entries to return, capped at MAX_ACCESS_HISTORY_ENTRIES.
+//
+// # Returns
+// - `200 OK` with `[{ accessed_by, action, timestamp, ip_addr }]`, newest first.
+// - `400 Bad Request` if the id is malformed.
+// - `403 Forbidden` if the file belongs to someone else.
+// - `404 Not Found` if the file doesn't exist.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn file_access_history(
+    req: &Request,
+    Path(id): Path<String>,
+    Query(query): Query<AccessHistoryQuery>,
+    db: Data<&Arc<Collection<DocumentEntry>>>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+) -> poem::Result<Json<Vec<AccessHistoryEntry>>, Error> {
+    let user = extract_user(req)?;
+    let file_id = parse_object_id(&id)?;
+
+    match get_document_by_id(&db, &id).await {
+        Ok(Some(doc)) if doc.user == user.username => {}
+        Ok(Some(_)) => return Err(Error::from_status(StatusCode::FORBIDDEN)),
+        Ok(None) => return Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => return Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_ACCESS_HISTORY_ENTRIES).clamp(1, MAX_ACCESS_HISTORY_ENTRIES);
+    get_access_history(&access_log, file_id, limit)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+#[derive(Deserialize)]
+pub struct RecentDownloadsQuery {
+    limit: Option<i64>,
+}
+
+// Handles GET requests to /files/recent, listing the files the caller downloaded most recently.
+//
+// Downloads are only recorded with RECENT_DOWNLOADS enabled, and the oldest are dropped once the
+// capped collection holding them is full, so the listing may be shorter than `limit`.
+//
+// # Arguments
+// - `Query(query)`: `?limit=20` - the number of downloads to return, capped at MAX_RECENT_DOWNLOADS.
+//
+// # Returns
+// - `200 OK` with `[{ file_id, filename, downloaded_at }]`, newest first. A file downloaded
+//   several times is listed for each download.
+// - `404 Not Found` if RECENT_DOWNLOADS isn't enabled.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn list_recent_downloads(
+    req: &Request,
+    Query(query): Query<RecentDownloadsQuery>,
+    recent_downloads: Data<&RecentDownloadLog>,
+) -> poem::Result<Json<Vec<RecentDownloadEntry>>, Error> {
+    let user = extract_user(req)?;
+    if !recent_downloads.enabled() {
+        return Err(Error::from_string("Recent downloads aren't tracked", StatusCode::NOT_FOUND));
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_RECENT_DOWNLOADS).clamp(1, MAX_RECENT_DOWNLOADS);
+    get_recent_downloads(&recent_downloads, &user.username, limit)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_handlers::testing::{bearer, unreachable_database};
+    use crate::auth::middleware::JwtMiddleware;
+    use crate::config::{Config, ImagePreset};
+    use poem::http::header::AUTHORIZATION;
+    use poem::test::TestClient;
+    use poem::{Endpoint, EndpointExt, Route, get, patch, post};
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("report.pdf"), "report.pdf");
+        assert_eq!(csv_field("a,b.txt"), "\"a,b.txt\"");
+        assert_eq!(csv_field("say \"hi\".txt"), "\"say \"\"hi\"\".txt\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn csv_formulas_are_kept_as_text() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("a=b"), "a=b");
+    }
+
+    #[test]
+    fn csv_rows_have_every_column() {
+        let id = ObjectId::new();
+        let row = FileExportRow {
+            id,
+            filename: "a,b.txt".to_string(),
+            size_bytes: Some(42),
+            content_type: None,
+        };
+        let uploaded_at = id.timestamp().try_to_rfc3339_string().unwrap();
+        assert_eq!(csv_row(&row), format!("{},\"a,b.txt\",42,,{}\r\n", id.to_hex(), uploaded_at));
+    }
+
+    #[test]
+    fn xml_text_is_escaped() {
+        assert_eq!(xml_escape("plain.txt"), "plain.txt");
+        assert_eq!(xml_escape("<a href=\"x\">&'</a>"), "&lt;a href=&quot;x&quot;&gt;&amp;&apos;&lt;/a&gt;");
+        assert_eq!(xml_escape("tab\tnew\nline\r"), "tab\tnew\nline\r");
+        assert_eq!(xml_escape("bell\u{7}null\u{0}"), "bellnull");
+        assert_eq!(xml_escape("blåbær 🎉"), "blåbær 🎉");
+    }
+
+    #[test]
+    fn xml_listing_leaves_out_missing_fields() {
+        let files = [FileEntry {
+            id: "abc".to_string(),
+            filename: "a&b.txt".to_string(),
+            description: None,
+            content_type: Some("text/plain".to_string()),
+            is_public: false,
+            folder: None,
+            tags: vec!["x".to_string(), "y".to_string()],
+        }];
+        assert_eq!(
+            files_xml(&files),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<files>\n  <file>\n    <id>abc</id>\n    <filename>a&amp;b.txt</filename>\n    \
+             <content_type>text/plain</content_type>\n    <is_public>false</is_public>\n    <tag>x</tag>\n    <tag>y</tag>\n  </file>\n</files>\n"
+        );
+    }
+
+    #[test]
+    fn listing_format_follows_accept() {
+        let format = |accept| ListingFormat::from_accept(accept);
+        assert!(matches!(format(None), Some(ListingFormat::Json)));
+        assert!(matches!(format(Some("*/*")), Some(ListingFormat::Json)));
+        assert!(matches!(format(Some("application/xml")), Some(ListingFormat::Xml)));
+        assert!(matches!(format(Some("text/xml, application/json")), Some(ListingFormat::Json)));
+        assert!(matches!(format(Some("application/json;q=0.5, application/xml")), Some(ListingFormat::Xml)));
+        assert!(matches!(format(Some("application/xml, application/json;q=0")), Some(ListingFormat::Xml)));
+        assert!(format(Some("text/html")).is_none());
+        assert!(format(Some("application/json;q=0")).is_none());
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        let time = DateTime::parse_from_rfc3339("1994-11-06T08:49:37Z").unwrap().with_timezone(&Utc);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(parse_http_date(""), None);
+    }
+
+    #[test]
+    fn etags_use_weak_comparison() {
+        assert!(etag_matches("W/\"abc\"", "W/\"abc\""));
+        assert!(etag_matches("\"abc\"", "W/\"abc\""));
+        assert!(etag_matches("\"x\", W/\"abc\"", "W/\"abc\""));
+        assert!(etag_matches("*", "W/\"abc\""));
+        assert!(!etag_matches("W/\"abd\"", "W/\"abc\""));
+        assert!(!etag_matches("", "W/\"abc\""));
+    }
+
+    #[tokio::test]
+    async fn images_are_read_up_to_the_limit() {
+        let image = vec![1u8; 1000];
+        assert_eq!(read_image(&image[..], 1000).await.unwrap(), Some(image.clone()));
+        assert_eq!(read_image(&image[..], 999).await.unwrap(), None);
+        assert_eq!(read_image(&[][..], 10).await.unwrap(), Some(Vec::new()));
+
+        // An endless image is cut off right after the limit.
+        let mut endless = tokio::io::repeat(1).take(u64::MAX);
+        assert_eq!(read_image(&mut endless, 1000).await.unwrap(), None);
+        assert_eq!(u64::MAX - endless.limit(), 1001);
+    }
+
+    #[test]
+    fn images_stay_below_the_document_limit() {
+        let config = |max_file_bytes| UploadConfig { max_file_bytes, ..Config::load().uploads };
+        assert_eq!(max_image_bytes(&config(1024)), 1024);
+        assert_eq!(max_image_bytes(&config(100 * 1024 * 1024)), MAX_IMAGE_BYTES);
+        const { assert!(MAX_IMAGE_BYTES < 16 * 1024 * 1024) };
+    }
+
+    #[tokio::test]
+    async fn oversized_batch_images_are_reported_as_failed() {
+        let config = UploadConfig { max_file_bytes: 1024, ..Config::load().uploads };
+        let images = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("file_handler_tests")
+            .collection("images");
+
+        let failed = store_batch_image(&images, "alice", "big.png".to_string(), None, &config).await.err().unwrap();
+        assert_eq!(failed.filename, "big.png");
+        assert_eq!(failed.reason, "Images can be at most 1024 bytes");
+    }
+
+    #[test]
+    fn only_allowed_image_types_are_accepted() {
+        let config = UploadConfig {
+            image_content_types: vec!["image/png".to_string(), "image/jpeg".to_string()],
+            ..Config::load().uploads
+        };
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(check_image_type(png, &config), Ok(()));
+
+        let bmp = b"BM\x3a\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0";
+        assert_eq!(
+            check_image_type(bmp, &config),
+            Err("Unsupported image type, allowed types are image/png, image/jpeg".to_string())
+        );
+        assert!(check_image_type(b"plain text", &config).is_err());
+        assert_eq!(check_image_type(b"", &config), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn empty_files_get_400_unless_allowed() {
+        let config = |allow_empty_files| UploadConfig { allow_empty_files, ..Config::load().uploads };
+
+        assert!(check_not_empty(0, &config(true)).is_ok());
+        assert!(check_not_empty(1, &config(false)).is_ok());
+
+        let response = check_not_empty(0, &config(false)).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.into_body().into_string().await.unwrap(), "empty_file");
+    }
+
+    async fn description_client() -> TestClient<impl Endpoint> {
+        let db = unreachable_database().await;
+        let limits = MetadataLimits { max_description_length: 10, ..Config::load().metadata };
+        TestClient::new(
+            Route::new()
+                .at("/files/:id/description", patch(update_file_description))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(Arc::new(db.collection::<DocumentEntry>("files")))
+                .data(Arc::new(db.collection::<FileMetadata>("file_metadata")))
+                .data(limits),
+        )
+    }
+
+    #[tokio::test]
+    async fn descriptions_over_the_limit_get_422() {
+        let response = description_client()
+            .await
+            .patch(format!("/files/{}/description", ObjectId::new()))
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .body_json(&serde_json::json!({ "description": "eleven char" }))
+            .send()
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        response.assert_json(serde_json::json!({
+            "errors": [{ "field": "description", "message": "Can't be longer than 10 characters" }]
+        })).await;
+    }
+
+    #[tokio::test]
+    async fn description_updates_need_a_user_and_a_valid_id() {
+        let client = description_client().await;
+        let path = format!("/files/{}/description", ObjectId::new());
+        let body = serde_json::json!({ "description": "fine" });
+
+        client.patch(&path).body_json(&body).send().await.assert_status(StatusCode::UNAUTHORIZED);
+        client
+            .patch("/files/abc/description")
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .body_json(&body)
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        // Past every check, so it fails on the database.
+        client
+            .patch(&path)
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .body_json(&body)
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn listings_include_the_description() {
+        let entry = |description: Option<&str>| FileEntry {
+            id: "abc".to_string(),
+            filename: "a.txt".to_string(),
+            description: description.map(ToString::to_string),
+            content_type: None,
+            is_public: false,
+            folder: None,
+            tags: Vec::new(),
+        };
+        assert_eq!(serde_json::to_value(entry(Some("notes"))).unwrap()["description"], "notes");
+        assert!(serde_json::to_value(entry(None)).unwrap().get("description").is_none());
+    }
+
+    #[tokio::test]
+    async fn filenames_must_match_a_single_file() {
+        let (first, second) = (ObjectId::new(), ObjectId::new());
+        assert_eq!(single_match(&[first]).unwrap(), first);
+        assert_eq!(single_match(&[]).unwrap_err().status(), StatusCode::NOT_FOUND);
+
+        let response = single_match(&[first, second]).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_str(&response.into_body().into_string().await.unwrap()).unwrap();
+        assert_eq!(body["ids"], serde_json::json!([first.to_hex(), second.to_hex()]));
+    }
+
+    #[tokio::test]
+    async fn missing_content_types_are_detected() {
+        let db = unreachable_database().await;
+        let (files, metadata) = (db.collection::<DocumentEntry>("files"), db.collection::<FileMetadata>("file_metadata"));
+        let document = |fields: bson::Document| -> DocumentEntry {
+            let mut document = doc! { "_id": ObjectId::new(), "filename": "a", "user": "alice" };
+            document.extend(fields);
+            bson::from_document(document).unwrap()
+        };
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        // Storing the detected type fails on the unreachable database, which doesn't stop the download.
+        assert_eq!(document_content_type(&files, &metadata, &document(doc! {}), png).await, "image/png");
+        assert_eq!(document_content_type(&files, &metadata, &document(doc! {}), b"plain").await, DEFAULT_CONTENT_TYPE);
+
+        let typed = document(doc! { "content_type": "text/plain" });
+        assert_eq!(document_content_type(&files, &metadata, &typed, png).await, "text/plain");
+    }
+
+    #[test]
+    fn uploaded_images_record_their_dimensions() {
+        let png = png(3, 2);
+        let size = png.len() as u64;
+
+        let document = new_image_document("dots.png".to_string(), "alice", png);
+        assert_eq!((document.width, document.height), (Some(3), Some(2)));
+        assert_eq!(document.format.as_deref(), Some("image/png"));
+        assert_eq!(document.size_bytes, Some(size));
+
+        // /images/:filename/info reads these fields back from the stored document.
+        let info: ImageInfo = bson::from_document(bson::to_document(&document).unwrap()).unwrap();
+        assert_eq!((info.width, info.height, info.size_bytes), (Some(3), Some(2), Some(size)));
+        assert_eq!(info.format.as_deref(), Some("image/png"));
+
+        let unreadable = new_image_document("broken.png".to_string(), "alice", b"\x89PNG".to_vec());
+        assert_eq!((unreadable.width, unreadable.height), (None, None));
+    }
+
+    async fn visibility_client() -> TestClient<impl Endpoint> {
+        let db = unreachable_database().await;
+        let blobs = Arc::new(db.collection::<crate::database::blob_db::Blob>("blobs"));
+        TestClient::new(
+            Route::new()
+                .at("/files/:id/visibility", post(set_file_visibility))
+                .at("/public/files/:id", get(download_public_file))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(Arc::new(db.collection::<DocumentEntry>("files")))
+                .data(Arc::new(db.collection::<FileMetadata>("file_metadata")))
+                .data(Arc::new(db.collection::<FileAccessLog>("file_access_log")))
+                .data(Storage::new(&crate::config::StorageConfig::Mongo, blobs))
+                .data(db.gridfs_bucket(None))
+                .data(Config::load().downloads),
+        )
+    }
+
+    #[tokio::test]
+    async fn public_files_need_no_token() {
+        let client = visibility_client().await;
+
+        // A malformed id can't be a public file, so it gets the same 404 as a private one.
+        client.get("/public/files/abc").send().await.assert_status(StatusCode::NOT_FOUND);
+        // Past every check without a token, so it fails on the database.
+        client
+            .get(format!("/public/files/{}", ObjectId::new()))
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn visibility_updates_need_a_user_and_a_valid_id() {
+        let client = visibility_client().await;
+        let path = format!("/files/{}/visibility", ObjectId::new());
+        let body = serde_json::json!({ "is_public": true });
+
+        client.post(&path).body_json(&body).send().await.assert_status(StatusCode::UNAUTHORIZED);
+        client
+            .post("/files/abc/visibility")
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .body_json(&body)
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        client
+            .post(&path)
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .body_json(&body)
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn files_are_private_unless_marked_public() {
+        let document: DocumentEntry = bson::from_document(doc! { "filename": "a", "user": "alice" }).unwrap();
+        assert!(!document.is_public);
+    }
+
+    #[test]
+    fn inline_sizes_default_and_are_capped() {
+        let query = |inline, max_bytes| ImageListQuery { inline, max_bytes };
+        assert_eq!(inline_max_bytes(&query(false, Some(10))), None);
+        assert_eq!(inline_max_bytes(&query(true, None)), Some(DEFAULT_INLINE_IMAGE_BYTES));
+        assert_eq!(inline_max_bytes(&query(true, Some(10))), Some(10));
+        assert_eq!(inline_max_bytes(&query(true, Some(u64::MAX))), Some(MAX_INLINE_IMAGE_BYTES));
+    }
+
+    #[test]
+    fn listings_stop_inlining_past_the_total() {
+        let image = |filename: &str, size: usize| ImageListEntry {
+            filename: filename.to_string(),
+            width: None,
+            height: None,
+            format: Some("image/png".to_string()),
+            size_bytes: Some(size as u64),
+            data: Some(Binary { subtype: BinarySubtype::Generic, bytes: vec![0; size] }),
+        };
+        let half = MAX_INLINE_LISTING_BYTES / 2;
+        let mut unsent = image("unsent.png", 0);
+        unsent.data = None;
+
+        let items = image_list_items(vec![image("a.png", half), image("b.png", half), image("c.png", 1), unsent]);
+        let inlined: Vec<_> = items.iter().map(|item| item.data.is_some()).collect();
+        assert_eq!(inlined, [true, true, false, false]);
+        assert_eq!(items[0].data.as_deref(), Some(format!("data:image/png;base64,{}", STANDARD.encode(vec![0; half])).as_str()));
+    }
+
+    #[test]
+    fn download_names_are_sanitized() {
+        let header = |filename| content_disposition("attachment", filename, "download.bin");
+        assert_eq!(header("report.pdf"), "attachment; filename=\"report.pdf\"");
+        assert_eq!(header("a\"b\\c/d\r\n.txt"), "attachment; filename=\"abcd.txt\"");
+        assert_eq!(header("café.txt"), "attachment; filename=\"caf_.txt\"; filename*=UTF-8''caf%C3%A9%2Etxt");
+        for unusable in ["", "  ", "\"/\"", ".", "..", "\u{7}"] {
+            assert_eq!(header(unusable), "attachment; filename=\"download.bin\"", "{:?}", unusable);
+        }
+    }
+
+    #[test]
+    fn downloads_without_a_name_use_the_fallback() {
+        let downloads = DownloadConfig { fallback_filename: "unnamed.bin".to_string(), ..Config::load().downloads };
+        let response = attachment_response("", &downloads, "text/plain", b"content".to_vec());
+        assert_eq!(response.headers()["Content-Disposition"], "attachment; filename=\"unnamed.bin\"");
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        image::RgbImage::new(width, height)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    async fn preset_client() -> TestClient<impl Endpoint> {
+        let db = unreachable_database().await;
+        TestClient::new(
+            Route::new()
+                .at("/images/:filename/:preset", get(download_image_preset))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(Arc::new(db.collection::<ImageDocument>("images")))
+                .data(Arc::new(db.collection::<ImageRendition>("image_renditions")))
+                .data(ImagePresets { presets: vec![ImagePreset { name: "small", width: 150, height: 150 }] })
+                .data(Config::load().downloads),
+        )
+    }
+
+    #[tokio::test]
+    async fn unknown_presets_get_400_listing_the_presets() {
+        let client = preset_client().await;
+        let response = client
+            .get("/images/cat.png/huge")
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        response.assert_text("Unknown preset \"huge\", valid presets are small").await;
+
+        // Past every check, so it fails on the database.
+        client
+            .get("/images/cat.png/small")
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn presets_scale_images_to_fit() {
+        let small = ImagePreset { name: "small", width: 150, height: 150 };
+        let resize = |bytes: &[u8]| {
+            let resized = image_conversion::resize_image(bytes, small.width, small.height, ImageFormat::Png, 85).unwrap();
+            image_conversion::image_dimensions(&resized)
+        };
+        assert_eq!(resize(&png(600, 300)), Some((150, 75)));
+        assert_eq!(resize(&png(100, 400)), Some((38, 150)));
+        // Images already smaller than the preset aren't enlarged.
+        assert_eq!(resize(&png(40, 20)), Some((40, 20)));
+    }
+
+    async fn recent_client(enabled: bool) -> TestClient<impl Endpoint> {
+        let db = unreachable_database().await;
+        TestClient::new(
+            Route::new()
+                .at("/files/recent", get(list_recent_downloads))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(RecentDownloadLog::new(Arc::new(db.collection("recent_downloads")), enabled)),
+        )
+    }
+
+    #[tokio::test]
+    async fn recent_downloads_are_opt_in() {
+        let request = |client: TestClient<_>| async move {
+            client.get("/files/recent").header(AUTHORIZATION, bearer("alice", &["user"])).send().await
+        };
+        request(recent_client(false).await).await.assert_status(StatusCode::NOT_FOUND);
+        // Past every check, so it fails on the database.
+        request(recent_client(true).await).await.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+
+        recent_client(true).await.get("/files/recent").send().await.assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn tags_are_normalized_and_applied() {
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        assert_eq!(normalize_tags(tags(&[" work ", "work", "inbox", ""])), tags(&["work", "inbox", ""]));
+
+        let current = tags(&["inbox", "2024"]);
+        assert_eq!(retagged(&current, &tags(&["work", "2024"]), &tags(&["inbox"])), tags(&["2024", "work"]));
+        assert_eq!(retagged(&current, &tags(&[]), &tags(&["missing"])), current);
+    }
+
+    async fn bulk_tag_client() -> TestClient<impl Endpoint> {
+        let db = unreachable_database().await;
+        TestClient::new(
+            Route::new()
+                .at("/files/bulk-tag", post(bulk_tag_files))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(Arc::new(db.collection::<DocumentEntry>("files")))
+                .data(Arc::new(db.collection::<FileMetadata>("file_metadata")))
+                .data(Config::load().metadata)
+                .data(BulkLimits { max_items: 2 }),
+        )
+    }
+
+    #[tokio::test]
+    async fn bulk_tags_are_checked_before_any_file() {
+        let client = bulk_tag_client().await;
+        let id = ObjectId::new().to_hex();
+        let tag = |body: serde_json::Value| {
+            client.post("/files/bulk-tag").header(AUTHORIZATION, bearer("alice", &["user"])).body_json(&body).send()
+        };
+
+        tag(serde_json::json!({ "ids": ["abc"], "add_tags": ["work"] })).await.assert_status(StatusCode::BAD_REQUEST);
+        tag(serde_json::json!({ "ids": [&id, &id, &id], "add_tags": ["work"] }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        for body in [
+            serde_json::json!({ "ids": [&id] }),
+            serde_json::json!({ "ids": [&id], "add_tags": [" "] }),
+            serde_json::json!({ "ids": [&id], "add_tags": ["work"], "remove_tags": ["work"] }),
+        ] {
+            tag(body).await.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        // Past every check, so it fails on the database.
+        tag(serde_json::json!({ "ids": [&id], "add_tags": ["work"], "remove_tags": ["inbox"] }))
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use poem::handler;
+use poem::http::StatusCode;
+use poem::web::{Data, Json};
+
+// Whether the startup setup (test users and indexes) has finished. Shared between the task
+// doing the setup and the /health handler.
+#[derive(Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn mark_ready(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+// Handles GET requests to /health. This route doesn't require a token.
+//
+// The server accepts requests while the indexes are still being built in the background, so load
+// balancers should wait for this to report ready before sending traffic.
+//
+// # Returns
+// - `200 OK` with `{ "status": "ready" }` once the startup setup has finished.
+// - `503 Service Unavailable` with `{ "status": "not ready" }` while it is still running.
+#[handler]
+pub async fn health(readiness: Data<&Readiness>) -> (StatusCode, Json<serde_json::Value>) {
+    if readiness.is_ready() {
+        (StatusCode::OK, Json(serde_json::json!({ "status": "ready" })))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({ "status": "not ready" })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poem::test::TestClient;
+    use poem::{get, EndpointExt, Route};
+
+    #[test]
+    fn readiness_is_shared_between_clones() {
+        let readiness = Readiness::default();
+        let handle = readiness.clone();
+        assert!(!readiness.is_ready());
+
+        handle.mark_ready();
+        assert!(readiness.is_ready());
+    }
+
+    #[tokio::test]
+    async fn health_reports_ready_once_setup_finished() {
+        let readiness = Readiness::default();
+        let client = TestClient::new(Route::new().at("/health", get(health)).data(readiness.clone()));
+
+        let response = client.get("/health").send().await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        response.assert_json(serde_json::json!({ "status": "not ready" })).await;
+
+        readiness.mark_ready();
+        let response = client.get("/health").send().await;
+        response.assert_status_is_ok();
+        response.assert_json(serde_json::json!({ "status": "ready" })).await;
+    }
+}
@@ -1,11 +1,112 @@
+pub mod admin_handlers;
+pub mod cors_handlers;
+pub mod csp_handlers;
+pub mod event_handlers;
 pub mod file_handlers;
+pub mod health_handlers;
+pub mod notification_handlers;
+pub mod quota_handlers;
+pub mod share_handlers;
+pub mod upload_ticket_handlers;
 pub mod user_handlers;
-use poem::{Request, http::StatusCode, Result};
+pub mod validation;
+use bson::oid::ObjectId;
+use poem::{Error, Request, http::StatusCode, Result};
 use crate::auth::AuthUser;
+use crate::database::is_max_time_error;
+use crate::middleware::client_ip::ClientIp;
 
 fn extract_user(req: &Request) -> Result<AuthUser> {
     req.extensions()
         .get::<AuthUser>()
         .cloned()
         .ok_or(StatusCode::UNAUTHORIZED.into())
-}
\ No newline at end of file
+}
+
+
+// Parses an id sent by the client, so a malformed id is answered with `400 Bad Request` before it
+// reaches the database.
+fn parse_object_id(id: &str) -> Result<ObjectId> {
+    ObjectId::parse_str(id).map_err(|_| {
+        Error::from_string(
+            format!("Invalid id {:?}, expected 24 hexadecimal characters", id),
+            StatusCode::BAD_REQUEST,
+        )
+    })
+}
+
+// Maps the error of a listing or search query to a response: `503 Service Unavailable` when it ran
+// longer than QUERY_MAX_TIME_MS, so clients can retry with a narrower query, and `500 Internal
+// Server Error` otherwise.
+fn query_error(e: mongodb::error::Error) -> Error {
+    let status = if is_max_time_error(&e) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Error::new(e, status)
+}
+
+// The IP address of the client that sent the request, if known. Behind a trusted proxy this is
+// the address the proxy forwarded, see `ClientIpMiddleware`.
+fn client_ip(req: &Request) -> Option<String> {
+    req.extensions()
+        .get::<ClientIp>()
+        .map(|ip| ip.0.to_string())
+}
+
+// Helpers for the handler tests, which run without a MongoDB server.
+#[cfg(test)]
+pub(crate) mod testing {
+    use crate::auth::jwt::{create_jwt, Claims};
+
+    // A database on a server that can't be reached, so every query fails after 100 ms. Handlers
+    // answering 500 have got past their checks of the request.
+    pub async fn unreachable_database() -> mongodb::Database {
+        mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("handler_tests")
+    }
+
+    // The `Authorization` header of a request made by `username` with `roles`.
+    pub fn bearer(username: &str, roles: &[&str]) -> String {
+        let claims = Claims::new(username.to_string(), roles.iter().map(|role| role.to_string()).collect());
+        format!("Bearer {}", create_jwt(claims).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_ids_parse() {
+        let id = ObjectId::new();
+        assert_eq!(parse_object_id(&id.to_hex()).unwrap(), id);
+        assert_eq!(parse_object_id("507f1f77bcf86cd799439011").unwrap().to_hex(), "507f1f77bcf86cd799439011");
+    }
+
+    #[test]
+    fn malformed_ids_are_a_bad_request() {
+        for id in ["", "abc", "507f1f77bcf86cd79943901", "507f1f77bcf86cd7994390111", "zzzzzzzzzzzzzzzzzzzzzzzz"] {
+            let error = parse_object_id(id).unwrap_err();
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(error.to_string(), format!("Invalid id {:?}, expected 24 hexadecimal characters", id));
+        }
+    }
+
+    #[test]
+    fn slow_queries_are_unavailable() {
+        use mongodb::error::{CommandError, ErrorKind};
+
+        let command_error = |code: i32| -> mongodb::error::Error {
+            let command_error: CommandError = bson::from_document(bson::doc! { "code": code, "errmsg": "test" }).unwrap();
+            ErrorKind::Command(command_error).into()
+        };
+        // MaxTimeMSExpired.
+        assert_eq!(query_error(command_error(50)).status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(query_error(command_error(2)).status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(query_error(std::io::Error::other("test").into()).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
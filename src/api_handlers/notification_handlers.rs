@@ -0,0 +1,89 @@
+use chrono::Utc;
+use mongodb::Collection;
+use poem::http::StatusCode;
+use poem::web::{Data, Json, Path};
+use poem::{handler, Error, Request};
+use serde::Deserialize;
+use std::sync::Arc;
+use crate::api_handlers::{extract_user, parse_object_id};
+use crate::database::notification_db::{get_unread_notifications, insert_notification, mark_notification_read, Notification, NotificationEntry};
+
+#[derive(Deserialize)]
+pub struct BroadcastRequest {
+    title: String,
+    body: String,
+}
+
+// Handles POST requests to /admin/broadcast, announcing something to every user.
+//
+// # Arguments
+// - `Json(payload)`: `{ "title": "...", "body": "..." }`
+//
+// # Returns
+// - `201 Created` with the id of the notification.
+// - `400 Bad Request` if the title or body is empty.
+#[poem_grants::protect("admin")]
+#[handler]
+pub async fn broadcast(
+    req: &Request,
+    Json(payload): Json<BroadcastRequest>,
+    db: Data<&Arc<Collection<Notification>>>,
+) -> Result<(StatusCode, String), Error> {
+    let user = extract_user(req)?;
+
+    if payload.title.trim().is_empty() || payload.body.trim().is_empty() {
+        return Err(Error::from_string("Both title and body are required", StatusCode::BAD_REQUEST));
+    }
+
+    let notification = Notification {
+        id: None,
+        title: payload.title,
+        body: payload.body,
+        created_by: user.username,
+        created_at: Utc::now(),
+        read_by: Vec::new(),
+    };
+
+    match insert_notification(&db, notification).await {
+        Ok(id) => Ok((StatusCode::CREATED, id.to_hex())),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+// Handles GET requests to /me/notifications, listing the caller's unread notifications, newest first.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn get_my_notifications(
+    req: &Request,
+    db: Data<&Arc<Collection<Notification>>>,
+) -> Result<Json<Vec<NotificationEntry>>, Error> {
+    let user = extract_user(req)?;
+
+    get_unread_notifications(&db, &user.username)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Handles POST requests to /me/notifications/:id/read, hiding the notification for the caller.
+//
+// # Returns
+// - `200 OK` if the notification is now marked as read, also if it already was.
+// - `400 Bad Request` if the id is malformed.
+// - `404 Not Found` if there is no notification with that id.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn read_notification(
+    req: &Request,
+    Path(id): Path<String>,
+    db: Data<&Arc<Collection<Notification>>>,
+) -> Result<StatusCode, Error> {
+    let user = extract_user(req)?;
+    let id = parse_object_id(&id)?;
+
+    match mark_notification_read(&db, id, &user.username).await {
+        Ok(0) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
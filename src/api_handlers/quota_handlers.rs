@@ -0,0 +1,94 @@
+use chrono::Utc;
+use mongodb::Collection;
+use poem::http::StatusCode;
+use poem::web::{Data, Json};
+use poem::{handler, Error, Request};
+use serde::Serialize;
+use std::sync::Arc;
+use crate::api_handlers::extract_user;
+use crate::config::QuotaConfig;
+use crate::database::file_db::{storage_used_by_user, DocumentEntry};
+use crate::database::quota_alert_db::{get_quota_alerts, record_quota_alert, QuotaAlert, QuotaAlertEntry};
+
+// The most alerts listed by /me/quota-alerts.
+const MAX_QUOTA_ALERTS: i64 = 50;
+
+#[derive(Serialize)]
+pub struct StorageUsage {
+    used_bytes: u64,
+    quota_bytes: u64,
+    used_percent: f64,
+    alert_at_percent: f64,
+}
+
+// Records a quota alert when `username` has used more than the alert threshold of their quota.
+// Called after each upload. Failures are only logged, as the upload itself went through.
+pub async fn check_storage_quota(
+    files: &Collection<DocumentEntry>,
+    alerts: &Collection<QuotaAlert>,
+    quota: &QuotaConfig,
+    username: &str,
+) {
+    let used_bytes = match storage_used_by_user(files, username).await {
+        Ok(used_bytes) => used_bytes,
+        Err(e) => {
+            tracing::warn!(username, error = %e, "Failed to compute storage used");
+            return;
+        }
+    };
+    let ratio = used_bytes as f64 / quota.quota_bytes.max(1) as f64;
+    if ratio <= quota.alert_threshold {
+        return;
+    }
+
+    let alert = QuotaAlert {
+        id: None,
+        username: username.to_string(),
+        used_bytes,
+        quota_bytes: quota.quota_bytes,
+        ratio,
+        alerted_at: Utc::now(),
+    };
+    if let Err(e) = record_quota_alert(alerts, alert).await {
+        tracing::warn!(username, error = %e, "Failed to record quota alert");
+    }
+}
+
+// Handles GET requests to /me/storage, reporting how much of their quota the caller has used.
+//
+// # Returns
+// - `200 OK` with `{ "used_bytes": 123, "quota_bytes": 1073741824, "used_percent": 0.0, "alert_at_percent": 90.0 }`.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn get_my_storage(
+    req: &Request,
+    files: Data<&Arc<Collection<DocumentEntry>>>,
+    quota: Data<&QuotaConfig>,
+) -> Result<Json<StorageUsage>, Error> {
+    let user = extract_user(req)?;
+    let used_bytes = storage_used_by_user(&files, &user.username)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Json(StorageUsage {
+        used_bytes,
+        quota_bytes: quota.quota_bytes,
+        used_percent: used_bytes as f64 / quota.quota_bytes.max(1) as f64 * 100.0,
+        alert_at_percent: quota.alert_threshold * 100.0,
+    }))
+}
+
+// Handles GET requests to /me/quota-alerts, listing the caller's latest quota alerts, newest first.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn get_my_quota_alerts(
+    req: &Request,
+    alerts: Data<&Arc<Collection<QuotaAlert>>>,
+) -> Result<Json<Vec<QuotaAlertEntry>>, Error> {
+    let user = extract_user(req)?;
+
+    get_quota_alerts(&alerts, &user.username, MAX_QUOTA_ALERTS)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
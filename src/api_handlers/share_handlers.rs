@@ -0,0 +1,405 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use mongodb::Collection;
+use poem::http::header::CACHE_CONTROL;
+use poem::http::StatusCode;
+use poem::web::{Data, Json, Path, Query};
+use poem::{handler, Error, Request, Response};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use crate::api_handlers::{client_ip, extract_user, parse_object_id};
+use crate::auth::presign::presigned_url;
+use crate::api_handlers::file_handlers::{attachment_response, document_content_type};
+use crate::config::{DownloadConfig, LinkConfig};
+use crate::services::qr_code::qr_code_png;
+use crate::database::file_metadata_db::FileMetadata;
+use crate::database::access_log_db::{log_file_access, FileAccessLog};
+use crate::database::blob_db::document_bytes;
+use crate::storage::Storage;
+use mongodb::gridfs::GridFsBucket;
+use crate::database::file_db::{get_document_by_id, DocumentEntry};
+use crate::database::share_db::{delete_share_link, find_active_share_link, get_active_share_links, insert_share_link, ShareLink, ShareLinkEntry};
+
+// Share links are valid for a day unless requested otherwise, and for a week at most.
+const DEFAULT_SHARE_LINK_SECONDS: i64 = 24 * 60 * 60;
+const MAX_SHARE_LINK_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+// Pre-signed URLs are valid for 5 minutes unless requested otherwise. They can't be revoked, so
+// they are valid for a day at most.
+const DEFAULT_PRESIGNED_SECONDS: i64 = 5 * 60;
+const MAX_PRESIGNED_SECONDS: i64 = 24 * 60 * 60;
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Deserialize)]
+pub struct ShareLinkRequest {
+    expires_in_seconds: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ShareLinkResponse {
+    id: String,
+    url: String,
+    expires_at: chrono::DateTime<Utc>,
+}
+
+// Handles POST requests to /files/:id/share-link, issuing a link anyone can use to download the file.
+//
+// # Arguments
+// - `Path(id)`: The ObjectId of the file as a hex string.
+// - `payload`: `{ "expires_in_seconds": 3600 }` - optional, defaults to a day and is capped at a week.
+//
+// # Returns
+// - `201 Created` with `{ "id", "url", "expires_at" }`. The url is only returned here, as the
+//   token itself isn't stored.
+// - `400 Bad Request` if the id is malformed or the expiry is out of range.
+// - `404 Not Found` if the file doesn't exist or belongs to someone else.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn create_share_link(
+    req: &Request,
+    Path(id): Path<String>,
+    payload: Option<Json<ShareLinkRequest>>,
+    files: Data<&Arc<Collection<DocumentEntry>>>,
+    db: Data<&Arc<Collection<ShareLink>>>,
+) -> Result<(StatusCode, Json<ShareLinkResponse>), Error> {
+    let user = extract_user(req)?;
+    let file_id = parse_object_id(&id)?;
+
+    let seconds = payload
+        .and_then(|Json(payload)| payload.expires_in_seconds)
+        .unwrap_or(DEFAULT_SHARE_LINK_SECONDS);
+    if !(1..=MAX_SHARE_LINK_SECONDS).contains(&seconds) {
+        return Err(Error::from_string(
+            format!("expires_in_seconds must be between 1 and {}", MAX_SHARE_LINK_SECONDS),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    match get_document_by_id(&files, &id).await {
+        Ok(Some(doc)) if doc.user == user.username => {}
+        Ok(_) => return Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => return Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+
+    let mut token_bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token_bytes);
+    let token = URL_SAFE_NO_PAD.encode(token_bytes);
+
+    let link = ShareLink {
+        id: None,
+        token_hash: hash_token(&token),
+        file_id,
+        issued_by: user.username,
+        expires_at: Utc::now() + Duration::seconds(seconds),
+    };
+    let expires_at = link.expires_at;
+
+    match insert_share_link(&db, link).await {
+        Ok(link_id) => Ok((StatusCode::CREATED, Json(ShareLinkResponse {
+            id: link_id.to_hex(),
+            url: format!("/shared/{}", token),
+            expires_at,
+        }))),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PresignRequest {
+    expires_in_seconds: Option<i64>,
+    method: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PresignResponse {
+    url: String,
+    expires_at: chrono::DateTime<Utc>,
+}
+
+// Handles POST requests to /files/:id/presign, issuing a pre-signed URL that downloads the file
+// without a token, for handing to services that can't authenticate.
+//
+// Unlike share links nothing is stored, the URL is verified by its signature, see `auth::presign`.
+// It therefore can't be revoked, and stays valid after the file is unshared.
+//
+// # Arguments
+// - `Path(id)`: The ObjectId of the file as a hex string.
+// - `payload`: `{ "expires_in_seconds": 300, "method": "GET" }` - both optional. Defaults to 5 minutes
+//   and is capped at a day. Only GET can be pre-signed.
+//
+// # Returns
+// - `201 Created` with `{ "url": "/files/:id?sig=...&exp=...", "expires_at" }`.
+// - `400 Bad Request` if the expiry is out of range or the method isn't GET.
+// - `404 Not Found` if the file doesn't exist or belongs to someone else.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn presign_file(
+    req: &Request,
+    Path(id): Path<String>,
+    payload: Option<Json<PresignRequest>>,
+    files: Data<&Arc<Collection<DocumentEntry>>>,
+) -> Result<(StatusCode, Json<PresignResponse>), Error> {
+    let user = extract_user(req)?;
+    let id = parse_object_id(&id)?
+        .to_hex();
+
+    let payload = payload.map(|Json(payload)| payload);
+    let seconds = payload
+        .as_ref()
+        .and_then(|payload| payload.expires_in_seconds)
+        .unwrap_or(DEFAULT_PRESIGNED_SECONDS);
+    if !(1..=MAX_PRESIGNED_SECONDS).contains(&seconds) {
+        return Err(Error::from_string(
+            format!("expires_in_seconds must be between 1 and {}", MAX_PRESIGNED_SECONDS),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    if payload
+        .and_then(|payload| payload.method)
+        .is_some_and(|method| !method.eq_ignore_ascii_case("GET"))
+    {
+        return Err(Error::from_string("Only GET URLs can be pre-signed", StatusCode::BAD_REQUEST));
+    }
+
+    match get_document_by_id(&files, &id).await {
+        Ok(Some(doc)) if doc.user == user.username => {}
+        Ok(_) => return Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => return Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+
+    let expires_at = Utc::now() + Duration::seconds(seconds);
+    Ok((StatusCode::CREATED, Json(PresignResponse {
+        url: presigned_url(&id, expires_at.timestamp() as u64),
+        expires_at,
+    })))
+}
+
+// The default, smallest and largest width and height of the QR codes served by /files/:id/link-qr.
+// Codes smaller than MIN_QR_CODE_SIZE can't be scanned reliably.
+const DEFAULT_QR_CODE_SIZE: u32 = 200;
+const MIN_QR_CODE_SIZE: u32 = 64;
+const MAX_QR_CODE_SIZE: u32 = 1024;
+
+#[derive(Deserialize)]
+pub struct LinkQrQuery {
+    size: Option<u32>,
+}
+
+// Handles GET requests to /files/:id/link-qr, returning a QR code of a pre-signed download URL for
+// the file, to share it by showing it on screen.
+//
+// The URL is absolute, built on PUBLIC_BASE_URL, and valid for DEFAULT_PRESIGNED_SECONDS, like one
+// from /files/:id/presign. The image may be cached by the client for 60 seconds.
+//
+// # Arguments
+// - `Query(query)`: `?size=200` - the width and height of the image in pixels.
+//
+// # Returns
+// - `200 OK` with the QR code as `image/png`.
+// - `400 Bad Request` if the id is malformed or the size is out of range.
+// - `404 Not Found` if the file doesn't exist or belongs to someone else.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn link_qr(
+    req: &Request,
+    Path(id): Path<String>,
+    Query(query): Query<LinkQrQuery>,
+    files: Data<&Arc<Collection<DocumentEntry>>>,
+    links: Data<&LinkConfig>,
+) -> Result<Response, Error> {
+    let user = extract_user(req)?;
+    let id = parse_object_id(&id)?
+        .to_hex();
+    let size = query.size.unwrap_or(DEFAULT_QR_CODE_SIZE);
+    if !(MIN_QR_CODE_SIZE..=MAX_QR_CODE_SIZE).contains(&size) {
+        return Err(Error::from_string(
+            format!("size must be between {} and {}", MIN_QR_CODE_SIZE, MAX_QR_CODE_SIZE),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    match get_document_by_id(&files, &id).await {
+        Ok(Some(doc)) if doc.user == user.username => {}
+        Ok(_) => return Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => return Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+
+    let expires_at = Utc::now() + Duration::seconds(DEFAULT_PRESIGNED_SECONDS);
+    let url = format!("{}{}", links.public_base_url, presigned_url(&id, expires_at.timestamp() as u64));
+    let png = qr_code_png(&url, size).map_err(|e| Error::from_string(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Response::builder()
+        .content_type("image/png")
+        .header(CACHE_CONTROL, "private, max-age=60")
+        .body(png))
+}
+
+// Handles GET requests to /shared/:token, downloading a file through a share link. Doesn't require a token.
+//
+// # Returns
+// - `200 OK` with the file content.
+// - `403 Forbidden` if the link doesn't exist, has expired or has been revoked.
+// - `404 Not Found` if the shared file has since been deleted.
+#[handler]
+pub async fn download_shared_file(
+    req: &Request,
+    Path(token): Path<String>,
+    db: Data<&Arc<Collection<ShareLink>>>,
+    files: Data<&Arc<Collection<DocumentEntry>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+    storage: Data<&Storage>,
+    bucket: Data<&GridFsBucket>,
+    access_log: Data<&Arc<Collection<FileAccessLog>>>,
+    downloads: Data<&DownloadConfig>,
+) -> Result<Response, Error> {
+    let link = find_active_share_link(&db, &hash_token(&token))
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_status(StatusCode::FORBIDDEN))?;
+
+    match get_document_by_id(&files, &link.file_id.to_hex()).await {
+        Ok(Some(doc)) => {
+            let bytes = document_bytes(&storage, &bucket, &doc)
+                .await
+                .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+                .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+            // Share links are anonymous, so the download is attributed to whoever issued the link.
+            log_file_access(&access_log, FileAccessLog::new(link.file_id, &link.issued_by, "shared_download", client_ip(req))).await;
+            let content_type = document_content_type(&files, &metadata, &doc, &bytes).await;
+            Ok(attachment_response(&doc.filename, &downloads, &content_type, bytes))
+        }
+        Ok(None) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+// Handles GET requests to /files/shares, listing the caller's share links that haven't expired.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn list_share_links(
+    req: &Request,
+    db: Data<&Arc<Collection<ShareLink>>>,
+) -> Result<Json<Vec<ShareLinkEntry>>, Error> {
+    let user = extract_user(req)?;
+
+    get_active_share_links(&db, &user.username)
+        .await
+        .map(Json)
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Handles DELETE requests to /files/shares/:id, revoking a share link before it expires.
+//
+// # Returns
+// - `200 OK` once the link no longer works.
+// - `404 Not Found` if the link doesn't exist or was issued by someone else.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn revoke_share_link(
+    req: &Request,
+    Path(id): Path<String>,
+    db: Data<&Arc<Collection<ShareLink>>>,
+) -> Result<StatusCode, Error> {
+    let user = extract_user(req)?;
+    let id = parse_object_id(&id)?;
+
+    match delete_share_link(&db, id, &user.username).await {
+        Ok(0) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_handlers::testing::{bearer, unreachable_database};
+    use crate::auth::middleware::JwtMiddleware;
+    use crate::config::Config;
+    use bson::oid::ObjectId;
+    use poem::http::header::AUTHORIZATION;
+    use poem::test::TestClient;
+    use poem::{delete, get, post, Endpoint, EndpointExt, Route};
+
+    async fn client() -> TestClient<impl Endpoint> {
+        let db = unreachable_database().await;
+        TestClient::new(
+            Route::new()
+                .at("/files/:id/share-link", post(create_share_link))
+                .at("/files/shares", get(list_share_links))
+                .at("/files/shares/:id", delete(revoke_share_link))
+                .with(JwtMiddleware::new(&Config::load().auth))
+                .data(Arc::new(db.collection::<DocumentEntry>("files")))
+                .data(Arc::new(db.collection::<ShareLink>("share_links"))),
+        )
+    }
+
+    #[test]
+    fn only_the_token_hash_is_stored() {
+        let token = "dGhlIHRva2Vu";
+        assert_eq!(hash_token(token), format!("{:x}", Sha256::digest(token.as_bytes())));
+        assert_eq!(hash_token(token).len(), 64);
+        assert_ne!(hash_token(token), hash_token("dGhlIHRva2Vv"));
+
+        let entry = ShareLinkEntry { id: "a".to_string(), file_id: "b".to_string(), expires_at: Utc::now() };
+        let listed = serde_json::to_value(entry).unwrap();
+        let mut fields: Vec<&String> = listed.as_object().unwrap().keys().collect();
+        fields.sort();
+        assert_eq!(fields, ["expires_at", "file_id", "id"]);
+    }
+
+    #[tokio::test]
+    async fn share_link_expiry_is_checked_before_the_file() {
+        let client = client().await;
+        let path = format!("/files/{}/share-link", ObjectId::new());
+        let token = bearer("alice", &["user"]);
+
+        for seconds in [0, MAX_SHARE_LINK_SECONDS + 1] {
+            let response = client
+                .post(&path)
+                .header(AUTHORIZATION, &token)
+                .body_json(&serde_json::json!({ "expires_in_seconds": seconds }))
+                .send()
+                .await;
+            response.assert_status(StatusCode::BAD_REQUEST);
+            response.assert_text(format!("expires_in_seconds must be between 1 and {}", MAX_SHARE_LINK_SECONDS)).await;
+        }
+        client
+            .post("/files/abc/share-link")
+            .header(AUTHORIZATION, &token)
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        client
+            .post(&path)
+            .header(AUTHORIZATION, &token)
+            .body_json(&serde_json::json!({ "expires_in_seconds": MAX_SHARE_LINK_SECONDS }))
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn share_links_are_managed_by_users() {
+        let client = client().await;
+        client.get("/files/shares").send().await.assert_status(StatusCode::UNAUTHORIZED);
+        client
+            .delete(format!("/files/shares/{}", ObjectId::new()))
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        client
+            .delete("/files/shares/abc")
+            .header(AUTHORIZATION, bearer("alice", &["user"]))
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,173 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use mongodb::Collection;
+use poem::http::StatusCode;
+use poem::web::{Data, Json};
+use poem::{handler, Error, Request};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use crate::api_handlers::extract_user;
+use crate::config::UploadConfig;
+use crate::database::upload_ticket_db::{insert_upload_ticket, redeem_upload_ticket, UploadTicket};
+use crate::database::user_db::{find_user, User};
+
+#[derive(Serialize)]
+pub struct UploadTicketResponse {
+    ticket: String,
+    upload_url: String,
+    expires_at: DateTime<Utc>,
+}
+
+fn hash_ticket(ticket: &str) -> String {
+    format!("{:x}", Sha256::digest(ticket.as_bytes()))
+}
+
+// Handles POST requests to /uploads/ticket, issuing a ticket that lets a client upload a single
+// file as the caller through /upload, without a token.
+//
+// This way a frontend that shouldn't hold the caller's token can still upload. A leaked ticket is
+// only good for one upload until UPLOAD_TICKET_TTL_SECS pass.
+//
+// # Returns
+// - `201 Created` with `{ "ticket", "upload_url": "/upload?ticket=...", "expires_at" }`.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn issue_upload_ticket(
+    req: &Request,
+    tickets: Data<&Arc<Collection<UploadTicket>>>,
+    upload_config: Data<&UploadConfig>,
+) -> Result<(StatusCode, Json<UploadTicketResponse>), Error> {
+    let user = extract_user(req)?;
+
+    let mut ticket_bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut ticket_bytes);
+    let ticket = URL_SAFE_NO_PAD.encode(ticket_bytes);
+    let expires_at = Utc::now() + upload_config.ticket_ttl;
+
+    let stored = UploadTicket {
+        token_hash: hash_ticket(&ticket),
+        username: user.username,
+        expires_at,
+    };
+    insert_upload_ticket(&tickets, &stored)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(UploadTicketResponse { upload_url: format!("/upload?ticket={}", ticket), ticket, expires_at }),
+    ))
+}
+
+// Who an upload is made by, and the ticket it was authorized with, if any.
+pub struct UploadAuthorization {
+    pub username: String,
+    ticket: Option<UploadTicket>,
+}
+
+impl UploadAuthorization {
+    // Puts the ticket back after an upload that stored nothing, so the client can try again with
+    // it. Failures are only logged, as the upload has failed already.
+    pub async fn restore_ticket(self, tickets: &Collection<UploadTicket>) {
+        let Some(ticket) = self.ticket else {
+            return;
+        };
+        if let Err(e) = insert_upload_ticket(tickets, &ticket).await {
+            tracing::warn!(username = %ticket.username, error = %e, "Failed to restore upload ticket");
+        }
+    }
+}
+
+// Finds who an upload is made by: the owner of `ticket`, which is used up, or otherwise the
+// authenticated user, who needs the user role.
+//
+// The owner of a ticket is looked up again, so a ticket issued before its owner was deactivated
+// or lost the user role can't be used anymore. The upload should restore the ticket with
+// `UploadAuthorization::restore_ticket` if it fails.
+//
+// # Returns
+// - `Ok(UploadAuthorization)`.
+// - `Err(Error)` with `401 Unauthorized` if the ticket is unknown, used or expired, its owner no
+//   longer exists, or there is neither a ticket nor a token, and `403 Forbidden` if the token or
+//   the owner of the ticket lacks the user role, or the owner is deactivated.
+pub async fn authorize_upload(
+    req: &Request,
+    ticket: Option<&str>,
+    tickets: &Collection<UploadTicket>,
+    users: &Collection<User>,
+) -> poem::Result<UploadAuthorization> {
+    let Some(ticket) = ticket else {
+        let user = extract_user(req).map_err(|_| StatusCode::UNAUTHORIZED)?;
+        if !user.has_role("user") {
+            return Err(Error::from_status(StatusCode::FORBIDDEN));
+        }
+        return Ok(UploadAuthorization { username: user.username, ticket: None });
+    };
+
+    let ticket = redeem_upload_ticket(tickets, &hash_ticket(ticket))
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or_else(|| Error::from_string("Invalid, used or expired upload ticket", StatusCode::UNAUTHORIZED))?;
+
+    let owner = match find_user(users, &ticket.username).await {
+        Ok(owner) => owner,
+        Err(e) => {
+            // Nothing was checked yet, so the ticket is still good.
+            UploadAuthorization { username: ticket.username.clone(), ticket: Some(ticket) }
+                .restore_ticket(tickets)
+                .await;
+            return Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+    match owner {
+        None => Err(Error::from_string("Invalid, used or expired upload ticket", StatusCode::UNAUTHORIZED)),
+        Some(owner) if !owner.active || !owner.role.iter().any(|role| role == "user") => {
+            Err(Error::from_status(StatusCode::FORBIDDEN))
+        }
+        Some(_) => Ok(UploadAuthorization { username: ticket.username.clone(), ticket: Some(ticket) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthUser;
+    use mongodb::Client;
+
+    #[test]
+    fn tickets_are_stored_as_their_sha256() {
+        assert_eq!(hash_ticket("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hash_ticket("abc"), hash_ticket("abc"));
+        assert_ne!(hash_ticket("abc"), hash_ticket("abd"));
+    }
+
+    // Uploads without a ticket are decided by the token alone, so the database is never reached.
+    #[tokio::test]
+    async fn uploads_without_a_ticket_need_a_user_token() {
+        let db = Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("upload_ticket_tests");
+        let (tickets, users) = (db.collection::<UploadTicket>("upload_tickets"), db.collection::<User>("users"));
+        let request = |permissions: &[&str]| {
+            let user = AuthUser {
+                username: "alice".to_string(),
+                permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            };
+            Request::builder().extension(user).finish()
+        };
+
+        let error = authorize_upload(&Request::default(), None, &tickets, &users).await.err().unwrap();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+
+        let error = authorize_upload(&request(&["admin"]), None, &tickets, &users).await.err().unwrap();
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+
+        let authorization = authorize_upload(&request(&["user"]), None, &tickets, &users).await.unwrap();
+        assert_eq!(authorization.username, "alice");
+        assert!(authorization.ticket.is_none());
+    }
+}
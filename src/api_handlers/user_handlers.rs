@@ -1,12 +1,38 @@
 use std::sync::Arc;
 use mongodb::Collection;
-use poem::{handler, Error, IntoResponse};
-use poem::http::StatusCode;
+use poem::{handler, Error, IntoResponse, Request, Response};
+use poem::http::{header::SET_COOKIE, StatusCode};
 use poem::web::{Data, Json, Path};
+use crate::auth::activation::{activation_token, verify_activation_token};
 use crate::auth::jwt::{create_jwt, Claims};
+use crate::auth::middleware::session_cookie;
+use crate::config::AuthConfig;
+use jsonwebtoken::jwk::JwkSet;
 use crate::database;
-use serde::{Deserialize};
+use serde::{Deserialize, Serialize};
 use crate::database::user_db::*;
+use crate::api_handlers::{client_ip, extract_user};
+use crate::config::PasswordPolicy;
+use crate::api_handlers::validation::{validate_user, ValidationErrors, MIN_PASSWORD_LENGTH};
+use crate::database::auth_event_db::{log_auth_event, AuthEvent, AuthEventLog};
+use crate::database::file_metadata_db::{count_files_for_user, FileMetadata};
+use crate::database::idempotency_db::{begin_idempotency_key, complete_idempotency_key, release_idempotency_key, IdempotencyKey, IdempotencyState};
+use crate::database::refresh_token_db::{
+    delete_refresh_tokens_for_user, find_reused_refresh_token, insert_refresh_token, rotate_refresh_token,
+    RefreshToken, REFRESH_TOKEN_DAYS,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// The maximum number of characters in a display name and a bio.
+const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+const MAX_BIO_LENGTH: usize = 500;
+
+// Only the start of longer passwords is scored, as scoring gets slow on very long input.
+const MAX_SCORED_PASSWORD_LENGTH: usize = 256;
 
 // Handles POST requests to /add_user. The #[handler] prefix is for poem to recognize it
 // This function receives JSON data like this
@@ -14,17 +40,73 @@ use crate::database::user_db::*;
 // into a User, and inserts it into the MongoDB collection.
 //
 // If the insert is successful, it returns HTTP 201 Created.
+// If any field is invalid, it returns HTTP 422 Unprocessable Entity listing every invalid field:
+// { "errors": [{ "field": "password", "message": "Must be at least 8 characters" }] }
 // If the insert fails, it returns HTTP 500 Internal Server Error.
+//
+// Clients can send an `Idempotency-Key` header to make retries safe. The response to the first
+// request with a key is stored, and replayed for 24 hours to any retry with the same key
+// (marked with an `Idempotent-Replayed: true` header) without creating the user again.
+// A retry sent while the first request is still being processed gets HTTP 409 Conflict.
+// Server errors aren't stored, so they can be retried with the same key.
 #[poem_grants::protect("admin")]
 #[handler]
 pub async fn add_user(
+    req: &Request,
     Json(payload): Json<User>,
     db: Data<&Arc<Collection<User>>>,
-) -> Result<StatusCode, Error> {
+    idempotency_keys: Data<&Arc<Collection<IdempotencyKey>>>,
+    passwords: Data<&PasswordPolicy>,
+) -> Result<Response, Error> {
     let collection = db.as_ref();
-    insert_user(collection, &payload).await?;
-    // the ? forces a return in case of an error and skips the Ok(status code) on the next line.
-    Ok(StatusCode::CREATED)
+
+    let mut errors = ValidationErrors::default();
+    validate_user(&mut errors, &passwords, &payload.username, &payload.password, &payload.role);
+    errors.into_result()?;
+
+    let Some(key) = req.header("Idempotency-Key") else {
+        insert_user(collection, &payload).await?;
+        // the ? forces a return in case of an error and skips the Ok(status code) on the next line.
+        return Ok(StatusCode::CREATED.into_response());
+    };
+
+    // Keys are scoped to the caller, so two admins can't replay each other's responses.
+    let user = extract_user(req)?;
+    let key_hash = format!("{:x}", Sha256::digest(format!("{}:{}", user.username, key).as_bytes()));
+
+    let state = begin_idempotency_key(&idempotency_keys, &key_hash)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    match state {
+        IdempotencyState::InProgress => Err(Error::from_string(
+            "A request with this Idempotency-Key is already being processed",
+            StatusCode::CONFLICT,
+        )),
+        IdempotencyState::Completed { status_code, response_body } => Ok(Response::builder()
+            .status(StatusCode::from_u16(status_code).unwrap_or(StatusCode::OK))
+            .header("Idempotent-Replayed", "true")
+            .body(response_body)),
+        IdempotencyState::New => {
+            let result = insert_user(collection, &payload).await;
+            let (status, body) = match &result {
+                Ok(()) => (StatusCode::CREATED, String::new()),
+                Err(e) => (e.status(), e.to_string()),
+            };
+
+            let stored = if status.is_server_error() {
+                release_idempotency_key(&idempotency_keys, &key_hash).await
+            } else {
+                complete_idempotency_key(&idempotency_keys, &key_hash, status.as_u16(), &body).await
+            };
+            if let Err(e) = stored {
+                tracing::error!("Failed to store idempotency key: {}", e);
+            }
+
+            result?;
+            Ok(StatusCode::CREATED.into_response())
+        }
+    }
 }
 
 
@@ -43,7 +125,7 @@ pub async fn add_user(
 pub async fn get_user(
     Path(name): Path<String>,
     db: Data<&Arc<Collection<User>>>,
-) -> Result<Json<User>, StatusCode> {
+) -> Result<Json<User>, Error> {
     // Get a reference to the MongoDB collection.
     let collection = db.as_ref();
 
@@ -52,9 +134,9 @@ pub async fn get_user(
         // If found, return it as JSON with 200 OK.
         Ok(Some(doc)) => Ok(Json(doc)),
         // If not found, return a 404 Not Found status.
-        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Ok(None) => Err(Error::from_status(StatusCode::NOT_FOUND)),
         // If a database error occurs, return a 500 Internal Server Error.
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
     }
 }
 
@@ -68,6 +150,7 @@ pub async fn get_user(
 // # Returns
 // - `200 OK` with a success message if the update was successful.
 // - `404 Not Found` if no document matched the name (i.e., nothing was updated).
+// - `422 Unprocessable Entity` listing every invalid field, like POST /user/add.
 // - `500 Internal Server Error` if a DB error occurs.
 #[poem_grants::protect("admin")]
 #[handler]
@@ -75,7 +158,12 @@ pub async fn user_update(
     Path(name): Path<String>,
     Json(payload): Json<User>,
     db: Data<&Arc<Collection<User>>>,
+    passwords: Data<&PasswordPolicy>,
 ) -> Result<StatusCode, Error> {
+    let mut errors = ValidationErrors::default();
+    validate_user(&mut errors, &passwords, &payload.username, &payload.password, &payload.role);
+    errors.into_result()?;
+
     let collection = db.as_ref();
     update_user(collection, &name, &payload).await?;
     Ok(StatusCode::OK)
@@ -102,27 +190,430 @@ pub async fn user_delete(
     Ok(StatusCode::OK)
 }
 
+// Handles GET requests to /me/notification-preferences, returning the caller's notification preferences.
+//
+// # Returns
+// - `200 OK` with `{ "email_on_share": bool, "webhook_on_share": "https://..." | null }`.
+// - `404 Not Found` if the user behind the token no longer exists.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn get_notification_preferences(
+    req: &Request,
+    db: Data<&Arc<Collection<User>>>,
+) -> Result<Json<NotificationPreferences>, Error> {
+    let user = extract_user(req)?;
+
+    match find_user(&db, &user.username).await {
+        Ok(Some(user)) => Ok(Json(user.notifications)),
+        Ok(None) => Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+// Handles PUT requests to /me/notification-preferences, replacing the caller's notification preferences.
+//
+// # Returns
+// - `200 OK` if the preferences were stored.
+// - `400 Bad Request` if the webhook isn't an http(s) URL.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn put_notification_preferences(
+    req: &Request,
+    Json(payload): Json<NotificationPreferences>,
+    db: Data<&Arc<Collection<User>>>,
+) -> Result<StatusCode, Error> {
+    let user = extract_user(req)?;
+
+    if let Some(webhook) = &payload.webhook_on_share {
+        let valid = reqwest::Url::parse(webhook)
+            .map(|url| url.scheme() == "http" || url.scheme() == "https")
+            .unwrap_or(false);
+        if !valid {
+            return Err(Error::from_string("The webhook must be an http or https URL", StatusCode::BAD_REQUEST));
+        }
+    }
+
+    update_notification_preferences(&db, &user.username, &payload).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Serialize)]
+pub struct PublicProfile {
+    username: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    file_count: u64,
+}
+
+// Handles GET requests to /users/:name/public-profile. This route doesn't require a token.
+//
+// # Returns
+// - `200 OK` with `{ "username", "display_name", "bio", "file_count" }`.
+// - `404 Not Found` if the user doesn't exist or hasn't made their profile public. Both cases
+//   respond the same, so the route can't be used to find out which usernames exist.
+#[handler]
+pub async fn public_profile(
+    Path(name): Path<String>,
+    db: Data<&Arc<Collection<User>>>,
+    metadata: Data<&Arc<Collection<FileMetadata>>>,
+) -> Result<Json<PublicProfile>, Error> {
+    let user = match find_user(&db, &name).await {
+        Ok(Some(user)) if user.public => user,
+        Ok(_) => return Err(Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => return Err(Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+
+    let file_count = count_files_for_user(&metadata, &user.username)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Json(PublicProfile {
+        username: user.username,
+        display_name: user.display_name,
+        bio: user.bio,
+        file_count,
+    }))
+}
+
+// Handles PUT requests to /me/profile, letting users change their own profile.
+//
+// Unlike the admin only PUT /user/:name, this can't touch the password or roles.
+//
+// # Arguments
+// - `Json(payload)`: `{ "display_name": "...", "bio": "...", "public": true }`. Fields left out are unchanged.
+//
+// # Returns
+// - `200 OK` if the profile was updated.
+// - `422 Unprocessable Entity` listing every invalid field, if the display name is longer than
+//   MAX_DISPLAY_NAME_LENGTH characters or contains a line break, or the bio is longer than
+//   MAX_BIO_LENGTH characters.
+// - `404 Not Found` if the user behind the token no longer exists.
+#[poem_grants::protect("user")]
+#[handler]
+pub async fn put_profile(
+    req: &Request,
+    Json(payload): Json<ProfileUpdate>,
+    db: Data<&Arc<Collection<User>>>,
+) -> Result<StatusCode, Error> {
+    let user = extract_user(req)?;
+
+    let mut errors = ValidationErrors::default();
+    if let Some(display_name) = &payload.display_name {
+        if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+            errors.add("display_name", format!("Can't be longer than {} characters", MAX_DISPLAY_NAME_LENGTH));
+        }
+        if display_name.contains(['\n', '\r']) {
+            errors.add("display_name", "Can't contain line breaks");
+        }
+    }
+    if let Some(bio) = &payload.bio
+        && bio.chars().count() > MAX_BIO_LENGTH
+    {
+        errors.add("bio", format!("Can't be longer than {} characters", MAX_BIO_LENGTH));
+    }
+    errors.into_result()?;
+
+    update_profile(&db, &user.username, &payload).await?;
+    Ok(StatusCode::OK)
+}
+
 #[derive(Deserialize)]
 struct LoginInfo {
     username: String,
     password: String,
 }
 
+// A new random refresh token, and the hash of it that is stored.
+fn new_refresh_token() -> (String, String) {
+    let mut token_bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token_bytes);
+    let token = URL_SAFE_NO_PAD.encode(token_bytes);
+    let hash = format!("{:x}", Sha256::digest(token.as_bytes()));
+    (token, hash)
+}
+
+// Handles POST requests to /login, exchanging a username and password for a token.
+//
+// Along with the token comes a refresh token, which /auth/refresh exchanges for a new token once
+// it expires, for up to REFRESH_TOKEN_DAYS days.
+//
+// Every attempt, successful or not, is recorded in the `auth_events` collection.
+//
+// With COOKIE_AUTH_ENABLED, the token is also set in the `session` cookie, so browsers are
+// authenticated without sending an `Authorization` header.
 #[handler]
-pub async fn login(Json(payload): Json<LoginInfo>, db: Data<&Arc<Collection<User>>>) -> poem::Result<impl IntoResponse> {
+pub async fn login(
+    req: &Request,
+    Json(payload): Json<LoginInfo>,
+    db: Data<&Arc<Collection<User>>>,
+    auth_events: Data<&AuthEventLog>,
+    refresh_tokens: Data<&Arc<Collection<RefreshToken>>>,
+    auth: Data<&AuthConfig>,
+) -> poem::Result<Response> {
     if payload.username.is_empty() || payload.password.is_empty() {
         return Err(Error::from_string("Either username or password is missing", StatusCode::UNAUTHORIZED));
     }
 
     match database::user_db::login(db.as_ref(), &payload.username, &payload.password).await {
         Ok(user) => {
+            log_auth_event(&auth_events, AuthEvent::new(&user.username, "login", client_ip(req))).await;
+
+            let (refresh_token, token_hash) = new_refresh_token();
+            let session = RefreshToken {
+                username: user.username.clone(),
+                token_hash,
+                used_hashes: Vec::new(),
+                expires_at: Utc::now() + Duration::days(REFRESH_TOKEN_DAYS),
+            };
+            insert_refresh_token(&refresh_tokens, session)
+                .await
+                .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
             let permissions = user.role;
             let claims = Claims::new(user.username, permissions);
             let jwt = create_jwt(claims)
                 .map_err(|e| Error::from_string(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))?;
 
-            Ok(Json(serde_json::json!({ "token": jwt })))
+            let mut response = Json(serde_json::json!({
+                "token": jwt,
+                "refresh_token": refresh_token,
+                "must_change_password": user.must_change_password,
+            }))
+            .into_response();
+            if auth.cookie_auth
+                && let Ok(cookie) = session_cookie(&jwt).parse()
+            {
+                response.headers_mut().insert(SET_COOKIE, cookie);
+            }
+            Ok(response)
+        }
+        Err(err) => {
+            if matches!(err.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+                log_auth_event(&auth_events, AuthEvent::new(&payload.username, "login_failed", client_ip(req))).await;
+            }
+            Err(err)
         }
-        Err(err) => Err(err),
     }
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+// Handles POST requests to /auth/refresh, exchanging a refresh token for a new token.
+//
+// Refresh tokens are rotated: every refresh returns a new refresh token, and the one sent stops
+// working. A rotated token being sent again means someone else holds a copy of it, so every
+// session of the user is ended, and they have to log in again. The roles in the new token are
+// read from the user again, so role changes take effect on the next refresh.
+//
+// # Returns
+// - `200 OK` with `{ "token": "...", "refresh_token": "..." }`.
+// - `401 Unauthorized` if the refresh token is unknown or expired, or with "Token reuse detected"
+//   if it has already been used.
+#[handler]
+pub async fn refresh(
+    req: &Request,
+    Json(payload): Json<RefreshRequest>,
+    db: Data<&Arc<Collection<User>>>,
+    refresh_tokens: Data<&Arc<Collection<RefreshToken>>>,
+    auth_events: Data<&AuthEventLog>,
+) -> poem::Result<Json<serde_json::Value>> {
+    let old_hash = format!("{:x}", Sha256::digest(payload.refresh_token.as_bytes()));
+    let (refresh_token, new_hash) = new_refresh_token();
+
+    let Some(session) = rotate_refresh_token(&refresh_tokens, &old_hash, &new_hash)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+    else {
+        let reused = find_reused_refresh_token(&refresh_tokens, &old_hash)
+            .await
+            .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+        if let Some(session) = reused {
+            tracing::warn!(username = %session.username, "Refresh token reused, ending every session of the user");
+            delete_refresh_tokens_for_user(&refresh_tokens, &session.username)
+                .await
+                .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+            log_auth_event(&auth_events, AuthEvent::new(&session.username, "refresh_token_reused", client_ip(req))).await;
+            return Err(Error::from_string("Token reuse detected", StatusCode::UNAUTHORIZED));
+        }
+        return Err(Error::from_string("Invalid or expired refresh token", StatusCode::UNAUTHORIZED));
+    };
+
+    // Users deleted or deactivated since they logged in can't refresh anymore.
+    let user = find_user(&db, &session.username)
+        .await
+        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .filter(|user| user.active)
+        .ok_or_else(|| Error::from_string("Invalid or expired refresh token", StatusCode::UNAUTHORIZED))?;
+
+    let jwt = create_jwt(Claims::new(user.username, user.role))
+        .map_err(|e| Error::from_string(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(Json(serde_json::json!({ "token": jwt, "refresh_token": refresh_token })))
+}
+
+// Handles POST requests to /register, letting anyone create an account with the `user` role.
+//
+// The account starts out inactive, and can't log in until it is activated with the token in the
+// response at /activate/:token. The token stands in for an activation email, and expires after
+// 24 hours.
+//
+// # Returns
+// - `201 Created` with `{ "username": "...", "activation_token": "..." }`.
+// - `409 Conflict` if the username is taken.
+// - `422 Unprocessable Entity` listing every invalid field, validated like /user/add.
+#[handler]
+pub async fn register(
+    req: &Request,
+    Json(payload): Json<LoginInfo>,
+    db: Data<&Arc<Collection<User>>>,
+    auth_events: Data<&AuthEventLog>,
+    passwords: Data<&PasswordPolicy>,
+) -> poem::Result<Response> {
+    let roles = vec!["user".to_string()];
+    let mut errors = ValidationErrors::default();
+    validate_user(&mut errors, &passwords, &payload.username, &payload.password, &roles);
+    errors.into_result()?;
+
+    let mut user = User::new(payload.username, payload.password, roles);
+    user.active = false;
+    insert_user(&db, &user).await?;
+    log_auth_event(&auth_events, AuthEvent::new(&user.username, "register", client_ip(req))).await;
+
+    let token = activation_token(&user.username);
+    Ok(Json(serde_json::json!({ "username": user.username, "activation_token": token }))
+        .with_status(StatusCode::CREATED)
+        .into_response())
+}
+
+// Handles GET requests to /activate/:token, activating an account created through /register.
+//
+// # Returns
+// - `200 OK` once the account is active. Activating an active account again does nothing.
+// - `400 Bad Request` if the token is invalid or has expired.
+// - `404 Not Found` if the account has been deleted since.
+#[handler]
+pub async fn activate(
+    Path(token): Path<String>,
+    db: Data<&Arc<Collection<User>>>,
+) -> poem::Result<StatusCode> {
+    let username = verify_activation_token(&token)
+        .ok_or_else(|| Error::from_string("Invalid or expired activation token", StatusCode::BAD_REQUEST))?;
+    activate_user(&db, &username).await?;
+    Ok(StatusCode::OK)
+}
+
+// Handles GET requests to /.well-known/jwks.json, publishing the key used to sign tokens.
+//
+// # Returns
+// - `200 OK` with a JWK set containing the RSA public key when tokens are signed with RS256.
+// - `404 Not Found` when tokens are signed with HS256, as there is no public key to share.
+#[handler]
+pub async fn jwks() -> Result<Json<JwkSet>, StatusCode> {
+    crate::auth::jwt::jwks().map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize)]
+pub struct PasswordStrengthRequest {
+    password: String,
+    // Passwords containing the username are weaker, so it is taken into account when sent.
+    username: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PasswordStrength {
+    score: u8,
+    feedback: Vec<String>,
+}
+
+// Handles POST requests to /password/strength, scoring a password for a strength meter. Doesn't
+// require a token, and nothing is stored.
+//
+// # Arguments
+// - `Json(body)`: `{ "password": "...", "username": "..." }`, where the username is optional.
+//
+// # Returns
+// - `200 OK` with `{ "score": 0-4, "feedback": ["..."] }` as scored by zxcvbn. The feedback also says
+//   when the password is shorter than /user/add accepts or on its blocklist, whatever its score.
+#[handler]
+pub async fn password_strength(
+    Json(body): Json<PasswordStrengthRequest>,
+    passwords: Data<&PasswordPolicy>,
+) -> Json<PasswordStrength> {
+    let password: String = body.password.chars().take(MAX_SCORED_PASSWORD_LENGTH).collect();
+    let user_inputs: Vec<&str> = body.username.as_deref().into_iter().collect();
+    let entropy = zxcvbn::zxcvbn(&password, &user_inputs);
+
+    let mut feedback = Vec::new();
+    if body.password.chars().count() < MIN_PASSWORD_LENGTH {
+        feedback.push(format!("Passwords must be at least {} characters", MIN_PASSWORD_LENGTH));
+    }
+    if passwords.is_blocked(&body.password) {
+        feedback.push("This is a common password, which /user/add doesn't accept".to_string());
+    }
+    if let Some(hints) = entropy.feedback() {
+        feedback.extend(hints.warning().map(|warning| warning.to_string()));
+        feedback.extend(hints.suggestions().iter().map(ToString::to_string));
+    }
+
+    Json(PasswordStrength { score: entropy.score().into(), feedback })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poem::test::TestClient;
+    use poem::{EndpointExt, Route, post};
+    use std::collections::HashSet;
+
+    // Only compiles while the `User` the handlers receive is the one the database layer stores.
+    #[test]
+    fn handlers_and_database_share_the_user_type() {
+        let _: fn(Json<User>) -> crate::database::user_db::User = |Json(user)| user;
+
+        #[allow(dead_code)]
+        async fn store(db: &Collection<User>, Json(payload): Json<User>) -> Result<(), Error> {
+            insert_user(db, &payload).await
+        }
+    }
+
+    #[test]
+    fn user_payloads_get_the_stored_defaults() {
+        let user: User =
+            serde_json::from_value(serde_json::json!({ "username": "Alice", "password": "secret", "role": ["user"] })).unwrap();
+        assert_eq!(user.username, "Alice");
+        assert_eq!(user.role, ["user"]);
+        assert!(user.active);
+        assert!(!user.public);
+        assert!(!user.must_change_password);
+        assert_eq!(user.display_name, None);
+    }
+
+    #[tokio::test]
+    async fn weak_passwords_score_low_and_strong_ones_high() {
+        let policy = PasswordPolicy { blocklist: Arc::new(HashSet::from(["password123".to_string()])) };
+        let client = TestClient::new(Route::new().at("/password/strength", post(password_strength)).data(policy));
+        let score = |body: serde_json::Value| {
+            let client = &client;
+            async move {
+                let response = client.post("/password/strength").body_json(&body).send().await;
+                response.assert_status_is_ok();
+                response.json().await.value().deserialize::<serde_json::Value>()
+            }
+        };
+
+        let weak = score(serde_json::json!({ "password": "Password123" })).await;
+        assert!(weak["score"].as_u64().unwrap() <= 1);
+        let feedback: Vec<&str> = weak["feedback"].as_array().unwrap().iter().filter_map(|hint| hint.as_str()).collect();
+        assert!(feedback.contains(&"This is a common password, which /user/add doesn't accept"));
+
+        let short = score(serde_json::json!({ "password": "abc" })).await;
+        assert!(short["score"].as_u64().unwrap() <= 1);
+        assert_eq!(short["feedback"][0], "Passwords must be at least 8 characters");
+
+        let strong = score(serde_json::json!({ "password": "correct-horse-battery-staple-91" })).await;
+        assert!(strong["score"].as_u64().unwrap() >= 3);
+        assert_eq!(strong["feedback"], serde_json::json!([]));
+    }
+}
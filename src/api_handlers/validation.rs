@@ -0,0 +1,178 @@
+use poem::http::StatusCode;
+use poem::web::Json;
+use poem::{Error, IntoResponse};
+use serde::Serialize;
+use crate::config::{BulkLimits, MetadataLimits, PasswordPolicy};
+
+// The shortest password accepted for new or updated users.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+// The longest username accepted for new or updated users.
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// Collects every problem with a request body, so they can be reported together instead of one
+// request at a time.
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationErrors {
+    errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: &str, message: impl Into<String>) {
+        self.errors.push(FieldError { field: field.to_string(), message: message.into() });
+    }
+
+    // # Returns
+    // - `Ok(())` if nothing was added.
+    // - `Err(Error)` with `422 Unprocessable Entity` and `{ "errors": [{ "field", "message" }] }` otherwise.
+    pub fn into_result(self) -> Result<(), Error> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        Err(Error::from_response(
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(self)).into_response(),
+        ))
+    }
+}
+
+// Checks the fields of a user sent to POST /user/add or PUT /user/:name.
+//
+// Passwords on the blocklist are reported with the message `password_too_common`.
+pub fn validate_user(
+    errors: &mut ValidationErrors,
+    policy: &PasswordPolicy,
+    username: &str,
+    password: &str,
+    roles: &[String],
+) {
+    if username.is_empty() || username.chars().count() > MAX_USERNAME_LENGTH {
+        errors.add("username", format!("Must be between 1 and {} characters", MAX_USERNAME_LENGTH));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) {
+        errors.add("username", "May only contain letters, digits, '.', '_' and '-'");
+    }
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        errors.add("password", format!("Must be at least {} characters", MIN_PASSWORD_LENGTH));
+    }
+    if policy.is_blocked(password) {
+        errors.add("password", "password_too_common");
+    }
+    if roles.is_empty() {
+        errors.add("role", "A user must have at least one role");
+    }
+    if roles.iter().any(|role| role.trim().is_empty()) {
+        errors.add("role", "Roles can't be empty");
+    }
+}
+
+// Checks the metadata of a file against the configured limits. Each exceeded limit is reported,
+// naming the limit.
+pub fn validate_metadata(errors: &mut ValidationErrors, limits: &MetadataLimits, description: Option<&str>, tags: &[String]) {
+    if let Some(description) = description
+        && description.chars().count() > limits.max_description_length
+    {
+        errors.add("description", format!("Can't be longer than {} characters", limits.max_description_length));
+    }
+    if tags.len() > limits.max_tags {
+        errors.add("tags", format!("At most {} tags are allowed", limits.max_tags));
+    }
+    if tags.iter().any(|tag| tag.chars().count() > limits.max_tag_length) {
+        errors.add("tags", format!("Tags can't be longer than {} characters", limits.max_tag_length));
+    }
+    let bytes = description.map_or(0, str::len) + tags.iter().map(String::len).sum::<usize>();
+    if bytes > limits.max_metadata_bytes {
+        errors.add("metadata", format!("The description and tags can't exceed {} bytes in total", limits.max_metadata_bytes));
+    }
+}
+
+// Checks the number of items sent to a bulk endpoint, before any of them is processed.
+//
+// # Returns
+// - `Err(Error)` with `400 Bad Request` and `{ "error": "too_many_items", "limit": 100 }` if there
+//   are more than BULK_MAX_ITEMS.
+pub fn check_item_count(limits: &BulkLimits, count: usize) -> Result<(), Error> {
+    if count <= limits.max_items {
+        return Ok(());
+    }
+    let body = serde_json::json!({ "error": "too_many_items", "limit": limits.max_items });
+    Err(Error::from_response((StatusCode::BAD_REQUEST, Json(body)).into_response()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    fn fields(errors: &ValidationErrors) -> Vec<&str> {
+        errors.errors.iter().map(|error| error.field.as_str()).collect()
+    }
+
+    #[test]
+    fn every_invalid_user_field_is_reported() {
+        let policy = PasswordPolicy { blocklist: Arc::new(HashSet::from(["password123".to_string()])) };
+
+        let mut errors = ValidationErrors::default();
+        validate_user(&mut errors, &policy, "", "short", &["user".to_string()]);
+        assert_eq!(fields(&errors), ["username", "password"]);
+        let error = errors.into_result().unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let mut errors = ValidationErrors::default();
+        validate_user(&mut errors, &policy, "bob", "PASSWORD123", &[]);
+        assert_eq!(fields(&errors), ["password", "role"]);
+        assert_eq!(errors.errors[0].message, "password_too_common");
+
+        let mut errors = ValidationErrors::default();
+        validate_user(&mut errors, &policy, "bob.smith", "correct horse", &["user".to_string()]);
+        assert!(errors.into_result().is_ok());
+    }
+
+    #[tokio::test]
+    async fn too_many_items_get_400_with_the_limit() {
+        let limits = BulkLimits { max_items: 2 };
+        assert!(check_item_count(&limits, 0).is_ok());
+        assert!(check_item_count(&limits, 2).is_ok());
+
+        let response = check_item_count(&limits, 3).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&response.into_body().into_string().await.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({ "error": "too_many_items", "limit": 2 }));
+    }
+
+    #[test]
+    fn every_exceeded_metadata_limit_is_reported() {
+        let limits = MetadataLimits { max_tags: 2, max_tag_length: 5, max_description_length: 10, max_metadata_bytes: 20 };
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        let check = |description: Option<&str>, tags: &[String]| {
+            let mut errors = ValidationErrors::default();
+            validate_metadata(&mut errors, &limits, description, tags);
+            errors.errors.into_iter().map(|error| error.message).collect::<Vec<_>>()
+        };
+
+        assert!(check(Some("ok"), &tags(&["a", "b"])).is_empty());
+        assert_eq!(check(None, &tags(&["a", "b", "c"])), ["At most 2 tags are allowed"]);
+        assert_eq!(check(None, &tags(&["toolong"])), ["Tags can't be longer than 5 characters"]);
+        assert_eq!(check(Some("eleven char"), &[]), ["Can't be longer than 10 characters"]);
+        // Characters are counted, not bytes.
+        assert!(check(Some("æææææææææ"), &[]).is_empty());
+        assert_eq!(
+            check(Some("ææææææææææ"), &tags(&["a"])),
+            ["The description and tags can't exceed 20 bytes in total"]
+        );
+        assert_eq!(
+            check(Some("a description that is too long"), &tags(&["a", "b", "toolong"])),
+            [
+                "Can't be longer than 10 characters",
+                "At most 2 tags are allowed",
+                "Tags can't be longer than 5 characters",
+                "The description and tags can't exceed 20 bytes in total",
+            ]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::auth::jwt::SECRET;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// How long a self-registered user has to activate their account.
+const ACTIVATION_TOKEN_HOURS: i64 = 24;
+
+// Activation tokens prove that whoever activates an account got the token from /register.
+// A token is `<username>.<expiry>.<signature>`, with the username base64 encoded and an
+// HMAC-SHA256 over both, keyed with the JWT secret, so it can't be altered or forged.
+
+fn mac(username: &str, exp: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("activate:{}:{}", username, exp).as_bytes());
+    mac
+}
+
+// Issues an activation token for `username`, valid for ACTIVATION_TOKEN_HOURS.
+pub fn activation_token(username: &str) -> String {
+    let exp = Utc::now().timestamp() + ACTIVATION_TOKEN_HOURS * 60 * 60;
+    let sig = URL_SAFE_NO_PAD.encode(mac(username, exp).finalize().into_bytes());
+    format!("{}.{}.{}", URL_SAFE_NO_PAD.encode(username), exp, sig)
+}
+
+// Checks an activation token.
+//
+// # Returns
+// - `Some(username)` if the token was issued by `activation_token` and hasn't expired.
+// - `None` otherwise. The signature is compared in constant time.
+pub fn verify_activation_token(token: &str) -> Option<String> {
+    let mut parts = token.split('.');
+    let (username, exp, sig) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let username = String::from_utf8(URL_SAFE_NO_PAD.decode(username).ok()?).ok()?;
+    let exp = exp.parse::<i64>().ok()?;
+    if exp < Utc::now().timestamp() {
+        return None;
+    }
+    let sig = URL_SAFE_NO_PAD.decode(sig).ok()?;
+    mac(&username, exp).verify_slice(&sig).ok()?;
+    Some(username)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A token as `activation_token` would issue it, but expiring at `exp`.
+    fn token_expiring_at(username: &str, exp: i64) -> String {
+        let sig = URL_SAFE_NO_PAD.encode(mac(username, exp).finalize().into_bytes());
+        format!("{}.{}.{}", URL_SAFE_NO_PAD.encode(username), exp, sig)
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        assert_eq!(verify_activation_token(&activation_token("alice")).as_deref(), Some("alice"));
+        // Usernames may contain the separator.
+        assert_eq!(verify_activation_token(&activation_token("a.b")).as_deref(), Some("a.b"));
+    }
+
+    #[test]
+    fn altered_tokens_are_rejected() {
+        let token = activation_token("alice");
+        let (_, rest) = token.split_once('.').unwrap();
+        assert_eq!(verify_activation_token(&format!("{}.{}", URL_SAFE_NO_PAD.encode("mallory"), rest)), None);
+
+        let (rest, sig) = token.rsplit_once('.').unwrap();
+        let (username, exp) = rest.split_once('.').unwrap();
+        let later = exp.parse::<i64>().unwrap() + 60;
+        assert_eq!(verify_activation_token(&format!("{}.{}.{}", username, later, sig)), None);
+
+        assert_eq!(verify_activation_token(&format!("{}.extra", token)), None);
+        assert_eq!(verify_activation_token("alice"), None);
+        assert_eq!(verify_activation_token(""), None);
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let token = token_expiring_at("alice", Utc::now().timestamp() - 1);
+        assert_eq!(verify_activation_token(&token), None);
+    }
+}
@@ -1,10 +1,104 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use chrono::{Duration, Utc};
-use jsonwebtoken::{self, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::jwk::{AlgorithmParameters, CommonParameters, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse, RSAKeyParameters, RSAKeyType};
+use jsonwebtoken::{self, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use poem_grants::error::AccessError::UnauthorizedRequest;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
+use crate::config::load_secret;
 
-const JWT_EXPIRATION_HOURS: i64 = 24;
-const SECRET: &str = "totallySecureMegaHDPassword";
+pub(crate) const JWT_EXPIRATION_HOURS: i64 = 24;
+// Also the key of pre-signed URLs, see `presign`. Read from JWT_SECRET or the file at
+// JWT_SECRET_FILE, falling back to a built-in secret for development.
+pub(crate) static SECRET: LazyLock<String> = LazyLock::new(|| {
+    load_secret("JWT_SECRET", "JWT_SECRET_FILE").unwrap_or_else(|| "totallySecureMegaHDPassword".to_string())
+});
+
+// Tokens are signed with HS256 using SECRET, unless JWT_RSA_PRIVATE_KEY_PATH points at a PEM encoded
+// RSA private key (PKCS#1 or PKCS#8), in which case they are signed with RS256 and the public key is
+// published at /.well-known/jwks.json.
+static KEYS: LazyLock<JwtKeys> = LazyLock::new(|| match std::env::var("JWT_RSA_PRIVATE_KEY_PATH") {
+    Ok(path) => JwtKeys::rs256_from_file(&path),
+    Err(_) => JwtKeys::hs256(&SECRET),
+});
+
+struct JwtKeys {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    // Only present in RS256 mode.
+    jwk: Option<Jwk>,
+}
+
+impl JwtKeys {
+    fn hs256(secret: &str) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret.as_bytes()),
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+            jwk: None,
+        }
+    }
+
+    // Loads the RSA private key at `path`. The server can't sign tokens without it, so any
+    // failure here is fatal.
+    fn rs256_from_file(path: &str) -> Self {
+        let pem = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to read JWT_RSA_PRIVATE_KEY_PATH {}: {}", path, e));
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .unwrap_or_else(|e| panic!("{} is not a PEM encoded RSA private key: {}", path, e));
+
+        let n = URL_SAFE_NO_PAD.encode(private_key.n().to_bytes_be());
+        let e = URL_SAFE_NO_PAD.encode(private_key.e().to_bytes_be());
+
+        // The key id is the RFC 7638 thumbprint of the public key, so it changes whenever the key is rotated.
+        let thumbprint_input = format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n);
+        let kid = URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint_input.as_bytes()));
+
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::RS256),
+                key_id: Some(kid),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: n.clone(),
+                e: e.clone(),
+            }),
+        };
+
+        Self {
+            algorithm: Algorithm::RS256,
+            encoding: EncodingKey::from_rsa_pem(pem.as_bytes())
+                .unwrap_or_else(|e| panic!("{} can't be used for RS256 signing: {}", path, e)),
+            decoding: DecodingKey::from_rsa_components(&n, &e)
+                .unwrap_or_else(|e| panic!("{} can't be used for RS256 verification: {}", path, e)),
+            jwk: Some(jwk),
+        }
+    }
+
+    // Signs `claims`, naming the key in the `kid` header in RS256 mode.
+    fn sign(&self, claims: &Claims) -> poem::Result<String> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.jwk.as_ref().and_then(|jwk| jwk.common.key_id.clone());
+        jsonwebtoken::encode(&header, claims, &self.encoding).map_err(|_| UnauthorizedRequest.into())
+    }
+
+    fn verify(&self, token: &str) -> poem::Result<Claims> {
+        jsonwebtoken::decode::<Claims>(token, &self.decoding, &Validation::new(self.algorithm))
+            .map(|token_data| token_data.claims)
+            .map_err(|_| UnauthorizedRequest.into())
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
@@ -24,25 +118,53 @@ impl Claims {
 }
 
 pub fn create_jwt(claims: Claims) -> poem::Result<String> {
-    let encoding_key = EncodingKey::from_secret(SECRET.as_bytes());
-    let result = jsonwebtoken::encode(&Header::default(), &claims, &encoding_key);
-
-    match result {
-        Ok(token) => Ok(token),
-        Err(_err) => Err(UnauthorizedRequest.into())
-    }
+    KEYS.sign(&claims)
 }
 
 pub fn decode_jwt(token: &str) -> poem::Result<Claims>{
-    let decoding_key = DecodingKey::from_secret(SECRET.as_bytes());
-    let result = jsonwebtoken::decode::<Claims>(token, &decoding_key, &Validation::default());
+    KEYS.verify(token)
+}
 
-    match result {
-        Ok(token_data) => {
-            Ok(token_data.claims)
-        }
-        Err(_err) => {
-            Err(UnauthorizedRequest.into())
-        }
+// Returns the public signing key as a JWK set, or `None` when tokens are signed with HS256,
+// as the shared secret must never be published.
+pub fn jwks() -> Option<JwkSet> {
+    KEYS.jwk.clone().map(|jwk| JwkSet { keys: vec![jwk] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/auth/testdata/rs256_private_key.pem");
+
+    #[test]
+    fn rs256_tokens_verify_against_the_published_key() {
+        let keys = JwtKeys::rs256_from_file(FIXTURE_KEY);
+        let token = keys.sign(&Claims::new("alice".to_string(), vec!["user".to_string()])).unwrap();
+
+        let jwk = keys.jwk.as_ref().unwrap();
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        assert_eq!(header.alg, Algorithm::RS256);
+        assert_eq!(header.kid, jwk.common.key_id);
+
+        let AlgorithmParameters::RSA(rsa) = &jwk.algorithm else { panic!("The JWK isn't an RSA key") };
+        let decoding = DecodingKey::from_rsa_components(&rsa.n, &rsa.e).unwrap();
+        let claims = jsonwebtoken::decode::<Claims>(&token, &decoding, &Validation::new(Algorithm::RS256)).unwrap().claims;
+        assert_eq!(claims.username, "alice");
+        assert_eq!(claims.permissions, ["user"]);
+        assert_eq!(keys.verify(&token).unwrap().username, "alice");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn hs256_tokens_carry_no_key_id() {
+        let keys = JwtKeys::hs256("test secret");
+        let token = keys.sign(&Claims::new("alice".to_string(), vec![])).unwrap();
+        assert_eq!(jsonwebtoken::decode_header(&token).unwrap().kid, None);
+        assert_eq!(keys.verify(&token).unwrap().username, "alice");
+        assert!(keys.jwk.is_none());
+
+        // A token is only accepted by the keys that signed it.
+        assert!(JwtKeys::hs256("another secret").verify(&token).is_err());
+        assert!(JwtKeys::rs256_from_file(FIXTURE_KEY).verify(&token).is_err());
+    }
+}
@@ -1,43 +1,202 @@
-use poem::http::header::AUTHORIZATION;
+use poem::http::header::{AUTHORIZATION, COOKIE};
+use poem::http::StatusCode;
 use poem::{
-    Endpoint, Middleware, Request, Result
+    Endpoint, Error, Middleware, Request, Result
 };
 use poem_grants::authorities::AttachAuthorities;
 use crate::auth::AuthUser;
+use crate::auth::jwt::JWT_EXPIRATION_HOURS;
+use crate::config::AuthConfig;
+use crate::middleware::access_log::LoggedUser;
 
-pub struct JwtMiddleware;
+// The cookie holding the token when cookie authentication is enabled.
+const SESSION_COOKIE: &str = "session";
+
+// Authenticates requests carrying a token in an `Authorization: Bearer <token>` header.
+//
+// With cookie authentication enabled, requests without an `Authorization` header are
+// authenticated by the token in the `session` cookie instead. An invalid or expired cookie is
+// ignored rather than rejected, so a stale cookie doesn't stop the browser from logging in again.
+//
+// Requests with an `Authorization` or `Cookie` header larger than `max_header_bytes` are rejected
+// with `431 Request Header Fields Too Large` before any token is read.
+pub struct JwtMiddleware {
+    cookie_auth: bool,
+    max_header_bytes: usize,
+}
+
+impl JwtMiddleware {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self { cookie_auth: config.cookie_auth, max_header_bytes: config.max_header_bytes }
+    }
+}
+
+// Builds the `Set-Cookie` header value that stores `token` in the session cookie. The cookie lives
+// as long as the token, can't be read by scripts, is only sent over TLS and is never sent on
+// cross-site requests.
+pub fn session_cookie(token: &str) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+        SESSION_COOKIE,
+        token,
+        JWT_EXPIRATION_HOURS * 60 * 60
+    )
+}
+
+// Finds the session cookie among the `Cookie` headers of a request.
+fn session_token(req: &Request) -> Option<&str> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, token)| token)
+        .filter(|token| !token.is_empty())
+}
+
+// Extracts the token from an `Authorization: Bearer <token>` header value.
+//
+// # Returns
+// - `Some(token)` with surrounding whitespace trimmed, which is empty for e.g. `"Bearer "`.
+// - `None` if the header uses a different scheme, in which case it is ignored.
+fn bearer_token(value: &str) -> Option<&str> {
+    let token = value.strip_prefix("Bearer")?;
+    if !token.is_empty() && !token.starts_with(char::is_whitespace) {
+        // e.g. "Bearerabc" isn't the Bearer scheme
+        return None;
+    }
+    Some(token.trim())
+}
 
 impl<E: Endpoint> Middleware<E> for JwtMiddleware {
     type Output = JwtMiddlewareImpl<E>;
 
     fn transform(&self, ep: E) -> Self::Output{
-        JwtMiddlewareImpl { ep }
+        JwtMiddlewareImpl { ep, cookie_auth: self.cookie_auth, max_header_bytes: self.max_header_bytes }
     }
 }
 
 pub struct JwtMiddlewareImpl<E> {
     ep: E,
+    cookie_auth: bool,
+    max_header_bytes: usize,
+}
+
+// Whether any `Authorization` or `Cookie` header of the request is larger than `max_bytes`.
+fn has_oversized_header(req: &Request, max_bytes: usize) -> bool {
+    [AUTHORIZATION, COOKIE]
+        .iter()
+        .flat_map(|name| req.headers().get_all(name))
+        .any(|value| value.len() > max_bytes)
+}
+
+fn authenticate(req: &mut Request, claims: crate::auth::jwt::Claims) {
+    req.attach(claims.permissions.clone());
+    if let Some(user) = req.extensions().get::<LoggedUser>() {
+        user.set(&claims.username);
+    }
+
+    req.extensions_mut().insert(AuthUser {
+        username: claims.username,
+        permissions: claims.permissions,
+    });
 }
 
 impl<E: Endpoint> Endpoint for JwtMiddlewareImpl<E> {
     type Output = E::Output;
 
     async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        if has_oversized_header(&req, self.max_header_bytes) {
+            return Err(Error::from_string(
+                "Authorization or Cookie header too large",
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ));
+        }
+
         if let Some(value) = req
             .headers()
             .get(AUTHORIZATION)
             .and_then(|value| value.to_str().ok())
-            .filter(|value| value.starts_with("Bearer "))
-            .map(|value| &value[7..])
+            .and_then(bearer_token)
         {
-            let claims = crate::auth::jwt::decode_jwt(value)?;
+            if value.is_empty() {
+                return Err(Error::from_string("Missing bearer token", StatusCode::UNAUTHORIZED));
+            }
 
-            req.attach(claims.permissions.clone());
-            
-            req.extensions_mut().insert(AuthUser {
-                username: claims.username,
-            });
+            let claims = crate::auth::jwt::decode_jwt(value)?;
+            authenticate(&mut req, claims);
+        } else if self.cookie_auth
+            && !req.headers().contains_key(AUTHORIZATION)
+            && let Some(claims) = session_token(&req).and_then(|token| crate::auth::jwt::decode_jwt(token).ok())
+        {
+            authenticate(&mut req, claims);
         }
         self.ep.call(req).await
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use poem::endpoint::make_sync;
+
+    fn middleware(max_header_bytes: usize) -> JwtMiddlewareImpl<impl Endpoint<Output = String>> {
+        let config = AuthConfig { max_header_bytes, ..Config::load().auth };
+        JwtMiddleware::new(&config).transform(make_sync(|req| req.extensions().get::<AuthUser>().is_some().to_string()))
+    }
+
+    #[test]
+    fn bearer_tokens_are_extracted() {
+        assert_eq!(bearer_token("Bearer abc.def.ghi"), Some("abc.def.ghi"));
+        assert_eq!(bearer_token("Bearer   tok"), Some("tok"));
+        assert_eq!(bearer_token("Bearer tok  "), Some("tok"));
+        assert_eq!(bearer_token("Bearer "), Some(""));
+        assert_eq!(bearer_token("Bearer"), Some(""));
+    }
+
+    #[test]
+    fn other_schemes_are_ignored() {
+        assert_eq!(bearer_token("Bearerabc"), None);
+        assert_eq!(bearer_token("Basic dXNlcjpwYXNz"), None);
+        assert_eq!(bearer_token("bearer tok"), None);
+        assert_eq!(bearer_token(""), None);
+    }
+
+    #[tokio::test]
+    async fn empty_bearer_tokens_get_401() {
+        let error = middleware(8192)
+            .call(Request::builder().header(AUTHORIZATION, "Bearer ").finish())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_token_are_passed_on_unauthenticated() {
+        let response = middleware(8192).call(Request::default()).await.unwrap();
+        assert_eq!(response, "false");
+        let response = middleware(8192)
+            .call(Request::builder().header(AUTHORIZATION, "Basic dXNlcjpwYXNz").finish())
+            .await
+            .unwrap();
+        assert_eq!(response, "false");
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_get_431() {
+        let at_limit = format!("Basic {}", "a".repeat(94));
+        assert_eq!(at_limit.len(), 100);
+        let response = middleware(100).call(Request::builder().header(AUTHORIZATION, &at_limit).finish()).await.unwrap();
+        assert_eq!(response, "false");
+
+        let over_limit = format!("{}a", at_limit);
+        let error = middleware(100).call(Request::builder().header(AUTHORIZATION, &over_limit).finish()).await.err().unwrap();
+        assert_eq!(error.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+        let error = middleware(100).call(Request::builder().header(COOKIE, &over_limit).finish()).await.err().unwrap();
+        assert_eq!(error.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+}
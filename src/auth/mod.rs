@@ -1,7 +1,59 @@
+pub mod activation;
 pub mod jwt;
 pub mod middleware;
+pub mod password;
+pub mod permissions;
+pub mod presign;
 
 #[derive(Debug, Clone)]
 pub struct AuthUser {
     pub username: String,
-}
\ No newline at end of file
+    pub permissions: Vec<String>,
+}
+
+impl AuthUser {
+    // Checks whether the authenticated user has been granted the given role.
+    //
+    // # Arguments
+    // - `role`: The role to look for, e.g. `"user"` or `"admin"`.
+    //
+    // # Returns
+    // - `true` if the role is present in the user's permissions, otherwise `false`.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.permissions.iter().any(|permission| permission == role)
+    }
+
+    // Shorthand for `has_role("admin")`, used where admins may override ownership checks.
+    pub fn is_admin(&self) -> bool {
+        self.has_role("admin")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(permissions: &[&str]) -> AuthUser {
+        AuthUser {
+            username: "alice".to_string(),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn roles_are_matched_exactly() {
+        assert!(!user(&[]).has_role("user"));
+        assert!(!user(&[]).is_admin());
+
+        assert!(user(&["user"]).has_role("user"));
+        assert!(!user(&["user"]).is_admin());
+
+        assert!(user(&["admin"]).is_admin());
+        assert!(!user(&["admin"]).has_role("user"));
+
+        assert!(user(&["user", "admin"]).has_role("user"));
+        assert!(user(&["user", "admin"]).is_admin());
+
+        assert!(!user(&["Admin"]).is_admin());
+    }
+}
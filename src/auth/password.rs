@@ -0,0 +1,63 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use poem::{http::StatusCode, Error as PoemError};
+
+// Hashes a password with Argon2id and a random salt, returning it in PHC string format
+// (`$argon2id$v=19$...`), which embeds the parameters and salt needed to verify it later.
+pub fn hash_password(password: &str) -> Result<String, PoemError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| PoemError::from_string("Failed to hash password", StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Whether a stored password is an Argon2 hash, as opposed to a plaintext password stored
+// before hashing was introduced.
+pub fn is_password_hash(stored: &str) -> bool {
+    stored.starts_with("$argon2") && PasswordHash::new(stored).is_ok()
+}
+
+// Checks a password against the stored value.
+//
+// Plaintext passwords that haven't been migrated yet (see `POST /admin/migrate/passwords`)
+// are still compared directly, so those users can keep logging in until they are.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    if !is_password_hash(stored) {
+        return password == stored;
+    }
+
+    PasswordHash::new(stored)
+        .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_passwords_verify() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(is_password_hash(&hash));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        // Each hash gets its own salt.
+        assert_ne!(hash, hash_password("correct horse").unwrap());
+    }
+
+    #[test]
+    fn plaintext_passwords_still_verify_until_migrated() {
+        assert!(!is_password_hash("secret"));
+        assert!(!is_password_hash("$argon2id$not a hash"));
+        assert!(verify_password("secret", "secret"));
+        assert!(!verify_password("Secret", "secret"));
+
+        // What /admin/migrate/passwords stores in place of the plaintext password.
+        let migrated = hash_password("secret").unwrap();
+        assert!(verify_password("secret", &migrated));
+        assert!(!verify_password(&migrated, &migrated));
+    }
+}
@@ -0,0 +1,100 @@
+// The role each action requires, mirroring the `poem_grants::protect` attributes on the handlers.
+// Keep it in sync when a route is added or its protection changes.
+//
+// Roles aren't hierarchical: `protect("user")` only lets in users holding the `user` role, so an
+// admin without it is refused too.
+const RULES: &[(&str, &str, &str)] = &[
+    ("files", "list", "user"),
+    ("files", "upload", "user"),
+    ("files", "download", "user"),
+    ("files", "update", "user"),
+    ("files", "delete", "user"),
+    ("files", "share", "user"),
+    ("images", "list", "user"),
+    ("images", "upload", "user"),
+    ("images", "download", "user"),
+    ("images", "convert", "user"),
+    ("notifications", "read", "user"),
+    ("notifications", "broadcast", "admin"),
+    ("profile", "update", "user"),
+    ("events", "subscribe", "user"),
+    ("users", "create", "admin"),
+    ("users", "read", "admin"),
+    ("users", "update", "admin"),
+    ("users", "delete", "admin"),
+    ("users", "update_roles", "admin"),
+    ("maintenance", "run", "admin"),
+    ("system", "read", "admin"),
+];
+
+// Checks whether a user with `roles` may perform `action` on `resource`, without performing it.
+//
+// # Returns
+// - `(true, reason)` naming the role that grants the action.
+// - `(false, reason)` naming the missing role, or saying the action is unknown.
+pub fn check_permission(roles: &[String], resource: &str, action: &str) -> (bool, String) {
+    let Some((_, _, required)) = RULES
+        .iter()
+        .find(|(rule_resource, rule_action, _)| *rule_resource == resource && *rule_action == action)
+    else {
+        return (false, format!("There is no action {} on {}", action, resource));
+    };
+
+    if roles.iter().any(|role| role == required) {
+        (true, format!("Granted by the {} role", required))
+    } else {
+        (false, format!("Requires the {} role, which the user doesn't have", required))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(roles: &[&str]) -> Vec<String> {
+        roles.iter().map(|role| role.to_string()).collect()
+    }
+
+    #[test]
+    fn actions_need_their_role() {
+        // POST /files/bulk-tag is a file update.
+        assert_eq!(
+            check_permission(&roles(&["user"]), "files", "update"),
+            (true, "Granted by the user role".to_string())
+        );
+        assert_eq!(
+            check_permission(&roles(&["user"]), "users", "delete"),
+            (false, "Requires the admin role, which the user doesn't have".to_string())
+        );
+        assert!(check_permission(&roles(&["admin"]), "maintenance", "run").0);
+        assert!(!check_permission(&roles(&[]), "files", "update").0);
+    }
+
+    #[test]
+    fn roles_are_not_hierarchical() {
+        assert!(!check_permission(&roles(&["admin"]), "files", "update").0);
+        assert!(check_permission(&roles(&["user", "admin"]), "files", "update").0);
+    }
+
+    #[test]
+    fn unknown_actions_are_refused() {
+        assert_eq!(
+            check_permission(&roles(&["user", "admin"]), "files", "rename"),
+            (false, "There is no action rename on files".to_string())
+        );
+    }
+
+    #[test]
+    fn rules_are_unique_and_name_known_roles() {
+        for (i, (resource, action, role)) in RULES.iter().enumerate() {
+            assert!(["user", "admin"].contains(role), "{} {}", resource, action);
+            assert!(
+                !RULES[..i].iter().any(|(other_resource, other_action, _)| other_resource == resource && other_action == action),
+                "{} {} is listed twice",
+                resource,
+                action
+            );
+        }
+    }
+}
+
@@ -0,0 +1,80 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::auth::jwt::SECRET;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Pre-signed URLs let a client hand a download link to a service that can't send a token.
+// The URL carries its expiry and an HMAC-SHA256 over the method, path and expiry, keyed with the
+// JWT secret, so it can't be altered or reused for another file.
+
+fn mac(id: &str, exp: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("GET:/files/{}:{}", id, exp).as_bytes());
+    mac
+}
+
+// Builds the path and query of a pre-signed download URL for the file `id`, valid until the unix
+// timestamp `exp`.
+pub fn presigned_url(id: &str, exp: u64) -> String {
+    let sig = URL_SAFE_NO_PAD.encode(mac(id, exp).finalize().into_bytes());
+    format!("/files/{}?sig={}&exp={}", id, sig, exp)
+}
+
+// Checks the signature of a pre-signed URL for the file `id`.
+//
+// # Returns
+// - `true` if `sig` was made by `presigned_url` for this file and expiry, and `exp` hasn't passed.
+// - `false` otherwise. The signature is compared in constant time.
+pub fn verify_presigned_url(id: &str, sig: &str, exp: u64) -> bool {
+    if exp < Utc::now().timestamp().max(0) as u64 {
+        return false;
+    }
+    let Ok(sig) = URL_SAFE_NO_PAD.decode(sig) else {
+        return false;
+    };
+    mac(id, exp).verify_slice(&sig).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Splits a URL from `presigned_url` into its signature and expiry.
+    fn sig_and_exp(url: &str) -> (String, u64) {
+        let (_, query) = url.split_once('?').unwrap();
+        let (sig, exp) = query.strip_prefix("sig=").unwrap().split_once("&exp=").unwrap();
+        (sig.to_string(), exp.parse().unwrap())
+    }
+
+    fn in_an_hour() -> u64 {
+        Utc::now().timestamp() as u64 + 3600
+    }
+
+    #[test]
+    fn signed_urls_verify() {
+        let url = presigned_url("abc123", in_an_hour());
+        assert!(url.starts_with("/files/abc123?sig="));
+        let (sig, exp) = sig_and_exp(&url);
+        assert!(verify_presigned_url("abc123", &sig, exp));
+    }
+
+    #[test]
+    fn urls_for_another_file_or_expiry_are_rejected() {
+        let (sig, exp) = sig_and_exp(&presigned_url("abc123", in_an_hour()));
+        assert!(!verify_presigned_url("def456", &sig, exp));
+        assert!(!verify_presigned_url("abc123", &sig, exp + 1));
+        assert!(!verify_presigned_url("abc123", "not base64!", exp));
+        assert!(!verify_presigned_url("abc123", "", exp));
+    }
+
+    #[test]
+    fn expired_urls_are_rejected() {
+        let exp = Utc::now().timestamp() as u64 - 1;
+        let (sig, _) = sig_and_exp(&presigned_url("abc123", exp));
+        assert!(!verify_presigned_url("abc123", &sig, exp));
+    }
+}
@@ -0,0 +1,598 @@
+use ipnet::IpNet;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Settings read from environment variables at startup. Every setting has a default, so the
+// API can still be started with a plain `cargo run`.
+pub struct Config {
+    pub mongo_uri: String,
+    pub rate_limit: RateLimitConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub uploads: UploadConfig,
+    pub cors: CorsConfig,
+    pub queries: QueryConfig,
+    pub auth: AuthConfig,
+    pub metadata: MetadataLimits,
+    pub bulk: BulkLimits,
+    pub downloads: DownloadConfig,
+    pub links: LinkConfig,
+    pub passwords: PasswordPolicy,
+    pub image_presets: ImagePresets,
+    pub quota: QuotaConfig,
+    pub storage: StorageConfig,
+    // Proxies whose X-Forwarded-For and Forwarded headers are trusted to carry the client IP.
+    pub trusted_proxies: Vec<IpNet>,
+    // Refuse to start while any user still has a plaintext password.
+    pub require_hashed_passwords: bool,
+    // Redirect paths with a trailing slash to the path without it, instead of serving them as is.
+    pub redirect_trailing_slash: bool,
+    // Link every auth event to the one before it, so changes to the log can be detected.
+    pub audit_hash_chain: bool,
+    pub log_format: LogFormat,
+    // Sent in the `Retry-After` header of requests rejected while MongoDB has no primary.
+    pub failover_retry_after: Duration,
+}
+
+// Where the content of uploaded files is kept, see `storage::Storage`. Metadata always stays in MongoDB.
+#[derive(Clone)]
+pub enum StorageConfig {
+    // In the `blobs` collection.
+    Mongo,
+    // In files below `root`, one per distinct content.
+    Filesystem { root: PathBuf },
+}
+
+// How the access log is written.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // A JSON object per line, for log aggregation.
+    Json,
+}
+
+// Requests allowed per client per window. Anonymous traffic is limited per IP address and
+// authenticated traffic per user, using the most generous limit among the user's roles.
+pub struct RateLimitConfig {
+    pub window: Duration,
+    pub anonymous: u32,
+    pub roles: Vec<(String, u32)>,
+}
+
+// Values of the security headers added to every response. A header set to `None` is not sent.
+#[derive(Clone)]
+pub struct SecurityHeadersConfig {
+    // Only sent on requests made over TLS, as browsers ignore it on plain HTTP.
+    pub strict_transport_security: Option<String>,
+    pub content_type_options: Option<String>,
+    pub frame_options: Option<String>,
+    pub content_security_policy: Option<String>,
+}
+
+// Limits on the cursors opened by listing endpoints, so a slow client can't keep a query running
+// on the server indefinitely.
+#[derive(Clone)]
+pub struct QueryConfig {
+    // Documents fetched from MongoDB per round trip.
+    pub batch_size: u32,
+    // How long MongoDB may spend on a query before aborting it.
+    pub max_time: Duration,
+}
+
+// Bounds on the metadata stored with a file, so it can't be used to store arbitrary amounts of data.
+#[derive(Clone)]
+pub struct MetadataLimits {
+    pub max_tags: usize,
+    // In characters.
+    pub max_tag_length: usize,
+    // In characters.
+    pub max_description_length: usize,
+    // The UTF-8 size of the description and tags together.
+    pub max_metadata_bytes: usize,
+}
+
+// Bounds on the arrays sent to bulk endpoints such as /users/batch, checked before any item is processed.
+#[derive(Clone)]
+pub struct BulkLimits {
+    pub max_items: usize,
+}
+
+// How downloaded files are named.
+#[derive(Clone)]
+pub struct DownloadConfig {
+    // Sent as the filename of files whose stored name is empty, or has nothing left once unsafe
+    // characters are removed.
+    pub fallback_filename: String,
+    // Record downloads in the capped `recent_downloads` collection listed by /files/recent.
+    pub track_recent: bool,
+    // The size the `recent_downloads` collection is created with.
+    pub recent_max_bytes: u64,
+}
+
+// How links to the API are built for use outside of it, e.g. in QR codes.
+#[derive(Clone)]
+pub struct LinkConfig {
+    // The URL clients reach the API at, without a trailing slash, e.g. `https://files.example.com`.
+    pub public_base_url: String,
+}
+
+// How much each user may store, and when they are warned about running out of space.
+#[derive(Clone)]
+pub struct QuotaConfig {
+    pub quota_bytes: u64,
+    // The share of the quota, between 0 and 1, beyond which an upload records a quota alert.
+    pub alert_threshold: f64,
+}
+
+// A named size images can be downloaded at from /images/:filename/:preset.
+#[derive(Clone)]
+pub struct ImagePreset {
+    pub name: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Clone)]
+pub struct ImagePresets {
+    pub presets: Vec<ImagePreset>,
+}
+
+impl ImagePresets {
+    pub fn find(&self, name: &str) -> Option<&ImagePreset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+
+    // The preset names, comma separated, for error messages.
+    pub fn names(&self) -> String {
+        self.presets.iter().map(|preset| preset.name).collect::<Vec<_>>().join(", ")
+    }
+}
+
+// Rules for new passwords beyond their length.
+#[derive(Clone, Default)]
+pub struct PasswordPolicy {
+    // Common passwords that are rejected, in lowercase.
+    pub blocklist: Arc<HashSet<String>>,
+}
+
+impl PasswordPolicy {
+    // Whether `password` is on the blocklist, ignoring case.
+    pub fn is_blocked(&self, password: &str) -> bool {
+        self.blocklist.contains(&password.to_lowercase())
+    }
+}
+
+// How clients may authenticate, besides an `Authorization: Bearer` header.
+#[derive(Clone)]
+pub struct AuthConfig {
+    // Accept the token from a `session` cookie, which /login then sets. Only safe over TLS, as the
+    // cookie is `Secure`, and the cookie is sent with every request to the API, so state changing
+    // requests need CSRF protection beyond its `SameSite=Strict`.
+    pub cookie_auth: bool,
+    // Requests with a larger `Authorization` or `Cookie` header are rejected before the token is read.
+    pub max_header_bytes: usize,
+}
+
+// Cross-origin access for browser clients. CORS is off unless at least one origin is allowed.
+pub struct CorsConfig {
+    // Exact origins such as `https://app.example.com`, or just `*` for any origin.
+    pub allowed_origins: Vec<String>,
+    // Lets browsers send cookies and read the responses. Never combined with `*`, which would
+    // let any website make authenticated requests.
+    pub allow_credentials: bool,
+}
+
+// Limits on uploads that are processed at the same time, as each holds the file in memory.
+#[derive(Clone)]
+pub struct UploadConfig {
+    pub max_concurrent: usize,
+    // Uploads a single user may have in progress at once, within `max_concurrent`.
+    pub max_per_user: usize,
+    // How long an upload waits for a free slot before being turned away.
+    pub queue_timeout: Duration,
+    // The most images accepted by a single /images/batch-upload request.
+    pub max_image_batch: usize,
+    // Files larger than this are rejected while they are being received.
+    pub max_file_bytes: u64,
+    // Files larger than this are streamed into GridFS, or the filesystem backend, instead of being
+    // kept in memory.
+    pub gridfs_threshold_bytes: usize,
+    // Keep the previous content when a file's content is replaced.
+    pub keep_versions: bool,
+    // Accept zero-byte files instead of rejecting them with `empty_file`.
+    pub allow_empty_files: bool,
+    // The image types accepted by /upload_image and /images/batch-upload, detected from the content.
+    pub image_content_types: Vec<String>,
+    // How long a ticket from /uploads/ticket can be used.
+    pub ticket_ttl: Duration,
+}
+
+impl Config {
+    // Reads the configuration from the environment.
+    //
+    // # Environment variables
+    // - `MONGO_URI` (default `mongodb://localhost:27017`), or `MONGO_URI_FILE` with the path of a file holding it
+    // - `JWT_SECRET`, or `JWT_SECRET_FILE` - read by `auth::jwt`, see `load_secret`
+    // - `RATE_LIMIT_WINDOW_SECS` (default 60)
+    // - `RATE_LIMIT_ANONYMOUS` (default 60) - also used for users without a configured role
+    // - `RATE_LIMIT_USER` (default 300)
+    // - `RATE_LIMIT_ADMIN` (default 1000)
+    // - `SECURITY_HSTS` (default `max-age=31536000; includeSubDomains`)
+    // - `SECURITY_CONTENT_TYPE_OPTIONS` (default `nosniff`)
+    // - `SECURITY_FRAME_OPTIONS` (default `DENY`)
+    // - `SECURITY_CSP` (default `default-src 'none'; frame-ancestors 'none'; report-uri /csp-report`)
+    //
+    // - `UPLOAD_MAX_CONCURRENT` (default 8)
+    // - `UPLOAD_MAX_PER_USER` (default 5)
+    // - `UPLOAD_QUEUE_TIMEOUT_MS` (default 2000)
+    // - `IMAGE_BATCH_MAX` (default 20)
+    // - `UPLOAD_MAX_BYTES` (default 100 MiB)
+    // - `UPLOAD_GRIDFS_THRESHOLD_BYTES` (default 8 MiB) - must stay below MongoDB's 16 MiB document limit
+    // - `FILE_VERSIONING` (default false)
+    // - `UPLOAD_ALLOW_EMPTY` (default false)
+    // - `UPLOAD_TICKET_TTL_SECS` (default 300)
+    // - `IMAGE_ALLOWED_TYPES` (default `image/png,image/jpeg,image/webp,image/gif`) - comma separated `image/*` MIME types
+    // - `QUERY_BATCH_SIZE` (default 100)
+    // - `QUERY_MAX_TIME_MS` (default 5000)
+    // - `CORS_ALLOWED_ORIGINS` (default none, CORS disabled) - comma separated origins, or `*`
+    // - `CORS_ALLOW_CREDENTIALS` (default false) - can't be combined with `*`
+    // - `TRUSTED_PROXIES` (default none) - comma separated IP addresses and CIDR ranges
+    // - `REQUIRE_HASHED_PASSWORDS` (default false)
+    // - `METADATA_MAX_TAGS` (default 20)
+    // - `METADATA_MAX_TAG_LENGTH` (default 50)
+    // - `METADATA_MAX_DESCRIPTION_LENGTH` (default 500)
+    // - `METADATA_MAX_BYTES` (default 4096)
+    // - `BULK_MAX_ITEMS` (default 100)
+    // - `COOKIE_AUTH_ENABLED` (default false)
+    // - `AUTH_MAX_HEADER_BYTES` (default 8 KiB)
+    // - `TRAILING_SLASH_REDIRECT` (default false) - redirect `/path/` to `/path` rather than serving it
+    // - `DOWNLOAD_FALLBACK_FILENAME` (default `download.bin`)
+    // - `RECENT_DOWNLOADS` (default false)
+    // - `RECENT_DOWNLOADS_MAX_BYTES` (default 16 MiB) - only applies when the collection is created
+    // - `PUBLIC_BASE_URL` (default `http://localhost:3000`)
+    // - `PASSWORD_BLOCKLIST_PATH` (default none) - a file with one common password per line
+    // - `AUDIT_HASH_CHAIN` (default false)
+    // - `IMAGE_PRESET_SMALL` (default `150x150`), `IMAGE_PRESET_MEDIUM` (default `480x480`) and
+    //   `IMAGE_PRESET_LARGE` (default `1280x1280`) - the box each preset scales images to fit within, each side at most 4096
+    // - `STORAGE_QUOTA_BYTES` (default 1 GiB)
+    // - `QUOTA_ALERT_THRESHOLD` (default 0.9) - must be above 0 and at most 1
+    // - `LOG_FORMAT` (default `text`) - `text` or `json`
+    // - `DB_FAILOVER_RETRY_AFTER_SECS` (default 5)
+    // - `STORAGE_BACKEND` (default `mongo`) - `mongo` or `fs`
+    // - `STORAGE_PATH` (default `./storage`) - the directory files are stored in with `STORAGE_BACKEND=fs`
+    //
+    // The security headers can be turned off one by one by setting the variable to an empty string.
+    //
+    // Panics with a descriptive message if a variable is set to something that can't be parsed,
+    // as silently falling back to the default would hide the misconfiguration.
+    pub fn load() -> Self {
+        Self {
+            mongo_uri: load_secret("MONGO_URI", "MONGO_URI_FILE")
+                .unwrap_or_else(|| "mongodb://localhost:27017".to_string()),
+            rate_limit: RateLimitConfig {
+                window: Duration::from_secs(env_or("RATE_LIMIT_WINDOW_SECS", 60)),
+                anonymous: env_or("RATE_LIMIT_ANONYMOUS", 60),
+                roles: vec![
+                    ("user".to_string(), env_or("RATE_LIMIT_USER", 300)),
+                    ("admin".to_string(), env_or("RATE_LIMIT_ADMIN", 1000)),
+                ],
+            },
+            security_headers: SecurityHeadersConfig {
+                strict_transport_security: header_or("SECURITY_HSTS", "max-age=31536000; includeSubDomains"),
+                content_type_options: header_or("SECURITY_CONTENT_TYPE_OPTIONS", "nosniff"),
+                frame_options: header_or("SECURITY_FRAME_OPTIONS", "DENY"),
+                content_security_policy: header_or(
+                    "SECURITY_CSP",
+                    "default-src 'none'; frame-ancestors 'none'; report-uri /csp-report",
+                ),
+            },
+            uploads: UploadConfig {
+                max_concurrent: env_or("UPLOAD_MAX_CONCURRENT", 8),
+                max_per_user: env_or("UPLOAD_MAX_PER_USER", 5),
+                queue_timeout: Duration::from_millis(env_or("UPLOAD_QUEUE_TIMEOUT_MS", 2000)),
+                max_image_batch: env_or("IMAGE_BATCH_MAX", 20),
+                max_file_bytes: env_or("UPLOAD_MAX_BYTES", 100 * 1024 * 1024),
+                gridfs_threshold_bytes: env_or("UPLOAD_GRIDFS_THRESHOLD_BYTES", 8 * 1024 * 1024),
+                keep_versions: env_or("FILE_VERSIONING", false),
+                allow_empty_files: env_or("UPLOAD_ALLOW_EMPTY", false),
+                image_content_types: image_content_types(),
+                ticket_ttl: Duration::from_secs(env_or("UPLOAD_TICKET_TTL_SECS", 300)),
+            },
+            queries: QueryConfig {
+                batch_size: env_or("QUERY_BATCH_SIZE", 100),
+                max_time: Duration::from_millis(env_or("QUERY_MAX_TIME_MS", 5000)),
+            },
+            metadata: MetadataLimits {
+                max_tags: env_or("METADATA_MAX_TAGS", 20),
+                max_tag_length: env_or("METADATA_MAX_TAG_LENGTH", 50),
+                max_description_length: env_or("METADATA_MAX_DESCRIPTION_LENGTH", 500),
+                max_metadata_bytes: env_or("METADATA_MAX_BYTES", 4096),
+            },
+            bulk: BulkLimits {
+                max_items: env_or("BULK_MAX_ITEMS", 100),
+            },
+            downloads: DownloadConfig {
+                fallback_filename: fallback_filename(),
+                track_recent: env_or("RECENT_DOWNLOADS", false),
+                recent_max_bytes: env_or("RECENT_DOWNLOADS_MAX_BYTES", 16 * 1024 * 1024),
+            },
+            links: LinkConfig {
+                public_base_url: public_base_url(),
+            },
+            passwords: PasswordPolicy {
+                blocklist: Arc::new(password_blocklist()),
+            },
+            image_presets: ImagePresets {
+                presets: vec![
+                    image_preset("small", "IMAGE_PRESET_SMALL", (150, 150)),
+                    image_preset("medium", "IMAGE_PRESET_MEDIUM", (480, 480)),
+                    image_preset("large", "IMAGE_PRESET_LARGE", (1280, 1280)),
+                ],
+            },
+            quota: QuotaConfig {
+                quota_bytes: env_or("STORAGE_QUOTA_BYTES", 1024 * 1024 * 1024),
+                alert_threshold: quota_alert_threshold(),
+            },
+            storage: storage_config(),
+            auth: AuthConfig {
+                cookie_auth: env_or("COOKIE_AUTH_ENABLED", false),
+                max_header_bytes: env_or("AUTH_MAX_HEADER_BYTES", 8 * 1024),
+            },
+            cors: cors_config(),
+            trusted_proxies: ip_list("TRUSTED_PROXIES"),
+            require_hashed_passwords: env_or("REQUIRE_HASHED_PASSWORDS", false),
+            redirect_trailing_slash: env_or("TRAILING_SLASH_REDIRECT", false),
+            audit_hash_chain: env_or("AUDIT_HASH_CHAIN", false),
+            log_format: log_format(),
+            failover_retry_after: Duration::from_secs(env_or("DB_FAILOVER_RETRY_AFTER_SECS", 5)),
+        }
+    }
+}
+
+fn cors_config() -> CorsConfig {
+    let allowed_origins: Vec<String> = std::env::var("CORS_ALLOWED_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(ToString::to_string)
+        .collect();
+    let allow_credentials = env_or("CORS_ALLOW_CREDENTIALS", false);
+
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        if allowed_origins.len() > 1 {
+            panic!("CORS_ALLOWED_ORIGINS must be either * or a list of origins, not both");
+        }
+        if allow_credentials {
+            panic!("CORS_ALLOW_CREDENTIALS can't be used with CORS_ALLOWED_ORIGINS=*, list the allowed origins instead");
+        }
+    }
+    for origin in &allowed_origins {
+        if poem::http::HeaderValue::from_str(origin).is_err() {
+            panic!("Invalid value for CORS_ALLOWED_ORIGINS: {:?}", origin);
+        }
+    }
+
+    CorsConfig { allowed_origins, allow_credentials }
+}
+
+// Reads a secret from the environment variable `env_var`, or else from the file named by
+// `file_env_var`, as Docker and Kubernetes mount secrets as files. Surrounding whitespace, like the
+// trailing newline of the file, is trimmed.
+//
+// # Returns
+// - `Some(secret)` if either variable is set, preferring `env_var`.
+// - `None` if neither is set.
+//
+// Panics if the file can't be read or is empty, as starting without the secret would fail later
+// in a less obvious way.
+pub fn load_secret(env_var: &str, file_env_var: &str) -> Option<String> {
+    if let Ok(value) = std::env::var(env_var) {
+        return Some(value.trim().to_string());
+    }
+    let path = std::env::var(file_env_var).ok()?;
+    let contents = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read {} from {} {:?}: {}", env_var, file_env_var, path, e));
+    let secret = contents.trim();
+    if secret.is_empty() {
+        panic!("{} {:?} is empty, expected it to hold {}", file_env_var, path, env_var);
+    }
+    Some(secret.to_string())
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid value for {}: {:?}", name, value)),
+        Err(_) => default,
+    }
+}
+
+// Reads a header value, where an empty value turns the header off.
+fn header_or(name: &str, default: &str) -> Option<String> {
+    let value = std::env::var(name).unwrap_or_else(|_| default.to_string());
+    if value.is_empty() {
+        return None;
+    }
+    if poem::http::HeaderValue::from_str(&value).is_err() {
+        panic!("Invalid value for {}: {:?}", name, value);
+    }
+    Some(value)
+}
+
+// Reads the fallback filename, which is sent as is, so it has to be a name that needs no sanitizing.
+fn fallback_filename() -> String {
+    let value = std::env::var("DOWNLOAD_FALLBACK_FILENAME").unwrap_or_else(|_| "download.bin".to_string());
+    let valid = !value.trim().is_empty()
+        && value.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+        && !value.contains(['"', '\\', '/']);
+    if !valid {
+        panic!("Invalid value for DOWNLOAD_FALLBACK_FILENAME: {:?}", value);
+    }
+    value.trim().to_string()
+}
+
+// Reads the image types accepted for upload. Only `image/*` types make sense, as everything stored
+// as an image is expected to have dimensions and be convertible.
+fn image_content_types() -> Vec<String> {
+    let value = std::env::var("IMAGE_ALLOWED_TYPES").unwrap_or_else(|_| "image/png,image/jpeg,image/webp,image/gif".to_string());
+    let types: Vec<String> = value
+        .split(',')
+        .map(|entry| entry.trim().to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    let valid = !types.is_empty()
+        && types.iter().all(|entry| entry.strip_prefix("image/").is_some_and(|subtype| !subtype.is_empty() && !subtype.contains('/')));
+    if !valid {
+        panic!("Invalid value for IMAGE_ALLOWED_TYPES: {:?}", value);
+    }
+    types
+}
+
+fn log_format() -> LogFormat {
+    match std::env::var("LOG_FORMAT") {
+        Err(_) => LogFormat::Text,
+        Ok(value) => match value.to_ascii_lowercase().as_str() {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => panic!("Invalid value for LOG_FORMAT: {:?}, expected text or json", value),
+        },
+    }
+}
+
+fn storage_config() -> StorageConfig {
+    match std::env::var("STORAGE_BACKEND") {
+        Err(_) => StorageConfig::Mongo,
+        Ok(value) => match value.to_ascii_lowercase().as_str() {
+            "mongo" => StorageConfig::Mongo,
+            "fs" => {
+                let root = std::env::var("STORAGE_PATH").unwrap_or_else(|_| "./storage".to_string());
+                if root.trim().is_empty() {
+                    panic!("Invalid value for STORAGE_PATH: {:?}", root);
+                }
+                StorageConfig::Filesystem { root: PathBuf::from(root) }
+            }
+            _ => panic!("Invalid value for STORAGE_BACKEND: {:?}, expected mongo or fs", value),
+        },
+    }
+}
+
+fn quota_alert_threshold() -> f64 {
+    let threshold = env_or("QUOTA_ALERT_THRESHOLD", 0.9);
+    if !(threshold > 0.0 && threshold <= 1.0) {
+        panic!("Invalid value for QUOTA_ALERT_THRESHOLD: {:?}, expected a share above 0 and at most 1", threshold);
+    }
+    threshold
+}
+
+// Reads the dimensions of an image preset, given as `WIDTHxHEIGHT`, e.g. `150x150`.
+fn image_preset(name: &'static str, env_var: &str, default: (u32, u32)) -> ImagePreset {
+    let (width, height) = match std::env::var(env_var) {
+        Ok(value) => parse_image_size(&value).unwrap_or_else(|| panic!("Invalid value for {}: {:?}", env_var, value)),
+        Err(_) => default,
+    };
+    ImagePreset { name, width, height }
+}
+
+// Parses `WIDTHxHEIGHT`, where both sides are between 1 and 4096.
+fn parse_image_size(value: &str) -> Option<(u32, u32)> {
+    value
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.trim().parse().ok()?, height.trim().parse().ok()?)))
+        .filter(|&(width, height): &(u32, u32)| (1..=4096).contains(&width) && (1..=4096).contains(&height))
+}
+
+// Reads the public base URL, which must be an absolute http or https URL without a query.
+fn public_base_url() -> String {
+    let value = std::env::var("PUBLIC_BASE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let valid = value
+        .parse::<poem::http::Uri>()
+        .is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some() && uri.query().is_none());
+    if !valid {
+        panic!("Invalid value for PUBLIC_BASE_URL: {:?}", value);
+    }
+    value.trim_end_matches('/').to_string()
+}
+
+// Reads the passwords in the file at PASSWORD_BLOCKLIST_PATH, one per line. Empty lines and lines
+// starting with `#` are skipped.
+fn password_blocklist() -> HashSet<String> {
+    let Ok(path) = std::env::var("PASSWORD_BLOCKLIST_PATH") else {
+        return HashSet::new();
+    };
+    let contents = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read PASSWORD_BLOCKLIST_PATH {:?}: {}", path, e));
+    parse_password_blocklist(&contents)
+}
+
+fn parse_password_blocklist(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// Reads a comma separated list of IP addresses and CIDR ranges. A plain address only matches itself.
+fn ip_list(name: &str) -> Vec<IpNet> {
+    let value = std::env::var(name).unwrap_or_default();
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .unwrap_or_else(|_| panic!("Invalid value for {}: {:?}", name, entry))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocklisted_passwords_are_matched_ignoring_case() {
+        let policy = PasswordPolicy {
+            blocklist: Arc::new(parse_password_blocklist("# common passwords\n\nPassword123\n  qwerty  \n")),
+        };
+        assert_eq!(policy.blocklist.len(), 2);
+        assert!(policy.is_blocked("password123"));
+        assert!(policy.is_blocked("PASSWORD123"));
+        assert!(policy.is_blocked("QWERTY"));
+        assert!(!policy.is_blocked("# common passwords"));
+        assert!(!policy.is_blocked(""));
+        assert!(!policy.is_blocked("correct horse battery staple"));
+    }
+
+    #[test]
+    fn image_sizes_are_parsed() {
+        assert_eq!(parse_image_size("150x150"), Some((150, 150)));
+        assert_eq!(parse_image_size(" 640 x 480 "), Some((640, 480)));
+        assert_eq!(parse_image_size("4096x1"), Some((4096, 1)));
+        for invalid in ["", "150", "150x", "x150", "0x150", "150x4097", "-1x5", "150*150"] {
+            assert_eq!(parse_image_size(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn presets_are_found_by_name() {
+        let presets = ImagePresets {
+            presets: vec![
+                ImagePreset { name: "small", width: 150, height: 150 },
+                ImagePreset { name: "large", width: 1280, height: 720 },
+            ],
+        };
+        assert_eq!(presets.find("large").map(|preset| (preset.width, preset.height)), Some((1280, 720)));
+        assert!(presets.find("Large").is_none());
+        assert!(presets.find("huge").is_none());
+        assert_eq!(presets.names(), "small, large");
+    }
+}
@@ -0,0 +1,97 @@
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+// One access to a file, stored in the `file_access_log` collection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileAccessLog {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub file_id: ObjectId,
+    pub accessed_by: String,
+    // What was done to the file, e.g. "upload" or "download".
+    pub action: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub timestamp: DateTime<Utc>,
+    pub ip_addr: Option<String>,
+}
+
+impl FileAccessLog {
+    pub fn new(file_id: ObjectId, accessed_by: &str, action: &str, ip_addr: Option<String>) -> Self {
+        Self {
+            id: None,
+            file_id,
+            accessed_by: accessed_by.to_string(),
+            action: action.to_string(),
+            timestamp: Utc::now(),
+            ip_addr,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessHistoryEntry {
+    pub accessed_by: String,
+    pub action: String,
+    pub timestamp: DateTime<Utc>,
+    pub ip_addr: Option<String>,
+}
+
+// Creates the indexes used to look up the history of a single file, and the activity of a
+// single user, newest first.
+pub async fn create_access_log_indexes(collection: &Collection<FileAccessLog>) -> Result<(), Error> {
+    let file_index = IndexModel::builder()
+        .keys(doc! { "file_id": 1, "timestamp": -1 })
+        .options(
+            IndexOptions::builder()
+                .name("file_id_timestamp_index".to_string())
+                .build(),
+        )
+        .build();
+    let user_index = IndexModel::builder()
+        .keys(doc! { "accessed_by": 1, "timestamp": -1, "_id": -1 })
+        .options(
+            IndexOptions::builder()
+                .name("accessed_by_timestamp_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_indexes([file_index, user_index]).await?;
+    Ok(())
+}
+
+// Records an access to a file. The access itself has already happened by the time this is
+// called, so a failed insert is only logged instead of failing the request.
+pub async fn log_file_access(collection: &Collection<FileAccessLog>, entry: FileAccessLog) {
+    if let Err(err) = collection.insert_one(entry).await {
+        tracing::error!("Failed to write file access log: {}", err);
+    }
+}
+
+// Returns the latest `limit` accesses to a file, newest first.
+pub async fn get_access_history(
+    collection: &Collection<FileAccessLog>,
+    file_id: ObjectId,
+    limit: i64,
+) -> Result<Vec<AccessHistoryEntry>, Error> {
+    let mut cursor = collection
+        .find(doc! { "file_id": file_id })
+        .sort(doc! { "timestamp": -1 })
+        .limit(limit)
+        .await?;
+
+    let mut history = Vec::new();
+    while let Some(entry) = cursor.try_next().await? {
+        history.push(AccessHistoryEntry {
+            accessed_by: entry.accessed_by,
+            action: entry.action,
+            timestamp: entry.timestamp,
+            ip_addr: entry.ip_addr,
+        });
+    }
+
+    Ok(history)
+}
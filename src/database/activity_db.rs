@@ -0,0 +1,152 @@
+use bson::{doc, oid::ObjectId, Document};
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, Collection};
+use serde::Serialize;
+use crate::database::access_log_db::FileAccessLog;
+use crate::database::auth_event_db::AuthEvent;
+
+// One entry in the activity timeline of a user, from either the auth events or the file access log.
+#[derive(Debug, Serialize)]
+pub struct ActivityEvent {
+    pub timestamp: DateTime<Utc>,
+    pub event_type: String,
+    pub details: serde_json::Value,
+    // Only used to build the pagination cursor.
+    #[serde(skip)]
+    id: ObjectId,
+}
+
+// Position in the timeline to continue after. Events are ordered by timestamp and then id, so
+// events with the same timestamp aren't skipped or repeated across pages.
+#[derive(Debug, Clone, Copy)]
+pub struct TimelineCursor {
+    timestamp: DateTime<Utc>,
+    id: ObjectId,
+}
+
+impl TimelineCursor {
+    // Parses a cursor returned as `next_cursor` by an earlier page.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (millis, id) = value.split_once('-')?;
+        Some(Self {
+            timestamp: Utc.timestamp_millis_opt(millis.parse().ok()?).single()?,
+            id: ObjectId::parse_str(id).ok()?,
+        })
+    }
+
+    fn of(event: &ActivityEvent) -> Self {
+        Self { timestamp: event.timestamp, id: event.id }
+    }
+}
+
+impl std::fmt::Display for TimelineCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.timestamp.timestamp_millis(), self.id.to_hex())
+    }
+}
+
+// A page of the activity timeline, newest first.
+#[derive(Debug, Serialize)]
+pub struct ActivityPage {
+    pub events: Vec<ActivityEvent>,
+    // Pass as `after` to get the next page. `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+// Builds the filter shared by both collections, as they store the user under different field names.
+fn timeline_filter(
+    user_field: &str,
+    username: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    after: Option<TimelineCursor>,
+) -> Document {
+    let mut filter = doc! { user_field: username };
+
+    let mut range = doc! {};
+    if let Some(from) = from {
+        range.insert("$gte", bson::DateTime::from_chrono(from));
+    }
+    if let Some(to) = to {
+        range.insert("$lte", bson::DateTime::from_chrono(to));
+    }
+    if !range.is_empty() {
+        filter.insert("timestamp", range);
+    }
+
+    if let Some(after) = after {
+        let timestamp = bson::DateTime::from_chrono(after.timestamp);
+        filter.insert("$or", vec![
+            doc! { "timestamp": { "$lt": timestamp } },
+            doc! { "timestamp": timestamp, "_id": { "$lt": after.id } },
+        ]);
+    }
+
+    filter
+}
+
+// Returns up to `page_size` events of a user from both the auth events and the file access log,
+// newest first, optionally limited to the time range `from..=to`.
+pub async fn get_activity_timeline(
+    auth_events: &Collection<AuthEvent>,
+    access_log: &Collection<FileAccessLog>,
+    username: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    after: Option<TimelineCursor>,
+    page_size: i64,
+) -> Result<ActivityPage, Error> {
+    let sort = doc! { "timestamp": -1, "_id": -1 };
+    // One extra event from each collection tells whether there is another page.
+    let auth_query = async {
+        auth_events
+            .find(timeline_filter("username", username, from, to, after))
+            .sort(sort.clone())
+            .limit(page_size + 1)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await
+    };
+    let file_query = async {
+        access_log
+            .find(timeline_filter("accessed_by", username, from, to, after))
+            .sort(sort.clone())
+            .limit(page_size + 1)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await
+    };
+    let (auth, files) = futures::try_join!(auth_query, file_query)?;
+
+    let mut events: Vec<ActivityEvent> = auth
+        .into_iter()
+        .filter_map(|event| {
+            Some(ActivityEvent {
+                id: event.id?,
+                timestamp: event.timestamp,
+                event_type: event.event_type,
+                details: serde_json::json!({ "ip_addr": event.ip_addr }),
+            })
+        })
+        .chain(files.into_iter().filter_map(|entry| {
+            Some(ActivityEvent {
+                id: entry.id?,
+                timestamp: entry.timestamp,
+                event_type: format!("file_{}", entry.action),
+                details: serde_json::json!({ "file_id": entry.file_id.to_hex(), "ip_addr": entry.ip_addr }),
+            })
+        }))
+        .collect();
+
+    events.sort_by_key(|event| std::cmp::Reverse((event.timestamp, event.id)));
+
+    let next_cursor = if events.len() > page_size as usize {
+        events.truncate(page_size as usize);
+        events.last().map(|event| TimelineCursor::of(event).to_string())
+    } else {
+        None
+    };
+
+    Ok(ActivityPage { events, next_cursor })
+}
@@ -0,0 +1,78 @@
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Utc};
+use mongodb::{error::Error, Database};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct IndexUsageEntry {
+    pub collection: String,
+    pub index_name: String,
+    pub keys: Document,
+    pub access_count: u64,
+    pub last_used: Option<DateTime<Utc>>,
+    pub unused: bool,
+}
+
+// Collects index usage statistics for every collection in the database.
+//
+// # Arguments
+// - `db`: The MongoDB database to inspect.
+//
+// # Returns
+// - `Ok(Vec<IndexUsageEntry>)` with one entry per index across all collections.
+// - `Err(error)` if listing the collections or running `$indexStats` fails.
+//
+// MongoDB only counts accesses since the last server restart (or index rebuild), and does not
+// record when an index was last hit. `last_used` is therefore the start of that counting window
+// (`accesses.since`) for indexes that have been used, and `None` for indexes with zero accesses.
+pub async fn get_index_usage(db: &Database) -> Result<Vec<IndexUsageEntry>, Error> {
+    let mut entries = Vec::new();
+
+    for collection in db.list_collection_names().await? {
+        let result = db
+            .run_command(doc! {
+                "aggregate": &collection,
+                "pipeline": [{ "$indexStats": {} }],
+                "cursor": {},
+            })
+            .await?;
+
+        let batch = result
+            .get_document("cursor")
+            .and_then(|cursor| cursor.get_array("firstBatch"))
+            .cloned()
+            .unwrap_or_default();
+
+        for stats in batch.iter().filter_map(Bson::as_document) {
+            entries.push(parse_index_stats(&collection, stats));
+        }
+    }
+
+    Ok(entries)
+}
+
+// Converts a single raw `$indexStats` document into an `IndexUsageEntry`.
+fn parse_index_stats(collection: &str, stats: &Document) -> IndexUsageEntry {
+    let accesses = stats.get_document("accesses").ok();
+    let access_count = accesses
+        .and_then(|accesses| accesses.get("ops"))
+        .and_then(|ops| match ops {
+            Bson::Int64(n) => Some(*n as u64),
+            Bson::Int32(n) => Some(*n as u64),
+            _ => None,
+        })
+        .unwrap_or(0);
+    let last_used = accesses
+        .filter(|_| access_count > 0)
+        .and_then(|accesses| accesses.get_datetime("since").ok())
+        .map(|since| since.to_chrono());
+
+    IndexUsageEntry {
+        collection: collection.to_string(),
+        index_name: stats.get_str("name").unwrap_or_default().to_string(),
+        keys: stats.get_document("key").cloned().unwrap_or_default(),
+        access_count,
+        last_used,
+        unused: access_count == 0,
+    }
+}
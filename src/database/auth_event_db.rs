@@ -0,0 +1,357 @@
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use crate::database::is_duplicate_key_error;
+
+// The `prev_hash` of the first entry of the hash chain.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// How often appending to the hash chain is retried when another entry took the same position.
+const MAX_CHAIN_APPEND_ATTEMPTS: usize = 10;
+
+// One authentication attempt, stored in the `auth_events` collection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthEvent {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // The username the attempt was made for, which doesn't have to exist.
+    pub username: String,
+    // "login", "login_failed", "register" or "refresh_token_reused".
+    pub event_type: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub timestamp: DateTime<Utc>,
+    pub ip_addr: Option<String>,
+    // With AUDIT_HASH_CHAIN, each event is numbered from 1 and carries the hash of the event before
+    // it, see `chain_hash`. Events recorded without it have none of these.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_seq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl AuthEvent {
+    pub fn new(username: &str, event_type: &str, ip_addr: Option<String>) -> Self {
+        Self {
+            id: None,
+            username: username.to_string(),
+            event_type: event_type.to_string(),
+            timestamp: Utc::now(),
+            ip_addr,
+            chain_seq: None,
+            prev_hash: None,
+            hash: None,
+        }
+    }
+
+    // The SHA-256 over the event and the hash of the event before it, so changing, removing or
+    // reordering any event breaks every link after it. The timestamp is hashed in milliseconds, the
+    // precision MongoDB stores it with.
+    fn chain_hash(&self) -> String {
+        let input = serde_json::json!([
+            self.prev_hash,
+            self.chain_seq,
+            self.username,
+            self.event_type,
+            self.timestamp.timestamp_millis(),
+            self.ip_addr,
+        ]);
+        format!("{:x}", Sha256::digest(input.to_string().as_bytes()))
+    }
+}
+
+// The hash of the newest event in the chain, stored in the `audit_chain_heads` collection. Without
+// it, removing the newest events would leave a chain that is still intact.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChainHead {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub chain_seq: i64,
+    pub hash: String,
+}
+
+// Where auth events are recorded, and whether they are hash chained.
+#[derive(Clone)]
+pub struct AuthEventLog {
+    events: Arc<Collection<AuthEvent>>,
+    heads: Arc<Collection<ChainHead>>,
+    hash_chain: bool,
+}
+
+impl AuthEventLog {
+    pub fn new(events: Arc<Collection<AuthEvent>>, heads: Arc<Collection<ChainHead>>, hash_chain: bool) -> Self {
+        Self { events, heads, hash_chain }
+    }
+}
+
+// Creates the index used to list the events of a single user, newest first, and the unique index
+// that keeps two events from taking the same position in the hash chain.
+pub async fn create_auth_event_indexes(collection: &Collection<AuthEvent>) -> Result<(), Error> {
+    let index_model = IndexModel::builder()
+        .keys(doc! { "username": 1, "timestamp": -1, "_id": -1 })
+        .options(
+            IndexOptions::builder()
+                .name("username_timestamp_index".to_string())
+                .build(),
+        )
+        .build();
+    let chain_index = IndexModel::builder()
+        .keys(doc! { "chain_seq": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .sparse(true)
+                .name("chain_seq_unique_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_indexes([index_model, chain_index]).await?;
+    Ok(())
+}
+
+// Records an authentication attempt. Like the file access log, a failed insert is only logged,
+// as it shouldn't stop anyone from logging in.
+pub async fn log_auth_event(log: &AuthEventLog, event: AuthEvent) {
+    let result = if log.hash_chain {
+        append_to_chain(log, event).await
+    } else {
+        log.events.insert_one(event).await.map(|_| ())
+    };
+    if let Err(err) = result {
+        tracing::error!("Failed to write auth event: {}", err);
+    }
+}
+
+// Appends an event to the end of the hash chain.
+//
+// The event is linked to the newest chained event and inserted at the next position. When another
+// server got there first, the unique index on `chain_seq` rejects the insert, and it is linked to
+// that event instead.
+async fn append_to_chain(log: &AuthEventLog, mut event: AuthEvent) -> Result<(), Error> {
+    for _ in 0..MAX_CHAIN_APPEND_ATTEMPTS {
+        let newest = log
+            .events
+            .find_one(doc! { "chain_seq": { "$exists": true } })
+            .sort(doc! { "chain_seq": -1 })
+            .await?;
+        let (seq, prev_hash) = match newest {
+            Some(AuthEvent { chain_seq: Some(seq), hash: Some(hash), .. }) => (seq + 1, hash),
+            _ => (1, GENESIS_HASH.to_string()),
+        };
+        event.chain_seq = Some(seq);
+        event.prev_hash = Some(prev_hash);
+        let hash = event.chain_hash();
+        event.hash = Some(hash.clone());
+
+        match log.events.insert_one(&event).await {
+            Ok(_) => {}
+            Err(e) if is_duplicate_key_error(&e) => continue,
+            Err(e) => return Err(e),
+        }
+
+        // Moves the head forward, unless an event after this one has already moved it. In that
+        // case the filter doesn't match, and the upsert fails on the existing `_id`.
+        let moved = log
+            .heads
+            .update_one(
+                doc! { "_id": "auth_events", "chain_seq": { "$lt": seq } },
+                doc! { "$set": { "chain_seq": seq, "hash": &hash } },
+            )
+            .upsert(true)
+            .await;
+        return match moved {
+            Err(e) if !is_duplicate_key_error(&e) => Err(e),
+            _ => Ok(()),
+        };
+    }
+    Err(Error::from(std::io::Error::other("Gave up appending to the auth event hash chain")))
+}
+
+// Where the hash chain is broken.
+#[derive(Debug, Serialize)]
+pub struct BrokenLink {
+    pub chain_seq: i64,
+    // `None` when the event is missing.
+    pub id: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChainReport {
+    pub valid: bool,
+    // The number of chained events checked.
+    pub checked: u64,
+    pub head_hash: Option<String>,
+    pub first_broken: Option<BrokenLink>,
+}
+
+// Checks the events of the hash chain one at a time, in `chain_seq` order.
+struct ChainVerifier {
+    checked: u64,
+    expected_seq: i64,
+    prev_hash: String,
+}
+
+impl ChainVerifier {
+    fn new() -> Self {
+        Self { checked: 0, expected_seq: 1, prev_hash: GENESIS_HASH.to_string() }
+    }
+
+    // Checks that `event` is in its place, links to the event before it and hasn't been changed
+    // since it was recorded.
+    fn check(&mut self, event: &AuthEvent) -> Result<(), BrokenLink> {
+        self.checked += 1;
+        let broken = |chain_seq, reason: &str| BrokenLink {
+            chain_seq,
+            id: event.id.map(|id| id.to_hex()),
+            reason: reason.to_string(),
+        };
+
+        let seq = event.chain_seq.unwrap_or_default();
+        if seq != self.expected_seq {
+            return Err(BrokenLink {
+                chain_seq: self.expected_seq,
+                id: None,
+                reason: "The event is missing".to_string(),
+            });
+        }
+        if event.prev_hash.as_deref() != Some(self.prev_hash.as_str()) {
+            return Err(broken(seq, "The event doesn't link to the event before it"));
+        }
+        let hash = event.chain_hash();
+        if event.hash.as_deref() != Some(hash.as_str()) {
+            return Err(broken(seq, "The event has been changed since it was recorded"));
+        }
+        self.prev_hash = hash;
+        self.expected_seq += 1;
+        Ok(())
+    }
+
+    // Checks that the events checked so far end at the stored head.
+    fn check_head(&self, head: &ChainHead) -> Result<(), BrokenLink> {
+        let last_seq = self.expected_seq - 1;
+        if head.chain_seq == last_seq && head.hash == self.prev_hash {
+            return Ok(());
+        }
+        Err(BrokenLink {
+            chain_seq: last_seq + 1,
+            id: None,
+            reason: format!("The chain ends before the stored head at event {}", head.chain_seq),
+        })
+    }
+}
+
+// Walks the hash chain from its first event, checking that every event is in its place, links to
+// the event before it and hasn't been changed since it was recorded, and that the chain ends at
+// the stored head.
+pub async fn verify_auth_event_chain(log: &AuthEventLog) -> Result<ChainReport, Error> {
+    let head = log.heads.find_one(doc! { "_id": "auth_events" }).await?;
+    let mut cursor = log
+        .events
+        .find(doc! { "chain_seq": { "$exists": true } })
+        .sort(doc! { "chain_seq": 1 })
+        .await?;
+
+    let mut verifier = ChainVerifier::new();
+    let mut first_broken = None;
+    while let Some(event) = cursor.try_next().await? {
+        if let Err(broken) = verifier.check(&event) {
+            first_broken = Some(broken);
+            break;
+        }
+    }
+    if first_broken.is_none()
+        && let Some(head) = &head
+    {
+        first_broken = verifier.check_head(head).err();
+    }
+
+    Ok(ChainReport {
+        valid: first_broken.is_none(),
+        checked: verifier.checked,
+        head_hash: head.map(|head| head.hash),
+        first_broken,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a chain of `length` events, linked the way `append_to_chain` links them, and its head.
+    fn chain(length: i64) -> (Vec<AuthEvent>, ChainHead) {
+        let mut events: Vec<AuthEvent> = Vec::new();
+        for seq in 1..=length {
+            let mut event = AuthEvent::new("alice", "login", Some("10.0.0.1".to_string()));
+            event.id = Some(ObjectId::new());
+            event.chain_seq = Some(seq);
+            event.prev_hash = Some(events.last().and_then(|prev| prev.hash.clone()).unwrap_or(GENESIS_HASH.to_string()));
+            event.hash = Some(event.chain_hash());
+            events.push(event);
+        }
+        let last = events.last().unwrap();
+        let head = ChainHead {
+            id: "auth_events".to_string(),
+            chain_seq: last.chain_seq.unwrap(),
+            hash: last.hash.clone().unwrap(),
+        };
+        (events, head)
+    }
+
+    fn first_broken(events: &[AuthEvent], head: &ChainHead) -> Option<BrokenLink> {
+        let mut verifier = ChainVerifier::new();
+        for event in events {
+            if let Err(broken) = verifier.check(event) {
+                return Some(broken);
+            }
+        }
+        verifier.check_head(head).err()
+    }
+
+    #[test]
+    fn an_intact_chain_is_valid() {
+        let (events, head) = chain(3);
+        assert!(first_broken(&events, &head).is_none());
+    }
+
+    #[test]
+    fn a_changed_event_is_reported() {
+        let (mut events, head) = chain(3);
+        events[1].username = "mallory".to_string();
+        let broken = first_broken(&events, &head).unwrap();
+        assert_eq!(broken.chain_seq, 2);
+        assert_eq!(broken.id, events[1].id.map(|id| id.to_hex()));
+        assert_eq!(broken.reason, "The event has been changed since it was recorded");
+
+        // Rehashing the changed event breaks the link of the event after it instead.
+        events[1].hash = Some(events[1].chain_hash());
+        let broken = first_broken(&events, &head).unwrap();
+        assert_eq!(broken.chain_seq, 3);
+        assert_eq!(broken.reason, "The event doesn't link to the event before it");
+    }
+
+    #[test]
+    fn a_removed_event_is_reported() {
+        let (mut events, head) = chain(3);
+        events.remove(1);
+        let broken = first_broken(&events, &head).unwrap();
+        assert_eq!(broken.chain_seq, 2);
+        assert_eq!(broken.id, None);
+        assert_eq!(broken.reason, "The event is missing");
+    }
+
+    #[test]
+    fn a_truncated_chain_is_reported() {
+        let (mut events, head) = chain(3);
+        events.pop();
+        let broken = first_broken(&events, &head).unwrap();
+        assert_eq!(broken.chain_seq, 3);
+        assert_eq!(broken.reason, "The chain ends before the stored head at event 3");
+    }
+}
@@ -0,0 +1,263 @@
+use bson::{doc, oid::ObjectId, Binary, Bson, Document};
+use futures_util::stream::TryStreamExt;
+use bson::spec::BinarySubtype;
+use mongodb::{error::Error, gridfs::GridFsBucket, Collection};
+use serde::{Deserialize, Serialize};
+use crate::database::file_db::DocumentEntry;
+use crate::database::gridfs_db::{delete_gridfs_file, gridfs_file_size, read_gridfs_file};
+use crate::database::{is_duplicate_key_error, TracedCollection};
+use crate::services::encryption;
+use crate::storage::{Storage, StorageBackend};
+
+// The content of one or more uploaded files, stored once in the `blobs` collection and keyed by
+// its SHA-256 hash. `ref_count` is the number of file documents referencing it, and the blob is
+// deleted once no file references it anymore.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Blob {
+    #[serde(rename = "_id")]
+    pub hash: String,
+    // None when the content is kept on the filesystem, see `storage::FsStorage`, in which case the
+    // blob only counts the references to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Binary>,
+    pub ref_count: i64,
+    // Set when the content is encrypted, see `services::encryption`. Blobs stored without
+    // encryption have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<Binary>,
+    // Set while the file of a blob kept on the filesystem is being written or removed. A pending
+    // blob can't gain references until that is done.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+}
+
+impl Blob {
+    // The plaintext content of the blob, or `None` if it isn't stored in MongoDB.
+    pub fn into_bytes(self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(content) = self.content else {
+            return Ok(None);
+        };
+        match self.nonce {
+            Some(nonce) => Ok(Some(encryption::decrypt(&content.bytes, &nonce.bytes)?)),
+            None => Ok(Some(content.bytes)),
+        }
+    }
+}
+
+// Stores `bytes` under `hash`, or adds a reference to the blob if identical content is already stored.
+// New blobs are encrypted when FILE_ENCRYPTION_KEY is set.
+pub async fn store_blob(collection: &Collection<Blob>, hash: &str, bytes: Vec<u8>) -> Result<(), Error> {
+    // Most of the time the content is new, but try the cheap update first to avoid sending the
+    // bytes to MongoDB for duplicates.
+    if add_blob_reference(collection, hash).await? {
+        return Ok(());
+    }
+
+    let (bytes, nonce) = match encryption::encrypt(&bytes) {
+        Some((ciphertext, nonce)) => (ciphertext, Some(Binary { subtype: BinarySubtype::Generic, bytes: nonce })),
+        None => (bytes, None),
+    };
+    let blob = Blob {
+        hash: hash.to_string(),
+        content: Some(Binary { subtype: BinarySubtype::Generic, bytes }),
+        ref_count: 1,
+        nonce,
+        pending: false,
+    };
+    match TracedCollection::from(collection).insert_one(blob).await {
+        Ok(_) => Ok(()),
+        // Someone uploaded the same content at the same time, so their blob is ours as well.
+        Err(e) if is_duplicate_key_error(&e) => add_blob_reference(collection, hash).await.map(|_| ()),
+        Err(e) => Err(e),
+    }
+}
+
+// Returns whether a blob with the hash existed and now has one more reference. Pending blobs
+// don't count as existing yet.
+pub async fn add_blob_reference(collection: &Collection<Blob>, hash: &str) -> Result<bool, Error> {
+    let result = TracedCollection::from(collection)
+        .update_one(doc! { "_id": hash, "pending": { "$ne": true } }, doc! { "$inc": { "ref_count": 1 } })
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+// Removes a reference to the blob, deleting it once it is no longer referenced by any file.
+//
+// # Returns
+// - `Ok(true)` if the blob was deleted.
+pub async fn release_blob(collection: &Collection<Blob>, hash: &str) -> Result<bool, Error> {
+    let collection = TracedCollection::from(collection);
+    collection
+        .update_one(doc! { "_id": hash }, doc! { "$inc": { "ref_count": -1 } })
+        .await?;
+    // Only deleted if nobody added a reference in the meantime.
+    let result = collection
+        .delete_one(doc! { "_id": hash, "ref_count": { "$lte": 0 } })
+        .await?;
+    Ok(result.deleted_count > 0)
+}
+
+// Returns the content of the blob stored under `hash`.
+//
+// # Returns
+// - `Ok(None)` if there is no such blob, or its content isn't stored in MongoDB.
+pub async fn get_blob(collection: &Collection<Blob>, hash: &str) -> Result<Option<Vec<u8>>, Error> {
+    match TracedCollection::from(collection).find_one(doc! { "_id": hash }, None).await? {
+        Some(blob) => blob.into_bytes(),
+        None => Ok(None),
+    }
+}
+
+// Releases the content of a file or file version: GridFS files are deleted and blobs lose a
+// reference in `storage`. Inline content is stored in the document itself and goes away with it.
+pub async fn release_content(
+    storage: &Storage,
+    bucket: &GridFsBucket,
+    content: Option<&Binary>,
+    gridfs_id: Option<ObjectId>,
+    content_hash: Option<&str>,
+) -> Result<(), Error> {
+    match (content, gridfs_id, content_hash) {
+        (None, Some(gridfs_id), _) => delete_gridfs_file(bucket, gridfs_id).await,
+        (None, None, Some(hash)) => storage.delete(hash).await,
+        _ => Ok(()),
+    }
+}
+
+// Returns the content of a file document, whether stored in GridFS, in `storage` or, for files
+// uploaded before deduplication, inline in the document itself.
+//
+// # Returns
+// - `Ok(None)` if the document references a blob that doesn't exist.
+pub async fn document_bytes(
+    storage: &Storage,
+    bucket: &GridFsBucket,
+    document: &DocumentEntry,
+) -> Result<Option<Vec<u8>>, Error> {
+    if let Some(content) = &document.content {
+        return Ok(Some(content.bytes.clone()));
+    }
+    if let Some(id) = document.gridfs_id {
+        return read_gridfs_file(bucket, id).await.map(Some);
+    }
+
+    match &document.content_hash {
+        Some(hash) => storage.get(hash).await,
+        None => Ok(None),
+    }
+}
+
+// Returns the size of a file's content in bytes without reading the content, for HEAD requests.
+//
+// The size recorded at upload is used when there is one, as stored content may be encrypted and
+// therefore larger. Older files, which are never encrypted, are measured.
+//
+// # Returns
+// - `Ok(None)` if the content doesn't exist.
+pub async fn document_size(
+    collection: &Collection<Blob>,
+    bucket: &GridFsBucket,
+    document: &DocumentEntry,
+) -> Result<Option<u64>, Error> {
+    if let Some(size) = document.size_bytes {
+        return Ok(Some(size));
+    }
+    if let Some(content) = &document.content {
+        return Ok(Some(content.bytes.len() as u64));
+    }
+    if let Some(id) = document.gridfs_id {
+        return gridfs_file_size(bucket, id).await;
+    }
+
+    match &document.content_hash {
+        Some(hash) => binary_size(collection, doc! { "_id": hash }, "$content").await,
+        None => Ok(None),
+    }
+}
+
+// Measures a binary field of the first document matching `filter` inside MongoDB, so the
+// content itself never has to be sent.
+pub async fn binary_size<T: Send + Sync>(
+    collection: &Collection<T>,
+    filter: Document,
+    field: &str,
+) -> Result<Option<u64>, Error> {
+    let mut cursor = TracedCollection::from(collection)
+        .aggregate(vec![
+            doc! { "$match": filter },
+            doc! { "$limit": 1 },
+            doc! { "$project": { "size": { "$binarySize": field } } },
+        ])
+        .await?;
+    let size = cursor
+        .try_next()
+        .await?
+        .and_then(|result| result.get("size").and_then(Bson::as_i64).or_else(|| result.get_i32("size").ok().map(i64::from)));
+    Ok(size.map(|size| size as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use std::sync::Arc;
+
+    fn blob(content: Option<&[u8]>, pending: bool) -> Blob {
+        Blob {
+            hash: "ab12".to_string(),
+            content: content.map(|bytes| Binary { subtype: BinarySubtype::Generic, bytes: bytes.to_vec() }),
+            ref_count: 2,
+            nonce: None,
+            pending,
+        }
+    }
+
+    // A file document as stored in MongoDB, with everything but the fields named in `fields` left out.
+    fn document(fields: Document) -> DocumentEntry {
+        let mut document = doc! { "filename": "a.txt", "user": "alice" };
+        document.extend(fields);
+        bson::from_document(document).unwrap()
+    }
+
+    #[test]
+    fn blobs_leave_out_unset_fields() {
+        let stored = bson::to_document(&blob(None, false)).unwrap();
+        assert_eq!(stored, doc! { "_id": "ab12", "ref_count": 2_i64 });
+
+        let stored = bson::to_document(&blob(Some(b"content"), true)).unwrap();
+        assert_eq!(stored.get_bool("pending"), Ok(true));
+        assert!(stored.contains_key("content"));
+    }
+
+    #[test]
+    fn unencrypted_blobs_are_returned_as_stored() {
+        assert_eq!(blob(Some(b"content"), false).into_bytes().unwrap(), Some(b"content".to_vec()));
+        assert_eq!(blob(None, false).into_bytes().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn inline_content_doesnt_touch_the_blobs() {
+        let db = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("blob_tests");
+        let blobs: Arc<Collection<Blob>> = Arc::new(db.collection("blobs"));
+        let storage = Storage::new(&StorageConfig::Mongo, blobs.clone());
+        let bucket = db.gridfs_bucket(None);
+
+        let content = Binary { subtype: BinarySubtype::Generic, bytes: b"inline".to_vec() };
+        let inline = document(doc! { "content": content.clone(), "content_hash": "ab12" });
+        assert_eq!(document_bytes(&storage, &bucket, &inline).await.unwrap(), Some(b"inline".to_vec()));
+        assert_eq!(document_size(&blobs, &bucket, &inline).await.unwrap(), Some(6));
+        assert!(release_content(&storage, &bucket, Some(&content), None, Some("ab12")).await.is_ok());
+
+        let without_content = document(doc! {});
+        assert_eq!(document_bytes(&storage, &bucket, &without_content).await.unwrap(), None);
+        assert!(release_content(&storage, &bucket, None, None, None).await.is_ok());
+
+        // Deduplicated content is read from the blobs, which can't be reached here.
+        let deduplicated = document(doc! { "content_hash": "ab12" });
+        assert!(document_bytes(&storage, &bucket, &deduplicated).await.is_err());
+        assert!(release_content(&storage, &bucket, None, None, Some("ab12")).await.is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, Collection};
+use serde::{Deserialize, Serialize};
+
+// A file whose content no longer matches its checksum, recorded by /files/:id/verify.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CorruptionReport {
+    pub file_id: ObjectId,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub detected_at: DateTime<Utc>,
+    pub stored_hash: String,
+    // `None` when the content couldn't be found at all.
+    pub computed_hash: Option<String>,
+}
+
+// A corruption report as listed by /admin/corruption-reports.
+#[derive(Debug, Serialize)]
+pub struct CorruptionReportEntry {
+    pub file_id: String,
+    pub detected_at: DateTime<Utc>,
+    pub stored_hash: String,
+    pub computed_hash: Option<String>,
+}
+
+pub async fn insert_corruption_report(
+    collection: &Collection<CorruptionReport>,
+    report: CorruptionReport,
+) -> Result<(), Error> {
+    collection.insert_one(report).await?;
+    Ok(())
+}
+
+// Returns the latest `limit` corruption reports, newest first.
+pub async fn get_corruption_reports(
+    collection: &Collection<CorruptionReport>,
+    limit: i64,
+) -> Result<Vec<CorruptionReportEntry>, Error> {
+    let mut cursor = collection
+        .find(doc! {})
+        .sort(doc! { "detected_at": -1 })
+        .limit(limit)
+        .await?;
+
+    let mut reports = Vec::new();
+    while let Some(report) = cursor.try_next().await? {
+        reports.push(CorruptionReportEntry {
+            file_id: report.file_id.to_hex(),
+            detected_at: report.detected_at,
+            stored_hash: report.stored_hash,
+            computed_hash: report.computed_hash,
+        });
+    }
+    Ok(reports)
+}
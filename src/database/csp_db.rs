@@ -0,0 +1,43 @@
+use chrono::{DateTime, Utc};
+use mongodb::{error::Error, Collection};
+use serde::{Deserialize, Serialize};
+
+// A Content-Security-Policy violation report as sent by browsers using the `report-uri` directive.
+// Browsers wrap the report in a `{ "csp-report": { ... } }` object, see `CspReportBody`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CspReport {
+    pub document_uri: Option<String>,
+    pub referrer: Option<String>,
+    pub violated_directive: Option<String>,
+    pub effective_directive: Option<String>,
+    pub original_policy: Option<String>,
+    pub disposition: Option<String>,
+    pub blocked_uri: Option<String>,
+    pub status_code: Option<u16>,
+    pub source_file: Option<String>,
+    pub line_number: Option<u32>,
+    pub column_number: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CspReportBody {
+    #[serde(rename = "csp-report")]
+    pub report: CspReport,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CspViolation {
+    #[serde(flatten)]
+    pub report: CspReport,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub received_at: DateTime<Utc>,
+}
+
+pub async fn insert_csp_violation(
+    collection: &Collection<CspViolation>,
+    violation: CspViolation,
+) -> Result<(), Error> {
+    collection.insert_one(violation).await?;
+    Ok(())
+}
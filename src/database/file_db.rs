@@ -1,10 +1,12 @@
-use bson::{Binary, Document, doc, binary};
-use futures_util::stream::Collect;
-use mongodb::{error::Error, Collection, bson::oid::ObjectId};
-use poem::http::StatusCode;
-use poem::web::Json;
+use bson::{Binary, Bson, Document, doc};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{error::Error, ClientSession, Collection, Cursor, IndexModel, bson::oid::ObjectId, options::{FindOneOptions, FindOptions, IndexOptions}};
 use serde::{Deserialize, Serialize};
-use futures_util::stream::TryStreamExt;
+use crate::config::QueryConfig;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use crate::database::TracedCollection;
 
 
 
@@ -12,28 +14,157 @@ use futures_util::stream::TryStreamExt;
 pub struct FileEntry {
     pub id: String,
     pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub is_public: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+// Restricts the admin file listing to files uploaded from a single IP address, or from any address
+// in a range.
+pub enum UploadIpFilter {
+    Exact(IpAddr),
+    Range(IpNet),
+}
+
+// A file in the admin file listing.
+#[derive(Debug, Serialize)]
+pub struct AdminFileEntry {
+    pub id: String,
+    pub filename: String,
+    pub user: String,
+    pub content_type: Option<String>,
+    pub size_bytes: Option<u64>,
+    pub upload_ip: Option<String>,
+    pub uploaded_at: DateTime<Utc>,
+}
+
+// Restricts a file listing to a single MIME type, or to every MIME type starting with a prefix (e.g. `image/`).
+pub enum ContentTypeFilter {
+    Exact(String),
+    Prefix(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ImageDocument {
     pub filename: String,
     pub data: Binary,
+    // The uploader and the image metadata are recorded at upload time. Images uploaded before
+    // that have none of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    // The MIME type detected from the content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+}
+
+// The metadata of a stored image, read without its content.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub format: Option<String>,
+    pub size_bytes: Option<u64>,
 }
 
 pub async fn insert_image(
     collection: &Collection<ImageDocument>,
     image: ImageDocument,
 ) -> mongodb::error::Result<()> {
-    collection.insert_one(image).await?;
+    TracedCollection::from(collection).insert_one(image).await?;
     Ok(())
 }
 
+// Looks up the metadata of an image uploaded by `user`, leaving out the image data.
+pub async fn get_image_info(
+    collection: &Collection<ImageDocument>,
+    filename: &str,
+    user: &str,
+) -> Result<Option<ImageInfo>, Error> {
+    let options = FindOneOptions::builder()
+        .projection(doc! { "_id": 0, "width": 1, "height": 1, "format": 1, "size_bytes": 1 })
+        .build();
+    TracedCollection::from(collection)
+        .clone_with_type::<ImageInfo>()
+        .find_one(doc! { "filename": filename, "user": user }, options)
+        .await
+}
+
+// An image in a listing. `data` is only read from the database when inlining was asked for and
+// the image is small enough.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageListEntry {
+    pub filename: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub format: Option<String>,
+    pub size_bytes: Option<u64>,
+    pub data: Option<Binary>,
+}
+
+// Lists the images uploaded by `user`, sorted by filename.
+//
+// With `inline_max_bytes` set, the data of every image of at most that many bytes is included,
+// the data of larger images is left out by the projection so it never leaves the database.
+pub async fn get_images_for_user(
+    collection: &Collection<ImageDocument>,
+    user: &str,
+    inline_max_bytes: Option<u64>,
+    queries: &QueryConfig,
+) -> Result<Vec<ImageListEntry>, Error> {
+    let options = FindOptions::builder()
+        .projection(image_list_projection(inline_max_bytes))
+        .sort(doc! { "filename": 1 })
+        .batch_size(queries.batch_size)
+        .max_time(queries.max_time)
+        .build();
+    TracedCollection::from(collection)
+        .clone_with_type::<ImageListEntry>()
+        .find(doc! { "user": user }, options)
+        .await?
+        .try_collect()
+        .await
+}
+
+// Projects the listed fields of an image, and its data if it is at most `inline_max_bytes` large.
+fn image_list_projection(inline_max_bytes: Option<u64>) -> Document {
+    let mut projection = doc! { "_id": 0, "filename": 1, "width": 1, "height": 1, "format": 1, "size_bytes": 1 };
+    if let Some(max_bytes) = inline_max_bytes {
+        projection.insert(
+            "data",
+            doc! {
+                "$cond": [
+                    // Null sorts below every number, so images without a recorded size are never inlined.
+                    { "$and": [{ "$isNumber": "$size_bytes" }, { "$lte": ["$size_bytes", max_bytes as i64] }] },
+                    "$data",
+                    "$$REMOVE",
+                ]
+            },
+        );
+    }
+    projection
+}
+
+// Looks up an image by filename. Filenames aren't unique, so when several images share one, the
+// first one uploaded is returned, and the image a filename refers to never changes.
 pub async fn get_image_by_filename(
     collection: &Collection<ImageDocument>,
     filename: &str,
 ) -> Result<Option<ImageDocument>, Error> {
     let filter = doc! { "filename": filename };
-    collection.find_one(filter).await
+    let options = FindOneOptions::builder().sort(doc! { "_id": 1 }).build();
+    TracedCollection::from(collection).find_one(filter, options).await
 }
 
 
@@ -42,17 +173,154 @@ pub struct DocumentEntry {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub filename: String,
-    pub content: Binary,
+    // Only set for files uploaded before deduplication. Newer files keep their content in the
+    // `blobs` collection or on disk, see `storage`, referenced by `content_hash`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Binary>,
+    // Hex encoded SHA-256 of the content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    // Set for files too large for a blob, whose content is stored in GridFS instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gridfs_id: Option<ObjectId>,
     pub user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // The MIME type sent along with the upload. Files uploaded before this was recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    // Users the owner has shared the file with, who may download it as well.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_with: Vec<String>,
+    // Public files can be downloaded by anyone from /public/files/:id, without a token.
+    #[serde(default)]
+    pub is_public: bool,
+    // Files uploaded before sizes were recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    // Starts at 1 and goes up every time the content is replaced through PUT /files/:id/content.
+    #[serde(default = "first_version")]
+    pub version: i64,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional"
+    )]
+    pub updated_at: Option<DateTime<Utc>>,
+    // The client IP the file was uploaded from, for forensic searches. Files uploaded before this
+    // was recorded, or without a known client IP, have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_ip: Option<String>,
+    // The folder the file is filed under, like `/archive/2024`. Files in the root folder have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    // Set with POST /files/bulk-tag.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 
+fn first_version() -> i64 {
+    1
+}
+
+// The new content of a file replacing its current content.
+pub struct ContentReplacement {
+    pub content_hash: String,
+    pub gridfs_id: Option<ObjectId>,
+    pub size_bytes: u64,
+    pub content_type: Option<String>,
+}
+
+// Creates the indexes used by the file listing queries. Safe to call on every startup.
+pub async fn create_file_indexes(collection: &Collection<DocumentEntry>) -> Result<(), Error> {
+    let index_model = IndexModel::builder()
+        .keys(doc! { "user": 1, "content_type": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("user_content_type_index".to_string())
+                .build(),
+        )
+        .build();
+
+    // Sparse, as only files uploaded since upload IPs are recorded have one.
+    let upload_ip_index = IndexModel::builder()
+        .keys(doc! { "upload_ip": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("upload_ip_index".to_string())
+                .sparse(true)
+                .build(),
+        )
+        .build();
+
+    collection.create_indexes([index_model, upload_ip_index]).await?;
+    Ok(())
+}
+
+// Lists the files of every user, newest first, leaving out their content.
+//
+// MongoDB can't match addresses against a CIDR range, so for `UploadIpFilter::Range` every file
+// with an upload IP is read and the range is checked here, until `limit` files have matched.
+pub async fn list_all_documents(
+    collection: &Collection<DocumentEntry>,
+    upload_ip: Option<&UploadIpFilter>,
+    limit: usize,
+    queries: &QueryConfig,
+) -> Result<Vec<AdminFileEntry>, Error> {
+    let filter = match upload_ip {
+        // Addresses are stored in their canonical form, which `to_string` produces as well.
+        Some(UploadIpFilter::Exact(ip)) => doc! { "upload_ip": ip.to_string() },
+        Some(UploadIpFilter::Range(_)) => doc! { "upload_ip": { "$type": "string" } },
+        None => doc! {},
+    };
+    let mut options = FindOptions::builder()
+        .projection(doc! { "content": 0 })
+        .sort(doc! { "_id": -1 })
+        .batch_size(queries.batch_size)
+        .max_time(queries.max_time)
+        .build();
+    if !matches!(upload_ip, Some(UploadIpFilter::Range(_))) {
+        options.limit = Some(limit as i64);
+    }
+
+    let mut cursor = TracedCollection::from(collection).find(filter, options).await?;
+    let mut files = Vec::new();
+    while files.len() < limit
+        && let Some(document) = cursor.try_next().await?
+    {
+        if let Some(UploadIpFilter::Range(range)) = upload_ip {
+            let in_range = document
+                .upload_ip
+                .as_deref()
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .is_some_and(|ip| range.contains(&ip));
+            if !in_range {
+                continue;
+            }
+        }
+        let Some(id) = document.id else { continue };
+        files.push(AdminFileEntry {
+            id: id.to_hex(),
+            filename: document.filename,
+            user: document.user,
+            content_type: document.content_type,
+            size_bytes: document.size_bytes,
+            upload_ip: document.upload_ip,
+            uploaded_at: id.timestamp().to_chrono(),
+        });
+    }
+    Ok(files)
+}
+
+// Inserts the document as part of the transaction of `session`, see `with_transaction`.
 pub async fn insert_document(
     collection: &Collection<DocumentEntry>,
     document: DocumentEntry,
+    session: &mut ClientSession,
 ) -> Result<ObjectId, Error> {
-    let result = collection.insert_one(document).await?;
+    let result = TracedCollection::from(collection).insert_one_in_session(document, session).await?;
     result.inserted_id.as_object_id().ok_or_else(|| {
-        Error::from(std::io::Error::new(std::io::ErrorKind::Other, "Missing ObjectId"))
+        Error::from(std::io::Error::other("Missing ObjectId"))
     })
 }
 
@@ -61,27 +329,275 @@ pub async fn get_document_by_id(
     id: &str,
 ) -> Result<Option<DocumentEntry>, Error> {
     let obj_id = ObjectId::parse_str(id)
-        .map_err(|_| Error::from(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid ObjectId")))?;;
+        .map_err(|_| Error::from(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid ObjectId")))?;
     let filter = doc! { "_id": obj_id };
-    collection.find_one(filter).await
+    TracedCollection::from(collection).find_one(filter, None).await
+}
+
+// A file as listed by the CSV export, read without its content.
+#[derive(Debug, Deserialize)]
+pub struct FileExportRow {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub filename: String,
+    pub size_bytes: Option<u64>,
+    pub content_type: Option<String>,
 }
 
-pub async fn get_documents_for_user(
+// Opens a cursor over every file of `user`, oldest first, for the CSV export.
+pub async fn get_file_export_rows(
     collection: &Collection<DocumentEntry>,
+    user: &str,
+    queries: &QueryConfig,
+) -> Result<Cursor<FileExportRow>, Error> {
+    let options = FindOptions::builder()
+        .projection(doc! { "_id": 1, "filename": 1, "size_bytes": 1, "content_type": 1 })
+        .sort(doc! { "_id": 1 })
+        .batch_size(queries.batch_size)
+        .max_time(queries.max_time)
+        .build();
+    TracedCollection::from(collection)
+        .clone_with_type::<FileExportRow>()
+        .find(doc! { "user": user }, options)
+        .await
+}
+
+#[derive(Debug, Deserialize)]
+struct DocumentOwner {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    user: String,
+}
+
+// Lists the id and owner of every file owned by one of `users`.
+pub async fn get_document_ids_for_users(
+    collection: &Collection<DocumentEntry>,
+    users: &[String],
+) -> Result<Vec<(ObjectId, String)>, Error> {
+    let options = FindOptions::builder().projection(doc! { "_id": 1, "user": 1 }).build();
+    let owners: Vec<DocumentOwner> = TracedCollection::from(collection)
+        .clone_with_type::<DocumentOwner>()
+        .find(doc! { "user": { "$in": users } }, options)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(owners.into_iter().map(|owner| (owner.id, owner.user)).collect())
+}
+
+// Adds up the size of every file owned by `username`. Files uploaded before sizes were recorded
+// count as empty.
+pub async fn storage_used_by_user(collection: &Collection<DocumentEntry>, username: &str) -> Result<u64, Error> {
+    let pipeline = vec![
+        doc! { "$match": { "user": username } },
+        doc! { "$group": { "_id": null, "used_bytes": { "$sum": "$size_bytes" } } },
+    ];
+    let mut cursor = TracedCollection::from(collection).aggregate(pipeline).await?;
+    let Some(total) = cursor.try_next().await? else {
+        return Ok(0);
+    };
+    // `$sum` only switches to int64 once the total doesn't fit an int32.
+    let used = total
+        .get_i32("used_bytes")
+        .map(i64::from)
+        .or_else(|_| total.get_i64("used_bytes"))
+        .unwrap_or_default();
+    Ok(used.max(0) as u64)
+}
+
+// Replaces the description of a file owned by the given user.
+//
+// # Returns
+// - `Ok(matched)`: the number of documents matched, `0` if the file doesn't exist or belongs to someone else.
+pub async fn update_document_description(
+    collection: &Collection<DocumentEntry>,
+    id: ObjectId,
     username: &str,
-) -> Result<Vec<FileEntry>, Error> {
-    let filter = doc! { "user": username };
-    let mut cursor = collection.find(filter).await?;
-    let mut files = Vec::new();
+    description: &str,
+) -> Result<u64, Error> {
+    let filter = doc! { "_id": id, "user": username };
+    let update = doc! { "$set": { "description": description } };
+    let result = TracedCollection::from(collection).update_one(filter, update).await?;
+    Ok(result.matched_count)
+}
 
-    while let Some(doc) = cursor.try_next().await? {
-        if let (Some(id), filename) = (doc.id, doc.filename) {
-            files.push(FileEntry {
-                id: id.to_hex(),
-                filename,
-            });
-        }
+// The updates adding `add` to the tags of a file and removing `remove` from them. They are sent
+// separately, as MongoDB refuses to `$addToSet` and `$pull` the same field in one update.
+pub fn tag_updates(add: &[String], remove: &[String]) -> Vec<Document> {
+    let mut updates = Vec::new();
+    if !add.is_empty() {
+        updates.push(doc! { "$addToSet": { "tags": { "$each": add } } });
+    }
+    if !remove.is_empty() {
+        updates.push(doc! { "$pull": { "tags": { "$in": remove } } });
     }
+    updates
+}
 
-    Ok(files)
-}
\ No newline at end of file
+// Adds and removes tags on the files among `ids` owned by the given user. Files owned by someone
+// else are left alone.
+pub async fn update_document_tags(
+    collection: &Collection<DocumentEntry>,
+    username: &str,
+    ids: &[ObjectId],
+    add: &[String],
+    remove: &[String],
+) -> Result<(), Error> {
+    let filter = doc! { "_id": { "$in": ids }, "user": username };
+    let collection = TracedCollection::from(collection);
+    for update in tag_updates(add, remove) {
+        collection.update_many(filter.clone(), update).await?;
+    }
+    Ok(())
+}
+
+// Moves a file owned by the given user into `folder`, or into the root folder for `None`.
+//
+// # Returns
+// - `Ok(matched)`: the number of documents matched, `0` if the file doesn't exist or belongs to someone else.
+pub async fn set_document_folder(
+    collection: &Collection<DocumentEntry>,
+    id: ObjectId,
+    owner: &str,
+    folder: Option<&str>,
+) -> Result<u64, Error> {
+    let filter = doc! { "_id": id, "user": owner };
+    let update = match folder {
+        Some(folder) => doc! { "$set": { "folder": folder } },
+        None => doc! { "$unset": { "folder": "" } },
+    };
+    let result = TracedCollection::from(collection).update_one(filter, update).await?;
+    Ok(result.matched_count)
+}
+
+// Grants `recipient` access to a file owned by `owner`.
+//
+// # Returns
+// - `Ok(Some(document))` with the file as it was before sharing, so callers can tell whether the
+//   recipient already had access.
+// - `Ok(None)` if the file doesn't exist or belongs to someone else.
+pub async fn share_document(
+    collection: &Collection<DocumentEntry>,
+    id: ObjectId,
+    owner: &str,
+    recipient: &str,
+) -> Result<Option<DocumentEntry>, Error> {
+    let filter = doc! { "_id": id, "user": owner };
+    let update = doc! { "$addToSet": { "shared_with": recipient } };
+    TracedCollection::from(collection).find_one_and_update(filter, update, None).await
+}
+
+// Makes a file owned by the given user public or private.
+//
+// # Returns
+// - `Ok(matched)`: the number of documents matched, `0` if the file doesn't exist or belongs to someone else.
+pub async fn set_document_visibility(
+    collection: &Collection<DocumentEntry>,
+    id: ObjectId,
+    owner: &str,
+    is_public: bool,
+) -> Result<u64, Error> {
+    let filter = doc! { "_id": id, "user": owner };
+    let update = doc! { "$set": { "is_public": is_public } };
+    let result = TracedCollection::from(collection).update_one(filter, update).await?;
+    Ok(result.matched_count)
+}
+
+// Replaces the content of a file owned by `owner`, as long as it is still at `expected_version`.
+//
+// # Returns
+// - `Ok(Some(document))` with the file as it was before, so its old content can be kept or released.
+// - `Ok(None)` if the file doesn't exist, belongs to someone else, or was replaced concurrently.
+pub async fn replace_document_content(
+    collection: &Collection<DocumentEntry>,
+    id: ObjectId,
+    owner: &str,
+    expected_version: i64,
+    replacement: &ContentReplacement,
+) -> Result<Option<DocumentEntry>, Error> {
+    // Files that were never replaced may have no version stored at all.
+    let version_filter = if expected_version == first_version() {
+        doc! { "$in": [expected_version, Bson::Null] }
+    } else {
+        doc! { "$eq": expected_version }
+    };
+    let filter = doc! { "_id": id, "user": owner, "version": version_filter };
+
+    let mut set = doc! {
+        "content_hash": &replacement.content_hash,
+        "size_bytes": replacement.size_bytes as i64,
+        "version": expected_version + 1,
+        "updated_at": bson::DateTime::now(),
+    };
+    let mut unset = doc! { "content": "" };
+    match replacement.gridfs_id {
+        Some(gridfs_id) => { set.insert("gridfs_id", gridfs_id); }
+        None => { unset.insert("gridfs_id", ""); }
+    }
+    if let Some(content_type) = &replacement.content_type {
+        set.insert("content_type", content_type);
+    }
+
+    TracedCollection::from(collection)
+        .find_one_and_update(filter, doc! { "$set": set, "$unset": unset }, None)
+        .await
+}
+
+// Deletes a file owned by the given user.
+//
+// # Returns
+// - `Ok(Some(document))` with the deleted file, so the caller can release its blob.
+// - `Ok(None)` if the file doesn't exist or belongs to someone else.
+pub async fn delete_document(
+    collection: &Collection<DocumentEntry>,
+    id: ObjectId,
+    owner: &str,
+) -> Result<Option<DocumentEntry>, Error> {
+    TracedCollection::from(collection).find_one_and_delete(doc! { "_id": id, "user": owner }).await
+}
+
+// Records the content type of a file uploaded before content types were stored. A content type
+// that is already set is left alone.
+pub async fn set_document_content_type(
+    collection: &Collection<DocumentEntry>,
+    id: ObjectId,
+    content_type: &str,
+) -> Result<(), Error> {
+    TracedCollection::from(collection)
+        .update_one(
+            doc! { "_id": id, "content_type": { "$exists": false } },
+            doc! { "$set": { "content_type": content_type } },
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_data_is_only_projected_when_inlining() {
+        assert!(!image_list_projection(None).contains_key("data"));
+
+        let projection = image_list_projection(Some(100));
+        let condition = &projection.get_document("data").unwrap().get_array("$cond").unwrap()[0];
+        assert_eq!(
+            condition,
+            &Bson::Document(doc! { "$and": [{ "$isNumber": "$size_bytes" }, { "$lte": ["$size_bytes", 100_i64] }] })
+        );
+    }
+
+    #[test]
+    fn tags_are_added_and_removed_in_separate_updates() {
+        let (add, remove) = (vec!["work".to_string()], vec!["inbox".to_string()]);
+        assert_eq!(
+            tag_updates(&add, &remove),
+            [
+                doc! { "$addToSet": { "tags": { "$each": ["work"] } } },
+                doc! { "$pull": { "tags": { "$in": ["inbox"] } } },
+            ]
+        );
+        assert_eq!(tag_updates(&add, &[]).len(), 1);
+        assert!(tag_updates(&[], &[]).is_empty());
+    }
+}
@@ -0,0 +1,562 @@
+use bson::{doc, oid::ObjectId, Document};
+use chrono::{DateTime, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, options::IndexOptions, ClientSession, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use crate::config::QueryConfig;
+use crate::database::escape_regex;
+use crate::database::file_db::{tag_updates, ContentTypeFilter, DocumentEntry, FileEntry};
+
+// Everything about a file except its content, so listings never have to read file content.
+//
+// Mirrors `DocumentEntry` under the same `_id`. The `files` collection stays the source of truth:
+// a metadata document that failed to be written is recreated by `sync_file_metadata`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileMetadata {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub filename: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    pub user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_with: Vec<String>,
+    #[serde(default)]
+    pub is_public: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // When the listed fields were last written. Metadata written before this was recorded has none,
+    // and counts as unchanged since the upload.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional"
+    )]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl FileMetadata {
+    // Returns `None` for a document that hasn't been inserted yet, as it has no id to mirror.
+    pub fn from_document(document: &DocumentEntry) -> Option<Self> {
+        Some(Self {
+            id: document.id?,
+            filename: document.filename.clone(),
+            content_hash: document.content_hash.clone(),
+            user: document.user.clone(),
+            description: document.description.clone(),
+            content_type: document.content_type.clone(),
+            shared_with: document.shared_with.clone(),
+            is_public: document.is_public,
+            folder: document.folder.clone(),
+            tags: document.tags.clone(),
+            updated_at: Some(Utc::now()),
+        })
+    }
+
+    // When the file as listed last changed: its upload, or the last write to its metadata.
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        let uploaded = self.id.timestamp().to_chrono();
+        self.updated_at.map_or(uploaded, |updated| updated.max(uploaded))
+    }
+}
+
+// The result of a metadata consistency check.
+#[derive(Debug, Serialize)]
+pub struct MetadataSyncReport {
+    pub checked: u64,
+    // Ids of the files whose metadata was missing and has been recreated.
+    pub repaired: Vec<String>,
+}
+
+// Files of one user that have identical content.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    #[serde(rename(deserialize = "_id"))]
+    pub content_hash: String,
+    pub files: Vec<DuplicateFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuplicateFile {
+    pub id: String,
+    pub filename: String,
+}
+
+// Creates the indexes used by the file listing and lookup queries. Safe to call on every startup.
+pub async fn create_file_metadata_indexes(collection: &Collection<FileMetadata>) -> Result<(), Error> {
+    let content_type_index = IndexModel::builder()
+        .keys(doc! { "user": 1, "content_type": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("user_content_type_index".to_string())
+                .build(),
+        )
+        .build();
+    let filename_index = IndexModel::builder()
+        .keys(doc! { "user": 1, "filename": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("user_filename_index".to_string())
+                .build(),
+        )
+        .build();
+
+    let content_hash_index = IndexModel::builder()
+        .keys(doc! { "user": 1, "content_hash": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("user_content_hash_index".to_string())
+                .build(),
+        )
+        .build();
+
+    // Used by the paginated /files listing, which pages through a user's files in `_id` order.
+    let id_index = IndexModel::builder()
+        .keys(doc! { "user": 1, "_id": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("user_id_index".to_string())
+                .build(),
+        )
+        .build();
+
+    // Used by /files?tag=..., a multikey index as files have several tags.
+    let tags_index = IndexModel::builder()
+        .keys(doc! { "user": 1, "tags": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("user_tags_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_indexes([content_type_index, filename_index, content_hash_index, id_index, tags_index]).await?;
+    Ok(())
+}
+
+// Inserts or replaces the metadata of a file.
+pub async fn upsert_file_metadata(
+    collection: &Collection<FileMetadata>,
+    metadata: &FileMetadata,
+) -> Result<(), Error> {
+    collection
+        .replace_one(doc! { "_id": metadata.id }, metadata)
+        .upsert(true)
+        .await?;
+    Ok(())
+}
+
+// Writes the metadata as part of the transaction of `session`, see `with_transaction`.
+pub async fn upsert_file_metadata_in_session(
+    collection: &Collection<FileMetadata>,
+    metadata: &FileMetadata,
+    session: &mut ClientSession,
+) -> Result<(), Error> {
+    collection
+        .replace_one(doc! { "_id": metadata.id }, metadata)
+        .upsert(true)
+        .session(session)
+        .await?;
+    Ok(())
+}
+
+// Where a page of a file listing starts. Pages are ordered by file id, so newer files come last.
+pub enum FileCursor {
+    Start,
+    // The files after this id.
+    After(ObjectId),
+    // The files before this id.
+    Before(ObjectId),
+}
+
+// One page of a file listing, with the cursors of the pages around it if there are any.
+pub struct FilePage {
+    pub files: Vec<FileEntry>,
+    // The most recent change to any file on the page, `None` for an empty page.
+    pub last_modified: Option<DateTime<Utc>>,
+    pub next: Option<ObjectId>,
+    pub prev: Option<ObjectId>,
+}
+
+// The filter of a page of `username`'s files. Every other condition is added next to the
+// username, so nothing the client sends can widen the listing to another user's files.
+fn user_files_filter(
+    username: &str,
+    content_type: Option<&ContentTypeFilter>,
+    tag: Option<&str>,
+    cursor: &FileCursor,
+) -> Document {
+    let mut filter = doc! { "user": username };
+    if let Some(tag) = tag {
+        filter.insert("tags", tag);
+    }
+    match content_type {
+        Some(ContentTypeFilter::Exact(value)) => {
+            filter.insert("content_type", value);
+        }
+        Some(ContentTypeFilter::Prefix(prefix)) => {
+            filter.insert("content_type", doc! { "$regex": format!("^{}", escape_regex(prefix)) });
+        }
+        None => {}
+    }
+    match cursor {
+        FileCursor::Start => {}
+        FileCursor::After(id) => {
+            filter.insert("_id", doc! { "$gt": id });
+        }
+        FileCursor::Before(id) => {
+            filter.insert("_id", doc! { "$lt": id });
+        }
+    }
+    filter
+}
+
+// Lists the files of a user, one page at a time.
+//
+// # Arguments
+// - `tag`: Only lists the files with this tag.
+// - `cursor`: Where the page starts.
+// - `page_size`: How many files the page holds, or `None` to return every file from the cursor on.
+// - `queries`: The batch size and time limit of the query. A query running out of time fails with
+//   an error recognized by `is_max_time_error`.
+//
+// # Returns
+// - `Ok(FilePage)` where `next` is `FileCursor::After` the last file if more files follow, and
+//   `prev` is `FileCursor::Before` the first file if files come before it.
+pub async fn get_metadata_for_user(
+    collection: &Collection<FileMetadata>,
+    username: &str,
+    content_type: Option<&ContentTypeFilter>,
+    tag: Option<&str>,
+    cursor: FileCursor,
+    page_size: Option<i64>,
+    queries: &QueryConfig,
+) -> Result<FilePage, Error> {
+    // Paging backwards reads the files in reverse, the page is flipped back afterwards.
+    let backwards = matches!(cursor, FileCursor::Before(_));
+    let filter = user_files_filter(username, content_type, tag, &cursor);
+
+    // One file more than the page size is read to tell whether another page follows.
+    let mut find = collection
+        .find(filter)
+        .sort(doc! { "_id": if backwards { -1 } else { 1 } })
+        .batch_size(queries.batch_size)
+        .max_time(queries.max_time);
+    if let Some(page_size) = page_size {
+        find = find.limit(page_size + 1);
+    }
+    let mut metadata: Vec<FileMetadata> = find.await?.try_collect().await?;
+
+    let has_more = page_size.is_some_and(|page_size| metadata.len() > page_size as usize);
+    if let Some(page_size) = page_size {
+        metadata.truncate(page_size as usize);
+    }
+    if backwards {
+        metadata.reverse();
+    }
+
+    let first = metadata.first().map(|file| file.id);
+    let last = metadata.last().map(|file| file.id);
+    let (next, prev) = match (&cursor, backwards) {
+        // Going backwards, the files after this page are the ones the cursor came from.
+        (_, true) => (last, if has_more { first } else { None }),
+        (FileCursor::After(_), false) => (if has_more { last } else { None }, first),
+        _ => (if has_more { last } else { None }, None),
+    };
+
+    let last_modified = metadata.iter().map(FileMetadata::last_modified).max();
+    let files = metadata
+        .into_iter()
+        .map(|metadata| FileEntry {
+            id: metadata.id.to_hex(),
+            filename: metadata.filename,
+            description: metadata.description,
+            content_type: metadata.content_type,
+            is_public: metadata.is_public,
+            folder: metadata.folder,
+            tags: metadata.tags,
+        })
+        .collect();
+
+    Ok(FilePage { files, last_modified, next, prev })
+}
+
+// Finds the files a user uploaded under `filename`. At most `limit` files are returned, which is
+// enough for callers that only need to know whether the name is ambiguous.
+pub async fn find_metadata_by_filename(
+    collection: &Collection<FileMetadata>,
+    username: &str,
+    filename: &str,
+    limit: i64,
+) -> Result<Vec<FileMetadata>, Error> {
+    collection
+        .find(doc! { "user": username, "filename": filename })
+        .limit(limit)
+        .await?
+        .try_collect()
+        .await
+}
+
+// Returns the files among `ids` that are owned by `username`.
+pub async fn get_metadata_by_ids(
+    collection: &Collection<FileMetadata>,
+    username: &str,
+    ids: &[ObjectId],
+) -> Result<Vec<FileMetadata>, Error> {
+    collection
+        .find(doc! { "_id": { "$in": ids }, "user": username })
+        .await?
+        .try_collect()
+        .await
+}
+
+// Groups the files of a user by content, returning the groups with more than one file.
+pub async fn find_duplicate_files(
+    collection: &Collection<FileMetadata>,
+    username: &str,
+) -> Result<Vec<DuplicateGroup>, Error> {
+    let pipeline = vec![
+        doc! { "$match": { "user": username, "content_hash": { "$exists": true } } },
+        doc! { "$group": {
+            "_id": "$content_hash",
+            "count": { "$sum": 1 },
+            "files": { "$push": { "id": { "$toString": "$_id" }, "filename": "$filename" } },
+        } },
+        doc! { "$match": { "count": { "$gt": 1 } } },
+        doc! { "$sort": { "count": -1, "_id": 1 } },
+    ];
+
+    collection
+        .aggregate(pipeline)
+        .with_type::<DuplicateGroup>()
+        .await?
+        .try_collect()
+        .await
+}
+
+// How many files of a user fall in each coarse category.
+#[derive(Debug, Default, Serialize)]
+pub struct FileCategoryCounts {
+    pub image: u64,
+    pub document: u64,
+    pub other: u64,
+}
+
+// Content types, besides `text/*`, counted as documents.
+const DOCUMENT_CONTENT_TYPE_PATTERN: &str = r"^(application/(pdf|rtf|msword|vnd\.ms-(excel|powerpoint)|vnd\.openxmlformats-officedocument\.|vnd\.oasis\.opendocument\.)|text/)";
+
+// Counts the files of a user by category: `image` for `image/*`, `document` for text, PDF and
+// office formats, and `other` for everything else, including files without a content type.
+//
+// The category is derived from the content type in the pipeline, so files uploaded before
+// categories existed are counted too.
+pub async fn count_files_by_category(
+    collection: &Collection<FileMetadata>,
+    username: &str,
+) -> Result<FileCategoryCounts, Error> {
+    let content_type = doc! { "$toLower": { "$ifNull": ["$content_type", ""] } };
+    let pipeline = vec![
+        doc! { "$match": { "user": username } },
+        doc! { "$group": {
+            "_id": { "$switch": {
+                "branches": [
+                    { "case": { "$regexMatch": { "input": &content_type, "regex": "^image/" } }, "then": "image" },
+                    { "case": { "$regexMatch": { "input": &content_type, "regex": DOCUMENT_CONTENT_TYPE_PATTERN } }, "then": "document" },
+                ],
+                "default": "other",
+            } },
+            "count": { "$sum": 1 },
+        } },
+    ];
+
+    let mut counts = FileCategoryCounts::default();
+    let mut cursor = collection.aggregate(pipeline).await?;
+    while let Some(group) = cursor.try_next().await? {
+        counts.add_group(&group);
+    }
+    Ok(counts)
+}
+
+impl FileCategoryCounts {
+    // Takes the count of a `{ "_id": <category>, "count": n }` group.
+    fn add_group(&mut self, group: &Document) {
+        // `$sum` only switches to int64 once the count doesn't fit an int32.
+        let count = group
+            .get_i32("count")
+            .map(i64::from)
+            .or_else(|_| group.get_i64("count"))
+            .unwrap_or_default() as u64;
+        match group.get_str("_id").unwrap_or_default() {
+            "image" => self.image = count,
+            "document" => self.document = count,
+            _ => self.other = count,
+        }
+    }
+}
+
+pub async fn count_files_for_user(collection: &Collection<FileMetadata>, username: &str) -> Result<u64, Error> {
+    collection.count_documents(doc! { "user": username }).await
+}
+
+pub async fn update_metadata_description(
+    collection: &Collection<FileMetadata>,
+    id: ObjectId,
+    description: &str,
+) -> Result<(), Error> {
+    collection
+        .update_one(doc! { "_id": id }, doc! { "$set": { "description": description, "updated_at": bson::DateTime::now() } })
+        .await?;
+    Ok(())
+}
+
+// Adds and removes tags on the listed files, which have to be owned by `username`.
+pub async fn update_metadata_tags(
+    collection: &Collection<FileMetadata>,
+    username: &str,
+    ids: &[ObjectId],
+    add: &[String],
+    remove: &[String],
+) -> Result<(), Error> {
+    let filter = doc! { "_id": { "$in": ids }, "user": username };
+    for mut update in tag_updates(add, remove) {
+        update.insert("$set", doc! { "updated_at": bson::DateTime::now() });
+        collection.update_many(filter.clone(), update).await?;
+    }
+    Ok(())
+}
+
+pub async fn set_metadata_visibility(
+    collection: &Collection<FileMetadata>,
+    id: ObjectId,
+    is_public: bool,
+) -> Result<(), Error> {
+    collection
+        .update_one(doc! { "_id": id }, doc! { "$set": { "is_public": is_public, "updated_at": bson::DateTime::now() } })
+        .await?;
+    Ok(())
+}
+
+pub async fn set_metadata_folder(
+    collection: &Collection<FileMetadata>,
+    id: ObjectId,
+    folder: Option<&str>,
+) -> Result<(), Error> {
+    let update = match folder {
+        Some(folder) => doc! { "$set": { "folder": folder, "updated_at": bson::DateTime::now() } },
+        None => doc! { "$unset": { "folder": "" }, "$set": { "updated_at": bson::DateTime::now() } },
+    };
+    collection.update_one(doc! { "_id": id }, update).await?;
+    Ok(())
+}
+
+pub async fn add_metadata_share(
+    collection: &Collection<FileMetadata>,
+    id: ObjectId,
+    recipient: &str,
+) -> Result<(), Error> {
+    collection
+        .update_one(doc! { "_id": id }, doc! { "$addToSet": { "shared_with": recipient } })
+        .await?;
+    Ok(())
+}
+
+pub async fn set_metadata_content_type(
+    collection: &Collection<FileMetadata>,
+    id: ObjectId,
+    content_type: &str,
+) -> Result<(), Error> {
+    collection
+        .update_one(
+            doc! { "_id": id, "content_type": { "$exists": false } },
+            doc! { "$set": { "content_type": content_type, "updated_at": bson::DateTime::now() } },
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_file_metadata(collection: &Collection<FileMetadata>, id: ObjectId) -> Result<(), Error> {
+    collection.delete_one(doc! { "_id": id }).await?;
+    Ok(())
+}
+
+// Recreates the metadata of every file that has none.
+//
+// Only the ids of the metadata are loaded up front, and the files are read without their content.
+pub async fn sync_file_metadata(
+    documents: &Collection<DocumentEntry>,
+    metadata: &Collection<FileMetadata>,
+) -> Result<MetadataSyncReport, Error> {
+    let known: HashSet<ObjectId> = metadata
+        .distinct("_id", doc! {})
+        .await?
+        .into_iter()
+        .filter_map(|id| id.as_object_id())
+        .collect();
+
+    let mut cursor = documents.find(doc! {}).projection(doc! { "content": 0 }).await?;
+    let mut report = MetadataSyncReport { checked: 0, repaired: Vec::new() };
+
+    while let Some(document) = cursor.try_next().await? {
+        report.checked += 1;
+        let Some(entry) = FileMetadata::from_document(&document) else {
+            continue;
+        };
+        if !known.contains(&entry.id) {
+            upsert_file_metadata(metadata, &entry).await?;
+            report.repaired.push(entry.id.to_hex());
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listings_are_limited_to_the_user() {
+        let id = ObjectId::new();
+        let content_types = [
+            None,
+            Some(ContentTypeFilter::Exact("text/plain".to_string())),
+            Some(ContentTypeFilter::Prefix("image/".to_string())),
+        ];
+        for content_type in &content_types {
+            for tag in [None, Some("work")] {
+                for cursor in [FileCursor::Start, FileCursor::After(id), FileCursor::Before(id)] {
+                    let filter = user_files_filter("alice", content_type.as_ref(), tag, &cursor);
+                    assert_eq!(filter.get_str("user"), Ok("alice"));
+                    assert_eq!(filter.keys().filter(|key| *key == "user").count(), 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn listing_filters_are_combined() {
+        let id = ObjectId::new();
+        let content_type = ContentTypeFilter::Prefix("image/".to_string());
+        let filter = user_files_filter("alice", Some(&content_type), Some("work"), &FileCursor::After(id));
+        assert_eq!(
+            filter,
+            doc! { "user": "alice", "tags": "work", "content_type": { "$regex": "^image/" }, "_id": { "$gt": id } }
+        );
+    }
+
+    #[test]
+    fn category_groups_are_counted() {
+        let mut counts = FileCategoryCounts::default();
+        counts.add_group(&doc! { "_id": "image", "count": 2 });
+        counts.add_group(&doc! { "_id": "document", "count": 5_000_000_000_i64 });
+        counts.add_group(&doc! { "_id": "other", "count": 1 });
+        assert_eq!((counts.image, counts.document, counts.other), (2, 5_000_000_000, 1));
+    }
+}
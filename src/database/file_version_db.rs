@@ -0,0 +1,74 @@
+use bson::{doc, oid::ObjectId, Binary};
+use chrono::{DateTime, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use crate::database::file_db::DocumentEntry;
+
+// The content a file had before it was replaced through PUT /files/:id/content, kept in the
+// `file_versions` collection when FILE_VERSIONING is enabled. The content is referenced the same
+// way as from `DocumentEntry`, so a blob stays alive while any version still uses it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileVersion {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub file_id: ObjectId,
+    pub version: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Binary>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gridfs_id: Option<ObjectId>,
+    pub size_bytes: Option<u64>,
+    pub content_type: Option<String>,
+    // When this content was replaced by the next version.
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub replaced_at: DateTime<Utc>,
+}
+
+impl FileVersion {
+    // Captures the current content of a file, just before it is replaced.
+    pub fn from_document(file_id: ObjectId, document: DocumentEntry) -> Self {
+        Self {
+            id: None,
+            file_id,
+            version: document.version,
+            content: document.content,
+            content_hash: document.content_hash,
+            gridfs_id: document.gridfs_id,
+            size_bytes: document.size_bytes,
+            content_type: document.content_type,
+            replaced_at: Utc::now(),
+        }
+    }
+}
+
+// Creates the index used to find the versions of a file.
+pub async fn create_file_version_indexes(collection: &Collection<FileVersion>) -> Result<(), Error> {
+    let index_model = IndexModel::builder()
+        .keys(doc! { "file_id": 1, "version": -1 })
+        .options(
+            IndexOptions::builder()
+                .name("file_id_version_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(index_model).await?;
+    Ok(())
+}
+
+pub async fn insert_file_version(collection: &Collection<FileVersion>, version: &FileVersion) -> Result<(), Error> {
+    collection.insert_one(version).await?;
+    Ok(())
+}
+
+// Deletes every version of a file, returning them so their content can be released.
+pub async fn delete_file_versions(collection: &Collection<FileVersion>, file_id: ObjectId) -> Result<Vec<FileVersion>, Error> {
+    let versions: Vec<FileVersion> = collection.find(doc! { "file_id": file_id }).await?.try_collect().await?;
+    if !versions.is_empty() {
+        collection.delete_many(doc! { "file_id": file_id }).await?;
+    }
+    Ok(versions)
+}
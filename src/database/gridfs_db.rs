@@ -0,0 +1,36 @@
+use bson::{doc, oid::ObjectId};
+use futures_util::io::AsyncReadExt;
+use mongodb::error::Error;
+use mongodb::gridfs::GridFsBucket;
+use crate::services::encryption;
+
+// The GridFS bucket holding the content of files too large to be kept in a blob document.
+pub const FILE_CONTENT_BUCKET: &str = "file_content";
+
+// Reads the whole content of a file stored in GridFS, decrypting it if it was stored encrypted.
+pub async fn read_gridfs_file(bucket: &GridFsBucket, id: ObjectId) -> Result<Vec<u8>, Error> {
+    let encrypted = bucket
+        .find_one(doc! { "_id": id })
+        .await?
+        .and_then(|file| file.metadata)
+        .is_some_and(|metadata| metadata.get_bool("encrypted").unwrap_or(false));
+
+    let mut stream = bucket.open_download_stream(id.into()).await?;
+    let mut bytes = Vec::new();
+    stream.read_to_end(&mut bytes).await?;
+    if encrypted {
+        bytes = encryption::decrypt_segments(&bytes)?;
+    }
+    Ok(bytes)
+}
+
+// Returns the length of a file stored in GridFS from its files collection entry, without reading any chunks.
+// For encrypted files this is the length of the encrypted content.
+pub async fn gridfs_file_size(bucket: &GridFsBucket, id: ObjectId) -> Result<Option<u64>, Error> {
+    let file = bucket.find_one(doc! { "_id": id }).await?;
+    Ok(file.map(|file| file.length))
+}
+
+pub async fn delete_gridfs_file(bucket: &GridFsBucket, id: ObjectId) -> Result<(), Error> {
+    bucket.delete(id.into()).await
+}
@@ -0,0 +1,111 @@
+use bson::doc;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use crate::database::is_duplicate_key_error;
+
+// How long a stored response is replayed for a repeated Idempotency-Key.
+pub const IDEMPOTENCY_KEY_HOURS: i64 = 24;
+
+// A request made with an `Idempotency-Key` header, stored in the `idempotency_keys` collection.
+// `status_code` and `response_body` are `None` while the first request is still being processed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdempotencyKey {
+    pub key_hash: String,
+    pub status_code: Option<u16>,
+    pub response_body: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+pub enum IdempotencyState {
+    // First time the key is seen - the caller should process the request and call `complete_idempotency_key`.
+    New,
+    // The key has already been processed, and this is the response to replay.
+    Completed { status_code: u16, response_body: String },
+    // Another request with the same key is being processed right now.
+    InProgress,
+}
+
+// Creates the unique index that makes claiming a key atomic, and a TTL index so MongoDB removes
+// keys once they're no longer replayed. Safe to call on every startup.
+pub async fn create_idempotency_indexes(collection: &Collection<IdempotencyKey>) -> Result<(), Error> {
+    let unique = IndexModel::builder()
+        .keys(doc! { "key_hash": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .name("key_hash_unique_index".to_string())
+                .build(),
+        )
+        .build();
+    let ttl = IndexModel::builder()
+        .keys(doc! { "created_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .expire_after(std::time::Duration::from_secs(IDEMPOTENCY_KEY_HOURS as u64 * 60 * 60))
+                .name("created_at_ttl_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_indexes([unique, ttl]).await?;
+    Ok(())
+}
+
+// Claims the key for the current request, or reports how an earlier request with the same key went.
+//
+// Claiming relies on the unique index on `key_hash`, so two requests racing with the same key
+// can't both be processed.
+pub async fn begin_idempotency_key(
+    collection: &Collection<IdempotencyKey>,
+    key_hash: &str,
+) -> Result<IdempotencyState, Error> {
+    // MongoDB only removes expired keys about once a minute, so clear out a stale one ourselves.
+    let expired = bson::DateTime::from_chrono(Utc::now() - Duration::hours(IDEMPOTENCY_KEY_HOURS));
+    collection
+        .delete_one(doc! { "key_hash": key_hash, "created_at": { "$lte": expired } })
+        .await?;
+
+    let claim = IdempotencyKey {
+        key_hash: key_hash.to_string(),
+        status_code: None,
+        response_body: None,
+        created_at: Utc::now(),
+    };
+    match collection.insert_one(claim).await {
+        Ok(_) => return Ok(IdempotencyState::New),
+        Err(e) if is_duplicate_key_error(&e) => {}
+        Err(e) => return Err(e),
+    }
+
+    match collection.find_one(doc! { "key_hash": key_hash }).await? {
+        Some(IdempotencyKey { status_code: Some(status_code), response_body, .. }) => Ok(IdempotencyState::Completed {
+            status_code,
+            response_body: response_body.unwrap_or_default(),
+        }),
+        _ => Ok(IdempotencyState::InProgress),
+    }
+}
+
+// Stores the response of a claimed key, so retries replay it.
+pub async fn complete_idempotency_key(
+    collection: &Collection<IdempotencyKey>,
+    key_hash: &str,
+    status_code: u16,
+    response_body: &str,
+) -> Result<(), Error> {
+    collection
+        .update_one(
+            doc! { "key_hash": key_hash },
+            doc! { "$set": { "status_code": status_code as i32, "response_body": response_body } },
+        )
+        .await?;
+    Ok(())
+}
+
+// Releases a claimed key without storing a response, so the request can be retried for real.
+pub async fn release_idempotency_key(collection: &Collection<IdempotencyKey>, key_hash: &str) -> Result<(), Error> {
+    collection.delete_one(doc! { "key_hash": key_hash }).await?;
+    Ok(())
+}
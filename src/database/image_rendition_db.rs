@@ -0,0 +1,72 @@
+use bson::{doc, Binary};
+use chrono::{DateTime, Utc};
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use crate::database::is_duplicate_key_error;
+
+// Renditions that haven't been created again for this long are removed, which also clears out
+// renditions of preset sizes that are no longer configured.
+const RENDITION_TTL_DAYS: u64 = 30;
+
+// A resized copy of an image, stored in the `image_renditions` collection by
+// /images/:filename/:preset so it only has to be resized once.
+//
+// Keyed by the filename and the preset dimensions, so changing the size of a preset doesn't
+// serve renditions of the old size. Images can't be deleted or replaced, and a filename always
+// refers to the first image uploaded with it, see `get_image_by_filename`, so a rendition can't go
+// stale. Keep it that way, or remove the renditions of an image whenever it changes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageRendition {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub data: Binary,
+    pub content_type: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl ImageRendition {
+    pub fn key(filename: &str, width: u32, height: u32) -> String {
+        format!("{}:{}x{}", filename, width, height)
+    }
+}
+
+// Creates the TTL index removing old renditions. Safe to call on every startup.
+pub async fn create_image_rendition_indexes(collection: &Collection<ImageRendition>) -> Result<(), Error> {
+    let ttl = IndexModel::builder()
+        .keys(doc! { "created_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .expire_after(std::time::Duration::from_secs(RENDITION_TTL_DAYS * 24 * 60 * 60))
+                .name("created_at_ttl_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(ttl).await?;
+    Ok(())
+}
+
+pub async fn find_image_rendition(collection: &Collection<ImageRendition>, key: &str) -> Result<Option<ImageRendition>, Error> {
+    collection.find_one(doc! { "_id": key }).await
+}
+
+// Stores a rendition. When another request stored the same rendition first, that one is kept.
+pub async fn store_image_rendition(collection: &Collection<ImageRendition>, rendition: &ImageRendition) -> Result<(), Error> {
+    match collection.insert_one(rendition).await {
+        Err(e) if !is_duplicate_key_error(&e) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renditions_are_keyed_by_filename_and_size() {
+        assert_eq!(ImageRendition::key("cat.png", 150, 150), "cat.png:150x150");
+        assert_ne!(ImageRendition::key("cat.png", 150, 150), ImageRendition::key("cat.png", 150, 151));
+        assert_ne!(ImageRendition::key("cat.png", 150, 150), ImageRendition::key("dog.png", 150, 150));
+    }
+}
@@ -0,0 +1,317 @@
+use bson::{doc, oid::ObjectId, Bson, Document};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::gridfs::GridFsBucket;
+use mongodb::{error::Error, Collection, Database};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::database::blob_db::document_bytes;
+use crate::database::file_db::DocumentEntry;
+use crate::database::file_metadata_db::FileMetadata;
+use crate::storage::Storage;
+
+// How many files the checksum migration lists at a time. The files themselves are read one by one.
+const CHECKSUM_BATCH_SIZE: i64 = 100;
+
+// A long running maintenance operation, stored in the `maintenance_jobs` collection so its
+// progress can be followed while it runs in the background.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MaintenanceJob {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // "vacuum", "vacuum_dry_run" when only estimating, or "checksum_migration".
+    pub job_type: String,
+    // "running", "completed" or "failed".
+    pub status: String,
+    pub requested_by: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub started_at: DateTime<Utc>,
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub finished_at: Option<DateTime<Utc>>,
+    // One entry per collection processed so far.
+    #[serde(default)]
+    pub collections: Vec<CollectionVacuumResult>,
+    // Only set for checksum migrations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksums: Option<ChecksumProgress>,
+    pub error: Option<String>,
+}
+
+// Files processed by a checksum migration so far.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChecksumProgress {
+    pub updated: i64,
+    // Files whose content couldn't be read, or that were removed while the migration ran.
+    pub failed: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionVacuumResult {
+    pub collection: String,
+    // Bytes released by `compact`, or for a dry run the free space WiredTiger reports for the
+    // collection, which is roughly what `compact` would release.
+    pub bytes_freed: i64,
+    // Only set for dry runs, whether `validate` found the collection consistent.
+    pub valid: Option<bool>,
+    pub error: Option<String>,
+}
+
+impl MaintenanceJob {
+    pub fn new(job_type: &str, requested_by: &str) -> Self {
+        Self {
+            id: None,
+            job_type: job_type.to_string(),
+            status: "running".to_string(),
+            requested_by: requested_by.to_string(),
+            started_at: Utc::now(),
+            finished_at: None,
+            collections: Vec::new(),
+            checksums: None,
+            error: None,
+        }
+    }
+}
+
+// Records a new job and returns its id.
+pub async fn insert_maintenance_job(
+    collection: &Collection<MaintenanceJob>,
+    job: &MaintenanceJob,
+) -> Result<ObjectId, Error> {
+    let result = collection.insert_one(job).await?;
+    Ok(result.inserted_id.as_object_id().unwrap_or_default())
+}
+
+pub async fn get_maintenance_job(
+    collection: &Collection<MaintenanceJob>,
+    id: ObjectId,
+) -> Result<Option<MaintenanceJob>, Error> {
+    collection.find_one(doc! { "_id": id }).await
+}
+
+// Compacts every collection in the database one at a time, recording the result of each on the
+// job as it goes. With `dry_run` set the collections are only validated and the reclaimable
+// space is estimated, nothing is compacted.
+//
+// A collection that fails to compact is recorded with its error and the remaining collections
+// are still processed. The job only fails if the collections can't be listed or the job itself
+// can't be updated.
+pub async fn run_vacuum(
+    db: &Database,
+    jobs: &Collection<MaintenanceJob>,
+    job_id: ObjectId,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let result = vacuum_collections(db, jobs, job_id, dry_run).await;
+    finish_job(jobs, job_id, result).await
+}
+
+// Records the outcome of a job, and passes it on.
+async fn finish_job(
+    jobs: &Collection<MaintenanceJob>,
+    job_id: ObjectId,
+    result: Result<(), Error>,
+) -> Result<(), Error> {
+    let update = match &result {
+        Ok(()) => doc! { "$set": { "status": "completed", "finished_at": bson::DateTime::now() } },
+        Err(e) => doc! { "$set": { "status": "failed", "finished_at": bson::DateTime::now(), "error": e.to_string() } },
+    };
+    jobs.update_one(doc! { "_id": job_id }, update).await?;
+    result
+}
+
+async fn vacuum_collections(
+    db: &Database,
+    jobs: &Collection<MaintenanceJob>,
+    job_id: ObjectId,
+    dry_run: bool,
+) -> Result<(), Error> {
+    for name in db.list_collection_names().await? {
+        let outcome = if dry_run {
+            estimate_collection(db, &name).await
+        } else {
+            compact_collection(db, &name).await
+        };
+
+        let entry = match outcome {
+            Ok((bytes_freed, valid)) => CollectionVacuumResult { collection: name, bytes_freed, valid, error: None },
+            Err(e) => CollectionVacuumResult { collection: name, bytes_freed: 0, valid: None, error: Some(e.to_string()) },
+        };
+        jobs.update_one(
+            doc! { "_id": job_id },
+            doc! { "$push": { "collections": bson::to_bson(&entry)? } },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn compact_collection(db: &Database, name: &str) -> Result<(i64, Option<bool>), Error> {
+    let result = db.run_command(doc! { "compact": name, "force": true }).await?;
+    Ok((number(&result, "bytesFreed"), None))
+}
+
+async fn estimate_collection(db: &Database, name: &str) -> Result<(i64, Option<bool>), Error> {
+    let validation = db
+        .run_command(doc! { "validate": name, "full": false })
+        .await?;
+    let stats = db.run_command(doc! { "collStats": name }).await?;
+    Ok((number(&stats, "freeStorageSize"), validation.get_bool("valid").ok()))
+}
+
+// MongoDB returns sizes as int32, int64 or double depending on their magnitude.
+fn number(document: &Document, key: &str) -> i64 {
+    match document.get(key) {
+        Some(Bson::Int32(n)) => *n as i64,
+        Some(Bson::Int64(n)) => *n,
+        Some(Bson::Double(n)) => *n as i64,
+        _ => 0,
+    }
+}
+
+// Computes the SHA-256 of every file stored before checksums were recorded, and stores it on the
+// file and its metadata. The counts on the job are updated after every batch.
+//
+// Only files without a checksum are processed, so an interrupted migration is resumed by starting
+// it again. Files that fail are counted and skipped, and retried by the next migration.
+pub async fn run_checksum_migration(
+    documents: &Collection<DocumentEntry>,
+    metadata: &Collection<FileMetadata>,
+    storage: &Storage,
+    bucket: &GridFsBucket,
+    jobs: &Collection<MaintenanceJob>,
+    job_id: ObjectId,
+) -> Result<(), Error> {
+    let result = backfill_checksums(documents, metadata, storage, bucket, jobs, job_id).await;
+    finish_job(jobs, job_id, result).await
+}
+
+async fn backfill_checksums(
+    documents: &Collection<DocumentEntry>,
+    metadata: &Collection<FileMetadata>,
+    storage: &Storage,
+    bucket: &GridFsBucket,
+    jobs: &Collection<MaintenanceJob>,
+    job_id: ObjectId,
+) -> Result<(), Error> {
+    let mut last_id: Option<ObjectId> = None;
+    loop {
+        let ids: Vec<ObjectId> = documents
+            .clone_with_type::<Document>()
+            .find(unchecked_files_filter(last_id))
+            .projection(doc! { "_id": 1 })
+            .sort(doc! { "_id": 1 })
+            .limit(CHECKSUM_BATCH_SIZE)
+            .await?
+            .try_collect::<Vec<Document>>()
+            .await?
+            .iter()
+            .filter_map(|document| document.get_object_id("_id").ok())
+            .collect();
+        let Some(&last) = ids.last() else {
+            return Ok(());
+        };
+        last_id = Some(last);
+
+        let (mut updated, mut failed) = (0, 0);
+        for id in ids {
+            match store_checksum(documents, metadata, storage, bucket, id).await {
+                Ok(true) => updated += 1,
+                Ok(false) => failed += 1,
+                Err(e) => {
+                    tracing::warn!("Failed to compute the checksum of file {}: {}", id, e);
+                    failed += 1;
+                }
+            }
+        }
+        jobs.update_one(
+            doc! { "_id": job_id },
+            doc! { "$inc": { "checksums.updated": updated, "checksums.failed": failed } },
+        )
+        .await?;
+    }
+}
+
+// Matches the files without a checksum that come after `after`. Null matches both a missing and
+// a null checksum. Paging by id keeps failed files from being picked up again by the next batch.
+fn unchecked_files_filter(after: Option<ObjectId>) -> Document {
+    let mut filter = doc! { "content_hash": null };
+    if let Some(after) = after {
+        filter.insert("_id", doc! { "$gt": after });
+    }
+    filter
+}
+
+// # Returns
+// - `Ok(true)` once the checksum is stored.
+// - `Ok(false)` if the file is gone or its content can't be found.
+async fn store_checksum(
+    documents: &Collection<DocumentEntry>,
+    metadata: &Collection<FileMetadata>,
+    storage: &Storage,
+    bucket: &GridFsBucket,
+    id: ObjectId,
+) -> Result<bool, Error> {
+    let Some(document) = documents.find_one(doc! { "_id": id }).await? else {
+        return Ok(false);
+    };
+    let Some(hash) = document_checksum(storage, bucket, &document).await? else {
+        return Ok(false);
+    };
+
+    // Leaves the file alone if its content was replaced, and got a checksum, in the meantime.
+    documents
+        .update_one(doc! { "_id": id, "content_hash": null }, doc! { "$set": { "content_hash": &hash } })
+        .await?;
+    metadata
+        .update_one(doc! { "_id": id, "content_hash": null }, doc! { "$set": { "content_hash": &hash } })
+        .await?;
+    Ok(true)
+}
+
+// The hex encoded SHA-256 of a file's content, as stored for new uploads.
+//
+// # Returns
+// - `Ok(None)` if the content can't be found.
+async fn document_checksum(
+    storage: &Storage,
+    bucket: &GridFsBucket,
+    document: &DocumentEntry,
+) -> Result<Option<String>, Error> {
+    let bytes = document_bytes(storage, bucket, document).await?;
+    Ok(bytes.map(|bytes| format!("{:x}", Sha256::digest(&bytes))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_handlers::testing::unreachable_database;
+    use crate::config::StorageConfig;
+    use bson::{Binary, spec::BinarySubtype};
+    use std::sync::Arc;
+
+    #[test]
+    fn migrations_resume_after_the_last_file() {
+        assert_eq!(unchecked_files_filter(None), doc! { "content_hash": null });
+        let last = ObjectId::new();
+        assert_eq!(unchecked_files_filter(Some(last)), doc! { "content_hash": null, "_id": { "$gt": last } });
+    }
+
+    #[tokio::test]
+    async fn checksums_are_the_sha256_of_the_content() {
+        let db = unreachable_database().await;
+        let storage = Storage::new(&StorageConfig::Mongo, Arc::new(db.collection("blobs")));
+        let bucket = db.gridfs_bucket(None);
+
+        // A file stored before checksums were recorded, with its content inline.
+        let content = Binary { subtype: BinarySubtype::Generic, bytes: b"abc".to_vec() };
+        let document: DocumentEntry =
+            bson::from_document(doc! { "_id": ObjectId::new(), "filename": "a.txt", "user": "alice", "content": content })
+                .unwrap();
+        assert_eq!(document.content_hash, None);
+
+        assert_eq!(
+            document_checksum(&storage, &bucket, &document).await.unwrap().as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+    }
+}
@@ -1,2 +1,340 @@
+pub mod access_log_db;
+pub mod activity_db;
+pub mod admin_db;
+pub mod auth_event_db;
+pub mod blob_db;
+pub mod corruption_db;
+pub mod csp_db;
 pub mod file_db;
-pub mod user_db;
\ No newline at end of file
+pub mod file_metadata_db;
+pub mod file_version_db;
+pub mod gridfs_db;
+pub mod idempotency_db;
+pub mod image_rendition_db;
+pub mod maintenance_db;
+pub mod notification_db;
+pub mod quota_alert_db;
+pub mod recent_download_db;
+pub mod refresh_token_db;
+pub mod share_db;
+pub mod upload_ticket_db;
+pub mod user_db;
+
+use bson::{Bson, Document};
+use futures::future::BoxFuture;
+use mongodb::error::UNKNOWN_TRANSACTION_COMMIT_RESULT;
+use mongodb::options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, UpdateModifications};
+use mongodb::results::{DeleteResult, InsertManyResult, InsertOneResult, UpdateResult};
+use mongodb::{Client, ClientSession, Collection, Cursor};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::future::IntoFuture;
+use std::time::Instant;
+use tracing::Instrument;
+
+// Escapes every regex metacharacter in `input`, so user supplied text can be embedded in a
+// MongoDB `$regex` and only ever match itself.
+pub fn escape_regex(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Whether a MongoDB query was aborted because it ran longer than its `max_time`.
+pub fn is_max_time_error(error: &mongodb::error::Error) -> bool {
+    use mongodb::error::ErrorKind;
+
+    const MAX_TIME_MS_EXPIRED: i32 = 50;
+    matches!(error.kind.as_ref(), ErrorKind::Command(command_error) if command_error.code == MAX_TIME_MS_EXPIRED)
+}
+
+// Whether a MongoDB operation failed because no primary could take it, as happens while a replica
+// set elects a new primary. Trying again shortly is likely to work.
+pub fn is_failover_error(error: &mongodb::error::Error) -> bool {
+    use mongodb::error::{ErrorKind, WriteFailure};
+
+    // NotWritablePrimary, NotPrimaryNoSecondaryOk and LegacyNotPrimary.
+    const NOT_PRIMARY: [i32; 3] = [10107, 13435, 10058];
+    // InterruptedAtShutdown, InterruptedDueToReplStateChange, NotPrimaryOrSecondary,
+    // PrimarySteppedDown and ShutdownInProgress.
+    const RECOVERING: [i32; 5] = [11600, 11602, 13436, 189, 91];
+
+    let code = match error.kind.as_ref() {
+        // No primary was found before the server selection timeout.
+        ErrorKind::ServerSelection { .. } => return true,
+        ErrorKind::Command(command_error) => command_error.code,
+        ErrorKind::Write(WriteFailure::WriteConcernError(write_concern_error)) => write_concern_error.code,
+        _ => return false,
+    };
+    NOT_PRIMARY.contains(&code) || RECOVERING.contains(&code)
+}
+
+// Whether a MongoDB operation failed because it would have violated a unique index.
+pub fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    use mongodb::error::{ErrorKind, WriteFailure};
+
+    const DUPLICATE_KEY: i32 = 11000;
+    match error.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) => write_error.code == DUPLICATE_KEY,
+        ErrorKind::Command(command_error) => command_error.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+// How often committing a transaction is retried when MongoDB can't tell whether it went through.
+const MAX_COMMIT_ATTEMPTS: usize = 3;
+
+// Runs `ops` in a multi-document transaction, committing it when they succeed and aborting it when
+// they fail, so either all of their writes are stored or none are. Every operation in `ops` has to
+// be passed the session to take part in the transaction.
+//
+// Transactions require MongoDB to run as a replica set, a single node one is enough. Against a
+// standalone server starting the transaction fails.
+//
+// # Arguments
+// - `ops`: Takes the session and returns the boxed operations, e.g.
+//   `|session| async move { collection.insert_one(doc).session(session).await }.boxed()`. Anything
+//   it uses has to be moved into it, like clones of the collections.
+pub async fn with_transaction<T, F>(client: &Client, ops: F) -> Result<T, mongodb::error::Error>
+where
+    F: for<'s> FnOnce(&'s mut ClientSession) -> BoxFuture<'s, Result<T, mongodb::error::Error>>,
+{
+    let mut session = client.start_session().await?;
+    session.start_transaction().await?;
+
+    let value = match ops(&mut session).await {
+        Ok(value) => value,
+        Err(e) => {
+            if let Err(abort) = session.abort_transaction().await {
+                tracing::warn!(error = %abort, "Failed to abort transaction");
+            }
+            return Err(e);
+        }
+    };
+
+    let mut attempt = 1;
+    loop {
+        match session.commit_transaction().await {
+            Ok(()) => return Ok(value),
+            Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < MAX_COMMIT_ATTEMPTS => {
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// A `Collection` whose queries are traced. Every traced operation runs in a `mongodb` span that
+// records the collection, the operation, the filter as JSON and the elapsed time, and failures
+// are logged. Aggregations record their leading `$match` stage as the filter.
+//
+// There is deliberately no way to reach the inner `Collection`, so queries can't skip tracing.
+// Index management isn't traced and is done on the `Collection` directly.
+//
+// Wrapping is cheap, as a `Collection` is a handle to shared state.
+pub struct TracedCollection<T: Send + Sync> {
+    inner: Collection<T>,
+}
+
+impl<T: Send + Sync> From<&Collection<T>> for TracedCollection<T> {
+    fn from(collection: &Collection<T>) -> Self {
+        Self { inner: collection.clone() }
+    }
+}
+
+// Renders a filter for a span. Filters matching on a password, like the one used by the password
+// migration, would otherwise put it in the logs.
+fn filter_json(filter: &Document) -> String {
+    let mut filter = filter.clone();
+    if filter.contains_key("password") {
+        filter.insert("password", "<redacted>");
+    }
+    Bson::Document(filter).into_relaxed_extjson().to_string()
+}
+
+impl<T: Send + Sync> TracedCollection<T> {
+    pub fn clone_with_type<U: Send + Sync>(&self) -> TracedCollection<U> {
+        TracedCollection { inner: self.inner.clone_with_type() }
+    }
+
+    async fn traced<R>(
+        &self,
+        operation: &'static str,
+        filter: Option<&Document>,
+        action: impl IntoFuture<Output = mongodb::error::Result<R>>,
+    ) -> mongodb::error::Result<R> {
+        let span = tracing::info_span!(
+            "mongodb",
+            collection = %self.inner.name(),
+            operation,
+            filter = filter.map(filter_json),
+            elapsed_ms = tracing::field::Empty,
+        );
+        async {
+            let start = Instant::now();
+            let result = action.await;
+            let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+            tracing::Span::current().record("elapsed_ms", elapsed_ms);
+            match &result {
+                Ok(_) => tracing::debug!("MongoDB {} took {:.2} ms", operation, elapsed_ms),
+                Err(e) => tracing::warn!("MongoDB {} failed after {:.2} ms: {}", operation, elapsed_ms, e),
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    pub async fn find_one(
+        &self,
+        filter: Document,
+        options: impl Into<Option<FindOneOptions>>,
+    ) -> mongodb::error::Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let action = self.inner.find_one(filter.clone()).with_options(options);
+        self.traced("find_one", Some(&filter), action).await
+    }
+
+    pub async fn find(
+        &self,
+        filter: Document,
+        options: impl Into<Option<FindOptions>>,
+    ) -> mongodb::error::Result<Cursor<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let action = self.inner.find(filter.clone()).with_options(options);
+        self.traced("find", Some(&filter), action).await
+    }
+
+    pub async fn insert_one(&self, document: impl Borrow<T>) -> mongodb::error::Result<InsertOneResult>
+    where
+        T: Serialize,
+    {
+        self.traced("insert_one", None, self.inner.insert_one(document)).await
+    }
+
+    pub async fn update_one(
+        &self,
+        filter: Document,
+        update: impl Into<UpdateModifications>,
+    ) -> mongodb::error::Result<UpdateResult> {
+        let action = self.inner.update_one(filter.clone(), update);
+        self.traced("update_one", Some(&filter), action).await
+    }
+
+    pub async fn delete_one(&self, filter: Document) -> mongodb::error::Result<DeleteResult> {
+        let action = self.inner.delete_one(filter.clone());
+        self.traced("delete_one", Some(&filter), action).await
+    }
+
+    // Inserts the document as part of the transaction of `session`, see `with_transaction`.
+    pub async fn insert_one_in_session(
+        &self,
+        document: impl Borrow<T>,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<InsertOneResult>
+    where
+        T: Serialize,
+    {
+        self.traced("insert_one", None, self.inner.insert_one(document).session(session)).await
+    }
+
+    // Not used yet, but there so batch inserts are traced like every other write.
+    #[allow(dead_code)]
+    pub async fn insert_many(
+        &self,
+        documents: impl IntoIterator<Item = impl Borrow<T>>,
+    ) -> mongodb::error::Result<InsertManyResult>
+    where
+        T: Serialize,
+    {
+        self.traced("insert_many", None, self.inner.insert_many(documents)).await
+    }
+
+    pub async fn update_many(
+        &self,
+        filter: Document,
+        update: impl Into<UpdateModifications>,
+    ) -> mongodb::error::Result<UpdateResult> {
+        let action = self.inner.update_many(filter.clone(), update);
+        self.traced("update_many", Some(&filter), action).await
+    }
+
+    pub async fn delete_many(&self, filter: Document) -> mongodb::error::Result<DeleteResult> {
+        let action = self.inner.delete_many(filter.clone());
+        self.traced("delete_many", Some(&filter), action).await
+    }
+
+    pub async fn find_one_and_update(
+        &self,
+        filter: Document,
+        update: impl Into<UpdateModifications>,
+        options: impl Into<Option<FindOneAndUpdateOptions>>,
+    ) -> mongodb::error::Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let action = self.inner.find_one_and_update(filter.clone(), update).with_options(options);
+        self.traced("find_one_and_update", Some(&filter), action).await
+    }
+
+    pub async fn find_one_and_delete(&self, filter: Document) -> mongodb::error::Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let action = self.inner.find_one_and_delete(filter.clone());
+        self.traced("find_one_and_delete", Some(&filter), action).await
+    }
+
+    // Use `Cursor::with_type` to read the results as something other than documents.
+    pub async fn aggregate(&self, pipeline: Vec<Document>) -> mongodb::error::Result<Cursor<Document>> {
+        let filter = pipeline.first().and_then(|stage| stage.get_document("$match").ok()).cloned();
+        let action = self.inner.aggregate(pipeline);
+        self.traced("aggregate", filter.as_ref(), action).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::error::{CommandError, ErrorKind, WriteConcernError, WriteFailure};
+
+    fn command_error(code: i32) -> mongodb::error::Error {
+        let command_error: CommandError = bson::from_document(bson::doc! { "code": code, "errmsg": "test" }).unwrap();
+        ErrorKind::Command(command_error).into()
+    }
+
+    #[test]
+    fn failover_errors_are_recognized() {
+        // NotWritablePrimary and InterruptedDueToReplStateChange.
+        assert!(is_failover_error(&command_error(10107)));
+        assert!(is_failover_error(&command_error(11602)));
+
+        let write_concern_error: WriteConcernError = bson::from_document(bson::doc! { "code": 91, "errmsg": "test" }).unwrap();
+        let error: mongodb::error::Error = ErrorKind::Write(WriteFailure::WriteConcernError(write_concern_error)).into();
+        assert!(is_failover_error(&error));
+    }
+
+    #[test]
+    fn other_errors_are_not_failovers() {
+        assert!(!is_failover_error(&command_error(11000)));
+        assert!(!is_failover_error(&command_error(50)));
+        assert!(!is_failover_error(&std::io::Error::other("test").into()));
+    }
+
+    #[test]
+    fn regex_metacharacters_are_escaped() {
+        assert_eq!(escape_regex("image/png"), "image/png");
+        assert_eq!(escape_regex("application/vnd.ms-excel"), r"application/vnd\.ms\-excel");
+        assert_eq!(escape_regex(r"a+b*c?(d)|[e]{f}^$\#&~"), r"a\+b\*c\?\(d\)\|\[e\]\{f\}\^\$\\\#\&\~");
+    }
+}
@@ -0,0 +1,81 @@
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, Collection};
+use serde::{Deserialize, Serialize};
+
+// An in-app announcement shown to every user, e.g. about a maintenance window.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Notification {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub title: String,
+    pub body: String,
+    pub created_by: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    // Usernames of the users who have marked the notification as read.
+    #[serde(default)]
+    pub read_by: Vec<String>,
+}
+
+// A notification as returned to users, without the list of who has read it.
+#[derive(Debug, Serialize)]
+pub struct NotificationEntry {
+    pub id: String,
+    pub title: String,
+    pub body: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn insert_notification(
+    collection: &Collection<Notification>,
+    notification: Notification,
+) -> Result<ObjectId, Error> {
+    let result = collection.insert_one(notification).await?;
+    result.inserted_id.as_object_id().ok_or_else(|| {
+        Error::from(std::io::Error::other("Missing ObjectId"))
+    })
+}
+
+// Returns the notifications the user hasn't marked as read yet, newest first.
+pub async fn get_unread_notifications(
+    collection: &Collection<Notification>,
+    username: &str,
+) -> Result<Vec<NotificationEntry>, Error> {
+    let mut cursor = collection
+        .find(doc! { "read_by": { "$ne": username } })
+        .sort(doc! { "created_at": -1 })
+        .await?;
+
+    let mut notifications = Vec::new();
+    while let Some(notification) = cursor.try_next().await? {
+        if let Some(id) = notification.id {
+            notifications.push(NotificationEntry {
+                id: id.to_hex(),
+                title: notification.title,
+                body: notification.body,
+                created_by: notification.created_by,
+                created_at: notification.created_at,
+            });
+        }
+    }
+
+    Ok(notifications)
+}
+
+// Marks a notification as read by the user. Marking it again has no effect.
+//
+// # Returns
+// - `Ok(matched)`: the number of notifications matched, `0` if the id doesn't exist.
+pub async fn mark_notification_read(
+    collection: &Collection<Notification>,
+    id: ObjectId,
+    username: &str,
+) -> Result<u64, Error> {
+    let result = collection
+        .update_one(doc! { "_id": id }, doc! { "$addToSet": { "read_by": username } })
+        .await?;
+    Ok(result.matched_count)
+}
@@ -0,0 +1,86 @@
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+// A user is alerted at most once per this many hours, however many files they upload meanwhile.
+const QUOTA_ALERT_INTERVAL_HOURS: i64 = 24;
+
+// A warning that a user has used more than QUOTA_ALERT_THRESHOLD of their storage quota, stored in
+// the `quota_alerts` collection after an upload.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuotaAlert {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub username: String,
+    pub used_bytes: u64,
+    pub quota_bytes: u64,
+    pub ratio: f64,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub alerted_at: DateTime<Utc>,
+}
+
+// A quota alert as listed by /me/quota-alerts.
+#[derive(Debug, Serialize)]
+pub struct QuotaAlertEntry {
+    pub used_bytes: u64,
+    pub quota_bytes: u64,
+    pub ratio: f64,
+    pub alerted_at: DateTime<Utc>,
+}
+
+// Creates the index used to find the latest alerts of a user.
+pub async fn create_quota_alert_indexes(collection: &Collection<QuotaAlert>) -> Result<(), Error> {
+    let index_model = IndexModel::builder()
+        .keys(doc! { "username": 1, "alerted_at": -1 })
+        .options(
+            IndexOptions::builder()
+                .name("username_alerted_at_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(index_model).await?;
+    Ok(())
+}
+
+// Stores the alert, unless the user was already alerted in the last QUOTA_ALERT_INTERVAL_HOURS.
+//
+// # Returns
+// - `Ok(true)` if the alert was stored.
+pub async fn record_quota_alert(collection: &Collection<QuotaAlert>, alert: QuotaAlert) -> Result<bool, Error> {
+    let since = bson::DateTime::from_chrono(alert.alerted_at - Duration::hours(QUOTA_ALERT_INTERVAL_HOURS));
+    let recent = collection
+        .find_one(doc! { "username": &alert.username, "alerted_at": { "$gt": since } })
+        .await?;
+    if recent.is_some() {
+        return Ok(false);
+    }
+    collection.insert_one(alert).await?;
+    Ok(true)
+}
+
+// Returns the latest `limit` alerts of a user, newest first.
+pub async fn get_quota_alerts(
+    collection: &Collection<QuotaAlert>,
+    username: &str,
+    limit: i64,
+) -> Result<Vec<QuotaAlertEntry>, Error> {
+    let mut cursor = collection
+        .find(doc! { "username": username })
+        .sort(doc! { "alerted_at": -1 })
+        .limit(limit)
+        .await?;
+
+    let mut alerts = Vec::new();
+    while let Some(alert) = cursor.try_next().await? {
+        alerts.push(QuotaAlertEntry {
+            used_bytes: alert.used_bytes,
+            quota_bytes: alert.quota_bytes,
+            ratio: alert.ratio,
+            alerted_at: alert.alerted_at,
+        });
+    }
+    Ok(alerts)
+}
@@ -0,0 +1,147 @@
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::{Error, ErrorKind}, options::IndexOptions, Collection, Database, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub const RECENT_DOWNLOADS_COLLECTION: &str = "recent_downloads";
+
+// MongoDB's error code for creating a collection that already exists.
+const NAMESPACE_EXISTS: i32 = 48;
+
+// A download of a file by a user, stored in the capped `recent_downloads` collection, so the
+// oldest downloads are dropped once it is full.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecentDownload {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub username: String,
+    pub file_id: ObjectId,
+    // Kept with the download, so the listing doesn't have to look up every file.
+    pub filename: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub downloaded_at: DateTime<Utc>,
+}
+
+// A download as listed by /files/recent.
+#[derive(Debug, Serialize)]
+pub struct RecentDownloadEntry {
+    pub file_id: String,
+    pub filename: String,
+    pub downloaded_at: DateTime<Utc>,
+}
+
+// Where downloads are recorded, and whether they are recorded at all.
+#[derive(Clone)]
+pub struct RecentDownloadLog {
+    collection: Arc<Collection<RecentDownload>>,
+    enabled: bool,
+}
+
+impl RecentDownloadLog {
+    pub fn new(collection: Arc<Collection<RecentDownload>>, enabled: bool) -> Self {
+        Self { collection, enabled }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+// Creates the capped collection of at most `max_bytes`, and the index used to list the downloads
+// of a single user, newest first. Safe to call on every startup, an existing collection keeps the
+// size it was created with.
+pub async fn create_recent_download_collection(database: &Database, max_bytes: u64) -> Result<(), Error> {
+    match database.create_collection(RECENT_DOWNLOADS_COLLECTION).capped(true).size(max_bytes).await {
+        Err(e) if !matches!(e.kind.as_ref(), ErrorKind::Command(command_error) if command_error.code == NAMESPACE_EXISTS) => return Err(e),
+        _ => {}
+    }
+
+    let index_model = IndexModel::builder()
+        .keys(doc! { "username": 1, "downloaded_at": -1, "_id": -1 })
+        .options(
+            IndexOptions::builder()
+                .name("username_downloaded_at_index".to_string())
+                .build(),
+        )
+        .build();
+
+    database
+        .collection::<RecentDownload>(RECENT_DOWNLOADS_COLLECTION)
+        .create_index(index_model)
+        .await?;
+    Ok(())
+}
+
+// Records a download when RECENT_DOWNLOADS is enabled. Like the file access log, a failed insert
+// is only logged, as the download has already happened.
+pub async fn record_download(log: &RecentDownloadLog, username: &str, file_id: ObjectId, filename: &str) {
+    if !log.enabled {
+        return;
+    }
+    let download = RecentDownload {
+        id: None,
+        username: username.to_string(),
+        file_id,
+        filename: filename.to_string(),
+        downloaded_at: Utc::now(),
+    };
+    if let Err(err) = log.collection.insert_one(download).await {
+        tracing::error!("Failed to record download: {}", err);
+    }
+}
+
+// Returns the latest `limit` downloads of a user, newest first.
+pub async fn get_recent_downloads(
+    log: &RecentDownloadLog,
+    username: &str,
+    limit: i64,
+) -> Result<Vec<RecentDownloadEntry>, Error> {
+    let mut cursor = log
+        .collection
+        .find(doc! { "username": username })
+        .sort(doc! { "downloaded_at": -1, "_id": -1 })
+        .limit(limit)
+        .await?;
+
+    let mut downloads = Vec::new();
+    while let Some(download) = cursor.try_next().await? {
+        downloads.push(RecentDownloadEntry::from(download));
+    }
+    Ok(downloads)
+}
+
+impl From<RecentDownload> for RecentDownloadEntry {
+    fn from(download: RecentDownload) -> Self {
+        RecentDownloadEntry {
+            file_id: download.file_id.to_hex(),
+            filename: download.filename,
+            downloaded_at: download.downloaded_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downloads_are_listed_without_the_username() {
+        let file_id = ObjectId::new();
+        let downloaded_at = DateTime::parse_from_rfc3339("2026-01-02T03:04:05Z").unwrap().with_timezone(&Utc);
+        let download = RecentDownload {
+            id: Some(ObjectId::new()),
+            username: "alice".to_string(),
+            file_id,
+            filename: "report.pdf".to_string(),
+            downloaded_at,
+        };
+
+        let entry = serde_json::to_value(RecentDownloadEntry::from(download)).unwrap();
+        assert_eq!(
+            entry,
+            serde_json::json!({ "file_id": file_id.to_hex(), "filename": "report.pdf", "downloaded_at": "2026-01-02T03:04:05Z" })
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use bson::doc;
+use chrono::{DateTime, Utc};
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+// How long a login can be kept alive with refresh tokens before the user has to log in again.
+pub const REFRESH_TOKEN_DAYS: i64 = 30;
+
+// A login session, stored in the `refresh_tokens` collection.
+//
+// Each refresh replaces `token_hash` with the hash of a new token and moves the old hash to
+// `used_hashes`, so a refresh token works only once. Only SHA-256 hashes of the tokens are stored,
+// like share links.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub username: String,
+    pub token_hash: String,
+    #[serde(default)]
+    pub used_hashes: Vec<String>,
+    // Refreshing doesn't extend the session, so a stolen token is only useful until then.
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires_at: DateTime<Utc>,
+}
+
+// Creates the unique index used to look up sessions by their current token, an index to find
+// reused tokens, and a TTL index so MongoDB removes expired sessions. Safe to call on every startup.
+pub async fn create_refresh_token_indexes(collection: &Collection<RefreshToken>) -> Result<(), Error> {
+    let unique = IndexModel::builder()
+        .keys(doc! { "token_hash": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .name("token_hash_unique_index".to_string())
+                .build(),
+        )
+        .build();
+    let used = IndexModel::builder()
+        .keys(doc! { "used_hashes": 1 })
+        .options(IndexOptions::builder().name("used_hashes_index".to_string()).build())
+        .build();
+    let ttl = IndexModel::builder()
+        .keys(doc! { "expires_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .expire_after(std::time::Duration::ZERO)
+                .name("expires_at_ttl_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_indexes([unique, used, ttl]).await?;
+    Ok(())
+}
+
+pub async fn insert_refresh_token(collection: &Collection<RefreshToken>, token: RefreshToken) -> Result<(), Error> {
+    collection.insert_one(token).await?;
+    Ok(())
+}
+
+// Replaces the current token of a session with a new one, as long as the session hasn't expired.
+// Done in a single update, so two requests racing with the same token can't both rotate it.
+//
+// # Returns
+// - `Ok(Some(session))` as it was before the rotation.
+// - `Ok(None)` if no live session has `old_hash` as its current token.
+pub async fn rotate_refresh_token(
+    collection: &Collection<RefreshToken>,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<Option<RefreshToken>, Error> {
+    let now = bson::DateTime::from_chrono(Utc::now());
+    collection
+        .find_one_and_update(
+            doc! { "token_hash": old_hash, "expires_at": { "$gt": now } },
+            doc! { "$set": { "token_hash": new_hash }, "$push": { "used_hashes": old_hash } },
+        )
+        .await
+}
+
+// Finds the session a token was rotated out of, meaning it is being used a second time.
+pub async fn find_reused_refresh_token(
+    collection: &Collection<RefreshToken>,
+    token_hash: &str,
+) -> Result<Option<RefreshToken>, Error> {
+    collection.find_one(doc! { "used_hashes": token_hash }).await
+}
+
+// Ends every session of a user, so none of their refresh tokens work anymore.
+//
+// # Returns
+// - `Ok(deleted)`: the number of sessions ended.
+pub async fn delete_refresh_tokens_for_user(collection: &Collection<RefreshToken>, username: &str) -> Result<u64, Error> {
+    let result = collection.delete_many(doc! { "username": username }).await?;
+    Ok(result.deleted_count)
+}
@@ -0,0 +1,100 @@
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Utc};
+use futures_util::stream::TryStreamExt;
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+// A share link issued by the owner of a file, stored in the `share_links` collection.
+//
+// Only the SHA-256 hash of the link's token is stored, so the links can't be recovered from
+// the database. Revoking a link deletes its document.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareLink {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub token_hash: String,
+    pub file_id: ObjectId,
+    pub issued_by: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires_at: DateTime<Utc>,
+}
+
+// A share link as listed to its issuer.
+#[derive(Debug, Serialize)]
+pub struct ShareLinkEntry {
+    pub id: String,
+    pub file_id: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+// Creates the unique index used to look up links by token. Safe to call on every startup.
+pub async fn create_share_link_indexes(collection: &Collection<ShareLink>) -> Result<(), Error> {
+    let index_model = IndexModel::builder()
+        .keys(doc! { "token_hash": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .name("token_hash_unique_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(index_model).await?;
+    Ok(())
+}
+
+pub async fn insert_share_link(collection: &Collection<ShareLink>, link: ShareLink) -> Result<ObjectId, Error> {
+    let result = collection.insert_one(link).await?;
+    result.inserted_id.as_object_id().ok_or_else(|| {
+        Error::from(std::io::Error::other("Missing ObjectId"))
+    })
+}
+
+// Finds the link with the given token hash, as long as it hasn't expired.
+pub async fn find_active_share_link(
+    collection: &Collection<ShareLink>,
+    token_hash: &str,
+) -> Result<Option<ShareLink>, Error> {
+    let now = bson::DateTime::from_chrono(Utc::now());
+    collection
+        .find_one(doc! { "token_hash": token_hash, "expires_at": { "$gt": now } })
+        .await
+}
+
+// Lists the links issued by `issued_by` that haven't expired, soonest to expire first.
+pub async fn get_active_share_links(
+    collection: &Collection<ShareLink>,
+    issued_by: &str,
+) -> Result<Vec<ShareLinkEntry>, Error> {
+    let now = bson::DateTime::from_chrono(Utc::now());
+    let mut cursor = collection
+        .find(doc! { "issued_by": issued_by, "expires_at": { "$gt": now } })
+        .sort(doc! { "expires_at": 1 })
+        .await?;
+
+    let mut links = Vec::new();
+    while let Some(link) = cursor.try_next().await? {
+        if let Some(id) = link.id {
+            links.push(ShareLinkEntry {
+                id: id.to_hex(),
+                file_id: link.file_id.to_hex(),
+                expires_at: link.expires_at,
+            });
+        }
+    }
+
+    Ok(links)
+}
+
+// Revokes a link issued by `issued_by`.
+//
+// # Returns
+// - `Ok(deleted)`: the number of links deleted, `0` if the link doesn't exist or was issued by someone else.
+pub async fn delete_share_link(
+    collection: &Collection<ShareLink>,
+    id: ObjectId,
+    issued_by: &str,
+) -> Result<u64, Error> {
+    let result = collection.delete_one(doc! { "_id": id, "issued_by": issued_by }).await?;
+    Ok(result.deleted_count)
+}
@@ -0,0 +1,51 @@
+use bson::doc;
+use chrono::{DateTime, Utc};
+use mongodb::{error::Error, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use crate::database::TracedCollection;
+
+// A ticket letting a client upload one file as `username` without a token, stored in the
+// `upload_tickets` collection. Only the SHA-256 hash of the ticket is stored, like share links.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadTicket {
+    #[serde(rename = "_id")]
+    pub token_hash: String,
+    pub username: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires_at: DateTime<Utc>,
+}
+
+// Creates the TTL index so MongoDB removes expired tickets. Safe to call on every startup.
+pub async fn create_upload_ticket_indexes(collection: &Collection<UploadTicket>) -> Result<(), Error> {
+    let ttl = IndexModel::builder()
+        .keys(doc! { "expires_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .expire_after(std::time::Duration::ZERO)
+                .name("expires_at_ttl_index".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(ttl).await?;
+    Ok(())
+}
+
+pub async fn insert_upload_ticket(collection: &Collection<UploadTicket>, ticket: &UploadTicket) -> Result<(), Error> {
+    TracedCollection::from(collection).insert_one(ticket).await?;
+    Ok(())
+}
+
+// Uses up a ticket. It is deleted in the same operation that finds it, so two uploads racing with
+// the same ticket can't both get it. The TTL index only runs once a minute, so expiry is checked
+// here as well.
+//
+// # Returns
+// - `Ok(Some(ticket))` if the ticket existed and hadn't expired.
+// - `Ok(None)` if it doesn't exist, was used already, or has expired.
+pub async fn redeem_upload_ticket(collection: &Collection<UploadTicket>, token_hash: &str) -> Result<Option<UploadTicket>, Error> {
+    let now = bson::DateTime::from_chrono(Utc::now());
+    TracedCollection::from(collection)
+        .find_one_and_delete(doc! { "_id": token_hash, "expires_at": { "$gt": now } })
+        .await
+}
@@ -1,13 +1,37 @@
-use mongodb::{bson::doc, Collection, IndexModel, options::{IndexOptions}};
+use mongodb::{bson::{doc, Document}, Collection, IndexModel, options::{FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument}};
 use poem::{http::StatusCode, Error as PoemError};
 use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
+use crate::auth::password::{hash_password, is_password_hash, verify_password};
+use crate::config::QueryConfig;
+use crate::database::{escape_regex, is_duplicate_key_error, TracedCollection};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
     pub password: String,
-    pub role: Vec<String>
+    pub role: Vec<String>,
+    #[serde(default)]
+    pub notifications: NotificationPreferences,
+    // Set for users whose plaintext password was hashed by the password migration, as that
+    // password was stored in the clear and should be considered compromised.
+    #[serde(default)]
+    pub must_change_password: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    // Whether the profile is visible on /users/:name/public-profile. Users have to opt in.
+    #[serde(default)]
+    pub public: bool,
+    // Self-registered users can't log in until they activate their account. Users created by an
+    // admin, and users created before activation existed, are active.
+    #[serde(default = "active_by_default")]
+    pub active: bool,
+}
+
+fn active_by_default() -> bool {
+    true
 }
 
 impl User {
@@ -15,12 +39,26 @@ impl User {
         Self {
             username,
             password,
-            role
+            role,
+            notifications: NotificationPreferences::default(),
+            must_change_password: false,
+            display_name: None,
+            bio: None,
+            public: false,
+            active: true,
         }
     }
 }
 
-// Inserts a new User into the MongoDB collection.
+// How a user wants to be told that a file has been shared with them. Users created before
+// these preferences existed get the defaults: no notifications at all.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NotificationPreferences {
+    pub email_on_share: bool,
+    pub webhook_on_share: Option<String>,
+}
+
+// Inserts a new User into the MongoDB collection. The password is hashed before it is stored.
 //
 // # Arguments
 // - `collection`: The MongoDB collection where the user will be inserted.
@@ -32,7 +70,8 @@ impl User {
      collection: &Collection<User>,
      user: &User,
  ) -> Result<(), PoemError> {
-     let existing_user = collection.find_one(doc! {"username": &user.username})
+     let collection = TracedCollection::from(collection);
+     let existing_user = collection.find_one(doc! {"username": &user.username}, None)
          .await
          .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
 
@@ -40,7 +79,19 @@ impl User {
          return Err(PoemError::from_string("User with that username already exists", StatusCode::CONFLICT));
      }
 
-     collection.insert_one(user)
+     let user = User {
+         username: user.username.clone(),
+         password: hash_password(&user.password)?,
+         role: user.role.clone(),
+         notifications: user.notifications.clone(),
+         must_change_password: false,
+         display_name: user.display_name.clone(),
+         bio: user.bio.clone(),
+         public: user.public,
+         active: user.active,
+     };
+
+     collection.insert_one(&user)
          .await
          .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
 
@@ -65,10 +116,10 @@ pub async fn find_user(
     // Create a filter to search for a document with the specified "name" field.
     let filter = doc! { "username": username };
     // Perform the query to find the user by name.
-    collection.find_one(filter).await
+    TracedCollection::from(collection).find_one(filter, None).await
 }
 
-// Updates a user in the MongoDB collection.
+// Updates a user in the MongoDB collection. The new password is hashed before it is stored.
 //
 // # Arguments
 // - `collection`: The MongoDB collection to update.
@@ -87,15 +138,17 @@ pub async fn update_user(
 ) -> Result<(), PoemError> {
     match find_user(collection, username).await{
         Ok(_) => {
-            let update = doc! { "$set": { "username": &new_user_details.username, "password": &new_user_details.password, "role": &new_user_details.role } };
-            let result = collection.update_one(doc! {"username": username}, update).await;
+            let password = hash_password(&new_user_details.password)?;
+            let update = doc! { "$set": { "username": &new_user_details.username, "password": password, "role": &new_user_details.role, "must_change_password": false } };
+            let result = TracedCollection::from(collection).update_one(doc! {"username": username}, update).await;
             match result {
                 Ok(_) => Ok(()),
-                Err(_) => Err(PoemError::from_string("Can't change username because it is already taken",StatusCode::CONFLICT))
+                Err(e) if is_duplicate_key_error(&e) => Err(PoemError::from_string("Can't change username because it is already taken",StatusCode::CONFLICT)),
+                Err(e) => Err(PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))
             }
         }
-        Err(_) => {
-            Err(PoemError::from_string("Internal server error", StatusCode::INTERNAL_SERVER_ERROR))
+        Err(e) => {
+            Err(PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))
         }
     }
 }
@@ -118,68 +171,335 @@ pub async fn delete_user(
     // Create a filter to find the user by name.
     let filter = doc! { "username": username };
     // Execute the delete operation.
-    match collection.delete_one(filter).await {
+    match TracedCollection::from(collection).delete_one(filter).await {
         Ok(deleted) => {
             if deleted.deleted_count == 0 {
                 return Err(PoemError::from_string("The user you are trying to delete doesn't exist.", StatusCode::NOT_FOUND))
             }
             Ok(())
         },
-        Err(_) => Err(PoemError::from_status(StatusCode::INTERNAL_SERVER_ERROR))
+        Err(e) => Err(PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))
     }
 }
  
+// Activates the account of a self-registered user.
+//
+// # Returns
+// - `Ok(())` if the user is active, including when it already was.
+// - `Err(PoemError)` with `404 Not Found` if the user doesn't exist, or `500` on a DB error.
+pub async fn activate_user(collection: &Collection<User>, username: &str) -> Result<(), PoemError> {
+    let result = TracedCollection::from(collection)
+        .update_one(doc! { "username": username }, doc! { "$set": { "active": true } })
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    if result.matched_count == 0 {
+        return Err(PoemError::from_string("User not found", StatusCode::NOT_FOUND));
+    }
+    Ok(())
+}
+
+// The outcome of deleting several users at once.
+#[derive(Debug, Serialize)]
+pub struct BulkDeleteResult {
+    pub deleted_count: u64,
+    // The usernames that didn't match any user.
+    pub not_found: Vec<String>,
+}
+
+// Deletes every user named in `usernames` in a single operation.
+//
+// # Returns
+// - `Ok(BulkDeleteResult)` with the number of users deleted, and the names that matched no user.
+// - `Err(PoemError)` with `500 Internal Server Error` on a DB error.
+pub async fn bulk_delete_users(
+    collection: &Collection<User>,
+    usernames: &[String],
+) -> Result<BulkDeleteResult, PoemError> {
+    let collection = TracedCollection::from(collection);
+    let filter = doc! { "username": { "$in": usernames } };
+
+    let existing: Vec<String> = collection
+        .clone_with_type::<Document>()
+        .find(filter.clone(), FindOptions::builder().projection(doc! { "_id": 0, "username": 1 }).build())
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .try_collect::<Vec<Document>>()
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .iter()
+        .filter_map(|user| user.get_str("username").ok().map(str::to_string))
+        .collect();
+
+    let deleted = collection
+        .delete_many(filter)
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let not_found = usernames.iter().filter(|name| !existing.contains(name)).cloned().collect();
+    Ok(BulkDeleteResult { deleted_count: deleted.deleted_count, not_found })
+}
+
+// A user without their password, as listed to admins.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserSummary {
+    pub username: String,
+    pub role: Vec<String>,
+    #[serde(default)]
+    pub must_change_password: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(default)]
+    pub public: bool,
+    #[serde(default = "active_by_default")]
+    pub active: bool,
+}
+
+// The users found by `find_users`.
+#[derive(Debug, Serialize)]
+pub struct UserBatch {
+    pub users: Vec<UserSummary>,
+    // The requested usernames that didn't match any user.
+    pub not_found: Vec<String>,
+}
+
+// Finds every user named in `usernames` in a single query, leaving out their passwords.
+//
+// # Returns
+// - `Ok(UserBatch)` with the users found, sorted by username, and the names that matched no user.
+// - `Err(PoemError)` with `500 Internal Server Error` on a DB error.
+pub async fn find_users(collection: &Collection<User>, usernames: &[String]) -> Result<UserBatch, PoemError> {
+    let options = FindOptions::builder()
+        .projection(doc! { "password": 0 })
+        .sort(doc! { "username": 1 })
+        .build();
+    let users: Vec<UserSummary> = TracedCollection::from(collection)
+        .clone_with_type::<UserSummary>()
+        .find(doc! { "username": { "$in": usernames } }, options)
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .try_collect()
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let not_found = missing_usernames(usernames, &users);
+    Ok(UserBatch { users, not_found })
+}
+
+// The names in `usernames` that none of `users` has.
+fn missing_usernames(usernames: &[String], users: &[UserSummary]) -> Vec<String> {
+    usernames
+        .iter()
+        .filter(|name| !users.iter().any(|user| &user.username == *name))
+        .cloned()
+        .collect()
+}
+
+// Where in a username a search query has to appear.
+pub enum UsernameMatch {
+    Anywhere,
+    Prefix,
+}
+
+// Finds the users whose name contains `query`, or starts with it, ignoring case, sorted by username.
+// The first `skip` users are left out.
+//
+// The query is matched literally. An unanchored regex can't seek in the username index, but MongoDB
+// scans the index rather than the documents, which keeps the search cheap for large user bases.
+pub async fn search_users(
+    collection: &Collection<User>,
+    query: &str,
+    matching: UsernameMatch,
+    skip: u64,
+    limit: i64,
+    queries: &QueryConfig,
+) -> mongodb::error::Result<Vec<UserSummary>> {
+    let options = FindOptions::builder()
+        .projection(doc! { "password": 0 })
+        .sort(doc! { "username": 1 })
+        .skip(skip)
+        .limit(limit)
+        .max_time(queries.max_time)
+        .build();
+    TracedCollection::from(collection)
+        .clone_with_type::<UserSummary>()
+        .find(username_search_filter(query, matching), options)
+        .await?
+        .try_collect()
+        .await
+}
+
+// Matches the usernames containing `query`, or starting with it, ignoring case.
+fn username_search_filter(query: &str, matching: UsernameMatch) -> Document {
+    let pattern = match matching {
+        UsernameMatch::Anywhere => escape_regex(query),
+        UsernameMatch::Prefix => format!("^{}", escape_regex(query)),
+    };
+    doc! { "username": { "$regex": pattern, "$options": "i" } }
+}
+
+// The profile fields a user can change about themselves. Fields left out are kept as they are.
+#[derive(Debug, Deserialize)]
+pub struct ProfileUpdate {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub public: Option<bool>,
+}
+
+// Updates the profile of a user, only setting the fields present in `update`.
+//
+// # Returns
+// - `Ok(())` if the profile was updated, or if there was nothing to update.
+// - `Err(PoemError)` with `404 Not Found` if the user doesn't exist, or `500` on a DB error.
+pub async fn update_profile(
+    collection: &Collection<User>,
+    username: &str,
+    update: &ProfileUpdate,
+) -> Result<(), PoemError> {
+    let mut fields = doc! {};
+    if let Some(display_name) = &update.display_name {
+        fields.insert("display_name", display_name);
+    }
+    if let Some(bio) = &update.bio {
+        fields.insert("bio", bio);
+    }
+    if let Some(public) = update.public {
+        fields.insert("public", public);
+    }
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    let result = TracedCollection::from(collection)
+        .update_one(doc! { "username": username }, doc! { "$set": fields })
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    if result.matched_count == 0 {
+        return Err(PoemError::from_string("User not found", StatusCode::NOT_FOUND));
+    }
+    Ok(())
+}
+
+// Replaces the notification preferences of a user.
+//
+// # Returns
+// - `Ok(())` if the preferences were stored.
+// - `Err(PoemError)` with `404 Not Found` if the user doesn't exist, or `500` on a DB error.
+pub async fn update_notification_preferences(
+    collection: &Collection<User>,
+    username: &str,
+    preferences: &NotificationPreferences,
+) -> Result<(), PoemError> {
+    let preferences = bson::to_bson(preferences)
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let result = TracedCollection::from(collection)
+        .update_one(doc! { "username": username }, doc! { "$set": { "notifications": preferences } })
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    if result.matched_count == 0 {
+        return Err(PoemError::from_string("User not found", StatusCode::NOT_FOUND));
+    }
+    Ok(())
+}
+
+// Adds and removes roles of a user in a single atomic update.
+//
+// MongoDB rejects `$addToSet` and `$pullAll` on the same field in one update as a conflict, so
+// the new role set is computed with an update pipeline instead, which removes `remove` and then
+// adds `add`. The filter only matches if the user keeps at least one role, so a concurrent
+// change can't leave the user without any.
+//
+// # Returns
+// - `Ok(roles)`: the roles of the user after the update.
+// - `Err(PoemError)` with `404 Not Found` if the user doesn't exist, `400 Bad Request` if the
+//   update would remove the last role, or `500` on a DB error.
+pub async fn modify_user_roles(
+    collection: &Collection<User>,
+    username: &str,
+    add: &[String],
+    remove: &[String],
+) -> Result<Vec<String>, PoemError> {
+    let mut filter = doc! { "username": username };
+    if add.is_empty() {
+        filter.insert("role", doc! { "$elemMatch": { "$nin": remove } });
+    }
+    let update = vec![doc! {
+        "$set": { "role": { "$setUnion": [ { "$setDifference": ["$role", remove] }, add ] } }
+    }];
+
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let updated = TracedCollection::from(collection)
+        .find_one_and_update(filter, update, options)
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    match updated {
+        Some(user) => Ok(user.role),
+        None => match find_user(collection, username).await {
+            Ok(Some(_)) => Err(PoemError::from_string("A user must have at least one role", StatusCode::BAD_REQUEST)),
+            Ok(None) => Err(PoemError::from_string("User not found", StatusCode::NOT_FOUND)),
+            Err(e) => Err(PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR)),
+        },
+    }
+}
+
  pub async fn login(collection: &Collection<User>, username: &str, password: &str) -> Result<User, PoemError>{
      // Attempt to find the user by username
-     let user = collection
-         .find_one(doc! { "username": username })
+     // Failures are logged by TracedCollection.
+     let user = TracedCollection::from(collection)
+         .find_one(doc! { "username": username }, None)
          .await
-         .map_err(|e| {
-             eprintln!("DB error: {}", e);
-             PoemError::from_string("Database error", StatusCode::INTERNAL_SERVER_ERROR)
-         })?
+         .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
          .ok_or_else(|| {
              // If no user is found
              PoemError::from_string("Invalid username or password", StatusCode::UNAUTHORIZED)
          })?;
 
      // Password check
-     if user.password != password {
+     if !verify_password(password, &user.password) {
          return Err(PoemError::from_string(
              "Invalid username or password",
              StatusCode::UNAUTHORIZED,
          ));
      }
 
+     // Only checked once the password is right, so it doesn't reveal which accounts exist.
+     if !user.active {
+         return Err(PoemError::from_string("account_not_activated", StatusCode::FORBIDDEN));
+     }
+
      Ok(user)
  }
 
- pub async fn initial_user_db_setup(collection: &Collection<User>) -> mongodb::error::Result<bool> {
+// The unique index that keeps two users from having the same name.
+fn username_index() -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! { "username": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .name("username_unique_index".to_string())
+                .build(),
+        )
+        .build()
+}
 
-     let index_model = IndexModel::builder()
-         .keys(doc! { "username": 1 })
-         .options(
-             IndexOptions::builder()
-                 .unique(true)
-                 .name("username_unique_index".to_string())
-                 .build(),
-         )
-         .build();
-
-     match collection.create_index(index_model).await {
+ pub async fn initial_user_db_setup(collection: &Collection<User>) -> mongodb::error::Result<bool> {
+     match collection.create_index(username_index()).await {
          Ok(_) => println!("Index on username is created or already exists"),
          Err(_) => println!("Failed to create index")
      }
      
      let users_to_find :Vec<&str> = ["test", "test2"].to_vec();
 
-     let cursor = collection.find(doc! {"username" : {"$in" : &users_to_find}}).await?;
+     let cursor = TracedCollection::from(collection).find(doc! {"username" : {"$in" : &users_to_find}}, None).await?;
      let test_users: Vec<User> = cursor.try_collect().await?;
-     let mut admin_vector = Vec::new();
-     admin_vector.push("admin".to_string());
-     admin_vector.push("user".to_string());
-     let mut user_vector = Vec::new();
-     user_vector.push("user".to_string());
+     let admin_vector = vec!["admin".to_string(), "user".to_string()];
+     let user_vector = vec!["user".to_string()];
      if test_users.is_empty() {
          println!("No test users found - creating 2 test users.");
          let test_user_1 : User = User::new("test".to_string(), "test".to_string(), admin_vector);
@@ -213,3 +533,171 @@ pub async fn delete_user(
 
      Ok(true)
  }
+
+
+// Matches users whose password doesn't look like an Argon2 hash.
+fn plaintext_password_filter() -> Document {
+    doc! { "password": { "$not": bson::Regex { pattern: "^\\$argon2".to_string(), options: String::new() } } }
+}
+
+// Counts the users whose password is still stored in plaintext, used by the startup check
+// enabled with `REQUIRE_HASHED_PASSWORDS`.
+pub async fn count_plaintext_passwords(collection: &Collection<User>) -> mongodb::error::Result<u64> {
+    let mut cursor = TracedCollection::from(collection).find(plaintext_password_filter(), None).await?;
+    let mut count = 0;
+    while let Some(user) = cursor.try_next().await? {
+        if !is_password_hash(&user.password) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Hashes every plaintext password left over from before passwords were hashed.
+//
+// As the plaintext passwords have been stored in the clear, the migrated users are flagged with
+// `must_change_password`. With `hash_in_place` set to false the users are only flagged, and their
+// plaintext passwords are left for them to change.
+//
+// # Returns
+// - `Ok(count)`: the number of users migrated.
+pub async fn migrate_plaintext_passwords(
+    collection: &Collection<User>,
+    hash_in_place: bool,
+) -> Result<u64, PoemError> {
+    let collection = TracedCollection::from(collection);
+    let mut cursor = collection
+        .find(plaintext_password_filter(), None)
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let mut migrated = 0;
+    while let Some(user) = cursor
+        .try_next()
+        .await
+        .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?
+    {
+        // The regex only looks at the prefix, so double check before touching the password.
+        if is_password_hash(&user.password) {
+            continue;
+        }
+
+        let update = if hash_in_place {
+            doc! { "$set": { "password": hash_password(&user.password)?, "must_change_password": true } }
+        } else {
+            doc! { "$set": { "must_change_password": true } }
+        };
+        // Matching on the old password as well means a password changed in the meantime is left alone.
+        collection
+            .update_one(doc! { "username": &user.username, "password": &user.password }, update)
+            .await
+            .map_err(|e| PoemError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+        migrated += 1;
+    }
+
+    Ok(migrated)
+}
+
+// Users sharing a username, which the unique username index should have prevented.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuplicateUsername {
+    #[serde(rename(deserialize = "_id"))]
+    pub username: String,
+    pub count: u64,
+    // The `_id`s of the users, as hex strings, so an admin can tell them apart.
+    pub ids: Vec<String>,
+}
+
+// Finds every username taken by more than one user.
+pub async fn find_duplicate_usernames(collection: &Collection<User>) -> mongodb::error::Result<Vec<DuplicateUsername>> {
+    let pipeline = vec![
+        doc! { "$group": {
+            "_id": "$username",
+            "count": { "$sum": 1 },
+            "ids": { "$push": { "$toString": "$_id" } },
+        } },
+        doc! { "$match": { "count": { "$gt": 1 } } },
+        doc! { "$sort": { "_id": 1 } },
+    ];
+
+    TracedCollection::from(collection)
+        .aggregate(pipeline)
+        .await?
+        .with_type::<DuplicateUsername>()
+        .try_collect()
+        .await
+}
+
+pub enum UsernameIndexRepair {
+    // The unique index was in place.
+    Intact,
+    // The index was missing or not unique, and has been created again.
+    Recreated,
+    // The index can't be created until these duplicates are resolved.
+    Conflicts(Vec<DuplicateUsername>),
+}
+
+// Makes sure the unique username index exists, recreating it when it was dropped or replaced by
+// an index that isn't unique.
+//
+// Duplicate usernames are looked for first, as they would make creating the index fail. An index
+// on `username` that isn't unique is dropped before the unique one is created, since MongoDB
+// doesn't allow two indexes on the same keys that only differ in their options.
+pub async fn repair_username_index(collection: &Collection<User>) -> mongodb::error::Result<UsernameIndexRepair> {
+    let duplicates = find_duplicate_usernames(collection).await?;
+    if !duplicates.is_empty() {
+        return Ok(UsernameIndexRepair::Conflicts(duplicates));
+    }
+
+    let indexes: Vec<IndexModel> = collection.list_indexes().await?.try_collect().await?;
+    let existing = indexes.iter().find(|index| index.keys == doc! { "username": 1 });
+    if let Some(index) = existing {
+        let options = index.options.as_ref();
+        if options.and_then(|options| options.unique) == Some(true) {
+            return Ok(UsernameIndexRepair::Intact);
+        }
+        if let Some(name) = options.and_then(|options| options.name.as_deref()) {
+            collection.drop_index(name).await?;
+        }
+    }
+
+    match collection.create_index(username_index()).await {
+        Ok(_) => Ok(UsernameIndexRepair::Recreated),
+        // A duplicate was added after the check above.
+        Err(e) if is_duplicate_key_error(&e) => Ok(UsernameIndexRepair::Conflicts(find_duplicate_usernames(collection).await?)),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_report_the_missing_usernames() {
+        let summary = |username: &str| -> UserSummary {
+            mongodb::bson::from_document(doc! { "username": username, "role": ["user"], "password": "secret" }).unwrap()
+        };
+        let users = [summary("alice"), summary("carol")];
+        let usernames = ["alice", "bob", "carol", "dave"].map(String::from);
+
+        let batch = UserBatch { not_found: missing_usernames(&usernames, &users), users: users.into() };
+        let body = serde_json::to_value(&batch).unwrap();
+        assert_eq!(body["not_found"], serde_json::json!(["bob", "dave"]));
+        let found: Vec<_> = batch.users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(found, ["alice", "carol"]);
+        assert!(body["users"].as_array().unwrap().iter().all(|user| user.get("password").is_none()));
+    }
+
+    #[test]
+    fn username_searches_are_literal_and_anchored() {
+        assert_eq!(
+            username_search_filter("a.b*", UsernameMatch::Prefix),
+            doc! { "username": { "$regex": r"^a\.b\*", "$options": "i" } }
+        );
+        assert_eq!(
+            username_search_filter("(ali)", UsernameMatch::Anywhere),
+            doc! { "username": { "$regex": r"\(ali\)", "$options": "i" } }
+        );
+    }
+}
@@ -1,38 +1,209 @@
+// poem::Error is large by design and is returned from most helpers and handlers.
+#![allow(clippy::result_large_err)]
+// Handlers take one extractor per collection or service they use.
+#![allow(clippy::too_many_arguments)]
+// Every `.data(...)` and middleware nests the type of the app one level deeper.
+#![recursion_limit = "256"]
+
 mod database;
 mod auth;
 mod api_handlers;
+mod config;
+mod middleware;
+mod services;
+mod storage;
 
 use database::user_db::*;
 use database::file_db::*;
 use api_handlers::user_handlers::*;
 use api_handlers::file_handlers::*;
+use api_handlers::admin_handlers::*;
+use api_handlers::csp_handlers::*;
+use api_handlers::notification_handlers::*;
+use api_handlers::quota_handlers::{get_my_quota_alerts, get_my_storage};
+use api_handlers::share_handlers::*;
+use api_handlers::upload_ticket_handlers::issue_upload_ticket;
+use api_handlers::health_handlers::{health, Readiness};
+use database::share_db::{create_share_link_indexes, ShareLink};
+use database::idempotency_db::{create_idempotency_indexes, IdempotencyKey};
+use database::blob_db::Blob;
+use storage::Storage;
+use database::gridfs_db::FILE_CONTENT_BUCKET;
+use database::file_metadata_db::{create_file_metadata_indexes, FileMetadata};
+use database::notification_db::Notification;
+use database::csp_db::CspViolation;
+use database::access_log_db::{create_access_log_indexes, FileAccessLog};
+use database::auth_event_db::{create_auth_event_indexes, AuthEvent, AuthEventLog, ChainHead};
+use database::maintenance_db::MaintenanceJob;
+use database::corruption_db::CorruptionReport;
+use database::upload_ticket_db::{create_upload_ticket_indexes, UploadTicket};
+use database::quota_alert_db::{create_quota_alert_indexes, QuotaAlert};
+use database::recent_download_db::{create_recent_download_collection, RecentDownload, RecentDownloadLog, RECENT_DOWNLOADS_COLLECTION};
+use database::image_rendition_db::{create_image_rendition_indexes, ImageRendition};
+use database::refresh_token_db::{create_refresh_token_indexes, RefreshToken};
+use database::file_version_db::{create_file_version_indexes, FileVersion};
 use auth::middleware::JwtMiddleware;
+use config::Config;
+use middleware::access_log::AccessLogMiddleware;
+use middleware::client_ip::ClientIpMiddleware;
+use middleware::db_failover::DbFailoverMiddleware;
+use middleware::cors::cors;
+use middleware::decompression::DecompressionMiddleware;
+use middleware::method_filter::MethodFilterMiddleware;
+use middleware::options::OptionsMiddleware;
+use middleware::rate_limit::RateLimitMiddleware;
+use middleware::response_time::ResponseTimeMiddleware;
+use services::upload_limiter::UploadLimiter;
+use services::event_bus::EventBus;
+use api_handlers::cors_handlers::cors_preflight;
+use api_handlers::event_handlers::events;
+use middleware::security_headers::SecurityHeadersMiddleware;
+use middleware::trailing_slash::TrailingSlashMiddleware;
 use poem::{
-    get, post, listener::TcpListener, Route, Server,
+    delete, get, head, patch, post, put, listener::TcpListener, Route, Server,
     EndpointExt,
     Result,
 };
-use mongodb::{bson::{Document}, Client};
+use poem::http::Method;
+use mongodb::{options::GridFsBucketOptions, Client};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 // The main entry point for the application, setting up the server and MongoDB connection.
 //
 // # Steps
-// 1. Connects to the MongoDB server at `localhost:27017`.
+// 1. Connects to the MongoDB server at MONGO_URI, `localhost:27017` by default.
 // 2. Selects (or creates) the database `my_api` and collection `users` - adds test users if they do not already exist, and ensures uniqueness of usernames.
-// 3. Sets up the API routes using Poem.
+//    This and the other index creation runs in the background, tracked by /health.
+// 3. Sets up the API routes using Poem, configured from the environment (see `Config::load`).
+
 
+// Refuses to start while `plaintext` users still have a plaintext password, with
+// REQUIRE_HASHED_PASSWORDS set.
+fn check_hashed_passwords(plaintext: u64) -> Result<(), String> {
+    if plaintext == 0 {
+        return Ok(());
+    }
+    Err(format!(
+        "Refusing to start: {} users have a plaintext password. Run post /admin/migrate/passwords \
+         with REQUIRE_HASHED_PASSWORDS unset first",
+        plaintext
+    ))
+}
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let client = Client::with_uri_str("mongodb://localhost:27017").await.unwrap();
+    tracing_subscriber::fmt::init();
+    let config = Config::load();
+    // Reads and validates FILE_ENCRYPTION_KEY now, rather than on the first upload.
+    if services::encryption::enabled() {
+        tracing::info!("File content is encrypted at rest");
+    }
+
+    let client = Client::with_uri_str(&config.mongo_uri).await.unwrap();
     let db = client.database("my_api");
+    let database = Arc::new(db.clone());
 
     let collection = Arc::new(db.collection::<User>("users"));
     let image_collection = Arc::new(db.collection::<ImageDocument>("images"));
     let files_collection = Arc::new(db.collection::<DocumentEntry>("files"));
+    let csp_collection = Arc::new(db.collection::<CspViolation>("csp_violations"));
+    let access_log_collection = Arc::new(db.collection::<FileAccessLog>("file_access_log"));
+    let notification_collection = Arc::new(db.collection::<Notification>("notifications"));
+    let share_link_collection = Arc::new(db.collection::<ShareLink>("share_links"));
+    let idempotency_collection = Arc::new(db.collection::<IdempotencyKey>("idempotency_keys"));
+    let blob_collection = Arc::new(db.collection::<Blob>("blobs"));
+    let file_metadata_collection = Arc::new(db.collection::<FileMetadata>("file_metadata"));
+    let auth_event_collection = Arc::new(db.collection::<AuthEvent>("auth_events"));
+    let chain_head_collection = Arc::new(db.collection::<ChainHead>("audit_chain_heads"));
+    let file_version_collection = Arc::new(db.collection::<FileVersion>("file_versions"));
+    let maintenance_job_collection = Arc::new(db.collection::<MaintenanceJob>("maintenance_jobs"));
+    let corruption_report_collection = Arc::new(db.collection::<CorruptionReport>("corruption_reports"));
+    let refresh_token_collection = Arc::new(db.collection::<RefreshToken>("refresh_tokens"));
+    let image_rendition_collection = Arc::new(db.collection::<ImageRendition>("image_renditions"));
+    let quota_alert_collection = Arc::new(db.collection::<QuotaAlert>("quota_alerts"));
+    let upload_ticket_collection = Arc::new(db.collection::<UploadTicket>("upload_tickets"));
+    let recent_download_collection = Arc::new(db.collection::<RecentDownload>(RECENT_DOWNLOADS_COLLECTION));
+    let file_content_bucket = db.gridfs_bucket(GridFsBucketOptions::builder().bucket_name(FILE_CONTENT_BUCKET.to_string()).build());
+
+    // With REQUIRE_HASHED_PASSWORDS set, plaintext passwords left over from before hashing was
+    // introduced have to be migrated before the server is allowed to start.
+    if config.require_hashed_passwords {
+        let plaintext = count_plaintext_passwords(&collection)
+            .await
+            .map_err(std::io::Error::other)?;
+        if let Err(refusal) = check_hashed_passwords(plaintext) {
+            eprintln!("{}", refusal);
+            return Err(std::io::Error::other("plaintext passwords found"));
+        }
+    }
+
+    // Building indexes on large existing collections can take a long time, so it happens in the
+    // background while the server already accepts requests. /health reports ready once it is done.
+    let readiness = Readiness::default();
+    {
+        let readiness = readiness.clone();
+        let collection = collection.clone();
+        let files_collection = files_collection.clone();
+        let file_metadata_collection = file_metadata_collection.clone();
+        let access_log_collection = access_log_collection.clone();
+        let share_link_collection = share_link_collection.clone();
+        let idempotency_collection = idempotency_collection.clone();
+        let auth_event_collection = auth_event_collection.clone();
+        let file_version_collection = file_version_collection.clone();
+        let refresh_token_collection = refresh_token_collection.clone();
+        let image_rendition_collection = image_rendition_collection.clone();
+        let quota_alert_collection = quota_alert_collection.clone();
+        let upload_ticket_collection = upload_ticket_collection.clone();
+        let db = db.clone();
+        let downloads = config.downloads.clone();
+        tokio::spawn(async move {
+            // Anything that failed is tried again, as /health only reports ready once all of it is done.
+            // Indexes that already exist are left as they are, so running the whole setup again is cheap.
+            let mut retry_in = Duration::from_secs(1);
+            loop {
+                let results = [
+                    ("the test users", initial_user_db_setup(&collection).await.map(|_| ())),
+                    ("file indexes", create_file_indexes(&files_collection).await),
+                    ("file metadata indexes", create_file_metadata_indexes(&file_metadata_collection).await),
+                    ("file access log indexes", create_access_log_indexes(&access_log_collection).await),
+                    ("share link indexes", create_share_link_indexes(&share_link_collection).await),
+                    ("idempotency key indexes", create_idempotency_indexes(&idempotency_collection).await),
+                    ("auth event indexes", create_auth_event_indexes(&auth_event_collection).await),
+                    ("file version indexes", create_file_version_indexes(&file_version_collection).await),
+                    ("refresh token indexes", create_refresh_token_indexes(&refresh_token_collection).await),
+                    ("image rendition indexes", create_image_rendition_indexes(&image_rendition_collection).await),
+                    ("quota alert indexes", create_quota_alert_indexes(&quota_alert_collection).await),
+                    ("upload ticket indexes", create_upload_ticket_indexes(&upload_ticket_collection).await),
+                    (
+                        "the recent downloads collection",
+                        if downloads.track_recent {
+                            create_recent_download_collection(&db, downloads.recent_max_bytes).await
+                        } else {
+                            Ok(())
+                        },
+                    ),
+                ];
+                let mut failed = 0;
+                for (step, result) in results {
+                    if let Err(e) = result {
+                        tracing::error!(error = %e, "Failed to set up {}", step);
+                        failed += 1;
+                    }
+                }
+                if failed == 0 {
+                    break;
+                }
+                tracing::warn!(failed, retry_in_secs = retry_in.as_secs(), "Startup setup incomplete, the server is not ready");
+                tokio::time::sleep(retry_in).await;
+                retry_in = (retry_in * 2).min(Duration::from_secs(60));
+            }
+            readiness.mark_ready();
+            println!("Startup setup finished, the server is ready");
+        });
+    }
 
-    let _ = initial_user_db_setup(&collection).await;
     // Configure the Poem app with routes for handling various HTTP methods.
     let app = Route::new()
         .at("/user/add", post(add_user))
@@ -42,18 +213,168 @@ async fn main() -> Result<(), std::io::Error> {
                 .put(user_update)
                 .delete(user_delete),
         )
+        .at("/health", get(health))
         .at("/login", post(api_handlers::user_handlers::login))
+        .at("/auth/refresh", post(refresh))
+        .at("/register", post(register))
+        .at("/activate/:token", get(activate))
+        .at("/.well-known/jwks.json", get(jwks))
         .at("/upload", post(upload_file))
+        .at("/uploads/ticket", post(issue_upload_ticket))
         .at("/download_file/:filename", get(download_file))
+        .at("/public/files/:id", get(download_public_file))
         .at("/files", get(get_files))
+        .at("/files/categories", get(file_categories))
+        .at("/files/export.csv", get(export_files_csv))
+        .at("/files/recent", get(list_recent_downloads))
+        .at("/files/by-name/:filename", get(download_file_by_name))
+        .at("/files/duplicates", get(get_duplicate_files))
+        .at("/files/bulk-tag", post(bulk_tag_files))
+        .at("/files/duplicates/resolve", post(resolve_duplicate_files))
+        .at("/files/download-batch", post(download_batch))
+        .at(
+            "/files/:id",
+            get(download_file)
+                .delete(delete_file)
+                .head(download_file_head)
+                .method(Method::from_str("MOVE").expect("MOVE is a valid method"), move_file_webdav),
+        )
+        .at("/files/:id/move", post(move_file_post))
+        .at("/files/:id/presign", post(presign_file))
+        .at("/files/:id/link-qr", get(link_qr))
+        .at("/files/:id/description", patch(update_file_description))
+        .at("/files/:id/content", put(replace_file_content))
+        .at("/files/:id/share", post(share_file))
+        .at("/files/:id/visibility", post(set_file_visibility))
+        .at("/files/:id/access-history", get(file_access_history))
+        .at("/files/:id/verify", get(verify_file))
+        .at("/files/:id/share-link", post(create_share_link))
+        .at("/files/shares", get(list_share_links))
+        .at("/files/shares/:id", delete(revoke_share_link))
+        .at("/shared/:token", get(download_shared_file))
+        .at(
+            "/me/notification-preferences",
+            get(get_notification_preferences)
+                .put(put_notification_preferences),
+        )
+        .at("/users/batch", post(users_batch))
+        .at("/users/search", get(user_prefix_search))
+        .at("/users/:name/public-profile", get(public_profile))
+        .at("/me/profile", put(put_profile))
+        .at("/events", get(events))
+        .at("/me/notifications", get(get_my_notifications))
+        .at("/me/storage", get(get_my_storage))
+        .at("/me/quota-alerts", get(get_my_quota_alerts))
+        .at("/me/notifications/:id/read", post(read_notification))
+        .at("/admin/broadcast", post(broadcast))
         .at("/upload_image", post(upload_image))
         .at("/download_image/:imagename", get(download_image) )
-        .with(JwtMiddleware)
+        .at("/images", get(list_images))
+        .at("/images/batch-upload", post(batch_upload_images))
+        .at("/images/:filename", head(download_image_head))
+        .at("/images/:filename/convert", get(convert_image))
+        .at("/images/:filename/info", get(image_info))
+        .at("/images/:filename/:preset", get(download_image_preset))
+        .at("/admin/index-usage", get(index_usage))
+        .at("/admin/system/version", get(system_version))
+        .at("/admin/files", get(list_files))
+        .at("/admin/corruption-reports", get(corruption_reports))
+        .at("/admin/audit/verify", get(verify_audit_chain))
+        .at("/admin/users/bulk", delete(bulk_delete))
+        .at("/admin/users/search", get(user_search))
+        .at("/admin/users/:name/activity-timeline", get(activity_timeline))
+        .at("/admin/users/:name/roles", patch(update_user_roles))
+        .at("/admin/users/:name/permissions-check", get(permissions_check))
+        .at("/admin/maintenance/metadata-sync", get(metadata_sync))
+        .at("/admin/repair/username-index", post(repair_username_index_handler))
+        .at("/admin/maintenance/vacuum", post(vacuum))
+        .at("/admin/maintenance/jobs/:id", get(maintenance_job))
+        .at("/admin/migrate/passwords", post(migrate_passwords))
+        .at("/admin/migrate/checksums", post(migrate_checksums))
+        // Allow each client 30 CSP reports per minute, so a misbehaving page can't flood the collection.
+        .at("/csp-report", post(csp_report).with(RateLimitMiddleware::new(30, Duration::from_secs(60))))
+        // Scoring is CPU heavy, so each client gets 20 checks per minute, plenty for a strength meter
+        // that checks as the user pauses typing.
+        .at("/password/strength", post(password_strength).with(RateLimitMiddleware::new(20, Duration::from_secs(60))))
+        // Only matches paths without a route of their own.
+        .at("/*path", cors_preflight)
+        // Innermost, so it sees the errors of the handlers before any middleware turns them into responses.
+        .with(DbFailoverMiddleware::new(config.failover_retry_after))
+        // Decompresses gzip request bodies, up to the size of the largest upload.
+        .with(DecompressionMiddleware::new(config.uploads.max_file_bytes))
+        // Normalizes the path right before routing. Redirects still count against the rate limit.
+        .with(TrailingSlashMiddleware::new(config.redirect_trailing_slash))
+        // Runs inside JwtMiddleware, so authenticated requests are limited per user and role.
+        .with(RateLimitMiddleware::from_config(&config.rate_limit))
+        .with(JwtMiddleware::new(&config.auth))
+        // Outside the JWT check and rate limiter, so OPTIONS needs no token and isn't counted.
+        .with(OptionsMiddleware)
+        // Resolves the client IP used by the rate limiter and the access logs.
+        .with(ClientIpMiddleware::new(&config.trusted_proxies))
+        // Outside the rate limiter and JWT check, so their error responses carry the CORS headers too.
+        .with_if(!config.cors.allowed_origins.is_empty(), cors(&config.cors))
+        // Outside the other middleware, so responses rejected by the other middleware get the headers as well.
+        .with(SecurityHeadersMiddleware::new(&config.security_headers))
+        // Runs before everything else, so TRACE and other unexpected methods never reach a handler.
+        .with(MethodFilterMiddleware)
+        // Outside everything but the access log, so rejected requests are timed as well.
+        .with(ResponseTimeMiddleware)
+        // Outermost, so every request is logged with the response it got, rejected or not.
+        .with(AccessLogMiddleware::new(config.log_format))
         .data(image_collection)
         .data(collection)
-        .data(files_collection);
+        .data(files_collection)
+        .data(csp_collection)
+        .data(access_log_collection)
+        .data(notification_collection)
+        .data(share_link_collection)
+        .data(idempotency_collection)
+        .data(Storage::new(&config.storage, blob_collection.clone()))
+        .data(blob_collection)
+        .data(file_metadata_collection)
+        .data(AuthEventLog::new(auth_event_collection.clone(), chain_head_collection, config.audit_hash_chain))
+        .data(auth_event_collection)
+        .data(maintenance_job_collection)
+        .data(corruption_report_collection)
+        .data(refresh_token_collection)
+        .data(image_rendition_collection)
+        .data(quota_alert_collection)
+        .data(upload_ticket_collection)
+        .data(RecentDownloadLog::new(recent_download_collection, config.downloads.track_recent))
+        .data(file_version_collection.clone())
+        .data(file_content_bucket)
+        .data(database)
+        .data(client)
+        .data(readiness)
+        .data(UploadLimiter::new(&config.uploads))
+        .data(config.uploads.clone())
+        .data(config.queries.clone())
+        .data(config.auth.clone())
+        .data(config.metadata.clone())
+        .data(config.bulk.clone())
+        .data(config.downloads.clone())
+        .data(config.links.clone())
+        .data(config.passwords.clone())
+        .data(config.image_presets.clone())
+        .data(config.quota.clone())
+        .data(EventBus::default());
 
     Server::new(TcpListener::bind("localhost:3000"))
         .run(app)
         .await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_passwords_stop_the_startup() {
+        assert!(check_hashed_passwords(0).is_ok());
+        assert_eq!(
+            check_hashed_passwords(2).unwrap_err(),
+            "Refusing to start: 2 users have a plaintext password. Run post /admin/migrate/passwords \
+             with REQUIRE_HASHED_PASSWORDS unset first"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use poem::http::{HeaderName, HeaderValue};
+use poem::{Endpoint, IntoResponse, Middleware, Request, Response, Result};
+use serde::Serialize;
+use std::io::Write;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use crate::config::LogFormat;
+
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Request ids sent by clients or proxies longer than this are replaced by a new one.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// Where `JwtMiddleware` leaves the username of an authenticated request, so it can be logged even
+// though the access log runs outside of it.
+#[derive(Clone, Default)]
+pub struct LoggedUser(Arc<OnceLock<String>>);
+
+impl LoggedUser {
+    pub fn set(&self, username: &str) {
+        let _ = self.0.set(username.to_string());
+    }
+}
+
+// One line of the access log.
+#[derive(Serialize)]
+pub struct AccessRecord {
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    // Without the query, which may hold tokens.
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: f64,
+    pub username: Option<String>,
+    pub request_id: String,
+}
+
+impl AccessRecord {
+    // Formats the record as a single line, without the line break.
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Json => serde_json::to_string(self).expect("access records always serialize"),
+            LogFormat::Text => format!(
+                "{} {} {} {} {:.1}ms user={} request_id={}",
+                self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+                self.method,
+                self.path,
+                self.status,
+                self.latency_ms,
+                self.username.as_deref().unwrap_or("-"),
+                self.request_id,
+            ),
+        }
+    }
+}
+
+// Writes a line to stdout for every request, as JSON with LOG_FORMAT=json and human readable
+// otherwise.
+//
+// Every request gets a request id, taken from its `X-Request-Id` header when it has a usable one,
+// which is logged and sent back in the `X-Request-Id` response header so clients can refer to it.
+pub struct AccessLogMiddleware {
+    format: LogFormat,
+}
+
+impl AccessLogMiddleware {
+    pub fn new(format: LogFormat) -> Self {
+        Self { format }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for AccessLogMiddleware {
+    type Output = AccessLogMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        AccessLogMiddlewareImpl { ep, format: self.format }
+    }
+}
+
+pub struct AccessLogMiddlewareImpl<E> {
+    ep: E,
+    format: LogFormat,
+}
+
+fn request_id(req: &Request) -> String {
+    req.header(&X_REQUEST_ID)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.chars().all(|c| c.is_ascii_graphic()))
+        .map(ToString::to_string)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()))
+}
+
+impl<E: Endpoint> Endpoint for AccessLogMiddlewareImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let started = Instant::now();
+        let request_id = request_id(&req);
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+        let user = LoggedUser::default();
+        req.extensions_mut().insert(user.clone());
+
+        let mut response = match self.ep.call(req).await {
+            Ok(output) => output.into_response(),
+            Err(err) => err.into_response(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(X_REQUEST_ID, value);
+        }
+
+        let record = AccessRecord {
+            timestamp: Utc::now(),
+            method,
+            path,
+            status: response.status().as_u16(),
+            latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+            username: user.0.get().cloned(),
+            request_id,
+        };
+        // Written in one call, so lines of concurrent requests don't interleave.
+        let _ = writeln!(std::io::stdout().lock(), "{}", record.format(self.format));
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(username: Option<&str>) -> AccessRecord {
+        AccessRecord {
+            timestamp: DateTime::parse_from_rfc3339("2024-05-01T12:30:00.250Z").unwrap().with_timezone(&Utc),
+            method: "GET".to_string(),
+            path: "/files".to_string(),
+            status: 200,
+            latency_ms: 12.345,
+            username: username.map(ToString::to_string),
+            request_id: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn json_lines_have_the_documented_fields() {
+        let line = record(Some("alice")).format(LogFormat::Json);
+        assert!(!line.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "timestamp": "2024-05-01T12:30:00.250Z",
+                "method": "GET",
+                "path": "/files",
+                "status": 200,
+                "latency_ms": 12.345,
+                "username": "alice",
+                "request_id": "abc",
+            })
+        );
+
+        let value: serde_json::Value = serde_json::from_str(&record(None).format(LogFormat::Json)).unwrap();
+        assert_eq!(value["username"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn text_lines_are_human_readable() {
+        assert_eq!(
+            record(Some("alice")).format(LogFormat::Text),
+            "2024-05-01T12:30:00.250Z GET /files 200 12.3ms user=alice request_id=abc"
+        );
+        assert_eq!(
+            record(None).format(LogFormat::Text),
+            "2024-05-01T12:30:00.250Z GET /files 200 12.3ms user=- request_id=abc"
+        );
+    }
+
+    #[test]
+    fn unusable_request_ids_are_replaced() {
+        let id = |value: &str| request_id(&Request::builder().header(X_REQUEST_ID, value).finish());
+        assert_eq!(id("req-1"), "req-1");
+        assert_eq!(id(&"a".repeat(MAX_REQUEST_ID_LENGTH)), "a".repeat(MAX_REQUEST_ID_LENGTH));
+        assert_ne!(id(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)).len(), MAX_REQUEST_ID_LENGTH + 1);
+        assert_ne!(id("has space"), "has space");
+        assert_eq!(request_id(&Request::default()).len(), 32);
+    }
+}
@@ -0,0 +1,168 @@
+use ipnet::IpNet;
+use poem::{Endpoint, Middleware, Request, Result};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+// The IP address of the client that sent a request, added to the request extensions by
+// `ClientIpMiddleware`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+// Determines the real client IP of every request.
+//
+// Requests from a trusted proxy carry the client IP in `X-Forwarded-For` or `Forwarded`. Those
+// headers are only believed when the connection comes from one of the trusted proxies, as anyone
+// else could put any address in them. Proxies append to the header, so it is read from the right,
+// skipping trusted proxies, and the first untrusted address is the client.
+pub struct ClientIpMiddleware {
+    trusted_proxies: Arc<Vec<IpNet>>,
+}
+
+impl ClientIpMiddleware {
+    pub fn new(trusted_proxies: &[IpNet]) -> Self {
+        Self { trusted_proxies: Arc::new(trusted_proxies.to_vec()) }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for ClientIpMiddleware {
+    type Output = ClientIpMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ClientIpMiddlewareImpl { ep, trusted_proxies: self.trusted_proxies.clone() }
+    }
+}
+
+pub struct ClientIpMiddlewareImpl<E> {
+    ep: E,
+    trusted_proxies: Arc<Vec<IpNet>>,
+}
+
+impl<E> ClientIpMiddlewareImpl<E> {
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    fn resolve(&self, req: &Request) -> Option<IpAddr> {
+        let peer = req.remote_addr().as_socket_addr()?.ip();
+        if !self.is_trusted(&peer) {
+            return Some(peer);
+        }
+
+        let chain = forwarded_chain(req);
+        // If every hop is a trusted proxy, the first one is as close to the client as we can get.
+        chain
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(ip))
+            .or(chain.first())
+            .copied()
+            .or(Some(peer))
+    }
+}
+
+// The addresses listed by the proxies, client first. `X-Forwarded-For` is used when present,
+// as it is what most proxies send, and the standard `Forwarded` header otherwise.
+fn forwarded_chain(req: &Request) -> Vec<IpAddr> {
+    let x_forwarded_for: Vec<IpAddr> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| parse_address(entry.trim()))
+        .collect();
+    if !x_forwarded_for.is_empty() {
+        return x_forwarded_for;
+    }
+
+    req.headers()
+        .get_all("Forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_address(value.trim_matches('"')))
+        })
+        .collect()
+}
+
+// Parses `1.2.3.4`, `1.2.3.4:8080`, `::1` and `[::1]:8080`.
+fn parse_address(value: &str) -> Option<IpAddr> {
+    if let Ok(ip) = value.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    value.rsplit_once(':')?.0.parse().ok()
+}
+
+impl<E: Endpoint> Endpoint for ClientIpMiddlewareImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        if let Some(ip) = self.resolve(&req) {
+            req.extensions_mut().insert(ClientIp(ip));
+        }
+        self.ep.call(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn addresses_parse_with_and_without_ports() {
+        assert_eq!(parse_address("203.0.113.7"), Some(ip("203.0.113.7")));
+        assert_eq!(parse_address("203.0.113.7:8080"), Some(ip("203.0.113.7")));
+        assert_eq!(parse_address("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_address("[2001:db8::1]:8080"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_address("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_address("unknown"), None);
+        assert_eq!(parse_address("_hidden"), None);
+        assert_eq!(parse_address(""), None);
+    }
+
+    #[test]
+    fn x_forwarded_for_is_read_client_first() {
+        let req = Request::builder()
+            .header("X-Forwarded-For", "203.0.113.7, 10.0.0.1")
+            .header("X-Forwarded-For", "10.0.0.2")
+            .finish();
+        assert_eq!(forwarded_chain(&req), vec![ip("203.0.113.7"), ip("10.0.0.1"), ip("10.0.0.2")]);
+    }
+
+    #[test]
+    fn forwarded_is_used_without_x_forwarded_for() {
+        let req = Request::builder()
+            .header("Forwarded", r#"for=203.0.113.7;proto=https, For="[2001:db8::1]:4711""#)
+            .header("Forwarded", "by=10.0.0.1;for=10.0.0.2")
+            .finish();
+        assert_eq!(forwarded_chain(&req), vec![ip("203.0.113.7"), ip("2001:db8::1"), ip("10.0.0.2")]);
+    }
+
+    #[test]
+    fn x_forwarded_for_wins_over_forwarded() {
+        let req = Request::builder()
+            .header("X-Forwarded-For", "203.0.113.7")
+            .header("Forwarded", "for=198.51.100.1")
+            .finish();
+        assert_eq!(forwarded_chain(&req), vec![ip("203.0.113.7")]);
+    }
+
+    #[test]
+    fn unparseable_entries_are_skipped() {
+        let req = Request::builder().header("X-Forwarded-For", "garbage, 203.0.113.7, ").finish();
+        assert_eq!(forwarded_chain(&req), vec![ip("203.0.113.7")]);
+        assert!(forwarded_chain(&Request::default()).is_empty());
+    }
+}
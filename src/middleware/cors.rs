@@ -0,0 +1,64 @@
+use poem::middleware::Cors;
+use crate::config::CorsConfig;
+use crate::middleware::response_time::X_RESPONSE_TIME;
+
+// Builds the CORS middleware from the configuration.
+//
+// With `*` any origin is allowed. Otherwise only the listed origins are, and other origins are
+// rejected with 403 Forbidden. The allowed origin is always echoed back in
+// `Access-Control-Allow-Origin` rather than sent as `*`, so with credentials enabled browsers
+// accept the response together with `Access-Control-Allow-Credentials: true`. `Config::load`
+// already refuses to combine credentials with `*`.
+//
+// `X-Response-Time` is exposed, so scripts on the allowed origins can read it.
+pub fn cors(config: &CorsConfig) -> Cors {
+    let cors = Cors::new()
+        .allow_credentials(config.allow_credentials)
+        .expose_header(X_RESPONSE_TIME);
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        return cors;
+    }
+
+    // Matched through a function rather than `allow_origins`, so poem adds `Vary: Origin` and
+    // caches don't serve one origin's response to another.
+    let allowed_origins = config.allowed_origins.clone();
+    cors.allow_origins_fn(move |origin| allowed_origins.iter().any(|allowed| allowed == origin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poem::endpoint::make_sync;
+    use poem::http::header::{ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN, VARY};
+    use poem::http::StatusCode;
+    use poem::{Endpoint, Middleware, Request, Response};
+
+    async fn get(origin: &str) -> Response {
+        let config = CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            allow_credentials: true,
+        };
+        let ep = cors(&config).transform(make_sync(|_| "ok"));
+        match ep.call(Request::builder().header(ORIGIN, origin).finish()).await {
+            Ok(response) => response,
+            Err(err) => err.into_response(),
+        }
+    }
+
+    #[tokio::test]
+    async fn listed_origins_are_echoed_with_credentials() {
+        let response = get("https://app.example.com").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert!(response.headers().get_all(VARY).iter().any(|vary| vary == "Origin"));
+    }
+
+    #[tokio::test]
+    async fn unlisted_origins_get_no_cors_headers() {
+        let response = get("https://evil.example.com").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+}
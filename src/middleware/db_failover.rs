@@ -0,0 +1,107 @@
+use poem::http::header::RETRY_AFTER;
+use poem::http::StatusCode;
+use poem::{Endpoint, Error, Middleware, Request, Response, Result};
+use std::time::Duration;
+use crate::database::is_failover_error;
+
+// Answers requests that failed because MongoDB had no primary, e.g. during a replica set election,
+// with `503 Service Unavailable` and a `Retry-After` header instead of `500 Internal Server Error`,
+// so clients know to try again.
+//
+// Only errors that carry the MongoDB error as their source are recognized, so handlers have to
+// keep it, e.g. with `Error::new(e, StatusCode::INTERNAL_SERVER_ERROR)`.
+pub struct DbFailoverMiddleware {
+    retry_after: Duration,
+}
+
+impl DbFailoverMiddleware {
+    pub fn new(retry_after: Duration) -> Self {
+        Self { retry_after }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for DbFailoverMiddleware {
+    type Output = DbFailoverMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        DbFailoverMiddlewareImpl { ep, retry_after: self.retry_after }
+    }
+}
+
+pub struct DbFailoverMiddlewareImpl<E> {
+    ep: E,
+    retry_after: Duration,
+}
+
+// The response for `error` if it was caused by a failover.
+pub fn failover_response(error: &Error, retry_after: Duration) -> Option<Response> {
+    let source = error.downcast_ref::<mongodb::error::Error>()?;
+    is_failover_error(source).then(|| {
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(RETRY_AFTER, retry_after.as_secs().max(1))
+            .body("The database is temporarily unavailable, try again shortly")
+    })
+}
+
+impl<E: Endpoint> Endpoint for DbFailoverMiddlewareImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        self.ep.call(req).await.map_err(|error| match failover_response(&error, self.retry_after) {
+            Some(response) => {
+                tracing::warn!(error = %error, "Rejected a request while the database has no primary");
+                Error::from_response(response)
+            }
+            None => error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::error::{CommandError, ErrorKind};
+    use poem::endpoint::make;
+
+    // A poem error wrapping a failed MongoDB command, as handlers return them.
+    fn command_error(code: i32, code_name: &str) -> Error {
+        let command_error: CommandError = bson::from_document(bson::doc! {
+            "code": code,
+            "codeName": code_name,
+            "errmsg": "test",
+        })
+        .unwrap();
+        Error::new(mongodb::error::Error::from(ErrorKind::Command(command_error)), StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    #[test]
+    fn not_primary_errors_get_503_with_retry_after() {
+        let response = failover_response(&command_error(10107, "NotWritablePrimary"), Duration::from_secs(5)).unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "5");
+    }
+
+    #[test]
+    fn retry_after_is_at_least_a_second() {
+        let response = failover_response(&command_error(189, "PrimarySteppedDown"), Duration::ZERO).unwrap();
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "1");
+    }
+
+    #[test]
+    fn other_errors_are_left_alone() {
+        assert!(failover_response(&command_error(11000, "DuplicateKey"), Duration::from_secs(5)).is_none());
+        // Without the MongoDB error as its source, the cause can't be told.
+        let bare = Error::from_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(failover_response(&bare, Duration::from_secs(5)).is_none());
+    }
+
+    #[tokio::test]
+    async fn middleware_turns_failover_errors_into_503() {
+        let ep = DbFailoverMiddleware::new(Duration::from_secs(3))
+            .transform(make(|_| async { Err::<(), _>(command_error(10107, "NotWritablePrimary")) }));
+        let response = ep.call(Request::default()).await.unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "3");
+    }
+}
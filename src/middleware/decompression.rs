@@ -0,0 +1,101 @@
+use async_compression::tokio::bufread::GzipDecoder;
+use poem::http::header::{CONTENT_ENCODING, CONTENT_LENGTH};
+use poem::http::StatusCode;
+use poem::{Body, Endpoint, Error, Middleware, Request, Result};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, BufReader, ReadBuf};
+
+// How much larger than the compressed body the decompressed body may get.
+const MAX_COMPRESSION_RATIO: u64 = 10;
+
+// Decompresses request bodies sent with `Content-Encoding: gzip`, so handlers always read plain bodies.
+//
+// The body is decompressed while the handler reads it. To stop zip bombs, it may decompress to at
+// most MAX_COMPRESSION_RATIO times the `Content-Length` of the compressed body, and never more than
+// `max_bytes`. Beyond that reading the body fails, and the handler rejects the request. Bodies
+// without a `Content-Length` are only held to `max_bytes`.
+//
+// `Content-Encoding` and `Content-Length` are removed from decompressed requests, as they describe
+// the compressed body. Encodings other than gzip are rejected with `415 Unsupported Media Type`.
+pub struct DecompressionMiddleware {
+    max_bytes: u64,
+}
+
+impl DecompressionMiddleware {
+    pub fn new(max_bytes: u64) -> Self {
+        Self { max_bytes }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for DecompressionMiddleware {
+    type Output = DecompressionMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        DecompressionMiddlewareImpl { ep, max_bytes: self.max_bytes }
+    }
+}
+
+pub struct DecompressionMiddlewareImpl<E> {
+    ep: E,
+    max_bytes: u64,
+}
+
+impl<E> DecompressionMiddlewareImpl<E> {
+    // The most bytes the body of `req` may decompress to.
+    fn limit(&self, req: &Request) -> u64 {
+        req.header(CONTENT_LENGTH)
+            .and_then(|length| length.parse::<u64>().ok())
+            .map_or(self.max_bytes, |length| length.saturating_mul(MAX_COMPRESSION_RATIO).min(self.max_bytes))
+    }
+}
+
+impl<E: Endpoint> Endpoint for DecompressionMiddlewareImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let Some(encoding) = req.header(CONTENT_ENCODING).map(|value| value.trim().to_ascii_lowercase()) else {
+            return self.ep.call(req).await;
+        };
+
+        match encoding.as_str() {
+            "gzip" | "x-gzip" => {
+                let limit = self.limit(&req);
+                let decoder = GzipDecoder::new(BufReader::new(req.take_body().into_async_read()));
+                req.set_body(Body::from_async_read(LimitedReader { inner: decoder, remaining: limit }));
+                req.headers_mut().remove(CONTENT_LENGTH);
+            }
+            "identity" => {}
+            _ => {
+                return Err(Error::from_string(
+                    format!("Unsupported Content-Encoding {}, only gzip is accepted", encoding),
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                ));
+            }
+        }
+        req.headers_mut().remove(CONTENT_ENCODING);
+        self.ep.call(req).await
+    }
+}
+
+// Fails once more than `remaining` bytes have been read.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = (buf.filled().len() - before) as u64;
+            if read > self.remaining {
+                return Poll::Ready(Err(io::Error::other("Decompressed request body is too large")));
+            }
+            self.remaining -= read;
+        }
+        result
+    }
+}
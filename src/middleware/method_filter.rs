@@ -0,0 +1,83 @@
+use poem::http::header::ALLOW;
+use poem::http::{Method, StatusCode};
+use poem::{Endpoint, Error, Middleware, Request, Response, Result};
+
+// The methods the API serves, sent in the `Allow` header of rejected requests. MOVE is the WebDAV
+// method for moving files between folders.
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, HEAD, OPTIONS, MOVE";
+
+// Rejects every request whose method isn't in ALLOWED_METHODS with `405 Method Not Allowed`,
+// before it reaches any handler or other middleware.
+//
+// This blocks `TRACE` and `TRACK`, which echo the request back and can be abused for cross-site
+// tracing to read cookies and credentials, as well as `CONNECT` and any non-standard method.
+// The rejection never includes anything from the request.
+pub struct MethodFilterMiddleware;
+
+impl<E: Endpoint> Middleware<E> for MethodFilterMiddleware {
+    type Output = MethodFilterMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        MethodFilterMiddlewareImpl { ep }
+    }
+}
+
+pub struct MethodFilterMiddlewareImpl<E> {
+    ep: E,
+}
+
+fn is_allowed(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::POST | Method::PUT | Method::PATCH | Method::DELETE | Method::HEAD | Method::OPTIONS
+    ) || method.as_str() == "MOVE"
+}
+
+impl<E: Endpoint> Endpoint for MethodFilterMiddlewareImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if !is_allowed(req.method()) {
+            let response = Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(ALLOW, ALLOWED_METHODS)
+                .finish();
+            return Err(Error::from_response(response));
+        }
+        self.ep.call(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poem::endpoint::make_sync;
+
+    #[tokio::test]
+    async fn trace_gets_405_without_the_request() {
+        let ep = MethodFilterMiddleware.transform(make_sync(|_| "reached the handler"));
+        let request = Request::builder()
+            .method(Method::TRACE)
+            .header("Cookie", "session=secret")
+            .body("echo me");
+
+        let response = ep.call(request).await.unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], ALLOWED_METHODS);
+        assert!(!response.headers().contains_key("Cookie"));
+        assert_eq!(response.into_body().into_string().await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn allowed_methods_are_passed_on() {
+        let ep = MethodFilterMiddleware.transform(make_sync(|_| "reached the handler"));
+        for method in ["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS", "MOVE"] {
+            let request = Request::builder().method(Method::from_bytes(method.as_bytes()).unwrap()).finish();
+            assert_eq!(ep.call(request).await.unwrap(), "reached the handler");
+        }
+        for method in ["TRACK", "CONNECT", "PROPFIND"] {
+            let request = Request::builder().method(Method::from_bytes(method.as_bytes()).unwrap()).finish();
+            assert_eq!(ep.call(request).await.unwrap_err().status(), StatusCode::METHOD_NOT_ALLOWED);
+        }
+    }
+}
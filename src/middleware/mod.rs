@@ -0,0 +1,11 @@
+pub mod access_log;
+pub mod client_ip;
+pub mod cors;
+pub mod db_failover;
+pub mod decompression;
+pub mod method_filter;
+pub mod options;
+pub mod rate_limit;
+pub mod response_time;
+pub mod security_headers;
+pub mod trailing_slash;
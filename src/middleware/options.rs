@@ -0,0 +1,238 @@
+use poem::http::header::ALLOW;
+use poem::http::{Method, StatusCode};
+use poem::{Endpoint, IntoResponse, Middleware, Request, Response, Result};
+
+// The methods each route is registered with in main.rs, which poem has no way to list. Keep it in
+// sync when a route is added or its methods change; `routes_match_main` fails when it isn't.
+//
+// HEAD is served for every route with GET, and OPTIONS for every route, so neither is listed.
+const ROUTES: &[(&str, &[&str])] = &[
+    ("/user/add", &["POST"]),
+    ("/user/:name", &["GET", "PUT", "DELETE"]),
+    ("/health", &["GET"]),
+    ("/login", &["POST"]),
+    ("/auth/refresh", &["POST"]),
+    ("/register", &["POST"]),
+    ("/activate/:token", &["GET"]),
+    ("/.well-known/jwks.json", &["GET"]),
+    ("/upload", &["POST"]),
+    ("/uploads/ticket", &["POST"]),
+    ("/download_file/:filename", &["GET"]),
+    ("/public/files/:id", &["GET"]),
+    ("/files", &["GET"]),
+    ("/files/categories", &["GET"]),
+    ("/files/export.csv", &["GET"]),
+    ("/files/recent", &["GET"]),
+    ("/files/by-name/:filename", &["GET"]),
+    ("/files/duplicates", &["GET"]),
+    ("/files/duplicates/resolve", &["POST"]),
+    ("/files/bulk-tag", &["POST"]),
+    ("/files/download-batch", &["POST"]),
+    ("/files/:id", &["GET", "DELETE", "MOVE"]),
+    ("/files/:id/move", &["POST"]),
+    ("/files/:id/presign", &["POST"]),
+    ("/files/:id/link-qr", &["GET"]),
+    ("/files/:id/description", &["PATCH"]),
+    ("/files/:id/content", &["PUT"]),
+    ("/files/:id/share", &["POST"]),
+    ("/files/:id/visibility", &["POST"]),
+    ("/files/:id/access-history", &["GET"]),
+    ("/files/:id/verify", &["GET"]),
+    ("/files/:id/share-link", &["POST"]),
+    ("/files/shares", &["GET"]),
+    ("/files/shares/:id", &["DELETE"]),
+    ("/shared/:token", &["GET"]),
+    ("/me/notification-preferences", &["GET", "PUT"]),
+    ("/users/batch", &["POST"]),
+    ("/users/search", &["GET"]),
+    ("/users/:name/public-profile", &["GET"]),
+    ("/me/profile", &["PUT"]),
+    ("/events", &["GET"]),
+    ("/me/notifications", &["GET"]),
+    ("/me/storage", &["GET"]),
+    ("/me/quota-alerts", &["GET"]),
+    ("/me/notifications/:id/read", &["POST"]),
+    ("/admin/broadcast", &["POST"]),
+    ("/upload_image", &["POST"]),
+    ("/download_image/:imagename", &["GET"]),
+    ("/images", &["GET"]),
+    ("/images/batch-upload", &["POST"]),
+    ("/images/:filename", &["HEAD"]),
+    ("/images/:filename/convert", &["GET"]),
+    ("/images/:filename/info", &["GET"]),
+    ("/images/:filename/:preset", &["GET"]),
+    ("/admin/index-usage", &["GET"]),
+    ("/admin/system/version", &["GET"]),
+    ("/admin/files", &["GET"]),
+    ("/admin/corruption-reports", &["GET"]),
+    ("/admin/audit/verify", &["GET"]),
+    ("/admin/users/bulk", &["DELETE"]),
+    ("/admin/users/search", &["GET"]),
+    ("/admin/users/:name/activity-timeline", &["GET"]),
+    ("/admin/users/:name/roles", &["PATCH"]),
+    ("/admin/users/:name/permissions-check", &["GET"]),
+    ("/admin/maintenance/metadata-sync", &["GET"]),
+    ("/admin/repair/username-index", &["POST"]),
+    ("/admin/maintenance/vacuum", &["POST"]),
+    ("/admin/maintenance/jobs/:id", &["GET"]),
+    ("/admin/migrate/passwords", &["POST"]),
+    ("/admin/migrate/checksums", &["POST"]),
+    ("/csp-report", &["POST"]),
+    ("/password/strength", &["POST"]),
+];
+
+// Answers `OPTIONS` requests for known routes with `204 No Content` and an `Allow` header listing
+// the methods of the route, without a token and before the rate limiter.
+//
+// CORS preflight requests from allowed origins are answered by the CORS middleware before they get
+// here. `OPTIONS` requests for unknown paths are passed on, and answered by `cors_preflight`.
+pub struct OptionsMiddleware;
+
+impl<E: Endpoint> Middleware<E> for OptionsMiddleware {
+    type Output = OptionsMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        OptionsMiddlewareImpl { ep }
+    }
+}
+
+pub struct OptionsMiddlewareImpl<E> {
+    ep: E,
+}
+
+// Finds the methods of the route matching `path`, preferring static segments over parameters like
+// the router does, so `/files/shares` isn't taken for `/files/:id`. Trailing slashes are ignored,
+// as they are removed before routing.
+fn route_methods(path: &str) -> Option<&'static [&'static str]> {
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    ROUTES
+        .iter()
+        .filter_map(|(pattern, methods)| {
+            let pattern: Vec<&str> = pattern.split('/').collect();
+            let matches = pattern.len() == segments.len()
+                && pattern
+                    .iter()
+                    .zip(&segments)
+                    .all(|(expected, actual)| expected == actual || (expected.starts_with(':') && !actual.is_empty()));
+            // Compared element by element, so the route whose first parameter comes latest wins.
+            let parameters: Vec<bool> = pattern.iter().map(|segment| segment.starts_with(':')).collect();
+            matches.then_some((parameters, *methods))
+        })
+        .min_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, methods)| methods)
+}
+
+// The value of the `Allow` header for a route with `methods`.
+fn allow(methods: &[&str]) -> String {
+    let mut allowed: Vec<&str> = methods.to_vec();
+    if methods.contains(&"GET") && !methods.contains(&"HEAD") {
+        allowed.push("HEAD");
+    }
+    allowed.push("OPTIONS");
+    allowed.join(", ")
+}
+
+impl<E: Endpoint> Endpoint for OptionsMiddlewareImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if req.method() == Method::OPTIONS
+            && let Some(methods) = route_methods(req.uri().path())
+        {
+            return Ok(StatusCode::NO_CONTENT.with_header(ALLOW, allow(methods)).into_response());
+        }
+        self.ep.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poem::endpoint::make_sync;
+    use poem::http::Uri;
+
+    fn options(path: &str) -> Request {
+        Request::builder().method(Method::OPTIONS).uri(path.parse::<Uri>().unwrap()).finish()
+    }
+
+    // The routes registered in main.rs, with the methods of each, read from its source.
+    fn registered_routes() -> Vec<(String, Vec<String>)> {
+        let source = include_str!("../main.rs");
+        let start = source.find("Route::new()").unwrap();
+        let end = source.find(".at(\"/*path\"").unwrap();
+        let code: String = source[start..end]
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("//"))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        code.split(".at(")
+            .skip(1)
+            .map(|route| {
+                let path = route.split('"').nth(1).unwrap().to_string();
+                let mut methods: Vec<String> = ["get", "post", "put", "delete", "patch", "head"]
+                    .iter()
+                    .filter(|method| {
+                        route.match_indices(&format!("{}(", method)).any(|(i, _)| {
+                            !route[..i].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+                        })
+                    })
+                    .map(|method| method.to_uppercase())
+                    .collect();
+                if let Some((_, custom)) = route.split_once("from_str(\"") {
+                    methods.push(custom.split('"').next().unwrap().to_string());
+                }
+                (path, methods)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn options_lists_the_methods_of_the_route() {
+        let ep = OptionsMiddleware.transform(make_sync(|_| "passed on"));
+
+        let response = ep.call(options("/user/bob")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[ALLOW], "GET, PUT, DELETE, HEAD, OPTIONS");
+
+        let response = ep.call(options("/unknown")).await.unwrap();
+        assert_eq!(response.into_body().into_string().await.unwrap(), "passed on");
+    }
+
+    #[test]
+    fn static_segments_win_over_parameters() {
+        assert_eq!(route_methods("/files/shares"), Some(&["GET"][..]));
+        assert_eq!(route_methods("/files/shares/"), Some(&["GET"][..]));
+        assert_eq!(route_methods("/files/abc"), Some(&["GET", "DELETE", "MOVE"][..]));
+        assert_eq!(route_methods("/files/shares/abc"), Some(&["DELETE"][..]));
+        assert_eq!(route_methods("/images/a.png/info"), Some(&["GET"][..]));
+        assert_eq!(route_methods("/images/a.png/thumbnail"), Some(&["GET"][..]));
+        assert_eq!(route_methods("/files//move"), None);
+        assert_eq!(route_methods("/nope"), None);
+    }
+
+    #[test]
+    fn routes_match_main() {
+        let normalize = |methods: &[String]| {
+            let mut methods: Vec<String> = methods
+                .iter()
+                .filter(|method| *method != "HEAD" || !methods.contains(&"GET".to_string()))
+                .cloned()
+                .collect();
+            methods.sort();
+            methods
+        };
+        let mut registered: Vec<(String, Vec<String>)> =
+            registered_routes().into_iter().map(|(path, methods)| (path, normalize(&methods))).collect();
+        let mut listed: Vec<(String, Vec<String>)> = ROUTES
+            .iter()
+            .map(|(path, methods)| {
+                let methods: Vec<String> = methods.iter().map(|method| method.to_string()).collect();
+                (path.to_string(), normalize(&methods))
+            })
+            .collect();
+        registered.sort();
+        listed.sort();
+        assert_eq!(registered, listed);
+    }
+}
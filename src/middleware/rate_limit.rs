@@ -0,0 +1,269 @@
+use poem::http::header::RETRY_AFTER;
+use poem::http::{HeaderMap, StatusCode};
+use poem::{Endpoint, Error, Middleware, Request, Response, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::auth::AuthUser;
+use crate::config::RateLimitConfig;
+use crate::middleware::client_ip::ClientIp;
+
+// A fixed-window rate limiter.
+//
+// Anonymous clients are keyed by IP address, as determined by `ClientIpMiddleware`, and may make
+// `max_requests` requests per `window`.
+// Authenticated users are keyed by username, and get the highest limit configured for any of
+// their roles, falling back to `max_requests`. Anonymous traffic therefore always gets the
+// strictest limit, as long as role limits are at least `max_requests`.
+//
+// Every response, including errors, carries `X-RateLimit-Limit`, `X-RateLimit-Remaining`,
+// `X-RateLimit-Reset` (unix timestamp in seconds) and `X-RateLimit-Policy` (`<limit>;w=<window seconds>`),
+// so clients can slow down before hitting the limit. Requests beyond the limit are rejected with
+// `429 Too Many Requests` and a `Retry-After` header until the window resets. Role limits only apply when the middleware runs inside `JwtMiddleware`,
+// as that is what attaches the `AuthUser`.
+pub struct RateLimitMiddleware {
+    max_requests: u32,
+    window: Duration,
+    role_limits: Arc<Vec<(String, u32)>>,
+    clients: Arc<Mutex<HashMap<String, Window>>>,
+}
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(max_requests: u32, window: Duration) -> Self {
+        Self {
+            max_requests,
+            window,
+            role_limits: Arc::new(Vec::new()),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Builds a limiter with per-role limits from the configuration.
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        let mut limiter = Self::new(config.anonymous, config.window);
+        limiter.role_limits = Arc::new(config.roles.clone());
+        limiter
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimitMiddleware {
+    type Output = RateLimitMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RateLimitMiddlewareImpl {
+            ep,
+            max_requests: self.max_requests,
+            window: self.window,
+            role_limits: self.role_limits.clone(),
+            clients: self.clients.clone(),
+        }
+    }
+}
+
+pub struct RateLimitMiddlewareImpl<E> {
+    ep: E,
+    max_requests: u32,
+    window: Duration,
+    role_limits: Arc<Vec<(String, u32)>>,
+    clients: Arc<Mutex<HashMap<String, Window>>>,
+}
+
+impl<E> RateLimitMiddlewareImpl<E> {
+    // Determines which bucket the request counts against, and the limit of that bucket.
+    fn bucket(&self, req: &Request) -> (String, u32) {
+        match req.extensions().get::<AuthUser>() {
+            Some(user) => {
+                let limit = self
+                    .role_limits
+                    .iter()
+                    .filter(|(role, _)| user.has_role(role))
+                    .map(|(_, limit)| *limit)
+                    .max()
+                    .unwrap_or(self.max_requests);
+                (format!("user:{}", user.username), limit)
+            }
+            None => {
+                let ip = req
+                    .extensions()
+                    .get::<ClientIp>()
+                    .map(|ip| ip.0.to_string())
+                    .unwrap_or_default();
+                (format!("ip:{}", ip), self.max_requests)
+            }
+        }
+    }
+
+    // Counts the request against the client's current window.
+    //
+    // # Returns
+    // - `Ok(state)` if the client is still within the limit.
+    // - `Err(state)` if the limit is exceeded. The request isn't counted.
+    fn check(&self, client: String, limit: u32) -> Result<LimitState, LimitState> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        // Drop clients whose window has expired so the map doesn't grow without bound.
+        clients.retain(|_, window| now.duration_since(window.started) < self.window);
+
+        let window = clients.entry(client).or_insert(Window { started: now, count: 0 });
+        let exceeded = window.count >= limit;
+        if !exceeded {
+            window.count += 1;
+        }
+        let state = LimitState {
+            limit,
+            remaining: limit.saturating_sub(window.count),
+            reset_in: self.window.saturating_sub(now.duration_since(window.started)),
+            window: self.window,
+        };
+        if exceeded { Err(state) } else { Ok(state) }
+    }
+}
+
+// Where a client stands in its current window.
+struct LimitState {
+    limit: u32,
+    remaining: u32,
+    // Time until the window resets.
+    reset_in: Duration,
+    window: Duration,
+}
+
+impl LimitState {
+    // Seconds until the window resets, rounded up so clients never retry too early.
+    fn reset_secs(&self) -> u64 {
+        self.reset_in.as_secs() + u64::from(self.reset_in.subsec_nanos() > 0)
+    }
+
+    fn reset_timestamp(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        now.as_secs() + self.reset_secs()
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert("X-RateLimit-Limit", self.limit.into());
+        headers.insert("X-RateLimit-Remaining", self.remaining.into());
+        headers.insert("X-RateLimit-Reset", self.reset_timestamp().into());
+        if let Ok(policy) = format!("{};w={}", self.limit, self.window.as_secs()).parse() {
+            headers.insert("X-RateLimit-Policy", policy);
+        }
+    }
+}
+
+impl<E: Endpoint> Endpoint for RateLimitMiddlewareImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let (client, limit) = self.bucket(&req);
+        let (state, limited) = match self.check(client.clone(), limit) {
+            Ok(state) => (state, false),
+            Err(state) => (state, true),
+        };
+        tracing::debug!(
+            client = %client,
+            limit = state.limit,
+            remaining = state.remaining,
+            reset = state.reset_timestamp(),
+            limited,
+            "Rate limit checked"
+        );
+
+        if limited {
+            let mut response = Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, state.reset_secs().max(1))
+                .body("Too many requests");
+            state.apply(response.headers_mut());
+            return Err(Error::from_response(response));
+        }
+
+        let mut response = self.ep.get_response(req).await;
+        state.apply(response.headers_mut());
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poem::endpoint::make_sync;
+
+    fn state(reset_in: Duration) -> LimitState {
+        LimitState { limit: 10, remaining: 5, reset_in, window: Duration::from_secs(60) }
+    }
+
+    #[test]
+    fn reset_secs_rounds_up() {
+        assert_eq!(state(Duration::ZERO).reset_secs(), 0);
+        assert_eq!(state(Duration::from_secs(30)).reset_secs(), 30);
+        assert_eq!(state(Duration::from_millis(29_001)).reset_secs(), 30);
+        assert_eq!(state(Duration::from_nanos(1)).reset_secs(), 1);
+    }
+
+    #[test]
+    fn headers_describe_the_window() {
+        let mut headers = HeaderMap::new();
+        state(Duration::from_secs(30)).apply(&mut headers);
+        assert_eq!(headers["X-RateLimit-Limit"], "10");
+        assert_eq!(headers["X-RateLimit-Remaining"], "5");
+        assert_eq!(headers["X-RateLimit-Policy"], "10;w=60");
+        let reset: u64 = headers["X-RateLimit-Reset"].to_str().unwrap().parse().unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!((now + 29..=now + 31).contains(&reset));
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_limit_get_429() {
+        let ep = RateLimitMiddleware::new(2, Duration::from_secs(60)).transform(make_sync(|_| "ok"));
+
+        let first = ep.call(Request::default()).await.unwrap();
+        assert_eq!(first.headers()["X-RateLimit-Remaining"], "1");
+        let second = ep.call(Request::default()).await.unwrap();
+        assert_eq!(second.headers()["X-RateLimit-Remaining"], "0");
+
+        let response = ep.call(Request::default()).await.unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["X-RateLimit-Remaining"], "0");
+        let retry_after: u64 = response.headers()[RETRY_AFTER].to_str().unwrap().parse().unwrap();
+        assert!((59..=60).contains(&retry_after));
+    }
+
+    #[tokio::test]
+    async fn admins_get_their_role_limit() {
+        let config = RateLimitConfig {
+            window: Duration::from_secs(60),
+            anonymous: 1,
+            roles: vec![("user".to_string(), 2), ("admin".to_string(), 5)],
+        };
+        let ep = RateLimitMiddleware::from_config(&config).transform(make_sync(|_| "ok"));
+        let request = |username: &str, permissions: &[&str]| {
+            let user = AuthUser {
+                username: username.to_string(),
+                permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            };
+            Request::builder().extension(user).finish()
+        };
+
+        for _ in 0..2 {
+            assert!(ep.call(request("bob", &["user"])).await.is_ok());
+        }
+        let error = ep.call(request("bob", &["user"])).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        for _ in 0..5 {
+            let response = ep.call(request("alice", &["user", "admin"])).await.unwrap();
+            assert_eq!(response.headers()["X-RateLimit-Limit"], "5");
+        }
+        let error = ep.call(request("alice", &["user", "admin"])).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert!(ep.call(Request::default()).await.is_ok());
+        let error = ep.call(Request::default()).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
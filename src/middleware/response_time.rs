@@ -0,0 +1,72 @@
+use poem::http::{HeaderName, HeaderValue};
+use poem::{Endpoint, IntoResponse, Middleware, Request, Response, Result};
+use std::time::Instant;
+
+pub const X_RESPONSE_TIME: HeaderName = HeaderName::from_static("x-response-time");
+
+// Sets the `X-Response-Time` header of every response, error responses included, to the time in
+// milliseconds it took to produce, e.g. `12.345`.
+//
+// The time only covers the app itself. For a streamed body it ends once the headers are ready,
+// not once the body has been sent.
+pub struct ResponseTimeMiddleware;
+
+impl<E: Endpoint> Middleware<E> for ResponseTimeMiddleware {
+    type Output = ResponseTimeMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ResponseTimeMiddlewareImpl { ep }
+    }
+}
+
+pub struct ResponseTimeMiddlewareImpl<E> {
+    ep: E,
+}
+
+impl<E: Endpoint> Endpoint for ResponseTimeMiddlewareImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let started = Instant::now();
+        let mut response = match self.ep.call(req).await {
+            Ok(output) => output.into_response(),
+            Err(err) => err.into_response(),
+        };
+
+        let elapsed = format!("{:.3}", started.elapsed().as_secs_f64() * 1000.0);
+        if let Ok(value) = HeaderValue::from_str(&elapsed) {
+            response.headers_mut().insert(X_RESPONSE_TIME, value);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poem::endpoint::make_sync;
+    use poem::http::StatusCode;
+    use poem::Error;
+
+    fn response_time(response: &Response) -> f64 {
+        response.headers()[X_RESPONSE_TIME].to_str().unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn successful_responses_are_timed() {
+        let ep = ResponseTimeMiddleware.transform(make_sync(|_| "ok"));
+        let response = ep.call(Request::default()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response_time(&response) >= 0.0);
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_timed() {
+        let ep = ResponseTimeMiddleware.transform(make_sync(|_| -> Result<&'static str> {
+            Err(Error::from_status(StatusCode::NOT_FOUND))
+        }));
+        let response = ep.call(Request::default()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response_time(&response) >= 0.0);
+    }
+}
@@ -0,0 +1,150 @@
+use poem::http::header::{CONTENT_SECURITY_POLICY, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS};
+use poem::http::uri::Scheme;
+use poem::http::{HeaderName, HeaderValue};
+use poem::{Endpoint, IntoResponse, Middleware, Request, Response, Result};
+use std::sync::Arc;
+use crate::config::SecurityHeadersConfig;
+
+// Adds the configured security headers to every response, including error responses.
+//
+// Headers already set by a handler are left alone, so a handler can e.g. relax the
+// Content-Security-Policy for a single route. `Strict-Transport-Security` is only sent when the
+// request came in over TLS, either directly or through a proxy setting `X-Forwarded-Proto: https`.
+pub struct SecurityHeadersMiddleware {
+    config: Arc<SecurityHeadersConfig>,
+}
+
+impl SecurityHeadersMiddleware {
+    pub fn new(config: &SecurityHeadersConfig) -> Self {
+        Self { config: Arc::new(config.clone()) }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for SecurityHeadersMiddleware {
+    type Output = SecurityHeadersMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        SecurityHeadersMiddlewareImpl { ep, config: self.config.clone() }
+    }
+}
+
+pub struct SecurityHeadersMiddlewareImpl<E> {
+    ep: E,
+    config: Arc<SecurityHeadersConfig>,
+}
+
+fn is_tls(req: &Request) -> bool {
+    req.scheme() == &Scheme::HTTPS
+        || req
+            .header("X-Forwarded-Proto")
+            .is_some_and(|proto| proto.eq_ignore_ascii_case("https"))
+}
+
+fn set_default(response: &mut Response, name: HeaderName, value: &Option<String>) {
+    if let Some(value) = value.as_deref().filter(|_| !response.headers().contains_key(&name)) {
+        // The values are validated when the configuration is loaded.
+        response.headers_mut().insert(name, HeaderValue::from_str(value).unwrap());
+    }
+}
+
+impl<E: Endpoint> Endpoint for SecurityHeadersMiddlewareImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let tls = is_tls(&req);
+        let mut response = match self.ep.call(req).await {
+            Ok(output) => output.into_response(),
+            Err(err) => err.into_response(),
+        };
+
+        if tls {
+            set_default(&mut response, STRICT_TRANSPORT_SECURITY, &self.config.strict_transport_security);
+        }
+        set_default(&mut response, X_CONTENT_TYPE_OPTIONS, &self.config.content_type_options);
+        set_default(&mut response, X_FRAME_OPTIONS, &self.config.frame_options);
+        set_default(&mut response, CONTENT_SECURITY_POLICY, &self.config.content_security_policy);
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::Client;
+    use poem::http::StatusCode;
+    use poem::test::{TestClient, TestResponse};
+    use poem::{get, post, EndpointExt, Route};
+    use crate::api_handlers::file_handlers::get_files;
+    use crate::api_handlers::health_handlers::{health, Readiness};
+    use crate::api_handlers::user_handlers::login;
+    use crate::auth::middleware::JwtMiddleware;
+    use crate::config::Config;
+    use crate::database::auth_event_db::AuthEventLog;
+
+    // The routes under test with the middleware that decides their headers, against a MongoDB that
+    // can't be reached. None of the requests below get far enough to need it.
+    async fn client() -> TestClient<impl Endpoint> {
+        let config = Config::load();
+        let db = Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("security_headers_tests");
+        let app = Route::new()
+            .at("/health", get(health))
+            .at("/login", post(login))
+            .at("/files", get(get_files))
+            .with(JwtMiddleware::new(&config.auth))
+            .with(SecurityHeadersMiddleware::new(&config.security_headers))
+            .data(Readiness::default())
+            .data(Arc::new(db.collection::<crate::database::user_db::User>("users")))
+            .data(Arc::new(db.collection::<crate::database::refresh_token_db::RefreshToken>("refresh_tokens")))
+            .data(AuthEventLog::new(Arc::new(db.collection("auth_events")), Arc::new(db.collection("audit_chain_heads")), false))
+            .data(Arc::new(db.collection::<crate::database::file_metadata_db::FileMetadata>("file_metadata")))
+            .data(config.auth.clone())
+            .data(config.queries.clone());
+        TestClient::new(app)
+    }
+
+    fn assert_security_headers(response: &TestResponse) {
+        response.assert_header(X_CONTENT_TYPE_OPTIONS, "nosniff");
+        response.assert_header(X_FRAME_OPTIONS, "DENY");
+        response.assert_header("Strict-Transport-Security", "max-age=31536000; includeSubDomains");
+        response.assert_header_exist(CONTENT_SECURITY_POLICY);
+        response.assert_header_is_not_exist("Server");
+    }
+
+    #[tokio::test]
+    async fn headers_are_sent_on_success_and_error_responses() {
+        let client = client().await;
+
+        // Not ready, as the startup setup never ran.
+        let response = client.get("/health").header("X-Forwarded-Proto", "https").send().await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_security_headers(&response);
+
+        let response = client
+            .post("/login")
+            .header("X-Forwarded-Proto", "https")
+            .body_json(&serde_json::json!({ "username": "", "password": "" }))
+            .send()
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+        assert_security_headers(&response);
+
+        let response = client.get("/files").header("X-Forwarded-Proto", "https").send().await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+        assert_security_headers(&response);
+
+        let response = client.get("/does-not-exist").header("X-Forwarded-Proto", "https").send().await;
+        response.assert_status(StatusCode::NOT_FOUND);
+        assert_security_headers(&response);
+    }
+
+    #[tokio::test]
+    async fn hsts_is_only_sent_over_tls() {
+        let response = client().await.get("/health").send().await;
+        response.assert_header_is_not_exist("Strict-Transport-Security");
+        response.assert_header(X_CONTENT_TYPE_OPTIONS, "nosniff");
+    }
+}
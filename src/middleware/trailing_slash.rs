@@ -0,0 +1,125 @@
+use poem::http::header::LOCATION;
+use poem::http::uri::PathAndQuery;
+use poem::http::{StatusCode, Uri};
+use poem::{Endpoint, Error, Middleware, Request, Response, Result};
+
+// Makes `/path/` resolve to the same route as `/path`, as the router treats them as different paths.
+//
+// By default the trailing slashes are removed before routing, so both are served the same.
+// With `redirect` set, requests with a trailing slash are answered with
+// `308 Permanent Redirect` to the path without it instead, keeping the query string. A 308
+// keeps the method and body, so uploads are redirected too.
+pub struct TrailingSlashMiddleware {
+    redirect: bool,
+}
+
+impl TrailingSlashMiddleware {
+    pub fn new(redirect: bool) -> Self {
+        Self { redirect }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for TrailingSlashMiddleware {
+    type Output = TrailingSlashMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        TrailingSlashMiddlewareImpl { ep, redirect: self.redirect }
+    }
+}
+
+pub struct TrailingSlashMiddlewareImpl<E> {
+    ep: E,
+    redirect: bool,
+}
+
+// Returns the path and query of `uri` without the trailing slashes of the path, or `None` if the
+// path has none. The root path `/` is left alone.
+//
+// Leading slashes are collapsed into one, and paths starting with a backslash are left alone, as
+// browsers read `Location: //host` and `/\host` as another site, which would be an open redirect.
+fn trimmed(uri: &Uri) -> Option<String> {
+    let path = uri.path();
+    let trimmed = path.trim_end_matches('/').trim_start_matches('/');
+    if !path.ends_with('/') || trimmed.is_empty() || trimmed.starts_with('\\') {
+        return None;
+    }
+    let trimmed = format!("/{}", trimmed);
+    Some(match uri.query() {
+        Some(query) => format!("{}?{}", trimmed, query),
+        None => trimmed.to_string(),
+    })
+}
+
+impl<E: Endpoint> Endpoint for TrailingSlashMiddlewareImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let Some(target) = trimmed(req.uri()) else {
+            return self.ep.call(req).await;
+        };
+
+        if self.redirect {
+            let response = Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(LOCATION, target)
+                .finish();
+            return Err(Error::from_response(response));
+        }
+
+        // Only the path changes, so the scheme and authority of absolute URIs are kept.
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = target.parse::<PathAndQuery>().ok();
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+        self.ep.call(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poem::handler;
+    use poem::test::TestClient;
+    use poem::web::Query;
+    use poem::{EndpointExt, Route, get};
+    use std::collections::HashMap;
+
+    #[handler]
+    fn files(Query(query): Query<HashMap<String, String>>) -> String {
+        format!("files {:?}", query.get("page"))
+    }
+
+    fn app(redirect: bool) -> impl Endpoint {
+        Route::new().at("/files", get(files)).with(TrailingSlashMiddleware::new(redirect))
+    }
+
+    #[tokio::test]
+    async fn trailing_slashes_are_removed_before_routing() {
+        let client = TestClient::new(app(false));
+        let response = client.get("/files/").query("page", &"2").send().await;
+        response.assert_status_is_ok();
+        response.assert_text("files Some(\"2\")").await;
+
+        client.get("/files//").send().await.assert_text("files None").await;
+        client.get("/files").send().await.assert_text("files None").await;
+    }
+
+    #[tokio::test]
+    async fn trailing_slashes_are_redirected() {
+        let client = TestClient::new(app(true));
+        let response = client.get("/files/").query("page", &"2").send().await;
+        response.assert_status(StatusCode::PERMANENT_REDIRECT);
+        response.assert_header(LOCATION, "/files?page=2");
+
+        client.get("/files").send().await.assert_text("files None").await;
+    }
+
+    #[test]
+    fn redirects_stay_on_this_site() {
+        assert_eq!(trimmed(&Uri::from_static("/")), None);
+        assert_eq!(trimmed(&Uri::from_static("/files")), None);
+        assert_eq!(trimmed(&Uri::from_static("//evil.example.com/")).as_deref(), Some("/evil.example.com"));
+        assert_eq!(trimmed(&Uri::from_static("/\\evil.example.com/")), None);
+    }
+}
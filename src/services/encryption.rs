@@ -0,0 +1,274 @@
+use aes_gcm::aead::{Aead, OsRng, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io;
+use std::sync::LazyLock;
+
+// File content is encrypted at rest with AES-256-GCM when FILE_ENCRYPTION_KEY holds a base64
+// encoded 32 byte key. Without it content is stored as is.
+//
+// Content is encrypted where it is stored, so hashes, deduplication and sizes all keep working on
+// the plaintext and clients never notice. Content stored before encryption was turned on stays
+// readable, as it is marked as unencrypted.
+static CIPHER: LazyLock<Option<Aes256Gcm>> = LazyLock::new(|| {
+    let key = std::env::var("FILE_ENCRYPTION_KEY").ok()?;
+    Some(cipher_from_key(&key).unwrap_or_else(|e| panic!("FILE_ENCRYPTION_KEY {}", e)))
+});
+
+// Builds the cipher for a base64 encoded 32 byte key, as FILE_ENCRYPTION_KEY holds it.
+fn cipher_from_key(key: &str) -> Result<Aes256Gcm, String> {
+    let key = STANDARD
+        .decode(key.trim())
+        .map_err(|e| format!("is not valid base64: {}", e))?;
+    if key.len() != 32 {
+        return Err(format!("must be 32 bytes, got {}", key.len()));
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+pub const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+// Large files are encrypted in segments of this many bytes, so they can be encrypted while they
+// are streamed into GridFS.
+const SEGMENT_LENGTH: usize = 64 * 1024;
+
+// Whether new content is encrypted. Also validates the key, so call it at startup.
+pub fn enabled() -> bool {
+    CIPHER.is_some()
+}
+
+fn cipher() -> io::Result<&'static Aes256Gcm> {
+    CIPHER
+        .as_ref()
+        .ok_or_else(|| io::Error::other("Content is encrypted, but FILE_ENCRYPTION_KEY isn't set"))
+}
+
+// Encrypts `plaintext` in one piece.
+//
+// # Returns
+// - `Some((ciphertext, nonce))` when encryption is enabled, `None` otherwise.
+pub fn encrypt(plaintext: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    CIPHER.as_ref().map(|cipher| encrypt_with(cipher, plaintext))
+}
+
+fn encrypt_with(cipher: &Aes256Gcm, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, plaintext).expect("AES-GCM encryption doesn't fail");
+    (ciphertext, nonce.to_vec())
+}
+
+// Decrypts content encrypted by `encrypt`. Fails if it has been tampered with.
+pub fn decrypt(ciphertext: &[u8], nonce: &[u8]) -> io::Result<Vec<u8>> {
+    decrypt_with(cipher()?, ciphertext, nonce)
+}
+
+fn decrypt_with(cipher: &Aes256Gcm, ciphertext: &[u8], nonce: &[u8]) -> io::Result<Vec<u8>> {
+    if nonce.len() != NONCE_LENGTH {
+        return Err(io::Error::other("Invalid nonce"));
+    }
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| io::Error::other("Failed to decrypt content"))
+}
+
+// The index of a segment and whether it is the last one are authenticated along with it, so
+// segments can't be reordered, dropped or cut off at the end without decryption failing.
+fn segment_aad(index: u64, last: bool) -> [u8; 9] {
+    let mut aad = [0; 9];
+    aad[..8].copy_from_slice(&index.to_be_bytes());
+    aad[8] = last as u8;
+    aad
+}
+
+// Encrypts a stream in segments of SEGMENT_LENGTH bytes, each written as its nonce followed by
+// the ciphertext and tag. The last segment is always shorter than a full one, and may be empty.
+pub struct SegmentEncryptor {
+    cipher: &'static Aes256Gcm,
+    buffer: Vec<u8>,
+    index: u64,
+}
+
+impl SegmentEncryptor {
+    // Returns `None` when encryption isn't enabled.
+    pub fn new() -> Option<Self> {
+        CIPHER.as_ref().map(Self::with_cipher)
+    }
+
+    fn with_cipher(cipher: &'static Aes256Gcm) -> Self {
+        Self { cipher, buffer: Vec::with_capacity(SEGMENT_LENGTH), index: 0 }
+    }
+
+    fn seal(&mut self, plaintext: &[u8], last: bool) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = segment_aad(self.index, last);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &aad })
+            .expect("AES-GCM encryption doesn't fail");
+        self.index += 1;
+
+        let mut segment = nonce.to_vec();
+        segment.extend_from_slice(&ciphertext);
+        segment
+    }
+
+    // Takes the next part of the plaintext, returning the encrypted segments it completed.
+    pub fn update(&mut self, mut plaintext: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        while !plaintext.is_empty() {
+            let take = (SEGMENT_LENGTH - self.buffer.len()).min(plaintext.len());
+            self.buffer.extend_from_slice(&plaintext[..take]);
+            plaintext = &plaintext[take..];
+            if self.buffer.len() == SEGMENT_LENGTH {
+                let segment = std::mem::take(&mut self.buffer);
+                output.extend(self.seal(&segment, false));
+            }
+        }
+        output
+    }
+
+    // Encrypts the rest of the plaintext as the last segment.
+    pub fn finish(mut self) -> Vec<u8> {
+        let rest = std::mem::take(&mut self.buffer);
+        self.seal(&rest, true)
+    }
+}
+
+// Decrypts content written by `SegmentEncryptor`.
+pub fn decrypt_segments(data: &[u8]) -> io::Result<Vec<u8>> {
+    decrypt_segments_with(cipher()?, data)
+}
+
+fn decrypt_segments_with(cipher: &Aes256Gcm, data: &[u8]) -> io::Result<Vec<u8>> {
+    let segment_length = NONCE_LENGTH + SEGMENT_LENGTH + TAG_LENGTH;
+    // Counting the short last segment. When the content ends on a full segment none is marked as
+    // last, and the check below rejects it.
+    let segments = data.len() / segment_length + 1;
+
+    let mut plaintext = Vec::with_capacity(data.len());
+    for (index, segment) in data.chunks(segment_length).enumerate() {
+        let last = index + 1 == segments;
+        if segment.len() < NONCE_LENGTH + TAG_LENGTH {
+            return Err(io::Error::other("Encrypted content is truncated"));
+        }
+        let (nonce, ciphertext) = segment.split_at(NONCE_LENGTH);
+        let aad = segment_aad(index as u64, last);
+        let decrypted = cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| io::Error::other("Failed to decrypt content"))?;
+        plaintext.extend(decrypted);
+    }
+    if data.len() % segment_length == 0 {
+        // The last segment is never full, so content ending on a full segment has lost its end.
+        return Err(io::Error::other("Encrypted content is truncated"));
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEGMENT: usize = NONCE_LENGTH + SEGMENT_LENGTH + TAG_LENGTH;
+
+    fn test_cipher(byte: u8) -> &'static Aes256Gcm {
+        Box::leak(Box::new(cipher_from_key(&STANDARD.encode([byte; 32])).unwrap()))
+    }
+
+    // Encrypts `plaintext` in uneven pieces, as it would arrive from a client.
+    fn encrypt_segments(cipher: &'static Aes256Gcm, plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor = SegmentEncryptor::with_cipher(cipher);
+        let mut encrypted = Vec::new();
+        for piece in plaintext.chunks(10_007) {
+            encrypted.extend(encryptor.update(piece));
+        }
+        encrypted.extend(encryptor.finish());
+        encrypted
+    }
+
+    fn content(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes_of_base64() {
+        assert!(cipher_from_key(&STANDARD.encode([1; 32])).is_ok());
+        assert!(cipher_from_key(&format!(" {}\n", STANDARD.encode([1; 32]))).is_ok());
+        assert!(cipher_from_key(&STANDARD.encode([1; 16])).is_err());
+        assert!(cipher_from_key("not base64!").is_err());
+    }
+
+    #[test]
+    fn content_round_trips_in_one_piece() {
+        let cipher = test_cipher(1);
+        let plaintext = content(1000);
+        let (ciphertext, nonce) = encrypt_with(cipher, &plaintext);
+        assert_ne!(ciphertext[..plaintext.len()], plaintext[..]);
+        assert_eq!(decrypt_with(cipher, &ciphertext, &nonce).unwrap(), plaintext);
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert!(decrypt_with(cipher, &tampered, &nonce).is_err());
+        assert!(decrypt_with(test_cipher(2), &ciphertext, &nonce).is_err());
+        assert!(decrypt_with(cipher, &ciphertext, &nonce[1..]).is_err());
+    }
+
+    #[test]
+    fn segments_round_trip_at_every_boundary() {
+        let cipher = test_cipher(1);
+        for length in [0, 1, SEGMENT_LENGTH - 1, SEGMENT_LENGTH, SEGMENT_LENGTH + 1, 3 * SEGMENT_LENGTH + 5] {
+            let plaintext = content(length);
+            let encrypted = encrypt_segments(cipher, &plaintext);
+            // Every segment adds a nonce and a tag, and the last one is never full.
+            assert_eq!(encrypted.len(), length + (length / SEGMENT_LENGTH + 1) * (NONCE_LENGTH + TAG_LENGTH));
+            let first = length.min(SEGMENT_LENGTH);
+            if first > 0 {
+                assert_ne!(encrypted[NONCE_LENGTH..NONCE_LENGTH + first], plaintext[..first]);
+            }
+            assert_eq!(decrypt_segments_with(cipher, &encrypted).unwrap(), plaintext, "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn reordered_segments_fail_to_decrypt() {
+        let cipher = test_cipher(1);
+        let encrypted = encrypt_segments(cipher, &content(2 * SEGMENT_LENGTH + 1));
+        let mut swapped = encrypted[SEGMENT..2 * SEGMENT].to_vec();
+        swapped.extend_from_slice(&encrypted[..SEGMENT]);
+        swapped.extend_from_slice(&encrypted[2 * SEGMENT..]);
+        assert!(decrypt_segments_with(cipher, &swapped).is_err());
+    }
+
+    #[test]
+    fn dropped_or_truncated_segments_fail_to_decrypt() {
+        let cipher = test_cipher(1);
+        let encrypted = encrypt_segments(cipher, &content(2 * SEGMENT_LENGTH + 1));
+
+        // Without the last segment the content ends on a full segment.
+        assert!(decrypt_segments_with(cipher, &encrypted[..2 * SEGMENT]).is_err());
+        // Without a segment in the middle the indices don't match.
+        let mut dropped = encrypted[..SEGMENT].to_vec();
+        dropped.extend_from_slice(&encrypted[2 * SEGMENT..]);
+        assert!(decrypt_segments_with(cipher, &dropped).is_err());
+        // Cut off inside the last segment.
+        assert!(decrypt_segments_with(cipher, &encrypted[..encrypted.len() - 1]).is_err());
+        // Not even a full nonce and tag.
+        assert!(decrypt_segments_with(cipher, &encrypted[..NONCE_LENGTH]).is_err());
+        assert!(decrypt_segments_with(cipher, &[]).is_err());
+    }
+
+    // An empty file still ends in a last segment, so cutting it off can't pass as empty content.
+    #[test]
+    fn empty_content_has_a_last_segment() {
+        let cipher = test_cipher(1);
+        let empty = encrypt_segments(cipher, &[]);
+        assert_eq!(empty.len(), NONCE_LENGTH + TAG_LENGTH);
+        assert_eq!(decrypt_segments_with(cipher, &empty).unwrap(), Vec::<u8>::new());
+
+        let full = encrypt_segments(cipher, &content(SEGMENT_LENGTH));
+        assert_eq!(full.len(), SEGMENT + NONCE_LENGTH + TAG_LENGTH);
+        assert!(decrypt_segments_with(cipher, &full[..SEGMENT]).is_err());
+    }
+}
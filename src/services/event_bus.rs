@@ -0,0 +1,51 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// How many events a slow connection may fall behind before it starts missing events.
+const CHANNEL_CAPACITY: usize = 64;
+
+// A file as it appears in a `FileEvent`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileRef {
+    pub id: String,
+    pub filename: String,
+}
+
+// Something that happened to one of a user's files, sent to their /events connections.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FileEvent {
+    FileUploaded { file: FileRef },
+    FileDeleted { file: FileRef },
+}
+
+// Fans file events out to every open /events connection of the user they concern, with one
+// broadcast channel per user that has a connection open. Shared by the handlers through `Data`.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    channels: Arc<Mutex<HashMap<String, broadcast::Sender<FileEvent>>>>,
+}
+
+impl EventBus {
+    // Starts receiving the events of `username`.
+    pub fn subscribe(&self, username: &str) -> broadcast::Receiver<FileEvent> {
+        let mut channels = self.channels.lock().unwrap();
+        // Drop the channels of users whose connections have all closed.
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels
+            .entry(username.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    // Sends an event to the open connections of `username`. Does nothing if there are none.
+    pub fn publish(&self, username: &str, event: FileEvent) {
+        let channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(username) {
+            // Only fails when every receiver is gone, which is cleaned up on the next subscribe.
+            let _ = sender.send(event);
+        }
+    }
+}
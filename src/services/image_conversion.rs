@@ -0,0 +1,117 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageError, ImageReader};
+use std::io::Cursor;
+
+// The formats images can be converted to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    // Parses the `?format=` query parameter.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(Self::Png),
+            "jpeg" | "jpg" => Some(Self::Jpeg),
+            "webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        match mime.trim().to_ascii_lowercase().as_str() {
+            "image/png" => Some(Self::Png),
+            "image/jpeg" => Some(Self::Jpeg),
+            "image/webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    // Picks the supported format the client prefers, according to the q-values of an `Accept` header.
+    // Wildcards like `image/*` are ignored, as they don't say which format the client wants.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        accept
+            .split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let format = Self::from_mime(parts.next()?)?;
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((format, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(format, _)| format)
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+        }
+    }
+}
+
+// Decodes `bytes` in whatever format they are stored in, and encodes them as `format`.
+//
+// `quality` (0-100) only applies to JPEG. WebP is always encoded losslessly, as that is the only
+// WebP encoding the `image` crate supports.
+pub fn convert_image(bytes: &[u8], format: ImageFormat, quality: u8) -> Result<Vec<u8>, ImageError> {
+    encode(&image::load_from_memory(bytes)?, format, quality)
+}
+
+// Scales an image down to fit within `width` x `height`, keeping its aspect ratio, and encodes it
+// as `format`. Images that already fit are only re-encoded, never enlarged.
+pub fn resize_image(bytes: &[u8], width: u32, height: u32, format: ImageFormat, quality: u8) -> Result<Vec<u8>, ImageError> {
+    let image = image::load_from_memory(bytes)?;
+    let image = if image.width() > width || image.height() > height {
+        image.resize(width, height, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    encode(&image, format, quality)
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat, quality: u8) -> Result<Vec<u8>, ImageError> {
+    let mut output = Vec::new();
+
+    match format {
+        ImageFormat::Png => image.write_with_encoder(PngEncoder::new(&mut output))?,
+        // JPEG has no alpha channel.
+        ImageFormat::Jpeg => image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality.max(1)))?,
+        ImageFormat::Webp => image.write_with_encoder(WebPEncoder::new_lossless(&mut output))?,
+    }
+
+    Ok(output)
+}
+
+// Reads the width and height of an image from its header, without decoding the pixels.
+//
+// # Returns
+// - `None` if the format isn't recognized or the header is malformed.
+pub fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
@@ -0,0 +1,8 @@
+pub mod encryption;
+pub mod image_conversion;
+pub mod multipart_mixed;
+pub mod notification;
+pub mod qr_code;
+pub mod upload_limiter;
+pub mod event_bus;
+pub mod upload_stream;
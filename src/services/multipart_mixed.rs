@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+// Loads one part of the body, resolving to its headers, each ending in `\r\n`, and its content.
+pub type PartFuture = Pin<Box<dyn Future<Output = io::Result<(String, Vec<u8>)>> + Send>>;
+
+// Writes a `multipart/mixed` body, loading each part only once the previous one has been read.
+//
+// Only one part is held in memory at a time, and the client receives the first part while the
+// rest are still to be loaded. A part that fails to load fails the read, which cuts the response
+// off before its closing boundary, so clients can tell the body is incomplete.
+pub struct MultipartMixedReader {
+    boundary: String,
+    parts: VecDeque<PartFuture>,
+    loading: Option<PartFuture>,
+    buffer: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl MultipartMixedReader {
+    pub fn new(boundary: String, parts: Vec<PartFuture>) -> Self {
+        Self {
+            boundary,
+            parts: parts.into(),
+            loading: None,
+            buffer: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+}
+
+impl AsyncRead for MultipartMixedReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.position < this.buffer.len() {
+                let take = buf.remaining().min(this.buffer.len() - this.position);
+                buf.put_slice(&this.buffer[this.position..this.position + take]);
+                this.position += take;
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(loading) = this.loading.as_mut() {
+                let (headers, content) = match loading.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => result?,
+                };
+                this.loading = None;
+                let mut part = format!("--{}\r\n{}\r\n", this.boundary, headers).into_bytes();
+                part.extend_from_slice(&content);
+                part.extend_from_slice(b"\r\n");
+                this.buffer = part;
+                this.position = 0;
+            } else if let Some(next) = this.parts.pop_front() {
+                this.loading = Some(next);
+            } else if !this.finished {
+                this.finished = true;
+                this.buffer = format!("--{}--\r\n", this.boundary).into_bytes();
+                this.position = 0;
+            } else {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use serde::Serialize;
+use crate::database::user_db::NotificationPreferences;
+
+// Sent to a user when another user shares a file with them.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileSharedEvent {
+    pub file_id: String,
+    pub filename: String,
+    pub shared_by: String,
+    pub recipient: String,
+}
+
+pub trait NotificationService {
+    async fn file_shared(&self, event: &FileSharedEvent) -> Result<(), String>;
+}
+
+// Placeholder until an email provider is configured - logs the email that would have been sent.
+pub struct EmailNotificationService;
+
+impl NotificationService for EmailNotificationService {
+    async fn file_shared(&self, event: &FileSharedEvent) -> Result<(), String> {
+        tracing::info!(
+            recipient = %event.recipient,
+            shared_by = %event.shared_by,
+            filename = %event.filename,
+            "Sending file shared email"
+        );
+        Ok(())
+    }
+}
+
+// POSTs the event as JSON to a user supplied URL.
+pub struct WebhookNotificationService {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookNotificationService {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+impl NotificationService for WebhookNotificationService {
+    async fn file_shared(&self, event: &FileSharedEvent) -> Result<(), String> {
+        self.client
+            .post(&self.url)
+            .json(event)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Notifies the recipient of a share through every channel they have opted into.
+//
+// The notifications are sent in the background, so a slow or failing webhook never delays
+// the request that triggered it. Failures are only logged.
+pub fn notify_file_shared(preferences: &NotificationPreferences, event: FileSharedEvent) {
+    if preferences.email_on_share {
+        let event = event.clone();
+        tokio::spawn(async move {
+            if let Err(err) = EmailNotificationService.file_shared(&event).await {
+                tracing::warn!(recipient = %event.recipient, "Failed to send share email: {}", err);
+            }
+        });
+    }
+
+    if let Some(url) = preferences.webhook_on_share.clone() {
+        tokio::spawn(async move {
+            if let Err(err) = WebhookNotificationService::new(url).file_shared(&event).await {
+                tracing::warn!(recipient = %event.recipient, "Failed to call share webhook: {}", err);
+            }
+        });
+    }
+}
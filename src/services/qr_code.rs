@@ -0,0 +1,22 @@
+use image::codecs::png::PngEncoder;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Luma};
+use qrcode::QrCode;
+
+// Encodes `data` as a QR code, returned as a `size` by `size` pixel greyscale PNG.
+//
+// The code is drawn with whole pixels per module as large as fits, then scaled to the exact size
+// without smoothing, so the modules keep their sharp edges.
+pub fn qr_code_png(data: &str, size: u32) -> Result<Vec<u8>, String> {
+    let code = QrCode::new(data.as_bytes()).map_err(|e| format!("Can't encode the URL as a QR code: {}", e))?;
+    let mut image = code.render::<Luma<u8>>().max_dimensions(size, size).build();
+    if image.width() != size || image.height() != size {
+        image = imageops::resize(&image, size, size, FilterType::Nearest);
+    }
+
+    let mut output = Vec::new();
+    DynamicImage::ImageLuma8(image)
+        .write_with_encoder(PngEncoder::new(&mut output))
+        .map_err(|e| e.to_string())?;
+    Ok(output)
+}
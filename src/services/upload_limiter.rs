@@ -0,0 +1,160 @@
+use poem::http::header::RETRY_AFTER;
+use poem::http::StatusCode;
+use poem::{Error, Response};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::config::UploadConfig;
+
+// Bounds how many uploads are processed at the same time, in total and per user. Shared by the
+// upload handlers through `Data`.
+#[derive(Clone)]
+pub struct UploadLimiter {
+    semaphore: Arc<Semaphore>,
+    queue_timeout: Duration,
+    // Uploads in progress per user. Users without any are removed.
+    in_progress: Arc<Mutex<HashMap<String, usize>>>,
+    max_per_user: usize,
+}
+
+// A slot for one upload, freed when it is dropped.
+pub struct UploadPermit {
+    _slot: OwnedSemaphorePermit,
+    _user: UserSlot,
+}
+
+// Counts an upload of `username` as in progress until it is dropped.
+struct UserSlot {
+    in_progress: Arc<Mutex<HashMap<String, usize>>>,
+    username: String,
+}
+
+impl Drop for UserSlot {
+    fn drop(&mut self) {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        if let Some(count) = in_progress.get_mut(&self.username) {
+            *count -= 1;
+            if *count == 0 {
+                in_progress.remove(&self.username);
+            }
+        }
+    }
+}
+
+impl UploadLimiter {
+    pub fn new(config: &UploadConfig) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(config.max_concurrent)),
+            queue_timeout: config.queue_timeout,
+            in_progress: Arc::default(),
+            max_per_user: config.max_per_user,
+        }
+    }
+
+    // Counts the upload against the user's UPLOAD_MAX_PER_USER.
+    fn reserve(&self, username: &str) -> Option<UserSlot> {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        let count = in_progress.entry(username.to_string()).or_default();
+        if *count >= self.max_per_user {
+            return None;
+        }
+        *count += 1;
+        Some(UserSlot { in_progress: self.in_progress.clone(), username: username.to_string() })
+    }
+
+    // Takes a slot for an upload by `username`, waiting up to the queue timeout for a free one.
+    // A user already at UPLOAD_MAX_PER_USER uploads is turned away at once, so one client can't take
+    // every slot.
+    //
+    // # Returns
+    // - `Err(Error)` with `429 Too Many Requests` if the user already has UPLOAD_MAX_PER_USER uploads in progress.
+    // - `Err(Error)` with `503 Service Unavailable` and a `Retry-After` header if no slot freed up in time.
+    pub async fn acquire(&self, username: &str) -> Result<UploadPermit, Error> {
+        let Some(user) = self.reserve(username) else {
+            tracing::warn!(username, "Rejected an upload, the user has too many uploads in progress");
+            return Err(Error::from_response(
+                Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(RETRY_AFTER, 1)
+                    .body(format!("At most {} uploads per user can be in progress at once", self.max_per_user)),
+            ));
+        };
+        match tokio::time::timeout(self.queue_timeout, self.semaphore.clone().acquire_owned()).await {
+            Ok(Ok(slot)) => Ok(UploadPermit { _slot: slot, _user: user }),
+            // The semaphore is never closed, so a closed semaphore is treated like a full one.
+            Ok(Err(_)) | Err(_) => {
+                tracing::warn!("Rejected an upload, too many uploads are in progress");
+                Err(Error::from_response(
+                    Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header(RETRY_AFTER, 1)
+                        .body("Too many uploads in progress, try again shortly"),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn limiter(max_concurrent: usize, max_per_user: usize) -> UploadLimiter {
+        UploadLimiter::new(&UploadConfig {
+            max_concurrent,
+            max_per_user,
+            queue_timeout: Duration::from_millis(50),
+            ..Config::load().uploads
+        })
+    }
+
+    #[tokio::test]
+    async fn uploads_beyond_the_limit_get_503() {
+        let limiter = limiter(1, 5);
+        let _permit = limiter.acquire("alice").await.unwrap();
+
+        let response = limiter.acquire("bob").await.err().unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn waiting_uploads_get_the_next_free_slot() {
+        let limiter = limiter(1, 5);
+        let permit = limiter.acquire("alice").await.unwrap();
+
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire("bob").await.is_ok() }
+        });
+        drop(permit);
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn users_beyond_their_limit_get_429() {
+        let limiter = limiter(10, 2);
+        let _first = limiter.acquire("alice").await.unwrap();
+        let _second = limiter.acquire("alice").await.unwrap();
+
+        let response = limiter.acquire("alice").await.err().unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+
+        // Other users aren't held back by alice.
+        assert!(limiter.acquire("bob").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn finished_uploads_free_the_users_slot() {
+        let limiter = limiter(10, 1);
+        let permit = limiter.acquire("alice").await.unwrap();
+        assert!(limiter.acquire("alice").await.is_err());
+
+        drop(permit);
+        assert!(limiter.in_progress.lock().unwrap().is_empty());
+        assert!(limiter.acquire("alice").await.is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+use bson::doc;
+use bson::oid::ObjectId;
+use futures_util::io::AsyncWriteExt;
+use mongodb::gridfs::{GridFsBucket, GridFsUploadStream};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::config::UploadConfig;
+use crate::services::encryption::SegmentEncryptor;
+use crate::storage::{FsStorage, StagedContent, Storage};
+
+// How much of an upload is read at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Where the content of a received file ended up.
+pub enum ReceivedContent {
+    // Small files are kept in memory, to be stored in a blob.
+    Buffered(Vec<u8>),
+    // Large files have already been written to GridFS under this id.
+    GridFs(ObjectId),
+    // Large files have already been stored in the filesystem backend under their hash, with a
+    // reference for this file, as if `StorageBackend::put` had been called.
+    Stored,
+}
+
+pub struct ReceivedFile {
+    pub content: ReceivedContent,
+    // Hex encoded SHA-256 of the content, computed while it was received.
+    pub hash: String,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub enum ReceiveError {
+    TooLarge,
+    // The client's upload couldn't be read, e.g. because the connection dropped.
+    Read(std::io::Error),
+    Storage(mongodb::error::Error),
+}
+
+// Where a file that outgrew the buffer is written to as it arrives.
+enum Spill<'a> {
+    GridFs(GridFsUploadStream, Option<SegmentEncryptor>),
+    Staged(&'a FsStorage, StagedContent),
+}
+
+impl<'a> Spill<'a> {
+    // The filesystem backend takes large files itself, with MongoDB they go to GridFS.
+    async fn open(storage: &'a Storage, bucket: &GridFsBucket, filename: &str) -> Result<Self, ReceiveError> {
+        match storage {
+            Storage::Filesystem(storage) => {
+                let staged = storage.stage().await.map_err(|e| ReceiveError::Storage(e.into()))?;
+                Ok(Spill::Staged(storage, staged))
+            }
+            Storage::Mongo(_) => {
+                let encryptor = SegmentEncryptor::new();
+                let stream = bucket
+                    .open_upload_stream(filename)
+                    .metadata(doc! { "encrypted": encryptor.is_some() })
+                    .await
+                    .map_err(ReceiveError::Storage)?;
+                Ok(Spill::GridFs(stream, encryptor))
+            }
+        }
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), ReceiveError> {
+        let result = match self {
+            Spill::GridFs(stream, Some(encryptor)) => stream.write_all(&encryptor.update(bytes)).await,
+            Spill::GridFs(stream, None) => stream.write_all(bytes).await,
+            Spill::Staged(_, staged) => staged.write(bytes).await,
+        };
+        result.map_err(|e| ReceiveError::Storage(e.into()))
+    }
+
+    async fn finish(self, hash: &str) -> Result<ReceivedContent, ReceiveError> {
+        match self {
+            Spill::GridFs(mut stream, encryptor) => {
+                if let Some(encryptor) = encryptor {
+                    stream.write_all(&encryptor.finish()).await.map_err(|e| ReceiveError::Storage(e.into()))?;
+                }
+                stream.close().await.map_err(|e| ReceiveError::Storage(e.into()))?;
+                let id = stream.id().as_object_id().expect("GridFS generates ObjectIds");
+                Ok(ReceivedContent::GridFs(id))
+            }
+            Spill::Staged(storage, staged) => {
+                storage.store_staged(hash, staged).await.map_err(ReceiveError::Storage)?;
+                Ok(ReceivedContent::Stored)
+            }
+        }
+    }
+
+    // Removes whatever was already written.
+    async fn abort(self) {
+        match self {
+            Spill::GridFs(mut stream, _) => {
+                if let Err(abort_error) = stream.abort().await {
+                    tracing::error!("Failed to abort GridFS upload: {}", abort_error);
+                }
+            }
+            Spill::Staged(_, staged) => staged.discard().await,
+        }
+    }
+}
+
+// Receives an uploaded file without holding more than `gridfs_threshold_bytes` of it in memory.
+//
+// The content is buffered until it grows past the threshold. From then on the buffer and every
+// following chunk are written to a GridFS upload stream instead, or with STORAGE_BACKEND=fs to a
+// file that is moved into the storage directory once the hash is known. The upload is aborted as
+// soon as it exceeds `max_file_bytes`, removing whatever was already written.
+//
+// With FILE_ENCRYPTION_KEY set, content written to GridFS or the storage directory is encrypted on
+// the way, and the GridFS file is marked with `metadata.encrypted`. Buffered content is encrypted
+// when its blob is stored.
+pub async fn receive_file(
+    mut reader: impl AsyncRead + Unpin,
+    storage: &Storage,
+    bucket: &GridFsBucket,
+    filename: &str,
+    config: &UploadConfig,
+) -> Result<ReceivedFile, ReceiveError> {
+    let mut hasher = Sha256::new();
+    let mut buffer = Vec::new();
+    let mut spill: Option<Spill> = None;
+    let mut received: u64 = 0;
+    let mut chunk = vec![0; CHUNK_SIZE];
+
+    let result = async {
+        loop {
+            let read = reader.read(&mut chunk).await.map_err(ReceiveError::Read)?;
+            if read == 0 {
+                return Ok(());
+            }
+            received += read as u64;
+            if received > config.max_file_bytes {
+                return Err(ReceiveError::TooLarge);
+            }
+            hasher.update(&chunk[..read]);
+
+            if spill.is_none() && buffer.len() + read > config.gridfs_threshold_bytes {
+                let mut opened = Spill::open(storage, bucket, filename).await?;
+                let written = opened.write(&buffer).await;
+                // Stored before checking, so a failed write is cleaned up below.
+                spill = Some(opened);
+                written?;
+                buffer = Vec::new();
+            }
+            match spill.as_mut() {
+                Some(spill) => spill.write(&chunk[..read]).await?,
+                None => buffer.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+    .await;
+
+    let hash = format!("{:x}", hasher.finalize());
+    match (result, spill) {
+        (Ok(()), None) => Ok(ReceivedFile { content: ReceivedContent::Buffered(buffer), hash, size: received }),
+        (Ok(()), Some(spill)) => {
+            let content = spill.finish(&hash).await?;
+            Ok(ReceivedFile { content, hash, size: received })
+        }
+        (Err(e), None) => Err(e),
+        (Err(e), Some(spill)) => {
+            spill.abort().await;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, StorageConfig};
+    use mongodb::{Client, Database};
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    // A MongoDB that can't be reached, so nothing gets past the first database access.
+    async fn database() -> Database {
+        Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("upload_stream_tests")
+    }
+
+    fn config(max_file_bytes: u64, gridfs_threshold_bytes: usize) -> UploadConfig {
+        UploadConfig { max_file_bytes, gridfs_threshold_bytes, ..Config::load().uploads }
+    }
+
+    fn filesystem_storage(db: &Database) -> (Storage, PathBuf) {
+        let root = std::env::temp_dir().join(format!("rustexam-upload-{:016x}", rand::random::<u64>()));
+        let storage = Storage::new(&StorageConfig::Filesystem { root: root.clone() }, Arc::new(db.collection("blobs")));
+        (storage, root)
+    }
+
+    // Reads `content`, noting whether anything was staged in `root` while it was being read.
+    struct WatchingReader<'a> {
+        content: &'a [u8],
+        root: PathBuf,
+        staged: bool,
+    }
+
+    impl AsyncRead for WatchingReader<'_> {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            self.staged |= std::fs::read_dir(&self.root).unwrap().count() > 0;
+            Pin::new(&mut self.content).poll_read(cx, buf)
+        }
+    }
+
+    // With the filesystem backend, a large file is written below STORAGE_PATH instead of to GridFS.
+    // Storing it fails here as its reference can't be counted, and the staged file is removed again.
+    #[tokio::test]
+    async fn large_files_are_staged_by_the_filesystem_backend() {
+        let db = database().await;
+        let (storage, root) = filesystem_storage(&db);
+        let content = vec![7u8; 300 * 1024];
+        let mut reader = WatchingReader { content: &content, root: root.clone(), staged: false };
+
+        let result = receive_file(&mut reader, &storage, &db.gridfs_bucket(None), "big.bin", &config(1 << 20, 64 * 1024)).await;
+        assert!(matches!(result, Err(ReceiveError::Storage(_))));
+        assert!(reader.staged);
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn staged_files_are_removed_when_the_upload_is_too_large() {
+        let db = database().await;
+        let (storage, root) = filesystem_storage(&db);
+        let content = vec![7u8; 300 * 1024];
+
+        let result = receive_file(&content[..], &storage, &db.gridfs_bucket(None), "big.bin", &config(200 * 1024, 64 * 1024)).await;
+        assert!(matches!(result, Err(ReceiveError::TooLarge)));
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_hash_is_computed_while_receiving() {
+        let db = database().await;
+        let storage = Storage::new(&StorageConfig::Mongo, Arc::new(db.collection("blobs")));
+        let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 253) as u8).collect();
+
+        let received = receive_file(&content[..], &storage, &db.gridfs_bucket(None), "file.bin", &config(1 << 21, 1 << 21))
+            .await
+            .unwrap();
+        assert_eq!(received.hash, format!("{:x}", Sha256::digest(&content)));
+        assert_eq!(received.size, content.len() as u64);
+        assert!(matches!(received.content, ReceivedContent::Buffered(bytes) if bytes == content));
+    }
+
+    // An endless upload is cut off right after the limit, rather than read into memory as a whole.
+    #[tokio::test]
+    async fn reading_stops_at_the_size_limit() {
+        let db = database().await;
+        let storage = Storage::new(&StorageConfig::Mongo, Arc::new(db.collection("blobs")));
+        let mut endless = tokio::io::repeat(7).take(u64::MAX);
+
+        let result = receive_file(&mut endless, &storage, &db.gridfs_bucket(None), "endless.bin", &config(1 << 20, 1 << 21)).await;
+        assert!(matches!(result, Err(ReceiveError::TooLarge)));
+        let read = u64::MAX - endless.limit();
+        assert!(read <= (1 << 20) + CHUNK_SIZE as u64);
+    }
+}
@@ -0,0 +1,387 @@
+use bson::doc;
+use mongodb::{error::Error, Collection};
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use crate::config::StorageConfig;
+use crate::database::blob_db::{add_blob_reference, get_blob, release_blob, store_blob, Blob};
+use crate::database::{is_duplicate_key_error, TracedCollection};
+use crate::services::encryption::{self, SegmentEncryptor};
+
+// Where the content of uploaded files is kept, keyed by its SHA-256 hash. Content is reference
+// counted like blobs are: `put` stores new content or adds a reference to identical content, and
+// `delete` removes a reference, deleting the content once nothing references it anymore.
+//
+// Files streamed into GridFS and content stored inline in old documents don't go through it. With
+// the filesystem backend, large files are streamed into the backend instead of GridFS, see
+// `FsStorage::stage`.
+pub trait StorageBackend {
+    fn put(&self, key: &str, bytes: Vec<u8>) -> impl Future<Output = Result<(), Error>> + Send;
+
+    // # Returns
+    // - `Ok(None)` if no content is stored under `key`.
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>, Error>> + Send;
+
+    fn delete(&self, key: &str) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+// The backend selected by STORAGE_BACKEND, shared by all handlers.
+#[derive(Clone)]
+pub enum Storage {
+    Mongo(MongoStorage),
+    Filesystem(FsStorage),
+}
+
+impl Storage {
+    // Panics if the STORAGE_PATH directory can't be created, as every upload would fail otherwise.
+    pub fn new(config: &StorageConfig, blobs: Arc<Collection<Blob>>) -> Self {
+        match config {
+            StorageConfig::Mongo => Storage::Mongo(MongoStorage { blobs }),
+            StorageConfig::Filesystem { root } => {
+                std::fs::create_dir_all(root)
+                    .unwrap_or_else(|e| panic!("Failed to create STORAGE_PATH {:?}: {}", root, e));
+                Storage::Filesystem(FsStorage { root: root.clone(), blobs })
+            }
+        }
+    }
+}
+
+impl StorageBackend for Storage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+        match self {
+            Storage::Mongo(storage) => storage.put(key, bytes).await,
+            Storage::Filesystem(storage) => storage.put(key, bytes).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            Storage::Mongo(storage) => storage.get(key).await,
+            Storage::Filesystem(storage) => storage.get(key).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match self {
+            Storage::Mongo(storage) => storage.delete(key).await,
+            Storage::Filesystem(storage) => storage.delete(key).await,
+        }
+    }
+}
+
+// Stores content in the `blobs` collection, see `blob_db`.
+#[derive(Clone)]
+pub struct MongoStorage {
+    blobs: Arc<Collection<Blob>>,
+}
+
+impl StorageBackend for MongoStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+        store_blob(&self.blobs, key, bytes).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        get_blob(&self.blobs, key).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        release_blob(&self.blobs, key).await.map(|_| ())
+    }
+}
+
+// How often, and how far apart, `FsStorage` checks whether a pending blob is done before giving up.
+const PENDING_ATTEMPTS: u32 = 50;
+const PENDING_RETRY: Duration = Duration::from_millis(20);
+
+// Stores content in a file per hash below `root`, spread over subdirectories named after the
+// first two characters of the hash. The references are still counted in the `blobs` collection,
+// by blobs without content.
+//
+// Encrypted content is written to a file ending in `.enc`, encrypted in segments by
+// `SegmentEncryptor` so large files can be encrypted while they are streamed in. Content stored in
+// MongoDB before switching to the filesystem stays readable there.
+//
+// A blob is marked `pending` while its file is written or removed, so content that is uploaded
+// while the last reference to it is being deleted waits for the removal and is then written again,
+// instead of being referenced while its file disappears.
+#[derive(Clone)]
+pub struct FsStorage {
+    root: PathBuf,
+    blobs: Arc<Collection<Blob>>,
+}
+
+// Content streamed into `FsStorage` a piece at a time, kept in a temporary file below the root
+// until `FsStorage::store_staged` moves it into place.
+pub struct StagedContent {
+    file: fs::File,
+    path: PathBuf,
+    encryptor: Option<SegmentEncryptor>,
+}
+
+impl StagedContent {
+    pub async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.encryptor.as_mut() {
+            Some(encryptor) => self.file.write_all(&encryptor.update(bytes)).await,
+            None => self.file.write_all(bytes).await,
+        }
+    }
+
+    // Removes the temporary file of content that won't be stored.
+    pub async fn discard(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.path).await;
+    }
+
+    // Writes the rest of the content to the temporary file.
+    //
+    // # Returns
+    // - `Ok((path, encrypted))` with the path of the complete temporary file.
+    async fn finish(mut self) -> io::Result<(PathBuf, bool)> {
+        let encrypted = self.encryptor.is_some();
+        let written = async {
+            if let Some(encryptor) = self.encryptor.take() {
+                self.file.write_all(&encryptor.finish()).await?;
+            }
+            self.file.flush().await
+        }
+        .await;
+        match written {
+            Ok(()) => Ok((self.path, encrypted)),
+            Err(e) => {
+                let _ = fs::remove_file(&self.path).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl FsStorage {
+    // The path of the content stored under `key`. Keys are hashes, anything else is refused so a
+    // key can never point outside of `root`.
+    fn path(&self, key: &str, encrypted: bool) -> io::Result<PathBuf> {
+        if key.len() < 2 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid storage key {:?}", key)));
+        }
+        let filename = if encrypted { format!("{}.enc", key) } else { key.to_string() };
+        Ok(self.root.join(&key[..2]).join(filename))
+    }
+
+    // Starts streaming new content in. It is stored by `store_staged` once it is complete, and
+    // encrypted on the way when FILE_ENCRYPTION_KEY is set.
+    pub async fn stage(&self) -> io::Result<StagedContent> {
+        // Kept in the root, so it can be moved into place without copying.
+        let path = self.root.join(format!(".staged.{:016x}.tmp", rand::random::<u64>()));
+        let file = fs::File::create(&path).await?;
+        Ok(StagedContent { file, path, encryptor: SegmentEncryptor::new() })
+    }
+
+    // Stores the staged content under `key`, or adds a reference to identical content if it is
+    // already stored, in which case the staged content is thrown away.
+    pub async fn store_staged(&self, key: &str, staged: StagedContent) -> Result<(), Error> {
+        let (temporary, encrypted) = staged.finish().await?;
+        let stored = self.claim(key, &temporary, encrypted).await;
+        // Only still there if it wasn't moved into place.
+        let _ = fs::remove_file(&temporary).await;
+        stored
+    }
+
+    // Adds a reference to the content stored under `key`, moving the complete file at `temporary`
+    // into place if there is none.
+    async fn claim(&self, key: &str, temporary: &Path, encrypted: bool) -> Result<(), Error> {
+        let blobs = TracedCollection::from(&*self.blobs);
+        for _ in 0..PENDING_ATTEMPTS {
+            if add_blob_reference(&self.blobs, key).await? {
+                return Ok(());
+            }
+
+            let blob = Blob { hash: key.to_string(), content: None, ref_count: 1, nonce: None, pending: true };
+            match blobs.insert_one(blob).await {
+                Ok(_) => {}
+                // Someone else is writing or removing the same content, wait for them.
+                Err(e) if is_duplicate_key_error(&e) => {
+                    tokio::time::sleep(PENDING_RETRY).await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+
+            let placed = async {
+                self.place(key, temporary, encrypted).await?;
+                blobs.update_one(doc! { "_id": key }, doc! { "$unset": { "pending": "" } }).await?;
+                Ok(())
+            }
+            .await;
+            if placed.is_err() {
+                // Nobody else can reference a pending blob, so it can go again.
+                let _ = self.remove_files(key).await;
+                let _ = blobs.delete_one(doc! { "_id": key, "pending": true }).await;
+            }
+            return placed;
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, format!("Stored content of {} stayed pending", key)).into())
+    }
+
+    // Moves a complete temporary file into place as the content stored under `key`.
+    async fn place(&self, key: &str, temporary: &Path, encrypted: bool) -> io::Result<()> {
+        let path = self.path(key, encrypted)?;
+        let directory = path.parent().expect("content is stored in a subdirectory of the root");
+        fs::create_dir_all(directory).await?;
+        // This replaces any file left behind by a removal that failed halfway.
+        fs::rename(temporary, &path).await
+    }
+
+    // Reads the content stored under `key` from its file, or `None` if there is none.
+    async fn read_file(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        if let Some(data) = Self::read(self.path(key, true)?).await? {
+            return encryption::decrypt_segments(&data).map(Some);
+        }
+        Self::read(self.path(key, false)?).await
+    }
+
+    // Reads the file at `path`, or `None` if there is none.
+    async fn read(path: PathBuf) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn remove_files(&self, key: &str) -> io::Result<()> {
+        for encrypted in [true, false] {
+            match fs::remove_file(self.path(key, encrypted)?).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for FsStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+        // As with blobs, skip writing the content if identical content is already stored.
+        if add_blob_reference(&self.blobs, key).await? {
+            return Ok(());
+        }
+
+        let mut staged = self.stage().await?;
+        if let Err(e) = staged.write(&bytes).await {
+            staged.discard().await;
+            return Err(e.into());
+        }
+        self.store_staged(key, staged).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(data) = self.read_file(key).await? {
+            return Ok(Some(data));
+        }
+        get_blob(&self.blobs, key).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let blobs = TracedCollection::from(&*self.blobs);
+        blobs
+            .update_one(doc! { "_id": key }, doc! { "$inc": { "ref_count": -1 } })
+            .await?;
+        // Only removed if nobody added a reference in the meantime, and marked pending first so
+        // nobody can while the file is removed.
+        let unreferenced = blobs
+            .find_one_and_update(
+                doc! { "_id": key, "ref_count": { "$lte": 0 }, "pending": { "$ne": true } },
+                doc! { "$set": { "pending": true } },
+                None,
+            )
+            .await?;
+        if unreferenced.is_none() {
+            return Ok(());
+        }
+
+        if let Err(e) = self.remove_files(key).await {
+            // The file may still be there, so the blob stays and can be referenced again.
+            let _ = blobs.update_one(doc! { "_id": key }, doc! { "$unset": { "pending": "" } }).await;
+            return Err(e.into());
+        }
+        blobs.delete_one(doc! { "_id": key, "pending": true }).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::Client;
+    use sha2::{Digest, Sha256};
+
+    // A backend in a new directory below the system's temporary directory, counting references in a
+    // MongoDB that can't be reached.
+    async fn storage() -> FsStorage {
+        let root = std::env::temp_dir().join(format!("rustexam-storage-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&root).unwrap();
+        let blobs = Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("storage_tests")
+            .collection("blobs");
+        FsStorage { root, blobs: Arc::new(blobs) }
+    }
+
+    fn entries(directory: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().path()).collect()
+    }
+
+    #[tokio::test]
+    async fn staged_content_round_trips_through_the_filesystem() {
+        let storage = storage().await;
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let key = format!("{:x}", Sha256::digest(&content));
+
+        let mut staged = storage.stage().await.unwrap();
+        for chunk in content.chunks(64 * 1024) {
+            staged.write(chunk).await.unwrap();
+        }
+        let (temporary, encrypted) = staged.finish().await.unwrap();
+        storage.place(&key, &temporary, encrypted).await.unwrap();
+
+        assert!(!temporary.exists());
+        assert!(storage.path(&key, encrypted).unwrap().exists());
+        assert_eq!(storage.read_file(&key).await.unwrap(), Some(content));
+
+        storage.remove_files(&key).await.unwrap();
+        assert_eq!(storage.read_file(&key).await.unwrap(), None);
+        // Removing content that is already gone is fine.
+        storage.remove_files(&key).await.unwrap();
+        std::fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn discarded_and_failed_content_leaves_nothing_behind() {
+        let storage = storage().await;
+
+        let mut staged = storage.stage().await.unwrap();
+        staged.write(b"never stored").await.unwrap();
+        staged.discard().await;
+        assert!(entries(&storage.root).is_empty());
+
+        // The references can't be counted, so nothing is stored.
+        assert!(storage.put("ab12", b"content".to_vec()).await.is_err());
+        assert!(entries(&storage.root).is_empty());
+        std::fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_must_be_hashes() {
+        let storage = storage().await;
+        for key in ["", "a", "../etc/passwd", "ab/cd", "not-hex"] {
+            assert_eq!(storage.path(key, false).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            assert!(storage.read_file(key).await.is_err());
+        }
+        assert_eq!(storage.path("ab12", false).unwrap(), storage.root.join("ab").join("ab12"));
+        assert_eq!(storage.path("ab12", true).unwrap(), storage.root.join("ab").join("ab12.enc"));
+        std::fs::remove_dir_all(&storage.root).unwrap();
+    }
+}